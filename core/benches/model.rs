@@ -0,0 +1,132 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    Benchmarks for the per-period hot path, i.e. running the model of every
+    speaker over a period of sense data. Run them with
+
+        cargo bench -p speakersafetyd-core
+
+    Each case times one period for 2, 4 and 6 speaker machines at 48 and
+    96 kHz, and for the 6 speaker one with the single precision model
+    (Globals/single_precision) too. The sense data is a mix of tones and
+    noise around -12 dBFS, so the model sees music-like power rather than
+    silence.
+*/
+use std::f32::consts::PI;
+use std::hint::black_box;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use configparser::ini::Ini;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use speakersafetyd_core::helpers;
+use speakersafetyd_core::types::{Globals, Speaker};
+
+/// (config, speakers) for the machines benchmarked
+const MACHINES: &[(&str, usize)] = &[("j313.conf", 2), ("j413.conf", 4), ("j314.conf", 6)];
+
+fn speakers(
+    conf: &str,
+    sample_rate: f32,
+    single_precision: bool,
+) -> (Globals, Vec<Speaker>, Vec<i16>) {
+    let mut cfg = Ini::new_cs();
+    cfg.load(
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../conf/apple")
+            .join(conf),
+    )
+    .unwrap();
+    cfg.set(
+        "Globals",
+        "single_precision",
+        Some(single_precision.to_string()),
+    );
+    let globals = Globals::parse(&cfg);
+
+    // An LCG is plenty for noise that only has to be the same every run
+    let mut seed: u32 = 0xbe4c;
+    let mut noise = || {
+        seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+        (seed >> 8) as f32 / (1 << 24) as f32 - 0.5
+    };
+    let mut buf = vec![0i16; globals.period * globals.channels];
+    let mut speakers = Vec::new();
+    for section in cfg.sections() {
+        let Some(name) = section.strip_prefix("Speaker/") else {
+            continue;
+        };
+        let mut spk = Speaker::offline(&globals, name, &cfg, 15.);
+        spk.set_sample_rate(sample_rate);
+
+        let z: f32 = helpers::parse_float(&cfg, &section, "z_nominal");
+        let vs_scale: f32 = helpers::parse_float(&cfg, &section, "vs_scale");
+        let is_scale: f32 = helpers::parse_float(&cfg, &section, "is_scale");
+        let vs_chan: usize = helpers::parse_int(&cfg, &section, "vs_chan");
+        let is_chan: usize = helpers::parse_int(&cfg, &section, "is_chan");
+        for (n, frame) in buf.chunks_mut(globals.channels).enumerate() {
+            let t = n as f32 / sample_rate;
+            let tones = [110., 440., 1760., 7040.]
+                .iter()
+                .map(|f| (2. * PI * f * t).sin())
+                .sum::<f32>();
+            let v = 4. * (tones / 8. + noise() / 2.);
+            frame[vs_chan] = (v / vs_scale * 32768.) as i16;
+            frame[is_chan] = (v / z / is_scale * 32768.) as i16;
+        }
+        speakers.push(spk);
+    }
+
+    (globals, speakers, buf)
+}
+
+fn bench(c: &mut Criterion, name: &str, cases: &[(&str, usize, f32, bool)]) {
+    let mut group = c.benchmark_group(name);
+
+    for &(conf, count, sample_rate, single_precision) in cases {
+        let (globals, mut speakers, buf) = speakers(conf, sample_rate, single_precision);
+        assert_eq!(speakers.len(), count);
+
+        // Per sample, so the cases compare against real time
+        group.throughput(Throughput::Elements(globals.period as u64));
+        let id = BenchmarkId::new(
+            format!("{} speakers", count),
+            format!("{} kHz", sample_rate / 1000.),
+        );
+        group.bench_function(id, |b| {
+            b.iter_custom(|iters| {
+                let mut time = Duration::ZERO;
+                for _ in 0..iters {
+                    let start = Instant::now();
+                    for spk in speakers.iter_mut() {
+                        black_box(spk.run_model(black_box(&buf)));
+                    }
+                    time += start.elapsed();
+                    // Stay clear of the limits, which would skew the numbers
+                    for spk in speakers.iter_mut() {
+                        spk.skip_model(1.);
+                    }
+                }
+                time
+            })
+        });
+    }
+
+    group.finish();
+}
+
+fn run_model(c: &mut Criterion) {
+    let cases: Vec<_> = MACHINES
+        .iter()
+        .flat_map(|&(conf, count)| [48000., 96000.].map(|rate| (conf, count, rate, false)))
+        .collect();
+    bench(c, "run_model", &cases);
+}
+
+fn run_model_f32(c: &mut Criterion) {
+    bench(c, "run_model_f32", &[("j314.conf", 6, 48000., true)]);
+}
+
+criterion_group!(benches, run_model, run_model_f32);
+criterion_main!(benches);
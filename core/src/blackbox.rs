@@ -0,0 +1,936 @@
+use crate::history::{EventRecord, History};
+use crate::schema::{self, Tag};
+use crate::types::{Globals, SpeakerParams, SpeakerState, MAX_NODES, MODEL_VERSION};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::ffi::{CStr, CString};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::slice;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/**
+    A blackbox dump is a single file, so it can't get separated from its
+    metadata. It consists of:
+
+    - MAGIC
+    - The format version (u32 LE)
+    - The header length in bytes (u32 LE)
+    - The header: JSON metadata, including a per-block index whose offsets
+      are relative to the start of the data
+    - The data: raw interleaved i16 LE samples for all blocks
+    - If a playback monitor is configured, its data for all blocks, in the
+      same format. The header's `monitor` object has its device, channel
+      count and offset within the data, and each block its own
+      `monitor_offset` (relative to that) and `monitor_count` in frames.
+      The monitor is read without blocking, so blocks may hold fewer
+      monitor frames than sense frames, or none at all.
+
+    Each block records its own sample rate (the top level one is that of
+    the first block) and the model state of every speaker after the model
+    ran on it, including the ambient temperature it was run against, so a
+    replay can follow the same ambient the daemon saw. Only the last block
+    of a dump taken because of a panic may have the state from before.
+
+    Dumps taken because of a panic also have a `context` object with the
+    last state of every speaker by name, the last PCM reads (frames, or
+    -errno) and the last control writes, each with how long before the
+    panic it was (`ago`, in seconds).
+
+    They also end with a snapshot of the full model state of every speaker
+    as of the last block, exactly as it was in memory (the JSON rounds, and
+    leaves out the outer nodes), so a replay can resume from it. The
+    header's `snapshot` object has its `offset` within the data and the
+    `name` and `group` of each speaker, in order. Each speaker is a
+    SNAPSHOT_SIZE byte record, all LE: t_coil, t_magnet and t_outer as f64,
+    then t_coil_hyst, t_magnet_hyst, min_gain, gain, power and impedance as
+    f32, amp_fault as i32 and t_ambient as f32. See snapshot().
+
+    The `model` object has the MODEL_VERSION the states were computed by,
+    and whether the model ran in deterministic mode or in single precision.
+    Snapshots from another model version are refused, see model_version().
+
+    Version 1 was a pair of files, `.fdr` (the JSON) and `.cvr` (the data).
+
+    The header is a Meta, which the daemon writes and the replay, fit and
+    plot tools read back. Everything but the essentials may be missing from
+    older dumps, so readers get defaults for what isn't there.
+*/
+const MAGIC: &[u8; 8] = b"SSDBBOX\0";
+pub const VERSION: u32 = 2;
+
+/// The header of a dump
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct Meta {
+    pub schema: Option<Tag>,
+    /// Why the dump was taken
+    pub message: String,
+    pub machine: String,
+    /// Of the first block
+    pub sample_rate: i32,
+    pub channels: usize,
+    pub t_ambient: Option<f32>,
+    pub t_window: Option<f32>,
+    pub t_hysteresis: Option<f32>,
+    /// The config text the daemon ran with
+    pub config: Option<String>,
+    pub config_path: String,
+    pub config_hash: String,
+    #[serde(deserialize_with = "schema::lenient")]
+    pub globals: Option<Globals>,
+    pub model: Option<ModelInfo>,
+    pub speakers: Vec<SpeakerParams>,
+    pub events: Vec<EventRecord>,
+    pub blocks: Vec<BlockInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context: Option<PanicContext>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub monitor: Option<MonitorInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snapshot: Option<SnapshotIndex>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
+#[serde(default)]
+pub struct ModelInfo {
+    pub version: u32,
+    pub deterministic: bool,
+    pub single_precision: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct BlockInfo {
+    pub sample_rate: i32,
+    /// In frames
+    pub sample_count: usize,
+    /// Of the sense data, in bytes
+    pub offset: usize,
+    /// The state of every speaker once the model ran on the block
+    pub speakers: Vec<StateRecord>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub monitor_offset: Option<usize>,
+    /// In frames
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub monitor_count: Option<usize>,
+}
+
+/// The model state of a speaker, as recorded (rounded) in the metadata
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct StateRecord {
+    #[serde(deserialize_with = "schema::nan")]
+    pub t_coil: f64,
+    #[serde(deserialize_with = "schema::nan")]
+    pub t_magnet: f64,
+    #[serde(deserialize_with = "schema::nan")]
+    pub t_coil_hyst: f32,
+    #[serde(deserialize_with = "schema::nan")]
+    pub t_magnet_hyst: f32,
+    #[serde(deserialize_with = "schema::nan")]
+    pub min_gain: f32,
+    #[serde(deserialize_with = "schema::nan")]
+    pub gain: f32,
+    #[serde(deserialize_with = "schema::nan")]
+    pub power: f32,
+    pub amp_fault: i32,
+    /// Older dumps don't have the ambient the model ran against
+    pub t_ambient: Option<f32>,
+    /// Only in the panic context, which goes by name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+/// Whatever is missing is unknown (NaN), not zero
+impl Default for StateRecord {
+    fn default() -> StateRecord {
+        StateRecord {
+            t_coil: f64::NAN,
+            t_magnet: f64::NAN,
+            t_coil_hyst: f32::NAN,
+            t_magnet_hyst: f32::NAN,
+            min_gain: f32::NAN,
+            gain: f32::NAN,
+            power: f32::NAN,
+            amp_fault: 0,
+            t_ambient: None,
+            name: None,
+        }
+    }
+}
+
+impl StateRecord {
+    fn new(speaker: &SpeakerState) -> StateRecord {
+        StateRecord {
+            t_coil: speaker.t_coil,
+            t_magnet: speaker.t_magnet,
+            t_coil_hyst: speaker.t_coil_hyst,
+            t_magnet_hyst: speaker.t_magnet_hyst,
+            min_gain: speaker.min_gain,
+            gain: speaker.gain,
+            power: speaker.power,
+            amp_fault: speaker.amp_fault,
+            t_ambient: Some(speaker.t_ambient),
+            name: None,
+        }
+    }
+}
+
+/// What led up to a panic, see Blackbox::panic_context()
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct PanicContext {
+    pub control_writes: Vec<ControlWrite>,
+    pub speakers: Vec<StateRecord>,
+    pub reads: Vec<ReadRecord>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct ControlWrite {
+    pub control: String,
+    pub value: i64,
+    /// How long before the panic (s)
+    pub ago: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct ReadRecord {
+    /// How long before the panic (s)
+    pub ago: f64,
+    /// Frames, or -errno
+    pub result: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct MonitorInfo {
+    pub device: String,
+    pub channels: usize,
+    /// Of its data, in bytes
+    pub offset: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct SnapshotIndex {
+    /// In bytes
+    pub offset: usize,
+    pub speakers: Vec<SnapshotSpeaker>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct SnapshotSpeaker {
+    pub name: String,
+    pub group: usize,
+}
+
+struct Block {
+    sample_rate: i32,
+    state: Vec<Vec<SpeakerState>>,
+    data: Vec<i16>,
+    monitor: Vec<i16>,
+}
+
+/// Maximum number of blocks in the ring buffer (around 30 seconds at 4096/48000)
+const MAX_BLOCKS: usize = 330;
+/// Number of PCM read results kept for the panic context
+const RECENT_READS: usize = 16;
+/// Size of a speaker's record in the snapshot
+pub const SNAPSHOT_SIZE: usize = 8 * MAX_NODES + 4 * 8;
+/// How long to keep recording after a limiter episode triggers a dump
+const EPISODE_POSTROLL: Duration = Duration::from_secs(5);
+
+fn write_snapshot(speaker: &SpeakerState, out: &mut Vec<u8>) {
+    out.extend(speaker.t_coil.to_le_bytes());
+    out.extend(speaker.t_magnet.to_le_bytes());
+    for t in speaker.t_outer.iter() {
+        out.extend(t.to_le_bytes());
+    }
+    for v in [
+        speaker.t_coil_hyst,
+        speaker.t_magnet_hyst,
+        speaker.min_gain,
+        speaker.gain,
+        speaker.power,
+        speaker.impedance,
+    ] {
+        out.extend(v.to_le_bytes());
+    }
+    out.extend(speaker.amp_fault.to_le_bytes());
+    out.extend(speaker.t_ambient.to_le_bytes());
+}
+
+fn read_snapshot(record: &[u8]) -> SpeakerState {
+    let f64_at = |i: usize| f64::from_le_bytes(record[i..i + 8].try_into().unwrap());
+    let f32_at = |i: usize| f32::from_le_bytes(record[i..i + 4].try_into().unwrap());
+    // Past the node temperatures
+    let base = 8 * MAX_NODES;
+
+    SpeakerState {
+        t_coil: f64_at(0),
+        t_magnet: f64_at(8),
+        t_outer: std::array::from_fn(|n| f64_at(16 + 8 * n)),
+        t_coil_hyst: f32_at(base),
+        t_magnet_hyst: f32_at(base + 4),
+        min_gain: f32_at(base + 8),
+        gain: f32_at(base + 12),
+        power: f32_at(base + 16),
+        impedance: f32_at(base + 20),
+        amp_fault: i32::from_le_bytes(record[base + 24..base + 28].try_into().unwrap()),
+        t_ambient: f32_at(base + 28),
+    }
+}
+
+struct Ring {
+    blocks: Vec<Block>,
+    /// Index of the next block to write
+    head: usize,
+    /// Number of valid blocks
+    len: usize,
+}
+
+impl Ring {
+    fn new(size: usize, monitor_size: usize, prefault: bool) -> Ring {
+        let buffer = |size: usize| {
+            let mut data = vec![0i16; size];
+            // Zeroed allocations are lazily mapped, so write every page
+            if prefault {
+                for x in data.iter_mut().step_by(2048) {
+                    unsafe { std::ptr::write_volatile(x, 0) };
+                }
+            }
+            data.clear();
+            data
+        };
+        let blocks = (0..MAX_BLOCKS)
+            .map(|_| Block {
+                sample_rate: 0,
+                state: Vec::new(),
+                data: buffer(size),
+                monitor: buffer(monitor_size),
+            })
+            .collect();
+
+        Ring {
+            blocks,
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn push(
+        &mut self,
+        sample_rate: i32,
+        data: &[i16],
+        monitor: &[i16],
+        state: Vec<Vec<SpeakerState>>,
+    ) {
+        let blk = &mut self.blocks[self.head];
+        blk.sample_rate = sample_rate;
+        blk.state = state;
+        blk.data.clear();
+        blk.data.extend_from_slice(data);
+        blk.monitor.clear();
+        blk.monitor.extend_from_slice(monitor);
+
+        self.head = (self.head + 1) % MAX_BLOCKS;
+        self.len = (self.len + 1).min(MAX_BLOCKS);
+    }
+
+    /// Replace the state recorded with the last block
+    fn set_state(&mut self, state: Vec<Vec<SpeakerState>>) {
+        if self.len > 0 {
+            self.blocks[(self.head + MAX_BLOCKS - 1) % MAX_BLOCKS].state = state;
+        }
+    }
+
+    /// The valid blocks, oldest first
+    fn iter(&self) -> impl Iterator<Item = &Block> {
+        let start = (self.head + MAX_BLOCKS - self.len) % MAX_BLOCKS;
+        (0..self.len).map(move |i| &self.blocks[(start + i) % MAX_BLOCKS])
+    }
+}
+
+fn check(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Create an anonymous file in `dir`, which only appears once it's linked in
+fn create_tmpfile(dir: &File) -> io::Result<File> {
+    let flags = libc::O_TMPFILE | libc::O_WRONLY | libc::O_CLOEXEC;
+    let fd = check(unsafe { libc::openat(dir.as_raw_fd(), c".".as_ptr(), flags, 0o644) })?;
+
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+fn create_at(dir: &File, name: &CStr) -> io::Result<File> {
+    let flags = libc::O_CREAT | libc::O_TRUNC | libc::O_WRONLY | libc::O_CLOEXEC;
+    let fd = check(unsafe { libc::openat(dir.as_raw_fd(), name.as_ptr(), flags, 0o644) })?;
+
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// A dump for the writer thread
+struct Job {
+    /// File name within the blackbox directory
+    name: String,
+    /// Everything but the block index
+    meta: Box<Meta>,
+    channels: usize,
+    ring: Ring,
+    /// Whether to end with a snapshot of the model state
+    snapshot: bool,
+}
+
+impl Job {
+    /**
+        Write the dump into `dir`. The file only appears under its final
+        name once all of it is on disk, so a crash or power loss halfway
+        through can't leave a truncated dump behind.
+    */
+    fn write(mut self, dir: &File) -> io::Result<Ring> {
+        let mut offset = 0;
+        let mut monitor_offset = 0;
+        let monitor_channels = self.meta.monitor.as_ref().map(|m| m.channels);
+
+        for block in self.ring.iter() {
+            let mut info = BlockInfo {
+                sample_rate: block.sample_rate,
+                sample_count: block.data.len() / self.channels,
+                offset,
+                speakers: block.state.iter().flatten().map(StateRecord::new).collect(),
+                ..Default::default()
+            };
+            offset += block.data.len() * std::mem::size_of::<i16>();
+            if let Some(channels) = monitor_channels {
+                info.monitor_offset = Some(monitor_offset);
+                info.monitor_count = Some(block.monitor.len() / channels);
+                monitor_offset += block.monitor.len() * std::mem::size_of::<i16>();
+            }
+            self.meta.blocks.push(info);
+        }
+
+        if let Some(monitor) = self.meta.monitor.as_mut() {
+            monitor.offset = Some(offset);
+        }
+
+        let mut snapshot = Vec::new();
+        if let Some(last) = self.ring.iter().last().filter(|_| self.snapshot) {
+            let mut speakers = Vec::new();
+            for (state, params) in last.state.iter().flatten().zip(self.meta.speakers.iter()) {
+                write_snapshot(state, &mut snapshot);
+                speakers.push(SnapshotSpeaker {
+                    name: params.name.clone(),
+                    group: params.group,
+                });
+            }
+            self.meta.snapshot = Some(SnapshotIndex {
+                offset: offset + monitor_offset,
+                speakers,
+            });
+        }
+        let header = serde_json::to_string(&self.meta)?;
+
+        let name = CString::new(self.name.as_str()).unwrap();
+        let tmp_name = CString::new(self.name.clone() + ".tmp").unwrap();
+        let (mut fd, anonymous) = match create_tmpfile(dir) {
+            Ok(fd) => (fd, true),
+            // Not all filesystems support O_TMPFILE
+            Err(_) => (create_at(dir, &tmp_name)?, false),
+        };
+
+        fd.write_all(MAGIC)?;
+        fd.write_all(&VERSION.to_le_bytes())?;
+        fd.write_all(&(header.len() as u32).to_le_bytes())?;
+        fd.write_all(header.as_bytes())?;
+
+        // meh unsafe (and we only run on little endian machines)
+        let as_u8 = |data: &[i16]| unsafe {
+            slice::from_raw_parts(
+                data.as_ptr() as *const u8,
+                data.len() * std::mem::size_of::<u16>(),
+            )
+        };
+        for blk in self.ring.iter() {
+            fd.write_all(as_u8(&blk.data))?;
+        }
+        if monitor_channels.is_some() {
+            for blk in self.ring.iter() {
+                fd.write_all(as_u8(&blk.monitor))?;
+            }
+        }
+        fd.write_all(&snapshot)?;
+
+        fd.sync_all()?;
+        if anonymous {
+            // linkat() with AT_EMPTY_PATH would need CAP_DAC_READ_SEARCH
+            let proc_path = CString::new(format!("/proc/self/fd/{}", fd.as_raw_fd())).unwrap();
+            check(unsafe {
+                libc::linkat(
+                    libc::AT_FDCWD,
+                    proc_path.as_ptr(),
+                    dir.as_raw_fd(),
+                    name.as_ptr(),
+                    libc::AT_SYMLINK_FOLLOW,
+                )
+            })?;
+        } else {
+            check(unsafe {
+                libc::renameat(
+                    dir.as_raw_fd(),
+                    tmp_name.as_ptr(),
+                    dir.as_raw_fd(),
+                    name.as_ptr(),
+                )
+            })?;
+        }
+        dir.sync_all()?;
+
+        Ok(self.ring)
+    }
+}
+
+/**
+    The blackbox records the last few seconds of sense data and model state.
+    Dumps are written by a separate thread, so a stalled disk can never hold
+    up the protection loop: the ring being dumped is handed over wholesale
+    and recording continues into another one.
+*/
+pub struct Blackbox {
+    machine: String,
+    globals: Globals,
+    config: String,
+    config_path: PathBuf,
+    speakers: Vec<SpeakerParams>,
+    ring: Ring,
+    /// A ring the writer is done with, for the next dump
+    spare: Option<Ring>,
+    /// Whether a dump is being written
+    busy: bool,
+    jobs: Option<SyncSender<Job>>,
+    done: Receiver<Ring>,
+    writer: Option<JoinHandle<()>>,
+    /// The last PCM read results (frames, or -errno), next one at reads_head
+    reads: [Option<(Instant, i64)>; RECENT_READS],
+    reads_head: usize,
+}
+
+impl Blackbox {
+    pub fn new(
+        machine: &str,
+        path: &Path,
+        globals: &Globals,
+        config: &str,
+        config_path: &Path,
+    ) -> io::Result<Blackbox> {
+        // Opened up front, so dumps don't depend on path lookups (or privileges) later
+        let dir = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_DIRECTORY)
+            .open(path)?;
+
+        let (jobs, rx) = mpsc::sync_channel::<Job>(1);
+        let (tx, done) = mpsc::channel();
+
+        let writer = thread::Builder::new()
+            .name("blackbox".into())
+            .spawn(move || {
+                for job in rx {
+                    let name = job.name.clone();
+                    match job.write(&dir) {
+                        Ok(ring) => {
+                            info!("Blackbox saved to {:?}", name);
+                            let _ = tx.send(ring);
+                        }
+                        // The ring is lost, the next dump allocates a new one
+                        Err(e) => warn!("Failed to write blackbox: {}", e),
+                    }
+                }
+            })
+            .expect("Failed to start blackbox writer");
+
+        Ok(Blackbox {
+            machine: machine.into(),
+            globals: globals.clone(),
+            config: config.into(),
+            config_path: config_path.into(),
+            speakers: Vec::new(),
+            // Allocate and touch the whole ring up front, so the safety loop
+            // never allocates or page faults to record a period.
+            ring: Ring::new(
+                globals.period * globals.channels,
+                Self::monitor_size(globals),
+                true,
+            ),
+            spare: None,
+            busy: false,
+            jobs: Some(jobs),
+            done,
+            writer: Some(writer),
+            reads: [None; RECENT_READS],
+            reads_head: 0,
+        })
+    }
+
+    /// Record the parsed speaker parameters, once they're known
+    pub fn set_speakers(&mut self, speakers: Vec<SpeakerParams>) {
+        self.speakers = speakers;
+    }
+
+    pub fn reset(&mut self) {
+        self.ring.len = 0;
+    }
+
+    fn monitor_size(globals: &Globals) -> usize {
+        match globals.monitor_pcm {
+            Some(_) => globals.period * globals.monitor_channels,
+            None => 0,
+        }
+    }
+
+    /// Record a period of sense data, and whatever the monitor had (if any)
+    pub fn push(
+        &mut self,
+        sample_rate: i32,
+        data: &[i16],
+        monitor: &[i16],
+        state: Vec<Vec<SpeakerState>>,
+    ) {
+        self.ring.push(sample_rate, data, monitor, state);
+    }
+
+    /// Note what a PCM read returned (frames, or -errno), for the panic context
+    pub fn record_read(&mut self, result: i64) {
+        self.reads[self.reads_head] = Some((Instant::now(), result));
+        self.reads_head = (self.reads_head + 1) % RECENT_READS;
+    }
+
+    /**
+        What led up to a panic, on top of the last `control_writes`: the last
+        state of every speaker by name and the last PCM reads, with how long
+        ago (s).
+    */
+    fn panic_context(&self, control_writes: Vec<ControlWrite>) -> PanicContext {
+        let mut speakers = Vec::new();
+        if let Some(last) = self.ring.iter().last() {
+            for (state, params) in last.state.iter().flatten().zip(self.speakers.iter()) {
+                speakers.push(StateRecord {
+                    name: Some(params.name.clone()),
+                    ..StateRecord::new(state)
+                });
+            }
+        }
+
+        let now = Instant::now();
+        let mut reads = Vec::new();
+        for i in 0..RECENT_READS {
+            if let Some((time, result)) = self.reads[(self.reads_head + i) % RECENT_READS] {
+                reads.push(ReadRecord {
+                    ago: (now - time).as_secs_f64(),
+                    result,
+                });
+            }
+        }
+
+        PanicContext {
+            control_writes,
+            speakers,
+            reads,
+        }
+    }
+
+    /**
+        Update the state of the last block once the model has run on it.
+        Blocks are pushed with the state the model started from, which is
+        what a dump taken because the model panicked ends up with.
+    */
+    pub fn set_state(&mut self, state: Vec<Vec<SpeakerState>>) {
+        self.ring.set_state(state);
+    }
+
+    /**
+        Hand the current contents to the writer thread and start over. Dumps
+        taken because of a panic get the last `control_writes` and more
+        context, see panic_context(), and end with a model state snapshot.
+    */
+    pub fn preserve(
+        &mut self,
+        reason: String,
+        history: &History,
+        control_writes: Option<Vec<ControlWrite>>,
+    ) {
+        if self.ring.len == 0 {
+            warn!("Blackbox is empty, nothing to save");
+            return;
+        }
+
+        for ring in self.done.try_iter() {
+            self.spare = Some(ring);
+            self.busy = false;
+        }
+        if self.busy {
+            warn!("Blackbox is still being written, not saving");
+            return;
+        }
+
+        let now = chrono::Local::now().to_rfc3339();
+        warn!("Preserving blackbox {}", now);
+
+        let snapshot = control_writes.is_some();
+        let meta = Meta {
+            schema: Some(schema::tag("blackbox", VERSION)),
+            message: reason,
+            machine: self.machine.clone(),
+            sample_rate: self.ring.iter().next().unwrap().sample_rate,
+            channels: self.globals.channels,
+            t_ambient: Some(self.globals.t_ambient),
+            t_window: Some(self.globals.t_window),
+            t_hysteresis: Some(self.globals.t_hysteresis),
+            config: Some(self.config.clone()),
+            config_path: self.config_path.to_string_lossy().to_string(),
+            config_hash: format!("{:016x}", crate::helpers::fnv1a64(self.config.as_bytes())),
+            globals: Some(self.globals.clone()),
+            model: Some(ModelInfo {
+                version: MODEL_VERSION,
+                deterministic: self.globals.deterministic,
+                single_precision: self.globals.single_precision,
+            }),
+            speakers: self.speakers.clone(),
+            events: history.records(),
+            blocks: Vec::new(),
+            context: control_writes.map(|w| self.panic_context(w)),
+            monitor: self.globals.monitor_pcm.as_ref().map(|device| MonitorInfo {
+                device: device.clone(),
+                channels: self.globals.monitor_channels,
+                offset: None,
+            }),
+            snapshot: None,
+        };
+
+        // A fresh ring is only faulted in as it's used, but this is rare
+        let size = self.globals.period * self.globals.channels;
+        let monitor_size = Self::monitor_size(&self.globals);
+        let mut ring = self
+            .spare
+            .take()
+            .unwrap_or_else(|| Ring::new(size, monitor_size, false));
+        ring.len = 0;
+        std::mem::swap(&mut ring, &mut self.ring);
+
+        let job = Job {
+            name: now + ".bbox",
+            meta: Box::new(meta),
+            channels: self.globals.channels,
+            ring,
+            snapshot,
+        };
+        match self.jobs.as_ref().map(|j| j.try_send(job)) {
+            Some(Ok(_)) => self.busy = true,
+            _ => warn!("Blackbox writer is gone, not saving"),
+        }
+    }
+
+    /// Wait for any pending dump to be written. No more dumps can be made after this.
+    pub fn finish(&mut self) {
+        self.jobs = None;
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/**
+    Decides when a limiter episode is worth a dump. Once a group gets
+    limited by more than the threshold, the dump is taken a few seconds
+    later, so it has what led up to the episode and how the limiter dealt
+    with it. Episodes closer together than the interval only get one
+    dump, users complaining about limiting tend to have plenty of them.
+*/
+pub struct EpisodeTrigger {
+    /// Gain a group must get below to trigger (dB)
+    threshold: f32,
+    interval: Duration,
+    last: Option<Instant>,
+    /// When the dump is due, and the group and gain that triggered it
+    pending: Option<(Instant, String, f32)>,
+}
+
+impl EpisodeTrigger {
+    /// A trigger for the config's blackbox_limiting, if set
+    pub fn new(globals: &Globals) -> Option<EpisodeTrigger> {
+        globals.blackbox_limiting.map(|t| EpisodeTrigger {
+            threshold: -t,
+            interval: Duration::from_secs_f32(globals.blackbox_limiting_interval),
+            last: None,
+            pending: None,
+        })
+    }
+
+    /**
+        Feed the gain of the group `label` as of `now`. Returns the reason
+        for the dump once one is due.
+    */
+    pub fn update(&mut self, label: &str, gain: f32, now: Instant) -> Option<String> {
+        match self.pending.as_mut() {
+            // Note the deepest the episode got
+            Some(p) if gain < p.2 => {
+                p.1 = label.into();
+                p.2 = gain;
+            }
+            Some(_) => {}
+            None if gain < self.threshold && self.last.is_none_or(|t| now - t >= self.interval) => {
+                info!(
+                    "Speaker group {} limited to {:.2} dB, saving the blackbox in {} s",
+                    label,
+                    gain,
+                    EPISODE_POSTROLL.as_secs()
+                );
+                self.pending = Some((now + EPISODE_POSTROLL, label.into(), gain));
+            }
+            None => {}
+        }
+
+        let (due, label, gain) = self.pending.take_if(|p| now >= p.0)?;
+        self.last = Some(due);
+        Some(format!(
+            "Limiter episode: group {} limited to {:.2} dB",
+            label, gain
+        ))
+    }
+}
+
+fn invalid(e: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// Split the contents of a v2 `.bbox` file into the metadata and the raw data
+pub fn parse(data: &[u8]) -> io::Result<(Meta, Vec<u8>)> {
+    if data.len() < 16 || &data[..8] != MAGIC {
+        return Err(invalid("Not a blackbox file".into()));
+    }
+    let version = u32::from_le_bytes(data[8..12].try_into().unwrap());
+    let hlen = u32::from_le_bytes(data[12..16].try_into().unwrap()) as usize;
+    if version != VERSION {
+        return Err(invalid(format!("Unsupported blackbox version {}", version)));
+    }
+    let header = data
+        .get(16..16 + hlen)
+        .ok_or_else(|| invalid("Truncated header".into()))?;
+    let meta = serde_json::from_slice(header).map_err(|e| invalid(e.to_string()))?;
+    Ok((meta, data[16 + hlen..].to_vec()))
+}
+
+/**
+    Load a dump: a v2 `.bbox` file, or a v1 `.fdr`/`.cvr` pair (given either
+    file or the common base name). Returns the metadata and the raw data.
+*/
+pub fn load(path: &Path) -> io::Result<(Meta, Vec<u8>)> {
+    if path.extension().is_some_and(|e| e == "bbox") {
+        return parse(&std::fs::read(path)?);
+    }
+
+    let base = match path.extension() {
+        Some(e) if e == "fdr" || e == "cvr" => path.with_extension(""),
+        _ => path.to_path_buf(),
+    };
+    // The names contain dots, so with_extension() won't do here
+    let with_ext = |ext: &str| {
+        let mut p = base.clone().into_os_string();
+        p.push(ext);
+        PathBuf::from(p)
+    };
+    let meta = std::fs::read(with_ext(".fdr"))?;
+    let meta = serde_json::from_slice(&meta).map_err(|e| invalid(e.to_string()))?;
+    let data = std::fs::read(with_ext(".cvr"))?;
+
+    Ok((meta, data))
+}
+
+/// The model version the states in a dump were computed by
+pub fn model_version(meta: &Meta) -> u32 {
+    meta.model.map_or(1, |m| m.version)
+}
+
+/**
+    The model state snapshot of a dump from load(), as (name, group, state)
+    for every speaker, if it has one.
+*/
+pub fn snapshot(meta: &Meta, data: &[u8]) -> Option<Vec<(String, usize, SpeakerState)>> {
+    let version = model_version(meta);
+    if version != MODEL_VERSION {
+        warn!(
+            "Snapshot is from model version {}, this is version {}, not using it",
+            version, MODEL_VERSION
+        );
+        return None;
+    }
+
+    let snapshot = meta.snapshot.as_ref()?;
+    let mut offset = snapshot.offset;
+    let mut speakers = Vec::new();
+    for speaker in snapshot.speakers.iter() {
+        let record = data.get(offset..offset.checked_add(SNAPSHOT_SIZE)?)?;
+        offset += SNAPSHOT_SIZE;
+        speakers.push((speaker.name.clone(), speaker.group, read_snapshot(record)));
+    }
+    Some(speakers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The seed of the blackbox fuzz target, see fuzz/
+    fn seed() -> Vec<u8> {
+        std::fs::read(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../fuzz/seeds/blackbox/seed.bbox"
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn parse_seed() {
+        let (meta, data) = parse(&seed()).unwrap();
+        assert_eq!(meta.channels, 2);
+        assert_eq!(data.len(), 32);
+    }
+
+    #[test]
+    fn truncated_header() {
+        let mut data = seed();
+        data.truncate(40);
+        assert!(parse(&data).is_err());
+    }
+
+    #[test]
+    fn header_past_the_end() {
+        let mut data = seed();
+        data[12..16].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(parse(&data).is_err());
+    }
+
+    /// What was written as null or not written at all reads back as unknown
+    #[test]
+    fn unknown_state() {
+        let rec: StateRecord = serde_json::from_str(r#"{"t_coil": null, "gain": -3}"#).unwrap();
+        assert!(rec.t_coil.is_nan());
+        assert!(rec.t_magnet.is_nan());
+        assert_eq!(rec.gain, -3.);
+        assert_eq!(rec.t_ambient, None);
+    }
+}
@@ -0,0 +1,136 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    Config schema versioning. A config declares the layout it is written
+    for in `[Globals] schema_version`, configs from before that existed are
+    schema 1. Older layouts are migrated in place before anything is
+    parsed, warning about every change so the config gets updated, which
+    lets the daemon and the configs shipped elsewhere move at their own
+    pace.
+
+    Speaker parameters given the way datasheets have them are translated
+    here too, see the datasheet module.
+*/
+use configparser::ini::Ini;
+use log::warn;
+
+use crate::datasheet;
+
+/// Version of the config file layout we write and parse
+pub const SCHEMA: u32 = 2;
+
+/**
+    A single layout change. The key moves to `to` (section, key), or is
+    dropped if that's None. A section ending in `/` matches every section
+    with that prefix (e.g. all speakers).
+*/
+struct Step {
+    section: &'static str,
+    key: &'static str,
+    to: Option<(&'static str, &'static str)>,
+    why: &'static str,
+}
+
+/// The changes from schema n to n + 1, at index n - 1
+const MIGRATIONS: &[&[Step]] = &[
+    // 1 -> 2
+    &[Step {
+        section: "Globals",
+        key: "link_gains",
+        to: None,
+        why: "the speakers in a group always share their gain",
+    }],
+];
+
+impl Step {
+    fn matches(&self, section: &str) -> bool {
+        match self.section.strip_suffix('/') {
+            Some(_) => section.starts_with(self.section),
+            None => section == self.section,
+        }
+    }
+
+    fn apply(&self, config: &mut Ini, section: &str) {
+        let Some(value) = config.remove_key(section, self.key) else {
+            return;
+        };
+
+        match self.to {
+            Some((to_section, to_key)) => {
+                // For section prefixes, keep the matched suffix
+                let to_section = match self.section.strip_suffix('/') {
+                    Some(_) => to_section.to_owned() + &section[self.section.len()..],
+                    None => to_section.to_string(),
+                };
+                warn!(
+                    "Config: {}/{} is now {}/{} ({})",
+                    section, self.key, to_section, to_key, self.why
+                );
+                if config.get(&to_section, to_key).is_some() {
+                    warn!(
+                        "Config: {}/{} is set too, ignoring the old key",
+                        to_section, to_key
+                    );
+                } else {
+                    config.set(&to_section, to_key, value);
+                }
+            }
+            None => warn!(
+                "Config: {}/{} is obsolete ({})",
+                section, self.key, self.why
+            ),
+        }
+    }
+}
+
+/// The schema version a config declares
+pub fn schema_version(config: &Ini) -> u32 {
+    config
+        .getuint("Globals", "schema_version")
+        .unwrap_or_else(|_| panic!("Globals/schema_version: Invalid value"))
+        .map(|v| v as u32)
+        .unwrap_or(1)
+}
+
+/**
+    Bring `config` up to the current schema. Configs newer than we know are
+    used as they are, with a warning, since anything they rely on that we
+    don't understand is ignored.
+*/
+pub fn migrate(config: &mut Ini) {
+    let version = schema_version(config);
+    if version > SCHEMA {
+        warn!(
+            "Config schema {} is newer than this daemon's ({}), some settings may be ignored",
+            version, SCHEMA
+        );
+        datasheet::translate(config);
+        return;
+    }
+    if version == 0 {
+        panic!("Globals/schema_version: Out of bounds");
+    }
+
+    for steps in &MIGRATIONS[version as usize - 1..] {
+        for step in steps.iter() {
+            let sections: Vec<String> = config
+                .sections()
+                .into_iter()
+                .filter(|s| step.matches(s))
+                .collect();
+            for section in sections {
+                step.apply(config, &section);
+            }
+        }
+    }
+
+    if version < SCHEMA {
+        warn!(
+            "Config is written for schema {}, please update it to schema {}",
+            version, SCHEMA
+        );
+        config.set("Globals", "schema_version", Some(SCHEMA.to_string()));
+    }
+
+    datasheet::translate(config);
+}
@@ -0,0 +1,183 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    Speaker parameters as driver datasheets publish them, translated into
+    the model's own. Thermal parameters usually come in the Thiele-Small
+    style naming of Klippel's measurements, and a config for a new machine
+    can give those instead of working out the ladder by hand:
+
+    | Key       | Unit | Meaning                                   | Becomes    |
+    |-----------|------|-------------------------------------------|------------|
+    | `re`      | Ω    | Voice coil DC resistance                  | z_nominal  |
+    | `rtv`     | K/W  | Thermal resistance, coil to magnet        | tr_coil    |
+    | `rtm`     | K/W  | Thermal resistance, magnet to ambient     | tr_magnet  |
+    | `tau_v`   | s    | Thermal time constant of the coil         | tau_coil   |
+    | `tau_m`   | s    | Thermal time constant of the magnet       | tau_magnet |
+    | `p_rated` | W    | Rated (long term) thermal power handling  | see below  |
+    | `t_rated` | °C   | Ambient p_rated applies at, default 25    |            |
+
+    The power rating is the power that takes the coil to t_limit from
+    t_rated, which gives the total thermal resistance. With either of rtv
+    and rtm, the other one is what's left of it.
+
+    Giving both a datasheet key and the model key it becomes is an error.
+*/
+use configparser::ini::Ini;
+use log::info;
+
+/// Ambient temperature power ratings are taken at, unless t_rated says (°C)
+const T_RATED: f32 = 25.;
+
+/// The keys that map one to one, datasheet key first
+const DIRECT: &[(&str, &str)] = &[
+    ("re", "z_nominal"),
+    ("rtv", "tr_coil"),
+    ("rtm", "tr_magnet"),
+    ("tau_v", "tau_coil"),
+    ("tau_m", "tau_magnet"),
+];
+
+fn parse(config: &Ini, section: &str, key: &str) -> Option<f32> {
+    let v = config.get(section, key)?;
+    let v = v
+        .trim()
+        .parse::<f32>()
+        .ok()
+        .filter(|v| v.is_finite())
+        .unwrap_or_else(|| panic!("{}/{}: Invalid value", section, key));
+    if v <= 0. {
+        panic!("{}/{}: Out of bounds", section, key);
+    }
+    Some(v)
+}
+
+/// Set the model key `to` from the datasheet key `from`
+fn set(config: &mut Ini, section: &str, from: &str, to: &str, value: f32) {
+    for key in [to, "nodes"] {
+        if config.get(section, key).is_some() {
+            panic!("{}/{}: Conflicts with {}", section, from, key);
+        }
+    }
+    info!(
+        "Config: {}/{} = {} gives {} = {}",
+        section, from, value, to, value
+    );
+    config.set(section, to, Some(value.to_string()));
+}
+
+/// The total thermal resistance of a speaker rated for `p_rated` (K/W)
+fn rated_resistance(config: &Ini, section: &str, p_rated: f32) -> f32 {
+    let t_limit = parse(config, section, "t_limit")
+        .unwrap_or_else(|| panic!("{}/p_rated: Needs t_limit", section));
+    let t_rated = match config.get(section, "t_rated") {
+        Some(_) => parse(config, section, "t_rated").unwrap(),
+        None => T_RATED,
+    };
+    if t_limit <= t_rated {
+        panic!("{}/t_rated: Out of bounds", section);
+    }
+    (t_limit - t_rated) / p_rated
+}
+
+/// Translate the datasheet parameters of every speaker in `config`
+pub fn translate(config: &mut Ini) {
+    let sections: Vec<String> = config
+        .sections()
+        .into_iter()
+        .filter(|s| s.starts_with("Speaker/"))
+        .collect();
+
+    for section in sections {
+        let mut given = Vec::new();
+        for (from, to) in DIRECT {
+            if let Some(v) = parse(config, &section, from) {
+                given.push((*from, *to, v));
+            }
+        }
+
+        if let Some(p_rated) = parse(config, &section, "p_rated") {
+            let total = rated_resistance(config, &section, p_rated);
+            let rtv = given.iter().find(|g| g.0 == "rtv").map(|g| g.2);
+            let rtm = given.iter().find(|g| g.0 == "rtm").map(|g| g.2);
+            let (key, to, rest) = match (rtv, rtm) {
+                (Some(_), Some(_)) => panic!("{}/p_rated: Redundant with rtv and rtm", section),
+                (Some(rtv), None) => ("rtm", "tr_magnet", total - rtv),
+                (None, Some(rtm)) => ("rtv", "tr_coil", total - rtm),
+                (None, None) => panic!("{}/p_rated: Needs rtv or rtm to split it", section),
+            };
+            if rest <= 0. {
+                panic!(
+                    "{}/p_rated: Out of bounds, leaves {:.2} K/W for {}",
+                    section, rest, key
+                );
+            }
+            given.push(("p_rated", to, rest));
+        }
+
+        for (from, to, v) in given {
+            set(config, &section, from, to, v);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use configparser::ini::Ini;
+
+    use crate::config;
+    use crate::types::tests::{load_config, patched};
+    use crate::types::{Globals, Speaker};
+
+    /// The j274 speaker given by its datasheet parameters is the same speaker
+    #[test]
+    fn datasheet_parameters() {
+        let speaker = |text: &str| {
+            let mut cfg = Ini::new_cs();
+            cfg.read(text.to_string()).unwrap();
+            config::migrate(&mut cfg);
+            let globals = Globals::parse(&cfg);
+            let spk: Speaker = Speaker::offline(&globals, "Mono", &cfg, 15.);
+            spk.params()
+        };
+
+        let mut cfg = Ini::new_cs();
+        cfg.read(patched("Speaker/Mono", "group", "0")).unwrap();
+        for key in [
+            "tr_coil",
+            "tr_magnet",
+            "tau_coil",
+            "tau_magnet",
+            "z_nominal",
+        ] {
+            cfg.remove_key("Speaker/Mono", key);
+        }
+        // 100 K/W in total from 40 °C up to t_limit
+        for (key, value) in [
+            ("re", "4.6"),
+            ("rtv", "40"),
+            ("p_rated", "1"),
+            ("t_rated", "40"),
+            ("tau_v", "3.7"),
+            ("tau_m", "250"),
+        ] {
+            cfg.set("Speaker/Mono", key, Some(value.into()));
+        }
+
+        assert_eq!(
+            speaker(&cfg.writes()),
+            speaker(&patched("Speaker/Mono", "group", "0"))
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Speaker/Mono/rtv: Conflicts with tr_coil")]
+    fn datasheet_and_model_keys() {
+        load_config(&patched("Speaker/Mono", "rtv", "40"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Speaker/Mono/p_rated: Needs rtv or rtm to split it")]
+    fn datasheet_rating_without_split() {
+        load_config(&patched("Speaker/Mono", "p_rated", "1"));
+    }
+}
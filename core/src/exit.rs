@@ -0,0 +1,102 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    Exit statuses by class of failure, so that systemd, the journal and
+    whatever watches them can tell a config that needs fixing from a card
+    that went away or speakers that got too hot. Fatal errors are still
+    panics, so the blackbox is saved on the way out; `fail()` notes the
+    class before panicking, and panics that didn't go through it are
+    classified by their message.
+
+    | Status | Class                                               |
+    |--------|-----------------------------------------------------|
+    | 0      | Clean shutdown                                      |
+    | 69     | Startup did not complete in time                    |
+    | 70     | Internal error (any other panic)                    |
+    | 78     | Invalid or unreadable config                        |
+    | 80     | Sound card missing                                  |
+    | 81     | A control is locked by another process              |
+    | 82     | Sense data invalid (no sample rate, wrong mapping)  |
+    | 83     | Model over temperature, gave up limiting            |
+
+    Two statuses don't come from a Failure, as nothing failed. The daemon
+    exits with 75 (EXIT_RESTART in main.rs) to be restarted by systemd, on
+    a profile switch or config reload, and with 1 if another instance is
+    already running. The client commands exit with 1 on any error.
+*/
+use std::fmt;
+use std::sync::Mutex;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Failure {
+    StartupTimeout,
+    Internal,
+    Config,
+    CardMissing,
+    ElementLocked,
+    SenseInvalid,
+    Overtemperature,
+}
+
+/// Config sections, as they appear at the start of config error messages
+const CONFIG_SECTIONS: &[&str] = &["Globals/", "Controls/", "Hooks/", "Speaker/"];
+
+static CLASS: Mutex<Option<Failure>> = Mutex::new(None);
+
+impl Failure {
+    pub fn code(self) -> i32 {
+        match self {
+            Failure::StartupTimeout => 69,
+            Failure::Internal => 70,
+            Failure::Config => 78,
+            Failure::CardMissing => 80,
+            Failure::ElementLocked => 81,
+            Failure::SenseInvalid => 82,
+            Failure::Overtemperature => 83,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Failure::StartupTimeout => "startup timeout",
+            Failure::Internal => "internal error",
+            Failure::Config => "config error",
+            Failure::CardMissing => "card missing",
+            Failure::ElementLocked => "element locked",
+            Failure::SenseInvalid => "sense data invalid",
+            Failure::Overtemperature => "over temperature",
+        }
+    }
+
+    /// Classify a panic that didn't go through fail(), from its message
+    pub fn classify(message: &str) -> Failure {
+        if CONFIG_SECTIONS.iter().any(|s| message.starts_with(s)) {
+            Failure::Config
+        } else {
+            Failure::Internal
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Panic with `message`, exiting with the status for `class`
+pub fn fail(class: Failure, message: String) -> ! {
+    if let Ok(mut c) = CLASS.lock() {
+        c.get_or_insert(class);
+    }
+    panic!("{}", message);
+}
+
+/// The class of the panic with `message`
+pub fn class_of(message: &str) -> Failure {
+    CLASS
+        .lock()
+        .ok()
+        .and_then(|c| *c)
+        .unwrap_or_else(|| Failure::classify(message))
+}
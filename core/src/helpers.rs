@@ -0,0 +1,133 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    Config parsing helpers. A value that is present but can't be parsed is
+    a panic naming the key, an absent optional one is None.
+*/
+use configparser::ini::Ini;
+
+/**
+    Wrapper around configparser::ini::Ini.getint()
+    to safely unwrap the Result<Option<i64>, E> returned by
+    it.
+*/
+pub fn parse_int<T: TryFrom<i64>>(config: &Ini, section: &str, key: &str) -> T
+where
+    <T as TryFrom<i64>>::Error: std::fmt::Debug,
+{
+    config
+        .getint(section, key)
+        .unwrap_or_else(|_| panic!("{}/{}: Invalid value", section, key))
+        .unwrap_or_else(|| panic!("{}/{}: Missing key", section, key))
+        .try_into()
+        .unwrap_or_else(|_| panic!("{}/{}: Out of bounds", section, key))
+}
+
+pub fn parse_opt_int<T: TryFrom<i64>>(config: &Ini, section: &str, key: &str) -> Option<T>
+where
+    <T as TryFrom<i64>>::Error: std::fmt::Debug,
+{
+    config
+        .getint(section, key)
+        .unwrap_or_else(|_| panic!("{}/{}: Invalid value", section, key))
+        .map(|a| {
+            a.try_into()
+                .unwrap_or_else(|_| panic!("{}/{}: Out of bounds", section, key))
+        })
+}
+
+pub fn parse_opt_bool(config: &Ini, section: &str, key: &str) -> Option<bool> {
+    config
+        .getbool(section, key)
+        .unwrap_or_else(|_| panic!("{}/{}: Invalid value", section, key))
+}
+
+/**
+    Wrapper around configparser::ini::Ini.getfloat()
+    to safely unwrap the Result<Option<f64>, E> returned by
+    it.
+*/
+pub fn parse_float(config: &Ini, section: &str, key: &str) -> f32 {
+    let val = config
+        .getfloat(section, key)
+        .unwrap_or_else(|_| panic!("{}/{}: Invalid value", section, key))
+        .unwrap_or_else(|| panic!("{}/{}: Missing key", section, key)) as f32;
+
+    if !val.is_finite() {
+        panic!("{}/{}: Invalid value", section, key);
+    }
+    val
+}
+
+pub fn parse_opt_float(config: &Ini, section: &str, key: &str) -> Option<f32> {
+    let val = config
+        .getfloat(section, key)
+        .unwrap_or_else(|_| panic!("{}/{}: Invalid value", section, key))? as f32;
+
+    if !val.is_finite() {
+        panic!("{}/{}: Invalid value", section, key);
+    }
+    Some(val)
+}
+
+/// A range of floats, written as "min, max"
+pub fn parse_opt_range(config: &Ini, section: &str, key: &str) -> Option<(f32, f32)> {
+    let val = config.get(section, key)?;
+    let range = val.split_once(',').and_then(|(min, max)| {
+        Some((
+            min.trim().parse::<f32>().ok()?,
+            max.trim().parse::<f32>().ok()?,
+        ))
+    });
+
+    match range {
+        Some((min, max)) if min.is_finite() && max.is_finite() && min <= max => Some((min, max)),
+        _ => panic!("{}/{}: Invalid value", section, key),
+    }
+}
+
+/**
+    Wrapper around configparser::ini::Ini.getfloat()
+    to safely unwrap the Result<Option<f64>, E> returned by
+    it.
+*/
+/// Shortest time step the model is run for (s)
+pub const MIN_DT: f64 = 1e-6;
+
+/**
+    Clamp the time since the last update to something the model can be
+    run for. The clock is monotonic, but timer anomalies can still make
+    it zero, or worse. Returns the time to use and whether it had to be
+    clamped.
+*/
+pub fn clamp_dt(dt: f64) -> (f64, bool) {
+    if dt.is_finite() && dt > 0. {
+        (dt, false)
+    } else {
+        (MIN_DT, true)
+    }
+}
+
+pub fn parse_string(config: &Ini, section: &str, key: &str) -> String {
+    config
+        .get(section, key)
+        .unwrap_or_else(|| panic!("{}/{}: Missing key", section, key))
+}
+
+/// How to refer to a speaker group, by number and name if it has one
+pub fn group_label(group: usize, name: Option<&str>) -> String {
+    match name {
+        Some(name) => format!("{} ({})", group, name),
+        None => group.to_string(),
+    }
+}
+
+/**
+    64-bit FNV-1a hash. Only used to identify config files in dumps, so it
+    doesn't need to be cryptographic.
+*/
+pub fn fnv1a64(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |h, &b| {
+        (h ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
@@ -0,0 +1,199 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    Event history. The log scrolls away and the blackbox only covers the
+    last few seconds, so we also keep the last few notable events in memory,
+    to be queried via the status socket and saved alongside the blackbox.
+*/
+use std::collections::VecDeque;
+use std::fmt;
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::helpers::group_label;
+use crate::sense::SenseFault;
+
+/// Number of events to keep
+const HISTORY_LEN: usize = 128;
+
+/// An event as it's handed out, in status replies and the blackbox
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct EventRecord {
+    /// RFC 3339
+    pub time: String,
+    pub event: String,
+}
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    LimiterEngaged {
+        group: usize,
+        name: Option<String>,
+        gain: f32,
+    },
+    LimiterReleased {
+        group: usize,
+        name: Option<String>,
+    },
+    ShortRead {
+        expected: usize,
+        got: usize,
+    },
+    Suspend,
+    /// The card went away under us and was set up again
+    Reinitialized {
+        reason: String,
+    },
+    /// The time since the last update wasn't positive, so it was clamped
+    TimerAnomaly {
+        dt: f64,
+    },
+    SampleRateChange {
+        from: i32,
+        to: i32,
+    },
+    /// A control was changed behind our back and we had to react
+    ControlTampered {
+        speaker: String,
+    },
+    Quarantined {
+        speaker: String,
+        fault: SenseFault,
+    },
+    AmpFault {
+        speaker: String,
+        fault: i32,
+    },
+    /// The sense channels of a speaker don't seem to belong together
+    MappingMismatch {
+        speaker: String,
+    },
+    BoostStarted {
+        seconds: f32,
+    },
+    BoostEnded,
+    /// Past the hard limits, held at min gain
+    OverTemperature {
+        speaker: String,
+    },
+    /// Still heating up at min gain, so the group got muted
+    Muted {
+        speaker: String,
+    },
+    Unmuted {
+        speaker: String,
+    },
+    /// Everything held at min gain and left to the kernel on request
+    SafeMode {
+        on: bool,
+    },
+    /// An unlock control wasn't refreshed, so the kernel's limits may apply
+    HeartbeatInterrupted {
+        control: String,
+        /// The write failed, rather than being held back
+        failed: bool,
+    },
+    HeartbeatRestored {
+        control: String,
+    },
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::LimiterEngaged { group, name, gain } => write!(
+                f,
+                "Group {} limiter engaged at {:.2} dB",
+                group_label(*group, name.as_deref()),
+                gain
+            ),
+            Event::LimiterReleased { group, name } => write!(
+                f,
+                "Group {} limiter released",
+                group_label(*group, name.as_deref())
+            ),
+            Event::ShortRead { expected, got } => {
+                write!(f, "Short read: {} of {} samples", got, expected)
+            }
+            Event::Suspend => write!(f, "Suspend"),
+            Event::Reinitialized { reason } => write!(f, "Reinitialized: {}", reason),
+            Event::TimerAnomaly { dt } => {
+                write!(f, "Timer anomaly: {} s since the last update", dt)
+            }
+            Event::SampleRateChange { from, to } => {
+                write!(f, "Sample rate change: {} -> {}", from, to)
+            }
+            Event::ControlTampered { speaker } => write!(f, "{}: Control tampered with", speaker),
+            Event::Quarantined { speaker, fault } => {
+                write!(f, "{}: Quarantined ({})", speaker, fault)
+            }
+            Event::AmpFault { speaker, fault } => write!(f, "{}: Amp fault {:#x}", speaker, fault),
+            Event::MappingMismatch { speaker } => {
+                write!(f, "{}: Sense channel mapping mismatch", speaker)
+            }
+            Event::BoostStarted { seconds } => write!(f, "Boost for {:.0} s", seconds),
+            Event::BoostEnded => write!(f, "Boost ended"),
+            Event::OverTemperature { speaker } => {
+                write!(f, "{}: Temperature limit exceeded", speaker)
+            }
+            Event::Muted { speaker } => write!(f, "{}: Muted, limit exceeded", speaker),
+            Event::Unmuted { speaker } => write!(f, "{}: Unmuted", speaker),
+            Event::SafeMode { on: true } => write!(f, "Safe mode on"),
+            Event::SafeMode { on: false } => write!(f, "Safe mode off"),
+            Event::HeartbeatInterrupted { control, failed } => write!(
+                f,
+                "{}: Heartbeat {}, kernel limits may apply",
+                control,
+                if *failed { "failed" } else { "withheld" }
+            ),
+            Event::HeartbeatRestored { control } => write!(f, "{}: Heartbeat restored", control),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct History {
+    events: VecDeque<(DateTime<Local>, Event)>,
+    /// Total number of events ever pushed, to tell whether anything changed
+    seq: u64,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History {
+            events: VecDeque::with_capacity(HISTORY_LEN),
+            seq: 0,
+        }
+    }
+}
+
+impl History {
+    pub fn push(&mut self, event: Event) {
+        if self.events.len() >= HISTORY_LEN {
+            self.events.pop_front();
+        }
+        self.events.push_back((Local::now(), event));
+        self.seq += 1;
+    }
+
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// The last `n` events, oldest first
+    pub fn last(&self, n: usize) -> impl Iterator<Item = &(DateTime<Local>, Event)> {
+        self.events.iter().skip(self.events.len().saturating_sub(n))
+    }
+
+    pub fn records(&self) -> Vec<EventRecord> {
+        self.events
+            .iter()
+            .map(|(time, event)| EventRecord {
+                time: time.to_rfc3339(),
+                event: event.to_string(),
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,19 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    The parts of speakersafetyd that don't need the hardware: the config
+    types and schema, the thermal model, the sense data checks and the
+    blackbox format. The daemon is built on this, and so can anything else
+    that wants to run or inspect the exact same model, such as analysis
+    tools or simulators. Nothing in here talks to ALSA; speakers reach
+    their controls through the `types::Controls` trait.
+*/
+pub mod blackbox;
+pub mod config;
+pub mod datasheet;
+pub mod exit;
+pub mod helpers;
+pub mod history;
+pub mod schema;
+pub mod sense;
+pub mod types;
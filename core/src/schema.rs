@@ -0,0 +1,107 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    Schemas of the JSON we hand to other programs: status replies, telemetry
+    reports and blackbox metadata. Each document carries a `schema` tag
+    with its kind, a version and the units its fields are in, so readers
+    can tell what they're looking at without guessing from field names.
+
+    The version goes up whenever a field is removed, renamed or changes its
+    meaning or unit. Adding fields doesn't bump it, readers are expected to
+    ignore what they don't know.
+
+    All numbers are plain JSON numbers in the units below, never localized
+    strings; values that aren't known (NaN) come out as null.
+
+    Each document is a serde type that the writer and its readers share,
+    so the two can't drift apart: the blackbox metadata in blackbox.rs, the
+    status, limits and telemetry replies in the daemon's status.rs.
+*/
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize};
+
+/// Version of the status reply (`speakersafetyd status --json`)
+pub const STATUS: u32 = 1;
+/// Version of the telemetry reports
+pub const TELEMETRY: u32 = 1;
+/// Version of the limits reply (`speakersafetyd limits --json`)
+pub const LIMITS: u32 = 1;
+
+/// Units of the fields, by quantity
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct Units {
+    /// t_*, headroom, margin_*
+    pub temperature: String,
+    /// power, max_power, peak_power
+    pub power: String,
+    /// gain, min_gain, min_gain_full, volume
+    pub gain: String,
+    pub impedance: String,
+    /// time_to_limit, boost, histogram times
+    pub time: String,
+    pub sample_rate: String,
+}
+
+impl Default for Units {
+    fn default() -> Units {
+        Units {
+            temperature: "degC".into(),
+            power: "W".into(),
+            gain: "dB".into(),
+            impedance: "ohm".into(),
+            time: "s".into(),
+            sample_rate: "Hz".into(),
+        }
+    }
+}
+
+/// The `schema` tag of a document
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct Tag {
+    pub kind: String,
+    pub version: u32,
+    pub units: Units,
+}
+
+/// The `schema` tag of a document of kind `kind`
+pub fn tag(kind: &str, version: u32) -> Tag {
+    Tag {
+        kind: kind.into(),
+        version,
+        units: Units::default(),
+    }
+}
+
+/// A document as a single line of JSON
+pub fn dump<T: Serialize>(doc: &T) -> String {
+    // Only maps keyed by something other than strings or numbers fail
+    serde_json::to_string(doc).expect("Failed to serialize document")
+}
+
+/**
+    For `deserialize_with`: reads the null an unknown value (NaN) is written
+    out as back into NaN, rather than refusing the whole document.
+*/
+pub fn nan<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + From<f32>,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_else(|| f32::NAN.into()))
+}
+
+/**
+    For `deserialize_with` on optional parts of a document whose shape
+    changed over time: anything that doesn't fit is left out, rather than
+    refusing the whole document.
+*/
+pub fn lenient<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned,
+{
+    let value = serde_json::Value::deserialize(deserializer)?;
+    Ok(serde_json::from_value(value).ok())
+}
@@ -0,0 +1,503 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    Plausibility checks for V/ISENSE data. A broken sense path (a damaged
+    speaker, a dead ISENSE line, a driver bug) would otherwise feed garbage
+    into the thermal model, which at best panics the daemon and at worst
+    makes it think the speaker is cooler than it is.
+*/
+use std::fmt;
+
+/// Fraction of full scale below which a channel is considered idle
+const IDLE_RMS: f32 = 0.01;
+
+/// Fraction of frames at full scale for a channel to be considered stuck clipped
+const CLIP_FRACTION: f32 = 0.5;
+
+/// Average power (W) below which the data cannot be right (ignoring rounding error)
+const NEGATIVE_POWER: f32 = -0.01;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SenseFault {
+    NegativePower,
+    VSenseStuckZero,
+    ISenseStuckZero,
+    VSenseClipped,
+    ISenseClipped,
+}
+
+impl fmt::Display for SenseFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            SenseFault::NegativePower => "negative_power",
+            SenseFault::VSenseStuckZero => "vsense_stuck_zero",
+            SenseFault::ISenseStuckZero => "isense_stuck_zero",
+            SenseFault::VSenseClipped => "vsense_clipped",
+            SenseFault::ISenseClipped => "isense_clipped",
+        })
+    }
+}
+
+impl SenseFault {
+    /**
+        Whether the data is only clipped, which still bounds the power from
+        below. Clipping comes with the loudest playback, where the coil
+        heats the most, so those periods are integrated at full scale
+        rather than skipped.
+    */
+    pub fn clipped(&self) -> bool {
+        matches!(self, SenseFault::VSenseClipped | SenseFault::ISenseClipped)
+    }
+}
+
+/// Per-period statistics of a speaker's sense channels
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SenseStats {
+    pub frames: usize,
+    pub pwr_avg: f32,
+    pub v_rms: f32,
+    pub i_rms: f32,
+    /// Highest VSENSE magnitude, normalized
+    pub v_peak: f32,
+    v_zero: usize,
+    i_zero: usize,
+    v_clip: usize,
+    i_clip: usize,
+}
+
+impl SenseStats {
+    pub fn analyze(buf: &[i16], channels: usize, vs_chan: usize, is_chan: usize) -> SenseStats {
+        let mut st: SenseStats = Default::default();
+        let mut v_sq = 0f32;
+        let mut i_sq = 0f32;
+        let mut vi = 0f32;
+
+        for sample in buf.chunks(channels) {
+            let v = sample[vs_chan];
+            let i = sample[is_chan];
+
+            st.v_zero += (v == 0) as usize;
+            st.i_zero += (i == 0) as usize;
+            st.v_clip += (v == i16::MIN || v == i16::MAX) as usize;
+            st.i_clip += (i == i16::MIN || i == i16::MAX) as usize;
+
+            let v = v as f32 / 32768.0;
+            let i = i as f32 / 32768.0;
+            st.v_peak = st.v_peak.max(v.abs());
+            v_sq += v * v;
+            i_sq += i * i;
+            vi += v * i;
+            st.frames += 1;
+        }
+
+        if st.frames > 0 {
+            let n = st.frames as f32;
+            st.v_rms = (v_sq / n).sqrt();
+            st.i_rms = (i_sq / n).sqrt();
+            st.pwr_avg = vi / n;
+        }
+
+        st
+    }
+
+    /// Scale the normalized power to watts
+    pub fn scale(&mut self, vs_scale: f32, is_scale: f32) {
+        self.pwr_avg *= vs_scale * is_scale;
+    }
+
+    /// Apparent impedance (ohms), NaN if there is too little current to tell
+    pub fn impedance(&self, vs_scale: f32, is_scale: f32) -> f32 {
+        if self.i_rms > IDLE_RMS {
+            (self.v_rms * vs_scale) / (self.i_rms * is_scale)
+        } else {
+            f32::NAN
+        }
+    }
+
+    /// Whether the VSENSE and ISENSE channels are stuck clipped
+    pub fn clipped(&self) -> (bool, bool) {
+        let clip = (self.frames as f32 * CLIP_FRACTION) as usize;
+        (self.v_clip > clip, self.i_clip > clip)
+    }
+
+    pub fn fault(&self) -> Option<SenseFault> {
+        let (v_clipped, i_clipped) = self.clipped();
+
+        if self.frames == 0 {
+            None
+        } else if self.pwr_avg < NEGATIVE_POWER {
+            Some(SenseFault::NegativePower)
+        } else if self.v_zero == self.frames && self.i_rms > IDLE_RMS {
+            Some(SenseFault::VSenseStuckZero)
+        } else if self.i_zero == self.frames && self.v_rms > IDLE_RMS {
+            Some(SenseFault::ISenseStuckZero)
+        } else if v_clipped {
+            Some(SenseFault::VSenseClipped)
+        } else if i_clipped {
+            Some(SenseFault::ISenseClipped)
+        } else {
+            None
+        }
+    }
+}
+
+/// Tracks consecutive implausible periods for one speaker
+#[derive(Debug, Default)]
+pub struct SenseCheck {
+    bad_periods: usize,
+    limit: usize,
+}
+
+impl SenseCheck {
+    pub fn new(limit: usize) -> SenseCheck {
+        SenseCheck {
+            bad_periods: 0,
+            limit,
+        }
+    }
+
+    pub fn bad_periods(&self) -> usize {
+        self.bad_periods
+    }
+
+    /// Feed one period's verdict. Returns the fault once it has persisted for
+    /// long enough that the speaker should be quarantined.
+    pub fn update(&mut self, fault: Option<SenseFault>) -> Option<SenseFault> {
+        match fault {
+            None => {
+                self.bad_periods = 0;
+                None
+            }
+            Some(fault) => {
+                self.bad_periods += 1;
+                if self.bad_periods >= self.limit {
+                    Some(fault)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+/// Periods with signal a speaker needs before its mapping is judged
+const MAPPING_PERIODS: usize = 16;
+
+/// Correlation below which a V/ISENSE pair is considered unrelated
+const MIN_CORRELATION: f32 = 0.3;
+
+/// Correlation above which a V/ISENSE pair is clearly the same driver
+const GOOD_CORRELATION: f32 = 0.8;
+
+#[derive(Debug, Default, Copy, Clone)]
+struct PairSums {
+    vv: f64,
+    ii: f64,
+    vi: f64,
+}
+
+impl PairSums {
+    fn correlation(&self) -> f32 {
+        if self.vv > 0. && self.ii > 0. {
+            (self.vi / (self.vv * self.ii).sqrt()) as f32
+        } else {
+            0.
+        }
+    }
+}
+
+/// A speaker whose configured sense channels don't seem to belong together
+#[derive(Debug, Clone)]
+pub struct Mismatch {
+    pub speaker: String,
+    pub configured: (usize, usize),
+    pub correlation: f32,
+    /// The best matching pair involving one of the configured channels
+    pub likely: (usize, usize),
+    pub likely_correlation: f32,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: vs_chan={} is_chan={} are uncorrelated ({:.2}), but vs_chan={} is_chan={} are ({:.2})",
+            self.speaker,
+            self.configured.0,
+            self.configured.1,
+            self.correlation,
+            self.likely.0,
+            self.likely.1,
+            self.likely_correlation
+        )
+    }
+}
+
+struct PendingSpeaker {
+    name: String,
+    vs_chan: usize,
+    is_chan: usize,
+    periods: usize,
+}
+
+/**
+    Passive check of the sense channel mapping. Over the first few seconds
+    of playback, we correlate every VSENSE channel with every ISENSE channel
+    in the config. A speaker whose own pair shows no correlation while
+    another pair involving one of its channels does is most likely
+    miswired, and the model would be protecting the wrong driver. With
+    identical content on all channels every pair correlates, so this can
+    only catch errors once the channels differ, but never false alarms.
+*/
+pub struct MappingCheck {
+    channels: usize,
+    vs_chans: Vec<usize>,
+    is_chans: Vec<usize>,
+    /// Indexed by vs_chans position * is_chans.len() + is_chans position
+    sums: Vec<PairSums>,
+    pending: Vec<PendingSpeaker>,
+}
+
+impl MappingCheck {
+    /// `speakers` are (name, vs_chan, is_chan) tuples
+    pub fn new(channels: usize, speakers: &[(String, usize, usize)]) -> MappingCheck {
+        let mut vs_chans: Vec<usize> = speakers.iter().map(|s| s.1).collect();
+        let mut is_chans: Vec<usize> = speakers.iter().map(|s| s.2).collect();
+        vs_chans.sort();
+        vs_chans.dedup();
+        is_chans.sort();
+        is_chans.dedup();
+
+        MappingCheck {
+            channels,
+            sums: vec![Default::default(); vs_chans.len() * is_chans.len()],
+            vs_chans,
+            is_chans,
+            pending: speakers
+                .iter()
+                .map(|s| PendingSpeaker {
+                    name: s.0.clone(),
+                    vs_chan: s.1,
+                    is_chan: s.2,
+                    periods: 0,
+                })
+                .collect(),
+        }
+    }
+
+    /// Whether every speaker has been judged
+    pub fn done(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    fn pair(&self, vs_chan: usize, is_chan: usize) -> &PairSums {
+        let v = self.vs_chans.binary_search(&vs_chan).unwrap();
+        let i = self.is_chans.binary_search(&is_chan).unwrap();
+        &self.sums[v * self.is_chans.len() + i]
+    }
+
+    /// Feed one period of sense data. Returns the speakers judged miswired.
+    pub fn update(&mut self, buf: &[i16]) -> Vec<Mismatch> {
+        let frames = buf.len() / self.channels;
+        if frames == 0 {
+            return Vec::new();
+        }
+
+        let active: Vec<bool> = (0..self.channels)
+            .map(|c| {
+                let sq: f64 = buf
+                    .chunks_exact(self.channels)
+                    .map(|f| (f[c] as f64 / 32768.).powi(2))
+                    .sum();
+                (sq / frames as f64).sqrt() > IDLE_RMS as f64
+            })
+            .collect();
+
+        for (vi, &v) in self.vs_chans.iter().enumerate() {
+            for (ii, &i) in self.is_chans.iter().enumerate() {
+                if !active[v] || !active[i] {
+                    continue;
+                }
+                let sums = &mut self.sums[vi * self.is_chans.len() + ii];
+                for f in buf.chunks_exact(self.channels) {
+                    let (v, i) = (f[v] as f64, f[i] as f64);
+                    sums.vv += v * v;
+                    sums.ii += i * i;
+                    sums.vi += v * i;
+                }
+            }
+        }
+
+        for spk in self.pending.iter_mut() {
+            if active[spk.vs_chan] {
+                spk.periods += 1;
+            }
+        }
+
+        let (ready, pending): (Vec<_>, Vec<_>) = self
+            .pending
+            .drain(..)
+            .partition(|s| s.periods >= MAPPING_PERIODS);
+        self.pending = pending;
+
+        ready
+            .into_iter()
+            .filter_map(|spk| {
+                let correlation = self.pair(spk.vs_chan, spk.is_chan).correlation();
+                let alternatives = self
+                    .is_chans
+                    .iter()
+                    .map(|&i| (spk.vs_chan, i))
+                    .chain(self.vs_chans.iter().map(|&v| (v, spk.is_chan)));
+                let (likely, likely_correlation) = alternatives
+                    .filter(|&p| p != (spk.vs_chan, spk.is_chan))
+                    .map(|p| (p, self.pair(p.0, p.1).correlation()))
+                    .max_by(|a, b| a.1.total_cmp(&b.1))?;
+
+                (correlation < MIN_CORRELATION && likely_correlation > GOOD_CORRELATION).then_some(
+                    Mismatch {
+                        speaker: spk.name,
+                        configured: (spk.vs_chan, spk.is_chan),
+                        correlation,
+                        likely,
+                        likely_correlation,
+                    },
+                )
+            })
+            .collect()
+    }
+}
+
+/// Active periods a speaker's scales are judged over, again and again
+const SCALE_PERIODS: usize = 256;
+
+/// How far VSENSE may peak above what the amp can put out (dB)
+const SCALE_TOLERANCE: f32 = 2.;
+
+/**
+    Plausible range of the apparent impedance, relative to z_nominal. The
+    coil inductance and heating push it up, so this is lopsided.
+*/
+const Z_RANGE: (f32, f32) = (0.5, 3.);
+
+/// A speaker whose sense data doesn't add up with its configured scales
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScaleMismatch {
+    /// VSENSE peaked above the amp's output at the gain in effect (V, V)
+    VoltageTooHigh { peak: f32, max: f32 },
+    /// The apparent impedance is way off z_nominal (ohms)
+    Impedance { measured: f32, nominal: f32 },
+}
+
+impl fmt::Display for ScaleMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScaleMismatch::VoltageTooHigh { peak, max } => write!(
+                f,
+                "VSENSE peaked at {:.2} V, but the amp can only put out {:.2} V at this gain, vs_scale looks too high",
+                peak, max
+            ),
+            ScaleMismatch::Impedance { measured, nominal } => write!(
+                f,
+                "apparent impedance is {:.2} ohm, but z_nominal is {:.2} ohm, check vs_scale and is_scale",
+                measured, nominal
+            ),
+        }
+    }
+}
+
+/**
+    Passive check of a speaker's sense scales against its amp. At a given
+    amp gain and level, the amp can't put out more than so many volts, so
+    VSENSE reading higher than that means vs_scale is too high (or the
+    amp gain isn't what the config was written for). And V/I has to come
+    out somewhere around z_nominal, which catches the ratio of the two
+    scales being off. A model with wrong scales is silently wrong, so the
+    check keeps running over windows of playback for as long as we do.
+*/
+pub struct ScaleCheck {
+    vs_scale: f32,
+    is_scale: f32,
+    z_nominal: f32,
+    /// Peak voltage at full scale and 0 dB level (V)
+    v_full: f32,
+    /// Level in effect in the previous period, the data may still be from it (dB)
+    last_level: f32,
+    periods: usize,
+    /// Highest VSENSE peak and what the amp could put out at the time (V)
+    peak: (f32, f32),
+    z_sum: f32,
+}
+
+impl ScaleCheck {
+    pub fn new(vs_scale: f32, is_scale: f32, z_nominal: f32, v_full: f32) -> ScaleCheck {
+        ScaleCheck {
+            vs_scale,
+            is_scale,
+            z_nominal,
+            v_full,
+            last_level: 0.,
+            periods: 0,
+            peak: (0., 1.),
+            z_sum: 0.,
+        }
+    }
+
+    /// Problems with the scales that are apparent without any data
+    pub fn startup(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.vs_scale < self.v_full / 2. {
+            problems.push(format!(
+                "vs_scale is {:.2} V, but the amp puts out up to {:.2} V, VSENSE would clip",
+                self.vs_scale, self.v_full
+            ));
+        }
+        let i_full = self.v_full / self.z_nominal;
+        if self.is_scale < i_full / 2. {
+            problems.push(format!(
+                "is_scale is {:.2} A, but the amp drives up to {:.2} A into z_nominal, ISENSE would clip",
+                self.is_scale, i_full
+            ));
+        }
+        problems
+    }
+
+    /**
+        Feed one period of `stats`, played at `level` (dB). Returns the
+        verdict at the end of each window.
+    */
+    pub fn update(&mut self, stats: &SenseStats, level: f32) -> Option<Result<(), ScaleMismatch>> {
+        let level = std::mem::replace(&mut self.last_level, level).max(level);
+        if stats.i_rms <= IDLE_RMS || stats.fault().is_some() {
+            return None;
+        }
+
+        let peak = (
+            stats.v_peak * self.vs_scale,
+            self.v_full * 10f32.powf(level / 20.),
+        );
+        if peak.0 / peak.1 > self.peak.0 / self.peak.1 {
+            self.peak = peak;
+        }
+        self.z_sum += stats.impedance(self.vs_scale, self.is_scale);
+        self.periods += 1;
+        if self.periods < SCALE_PERIODS {
+            return None;
+        }
+
+        let n = std::mem::take(&mut self.periods) as f32;
+        let (peak, max) = std::mem::replace(&mut self.peak, (0., 1.));
+        let z = std::mem::take(&mut self.z_sum) / n;
+
+        Some(if peak / max > 10f32.powf(SCALE_TOLERANCE / 20.) {
+            Err(ScaleMismatch::VoltageTooHigh { peak, max })
+        } else if !(Z_RANGE.0..=Z_RANGE.1).contains(&(z / self.z_nominal)) {
+            Err(ScaleMismatch::Impedance {
+                measured: z,
+                nominal: self.z_nominal,
+            })
+        } else {
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,1941 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    The config types and the thermal model. Talking to the hardware is left
+    to whoever drives the model, through the Controls trait.
+*/
+use configparser::ini::Ini;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::exit::{self, Failure};
+use crate::helpers;
+use crate::sense::{ScaleCheck, SenseCheck, SenseFault, SenseStats};
+
+/**
+    The controls of a speaker's amp, as far as the model is concerned.
+    `Handle` is whatever the implementation needs passed in to reach them,
+    e.g. the card's control interface.
+*/
+pub trait Controls {
+    type Handle;
+
+    /// The user volume (dB), if it is tracked
+    fn volume(&mut self, handle: &Self::Handle) -> Option<f32>;
+    /// The amp fault register, if there is one
+    fn fault(&mut self, handle: &Self::Handle) -> Option<i32>;
+    /// Whether the named control is one of ours
+    fn owns(&self, name: &str) -> bool;
+    /// Whether the controls still hold the values we wrote
+    fn verify(&mut self, handle: &Self::Handle) -> bool;
+    /// Rewrite our values after somebody else changed them, false if that failed
+    fn restore(&mut self, handle: &Self::Handle) -> bool;
+    /// Set the speaker level (dB)
+    fn set_level(&mut self, handle: &Self::Handle, gain: f32);
+    /// Whether the last level set has reached the control, so it can be checked
+    fn level_settled(&self) -> bool;
+    /**
+        Read back the level after setting it to `gain` (dB), returning what
+        the control holds if that's further off than its resolution allows
+    */
+    fn check_level(&mut self, handle: &Self::Handle, gain: f32) -> Option<f32>;
+}
+
+/// For speakers without any controls behind them
+pub enum NoControls {}
+
+impl Controls for NoControls {
+    type Handle = ();
+
+    fn volume(&mut self, _: &()) -> Option<f32> {
+        match *self {}
+    }
+
+    fn fault(&mut self, _: &()) -> Option<i32> {
+        match *self {}
+    }
+
+    fn owns(&self, _: &str) -> bool {
+        match *self {}
+    }
+
+    fn verify(&mut self, _: &()) -> bool {
+        match *self {}
+    }
+
+    fn restore(&mut self, _: &()) -> bool {
+        match *self {}
+    }
+
+    fn set_level(&mut self, _: &(), _: f32) {
+        match *self {}
+    }
+
+    fn level_settled(&self) -> bool {
+        match *self {}
+    }
+
+    fn check_level(&mut self, _: &(), _: f32) -> Option<f32> {
+        match *self {}
+    }
+}
+
+/// What to do when somebody else changes one of our controls
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TamperPolicy {
+    /// Retake the lock and rewrite our value, panic if that fails
+    Rewrite,
+    /// Panic right away and let the kernel take over
+    Panic,
+}
+
+impl TamperPolicy {
+    fn parse(config: &Ini) -> Self {
+        match config.get("Globals", "tamper_policy").as_deref() {
+            None | Some("rewrite") => TamperPolicy::Rewrite,
+            Some("panic") => TamperPolicy::Panic,
+            Some(p) => panic!("Globals/tamper_policy: Invalid value '{}'", p),
+        }
+    }
+}
+
+/// What to do when a speaker keeps heating up past its limits at min gain
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OverLimitPolicy {
+    /// Panic and let the kernel take over
+    Panic,
+    /// Mute the group until it has cooled down to t_hysteresis under its limits
+    Mute,
+}
+
+impl OverLimitPolicy {
+    fn parse(config: &Ini) -> Self {
+        match config.get("Globals", "over_limit").as_deref() {
+            None | Some("panic") => OverLimitPolicy::Panic,
+            Some("mute") => OverLimitPolicy::Mute,
+            Some(p) => panic!("Globals/over_limit: Invalid value '{}'", p),
+        }
+    }
+}
+
+/// What to do when the sense channel mapping looks wrong
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MappingPolicy {
+    Off,
+    /// Log loudly and keep going
+    Warn,
+    /// Panic and let the kernel take over
+    Panic,
+}
+
+impl MappingPolicy {
+    fn parse(config: &Ini) -> Self {
+        match config.get("Globals", "mapping_check").as_deref() {
+            Some("off") => MappingPolicy::Off,
+            None | Some("warn") => MappingPolicy::Warn,
+            Some("panic") => MappingPolicy::Panic,
+            Some(p) => panic!("Globals/mapping_check: Invalid value '{}'", p),
+        }
+    }
+}
+
+/**
+    Per group unlock controls, given as unlock_group<N> in [Controls]. If
+    the kernel locks groups separately, this lets us withdraw the heartbeat
+    for just the group with a problem.
+*/
+fn parse_group_unlock(config: &Ini) -> BTreeMap<usize, String> {
+    let Some(controls) = config.get_map_ref().get("Controls") else {
+        return BTreeMap::new();
+    };
+
+    controls
+        .iter()
+        .filter_map(|(key, name)| {
+            let group = key.strip_prefix("unlock_group")?;
+            let group = group
+                .parse()
+                .unwrap_or_else(|_| panic!("Controls/{}: Invalid group", key));
+            let name = name
+                .clone()
+                .unwrap_or_else(|| panic!("Controls/{}: Missing value", key));
+            Some((group, name))
+        })
+        .collect()
+}
+
+/// Also recorded as is in the blackbox
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Globals {
+    pub visense_pcm: usize,
+    pub channels: usize,
+    pub period: usize,
+    pub t_ambient: f32,
+    pub t_window: f32,
+    pub t_hysteresis: f32,
+    pub ctl_vsense: String,
+    pub ctl_isense: String,
+    pub ctl_amp_gain: String,
+    pub ctl_volume: String,
+    pub ctl_limiter: Option<String>,
+    pub ctl_fault: Option<String>,
+    pub ctl_unlock: String,
+    /// Unlock controls of their own, by group
+    pub ctl_group_unlock: BTreeMap<usize, String>,
+    pub fault_min_gain: bool,
+    pub track_volume: bool,
+    pub uclamp_min: Option<usize>,
+    pub uclamp_max: Option<usize>,
+    pub sched_fifo: Option<u32>,
+    pub cpu_affinity: Option<String>,
+    pub reopen_pcm: bool,
+    pub idle: bool,
+    pub battery_batch: usize,
+    pub emergency_time: f32,
+    pub over_limit: OverLimitPolicy,
+    pub sense_fault_periods: usize,
+    pub tamper_policy: TamperPolicy,
+    pub mapping_check: MappingPolicy,
+    /// Playback monitor PCM to record into the blackbox, if any
+    pub monitor_pcm: Option<String>,
+    pub monitor_channels: usize,
+    /// Time allowed from startup to the first sense data (s), 0 for no limit
+    pub startup_timeout: f32,
+    /// Run the thermal model in fixed point, for bit-identical replays
+    pub deterministic: bool,
+    /// Run the thermal model on the mean power of this many samples at a time
+    pub decimation: usize,
+    /// Run the thermal model in f32 rather than f64
+    pub single_precision: bool,
+    /// Cross-check the sense scales against the amp during playback
+    pub scale_check: bool,
+    /// Save the blackbox when a group gets limited by more than this (dB)
+    pub blackbox_limiting: Option<f32>,
+    /// Least time between blackboxes of limiter episodes (s)
+    pub blackbox_limiting_interval: f32,
+}
+
+impl Globals {
+    pub fn parse(config: &Ini) -> Self {
+        let globals = Self {
+            visense_pcm: helpers::parse_int(config, "Globals", "visense_pcm"),
+            channels: helpers::parse_int(config, "Globals", "channels"),
+            period: helpers::parse_int(config, "Globals", "period"),
+            t_ambient: helpers::parse_float(config, "Globals", "t_ambient"),
+            t_window: helpers::parse_float(config, "Globals", "t_window"),
+            t_hysteresis: helpers::parse_float(config, "Globals", "t_hysteresis"),
+            ctl_vsense: helpers::parse_string(config, "Controls", "vsense"),
+            ctl_isense: helpers::parse_string(config, "Controls", "isense"),
+            ctl_amp_gain: helpers::parse_string(config, "Controls", "amp_gain"),
+            ctl_volume: helpers::parse_string(config, "Controls", "volume"),
+            ctl_limiter: config.get("Controls", "limiter"),
+            ctl_fault: config.get("Controls", "fault"),
+            ctl_unlock: config
+                .get("Controls", "unlock")
+                .unwrap_or_else(|| "Speaker Volume Unlock".to_string()),
+            ctl_group_unlock: parse_group_unlock(config),
+            fault_min_gain: helpers::parse_opt_bool(config, "Globals", "fault_min_gain")
+                .unwrap_or(false),
+            track_volume: helpers::parse_opt_bool(config, "Globals", "track_volume")
+                .unwrap_or(false),
+            uclamp_min: helpers::parse_opt_int(config, "Globals", "uclamp_min"),
+            uclamp_max: helpers::parse_opt_int(config, "Globals", "uclamp_max"),
+            sched_fifo: helpers::parse_opt_int(config, "Globals", "sched_fifo"),
+            cpu_affinity: config.get("Globals", "cpu_affinity"),
+            reopen_pcm: helpers::parse_opt_bool(config, "Globals", "reopen_pcm").unwrap_or(false),
+            idle: helpers::parse_opt_bool(config, "Globals", "idle").unwrap_or(true),
+            battery_batch: helpers::parse_opt_int(config, "Globals", "battery_batch")
+                .unwrap_or(1)
+                .max(1),
+            emergency_time: helpers::parse_opt_float(config, "Globals", "emergency_time")
+                .unwrap_or(2.),
+            over_limit: OverLimitPolicy::parse(config),
+            sense_fault_periods: helpers::parse_opt_int(config, "Globals", "sense_fault_periods")
+                .unwrap_or(8),
+            tamper_policy: TamperPolicy::parse(config),
+            mapping_check: MappingPolicy::parse(config),
+            monitor_pcm: config.get("Globals", "monitor_pcm"),
+            monitor_channels: helpers::parse_opt_int(config, "Globals", "monitor_channels")
+                .unwrap_or(2),
+            startup_timeout: helpers::parse_opt_float(config, "Globals", "startup_timeout")
+                .unwrap_or(60.),
+            deterministic: helpers::parse_opt_bool(config, "Globals", "deterministic")
+                .unwrap_or(false),
+            decimation: helpers::parse_opt_int(config, "Globals", "decimation").unwrap_or(1),
+            single_precision: helpers::parse_opt_bool(config, "Globals", "single_precision")
+                .unwrap_or(false),
+            scale_check: helpers::parse_opt_bool(config, "Globals", "scale_check").unwrap_or(true),
+            blackbox_limiting: helpers::parse_opt_float(config, "Globals", "blackbox_limiting"),
+            blackbox_limiting_interval: helpers::parse_opt_float(
+                config,
+                "Globals",
+                "blackbox_limiting_interval",
+            )
+            .unwrap_or(600.),
+        };
+
+        // These size the sense buffers
+        if !(1..=MAX_CHANNELS).contains(&globals.channels) {
+            panic!("Globals/channels: Out of bounds");
+        }
+        if !(1..=MAX_PERIOD).contains(&globals.period) {
+            panic!("Globals/period: Out of bounds");
+        }
+        if !(1..=MAX_CHANNELS).contains(&globals.monitor_channels) {
+            panic!("Globals/monitor_channels: Out of bounds");
+        }
+        if globals.battery_batch > MAX_BATCH {
+            panic!("Globals/battery_batch: Out of bounds");
+        }
+        if !(1..=MAX_DECIMATION).contains(&globals.decimation) {
+            panic!("Globals/decimation: Out of bounds");
+        }
+        // Deterministic mode has an integration of its own
+        if globals.single_precision && globals.deterministic {
+            panic!("Globals/single_precision: Not with deterministic");
+        }
+        if globals.startup_timeout < 0. {
+            panic!("Globals/startup_timeout: Out of bounds");
+        }
+        if globals.blackbox_limiting.is_some_and(|t| t <= 0.) {
+            panic!("Globals/blackbox_limiting: Out of bounds");
+        }
+        if globals.blackbox_limiting_interval < 0. {
+            panic!("Globals/blackbox_limiting_interval: Out of bounds");
+        }
+
+        globals
+    }
+}
+
+/// Upper bounds for the sense buffer geometry, far beyond any real hardware
+const MAX_CHANNELS: usize = 64;
+const MAX_PERIOD: usize = 1 << 16;
+const MAX_BATCH: usize = 64;
+/// Beyond this, a step gets long enough to smear over short bursts
+const MAX_DECIMATION: usize = 64;
+
+/// Maximum number of thermal nodes per speaker (coil, magnet and beyond)
+pub const MAX_NODES: usize = 6;
+
+/**
+    Version of the thermal model. Anything holding model state (blackbox
+    dumps, usage statistics) records it, so that state isn't read back by a
+    model that would make something else of it. Goes up whenever what the
+    state means changes, e.g. the integration or the limiter curve. Anything
+    from before it was recorded is version 1.
+*/
+pub const MODEL_VERSION: u32 = 1;
+/// How far the amp gain may be from expected_amp_gain_db (dB)
+const AMP_GAIN_TOLERANCE: f32 = 0.01;
+
+/// Beyond this many total time constants, a skipped model has settled at ambient
+const SKIP_SETTLED: f64 = 20.;
+
+/**
+    Fixed point scale of the temperatures in deterministic mode (1 °C), and
+    the extra fraction bits of the per node power coefficients.
+*/
+const FIXED_ONE: f64 = (1u64 << 32) as f64;
+const FIXED_POWER_SHIFT: u32 = 16;
+
+/// Gain for muting, well below the bottom of any volume control (dB)
+const MUTE_GAIN: f32 = -120.;
+
+/// Temperature the coil resistance coefficient is relative to (°C)
+const T_RDC_REF: f64 = 35.;
+
+/// How far ahead we look for the time to limit (s)
+const TTL_HORIZON: f64 = 600.;
+
+/**
+    Limiter window while boosted (°C). A boost is only granted if even
+    worst case power for its whole duration keeps the speaker below this
+    much under t_limit.
+*/
+const BOOST_WINDOW: f32 = 5.;
+
+/**
+    Tracks a speaker past t_limit + t_headroom. Rather than giving up on the
+    speakers right away, we hold the speaker at min gain, and only panic if
+    it stays past them regardless for emergency_time.
+*/
+#[derive(Debug, Copy, Clone)]
+struct Emergency {
+    /// Highest temperature beyond the limits so far (°C)
+    peak: f64,
+    /// How long it has been past the limits without a break (s)
+    over: f32,
+    /// Min gain wasn't enough, so we muted instead, per OverLimitPolicy::Mute
+    muted: bool,
+}
+
+/**
+    exp() out of nothing but IEEE arithmetic, which rounds the same way
+    everywhere: halve the argument until the series converges in a few
+    terms, then square it back up.
+*/
+fn exp_fixed(x: f64) -> f64 {
+    if !x.is_finite() {
+        return x.exp();
+    }
+    let mut halvings = 0;
+    let mut r = x;
+    while r.abs() > 1. / 256. && halvings < 1100 {
+        r /= 2.;
+        halvings += 1;
+    }
+    let mut sum = 1.;
+    let mut term = 1.;
+    for n in 1..=8 {
+        term *= r / n as f64;
+        sum += term;
+    }
+    for _ in 0..halvings {
+        sum *= sum;
+    }
+    sum
+}
+
+/// One stage of the thermal RC ladder
+#[derive(Debug, Copy, Clone)]
+struct ThermalNode {
+    /// Time constant (s)
+    tau: f32,
+    /// Thermal resistance to the next node out, or ambient for the last one (°C/W)
+    tr: f32,
+    /// The rise per unit of raw VSENSE * ISENSE, in fixed point
+    rise_q: i64,
+}
+
+impl ThermalNode {
+    /// The filter coefficient for a step of `step` seconds
+    fn alpha(&self, step: f32) -> f64 {
+        (step / (self.tau + step)) as f64
+    }
+}
+
+/// Sample rates a speaker keeps the coefficients of, for switching back and forth
+const MAX_RATES: usize = 4;
+
+/// The filter coefficients of every node for one step of the model
+#[derive(Debug, Default, Copy, Clone)]
+struct Step {
+    alpha: [f64; MAX_NODES],
+    /// The same in single precision, and in fixed point
+    alpha_f: [f32; MAX_NODES],
+    alpha_q: [i64; MAX_NODES],
+    /// 1 / frames in the step
+    inv_frames: f32,
+}
+
+/// The filter coefficients for one sample rate, see Speaker::set_sample_rate()
+#[derive(Debug, Default, Copy, Clone)]
+struct Coefficients {
+    sample_rate: f32,
+    /// For a step of Globals/decimation frames
+    full: Step,
+    /// For the shorter last step of a period, with its length in frames
+    tail: Option<(usize, Step)>,
+}
+
+/**
+    Parse the coupling of a speaker to its neighbours in the group, as a list
+    of name:coefficient pairs. Each neighbour's magnet rise above ambient,
+    times its coefficient, adds to this speaker's ambient. Speakers sharing
+    an enclosure or magnet structure heat each other that way.
+*/
+fn parse_coupling(config: &Ini, section: &str) -> Vec<(String, f32)> {
+    let Some(coupling) = config.get(section, "coupling") else {
+        return Vec::new();
+    };
+    let coupling: Vec<(String, f32)> = coupling
+        .split(',')
+        .filter(|c| !c.trim().is_empty())
+        .map(|c| {
+            let (name, k) = c.rsplit_once(':').unwrap_or_else(|| {
+                panic!(
+                    "{}/coupling: Expected name:coefficient, got '{}'",
+                    section, c
+                )
+            });
+            let k = k
+                .trim()
+                .parse::<f32>()
+                .ok()
+                .filter(|k| (0. ..=1.).contains(k))
+                .unwrap_or_else(|| panic!("{}/coupling: Invalid value '{}'", section, k));
+            (name.trim().to_string(), k)
+        })
+        .collect();
+    if coupling.iter().map(|(_, k)| k).sum::<f32>() > 1. {
+        panic!("{}/coupling: Out of bounds", section);
+    }
+    coupling
+}
+
+/**
+    Parse the thermal ladder of a speaker. The usual coil + magnet model is
+    given by tau_coil/tr_coil and tau_magnet/tr_magnet, anything more
+    involved (e.g. significant coupling to the basket or chassis) as a list
+    of tau:tr pairs from the coil outwards in the nodes key.
+*/
+fn parse_nodes(config: &Ini, section: &str) -> Vec<ThermalNode> {
+    let pairs: Vec<(f32, f32)> = match config.get(section, "nodes") {
+        Some(nodes) => nodes
+            .split(',')
+            .map(|node| {
+                let parse = |v: &str| {
+                    v.trim()
+                        .parse::<f32>()
+                        .ok()
+                        .filter(|v| v.is_finite() && *v > 0.)
+                        .unwrap_or_else(|| panic!("{}/nodes: Invalid value '{}'", section, v))
+                };
+                let (tau, tr) = node.split_once(':').unwrap_or_else(|| {
+                    panic!("{}/nodes: Expected tau:tr, got '{}'", section, node)
+                });
+                (parse(tau), parse(tr))
+            })
+            .collect(),
+        None => {
+            let parse = |key: &str| {
+                let v = helpers::parse_float(config, section, key);
+                if v <= 0. {
+                    panic!("{}/{}: Out of bounds", section, key);
+                }
+                v
+            };
+            vec![
+                (parse("tau_coil"), parse("tr_coil")),
+                (parse("tau_magnet"), parse("tr_magnet")),
+            ]
+        }
+    };
+
+    assert!(
+        (2..=MAX_NODES).contains(&pairs.len()),
+        "{}: Need 2 to {} thermal nodes",
+        section,
+        MAX_NODES
+    );
+
+    pairs
+        .into_iter()
+        .map(|(tau, tr)| ThermalNode { tau, tr, rise_q: 0 })
+        .collect()
+}
+
+/**
+    Struct representing a driver. Parameters are parsed out of a config
+    file, which is loaded at runtime based on the machine's DT compatible
+    string.
+
+    name:        driver name as it appears in ALSA
+    controls:    the driver's control elements, None when replaying sense
+                 data without the hardware
+    r_dc:        dc resistance of the voice coil (ohms)
+    nodes:       thermal RC ladder, coil first, then magnet and beyond
+    t_limit:  absolute max temp of the voice coil (*C)
+    t_limit_magnet: absolute max temp of the magnet (*C), if different
+
+    Borrows the handle to the control interface to do calculations.
+*/
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SpeakerState {
+    pub t_coil: f64,
+    pub t_magnet: f64,
+    /// Any further nodes beyond the magnet
+    pub t_outer: [f64; MAX_NODES - 2],
+
+    pub t_coil_hyst: f32,
+    pub t_magnet_hyst: f32,
+
+    pub min_gain: f32,
+    pub gain: f32,
+
+    /// Average power over the last period (W)
+    pub power: f32,
+    /// Apparent impedance over the last period (ohms), NaN while idle
+    pub impedance: f32,
+
+    pub amp_fault: i32,
+
+    /// Ambient temperature the model runs against (°C)
+    pub t_ambient: f32,
+}
+
+/**
+    The parsed model parameters of a speaker, as recorded in the blackbox
+    and handed out with the status. See Speaker::params().
+*/
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default)]
+pub struct SpeakerParams {
+    pub name: String,
+    pub group: usize,
+    pub group_name: Option<String>,
+    pub tau_coil: f32,
+    pub tau_magnet: f32,
+    pub tr_coil: f32,
+    pub tr_magnet: f32,
+    /// (tau, tr) of every node, coil first
+    pub nodes: Vec<[f32; 2]>,
+    pub t_limit: f32,
+    pub t_headroom: f32,
+    pub t_limit_magnet: f32,
+    pub t_headroom_magnet: f32,
+    pub z_nominal: f32,
+    pub a_rdc: Option<f32>,
+    pub is_scale: f32,
+    pub vs_scale: f32,
+    pub is_chan: usize,
+    pub vs_chan: usize,
+    pub t_ambient: f32,
+    /// Coupling factor to each neighbour, by name
+    pub coupling: BTreeMap<String, f32>,
+    pub t_window: f32,
+    pub t_hysteresis: f32,
+}
+
+pub struct Speaker<C: Controls = NoControls> {
+    pub name: String,
+    pub group: usize,
+    /// Human readable name of the group, e.g. "Woofers Left"
+    pub group_name: Option<String>,
+    pub enabled: bool,
+    pub fault: Option<SenseFault>,
+    pub tamper_count: u64,
+    /// Times the level read back didn't match what we asked for
+    pub level_mismatches: u64,
+    /// Whether the last level read back didn't match, to only warn once per run
+    level_mismatch: bool,
+    /// A level was set that hasn't been read back yet
+    level_pending: bool,
+    /// Scale check windows the sense data didn't add up with the config in
+    pub scale_mismatches: u64,
+    /// Whether the last scale check window failed, to only warn on changes
+    scale_mismatch: bool,
+    scale_check: Option<ScaleCheck>,
+    /// Level last applied (dB)
+    level: f32,
+    /// RMS voltage and current over the last period (V, A)
+    rms: (f32, f32),
+    controls: Option<C>,
+    nodes: Vec<ThermalNode>,
+    t_limit: f32,
+    t_headroom: f32,
+    /// The magnet may have its own limits (ferrofluid, adhesives)
+    t_limit_magnet: f32,
+    t_headroom_magnet: f32,
+    z_nominal: f32,
+    /// Coil resistance temperature coefficient (1/°C), if accounted for
+    a_rdc: Option<f32>,
+    is_scale: f32,
+    vs_scale: f32,
+    is_chan: usize,
+    vs_chan: usize,
+    /// Min gain with the user volume at 0 dB
+    min_gain_full: f32,
+    /// Worst case peak power at full scale (W)
+    peak_pwr: f32,
+    /// Steady state power that takes the coil or the magnet to its limit (W)
+    max_pwr: f32,
+    /// Whether a temporary boost is in effect
+    boost: bool,
+    /// Held at min gain on request (safe mode), the model keeps running
+    parked: bool,
+    /// Set while past the hard limits
+    emergency: Option<Emergency>,
+    /// Length of a sample (s)
+    sample_time: f32,
+    /// For the current sample rate, and the last few seen
+    coeffs: Coefficients,
+    rates: Vec<Coefficients>,
+    sense_check: SenseCheck,
+    /// Neighbours that heat this speaker, by name, with their coefficients
+    coupling: Vec<(String, f32)>,
+    /// The same, by index within the group, see resolve_coupling()
+    neighbors: Vec<(usize, f32)>,
+    /// How far the neighbours heat up our ambient (°C)
+    t_neighbors: f64,
+
+    g: Globals,
+    pub s: SpeakerState,
+}
+
+impl<C: Controls> Speaker<C> {
+    /// A speaker driven through `controls`, with the amp at `amp_gain` dBV
+    pub fn with_controls(
+        globals: &Globals,
+        name: &str,
+        config: &Ini,
+        amp_gain: f32,
+        cold_boot: bool,
+        controls: C,
+        handle: &C::Handle,
+    ) -> Self {
+        let mut speaker = Self::from_config(globals, name, config, amp_gain, cold_boot);
+        speaker.controls = Some(controls);
+        speaker.track_volume(handle);
+
+        speaker
+    }
+
+    /**
+        A speaker with no controls behind it, for replaying recorded sense
+        data. The amp gain would otherwise come from the driver.
+    */
+    pub fn offline(globals: &Globals, name: &str, config: &Ini, amp_gain: f32) -> Self {
+        Self::from_config(globals, name, config, amp_gain, true)
+    }
+
+    fn from_config(
+        globals: &Globals,
+        name: &str,
+        config: &Ini,
+        amp_gain: f32,
+        cold_boot: bool,
+    ) -> Self {
+        info!("Speaker [{}]:", name);
+
+        let section = "Speaker/".to_owned() + name;
+
+        // Tweeters and woofers may need their own thermal settings
+        let mut globals = globals.clone();
+        for (key, val) in [
+            ("t_ambient", &mut globals.t_ambient),
+            ("t_window", &mut globals.t_window),
+            ("t_hysteresis", &mut globals.t_hysteresis),
+        ] {
+            if let Some(v) = helpers::parse_opt_float(config, &section, key) {
+                info!("  {}: {:.1} (overrides {:.1})", key, v, val);
+                *val = v;
+            }
+        }
+        let globals = &globals;
+
+        let mut new_speaker = Self {
+            name: name.to_string(),
+            controls: None,
+            group: helpers::parse_int(config, &section, "group"),
+            // Quotes are allowed, as in group_name = "Woofers Left"
+            group_name: config
+                .get(&section, "group_name")
+                .map(|n| n.trim_matches('"').to_string()),
+            enabled: !helpers::parse_opt_bool(config, &section, "disabled").unwrap_or(false),
+            fault: None,
+            tamper_count: 0,
+            level_mismatches: 0,
+            level_mismatch: false,
+            level_pending: false,
+            scale_mismatches: 0,
+            scale_mismatch: false,
+            scale_check: None,
+            level: 0.,
+            rms: (0., 0.),
+            nodes: parse_nodes(config, &section),
+            t_limit: helpers::parse_float(config, &section, "t_limit"),
+            t_headroom: helpers::parse_float(config, &section, "t_headroom"),
+            t_limit_magnet: 0.,
+            t_headroom_magnet: 0.,
+            z_nominal: helpers::parse_float(config, &section, "z_nominal"),
+            a_rdc: helpers::parse_opt_float(config, &section, "a_rdc"),
+            is_scale: helpers::parse_float(config, &section, "is_scale"),
+            vs_scale: helpers::parse_float(config, &section, "vs_scale"),
+            is_chan: helpers::parse_int(config, &section, "is_chan"),
+            vs_chan: helpers::parse_int(config, &section, "vs_chan"),
+            min_gain_full: 0.,
+            peak_pwr: 0.,
+            max_pwr: 0.,
+            boost: false,
+            parked: false,
+            emergency: None,
+            sample_time: 0.,
+            coeffs: Default::default(),
+            rates: Vec::new(),
+            sense_check: SenseCheck::new(globals.sense_fault_periods),
+            coupling: parse_coupling(config, &section),
+            neighbors: Vec::new(),
+            t_neighbors: 0.,
+            g: globals.clone(),
+            s: Default::default(),
+        };
+
+        new_speaker.t_limit_magnet = helpers::parse_opt_float(config, &section, "t_limit_magnet")
+            .unwrap_or(new_speaker.t_limit);
+        new_speaker.t_headroom_magnet =
+            helpers::parse_opt_float(config, &section, "t_headroom_magnet")
+                .unwrap_or(new_speaker.t_headroom);
+
+        new_speaker.reset_state(cold_boot);
+        new_speaker.s.t_ambient = globals.t_ambient;
+
+        let s = &mut new_speaker.s;
+
+        // The steady state power that takes the coil or the magnet to its limit
+        let tr_coil: f32 = new_speaker.nodes.iter().map(|n| n.tr).sum();
+        let tr_magnet: f32 = new_speaker.nodes[1..].iter().map(|n| n.tr).sum();
+        let max_pwr = ((new_speaker.t_limit - globals.t_ambient) / tr_coil)
+            .min((new_speaker.t_limit_magnet - globals.t_ambient) / tr_magnet);
+
+        // Worst-case peak power is 2x RMS power
+        let peak_pwr = 10f32.powf(amp_gain / 10.) / new_speaker.z_nominal * 2.;
+
+        s.min_gain = ((max_pwr / peak_pwr).log10() * 10.).min(0.);
+
+        if new_speaker.is_chan >= globals.channels {
+            panic!("{}/is_chan: Out of bounds", section);
+        }
+        if new_speaker.vs_chan >= globals.channels {
+            panic!("{}/vs_chan: Out of bounds", section);
+        }
+        if new_speaker.t_limit - globals.t_window <= globals.t_ambient {
+            panic!("{}/t_limit: Not above t_ambient + t_window", section);
+        }
+        if new_speaker.t_limit_magnet - globals.t_window <= globals.t_ambient {
+            panic!("{}/t_limit_magnet: Not above t_ambient + t_window", section);
+        }
+        // The limits only hold for the amp gain the config was tuned for
+        if let Some(expected) = helpers::parse_opt_float(config, &section, "expected_amp_gain_db") {
+            if (amp_gain - expected).abs() > AMP_GAIN_TOLERANCE {
+                panic!(
+                    "{}/expected_amp_gain_db: Amp gain is {:.2} dB, not {:.2} dB",
+                    section, amp_gain, expected
+                );
+            }
+        }
+        if let Some((min, max)) =
+            helpers::parse_opt_range(config, &section, "expected_min_gain_range")
+        {
+            if !(min..=max).contains(&s.min_gain) {
+                panic!(
+                    "{}/expected_min_gain_range: Min gain is {:.2} dB, not within {:.2}..{:.2} dB",
+                    section, s.min_gain, min, max
+                );
+            }
+        }
+
+        info!(
+            "  Group: {}",
+            helpers::group_label(new_speaker.group, new_speaker.group_name.as_deref())
+        );
+        info!("  Max temperature: {:.1} °C", new_speaker.t_limit);
+        if new_speaker.t_limit_magnet != new_speaker.t_limit {
+            info!(
+                "  Max magnet temperature: {:.1} °C",
+                new_speaker.t_limit_magnet
+            );
+        }
+        info!("  Amp gain: {} dBV", amp_gain);
+        info!("  Max power: {:.2} W", max_pwr);
+        info!("  Peak power: {} W", peak_pwr);
+        info!("  Min gain: {:.2} dB", s.min_gain);
+        if !new_speaker.enabled {
+            warn!("  Disabled in config, will be held at min gain");
+        }
+
+        new_speaker.min_gain_full = new_speaker.s.min_gain;
+        new_speaker.peak_pwr = peak_pwr;
+        new_speaker.max_pwr = max_pwr;
+
+        if globals.scale_check {
+            // The peak voltage of the worst case peak power
+            let v_full = (peak_pwr * new_speaker.z_nominal).sqrt();
+            let check = ScaleCheck::new(
+                new_speaker.vs_scale,
+                new_speaker.is_scale,
+                new_speaker.z_nominal,
+                v_full,
+            );
+            for problem in check.startup() {
+                warn!("  Calibration: {}", problem);
+                new_speaker.scale_mismatches += 1;
+            }
+            new_speaker.scale_check = Some(check);
+        }
+
+        new_speaker
+    }
+
+    fn reset_state(&mut self, cold_boot: bool) {
+        let s = &mut self.s;
+
+        s.t_coil = if cold_boot {
+            // Assume warm but not warm enough to limit
+            (self.t_limit - self.g.t_window) as f64 - 1f64
+        } else {
+            // Worst case startup assumption
+            self.t_limit as f64
+        };
+
+        // The outer nodes at their share of the steady state rise
+        let tr_total: f32 = self.nodes.iter().map(|n| n.tr).sum();
+        let mut rise = s.t_coil - self.g.t_ambient as f64;
+        // Keep a lower magnet limit in the same place relative to the magnet
+        let share = (tr_total - self.nodes[0].tr) as f64 / tr_total as f64;
+        let limit_offset = (self.t_limit - self.t_limit_magnet) as f64;
+        if limit_offset > 0. {
+            rise = rise.min((s.t_coil - limit_offset - self.g.t_ambient as f64) / share);
+            s.t_coil = self.g.t_ambient as f64 + rise;
+        }
+        let mut tr_left = tr_total;
+        let mut t = self.temps();
+        for (t, inner) in t[1..self.nodes.len()].iter_mut().zip(self.nodes.iter()) {
+            tr_left -= inner.tr;
+            *t = self.g.t_ambient as f64 + rise * (tr_left / tr_total) as f64;
+        }
+        self.set_temps(&t);
+
+        let s = &mut self.s;
+        s.t_coil_hyst = 0.;
+        s.t_magnet_hyst = 0.;
+    }
+
+    /**
+        Enable or disable the speaker at runtime. Disabled speakers are
+        excluded from the model and held at min gain. Since we know nothing
+        about what happened while a speaker was disabled, re-enabling it
+        starts it off from the worst case startup assumption.
+    */
+    pub fn set_enabled(&mut self, enabled: bool) {
+        if enabled == self.enabled {
+            return;
+        }
+        if enabled {
+            info!("{}: Enabled", self.name);
+            self.reset_state(false);
+            self.fault = None;
+            self.sense_check = SenseCheck::new(self.g.sense_fault_periods);
+        } else {
+            warn!("{}: Disabled, holding at min gain", self.name);
+        }
+        self.enabled = enabled;
+    }
+
+    /**
+        Switch the filter coefficients over to a new sample rate, computing
+        them only for a rate not seen lately. This is the only place they
+        are computed, run_model() just looks them up. The thermal state
+        itself is left untouched.
+    */
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_time = 1. / sample_rate;
+        // Full scale is 32768 on both channels
+        let power_scale = self.vs_scale as f64 * self.is_scale as f64 / (32768. * 32768.);
+        for node in self.nodes.iter_mut() {
+            node.rise_q =
+                (power_scale * node.tr as f64 * FIXED_ONE * (1u64 << FIXED_POWER_SHIFT) as f64)
+                    .round() as i64;
+        }
+
+        if self.coeffs.sample_rate == sample_rate {
+            return;
+        }
+        self.coeffs = match self.rates.iter().find(|c| c.sample_rate == sample_rate) {
+            Some(coeffs) => *coeffs,
+            None => {
+                let coeffs = self.coefficients(sample_rate);
+                if self.rates.len() == MAX_RATES {
+                    self.rates.remove(0);
+                }
+                self.rates.push(coeffs);
+                coeffs
+            }
+        };
+    }
+
+    /// The filter coefficients for `sample_rate`, periods of Globals/period frames
+    fn coefficients(&self, sample_rate: f32) -> Coefficients {
+        let tail = match self.g.period % self.g.decimation {
+            0 => None,
+            frames => Some((frames, self.step(frames, sample_rate))),
+        };
+
+        Coefficients {
+            sample_rate,
+            full: self.step(self.g.decimation, sample_rate),
+            tail,
+        }
+    }
+
+    /// The filter coefficients for a step of `frames` frames at `sample_rate`
+    fn step(&self, frames: usize, sample_rate: f32) -> Step {
+        let mut step = Step {
+            inv_frames: 1. / frames as f32,
+            ..Default::default()
+        };
+        for (k, node) in self.nodes.iter().enumerate() {
+            step.alpha[k] = node.alpha(frames as f32 / sample_rate);
+            step.alpha_f[k] = step.alpha[k] as f32;
+            step.alpha_q[k] = (step.alpha[k] * FIXED_ONE).round() as i64;
+        }
+        step
+    }
+
+    /**
+        Run the model over one period of sense data and return the gain
+        the speaker needs. Returns None if the speaker got quarantined
+        because of implausible sense data, in which case it is disabled and
+        held at min gain from now on.
+    */
+    pub fn run_model(&mut self, buf: &[i16]) -> Option<f32> {
+        let mut stats = SenseStats::analyze(buf, self.g.channels, self.vs_chan, self.is_chan);
+        stats.scale(self.vs_scale, self.is_scale);
+        self.rms = (stats.v_rms * self.vs_scale, stats.i_rms * self.is_scale);
+
+        let fault = stats.fault();
+        if let Some(fault) = self.sense_check.update(fault) {
+            warn!(
+                "{}: Sense fault: kind={} periods={} v_rms={:.4} i_rms={:.4} pwr_avg={:.3}",
+                self.name,
+                fault,
+                self.sense_check.bad_periods(),
+                stats.v_rms,
+                stats.i_rms,
+                stats.pwr_avg
+            );
+            warn!("{}: Quarantined, holding at min gain", self.name);
+            self.fault = Some(fault);
+            self.enabled = false;
+            return None;
+        }
+        /*
+         * While we wait to see if a fault persists, a clipped channel is
+         * taken to be at full scale throughout, the most the sense path
+         * can measure, so the model can't fall behind the loudest
+         * playback. Anything else can't be trusted for even that, and
+         * mustn't cool the model down either, so it isn't integrated.
+         */
+        let pinned: Vec<i16>;
+        let buf = match fault {
+            None => {
+                self.check_scales(&stats);
+                buf
+            }
+            Some(fault) if fault.clipped() => {
+                debug!(
+                    "{}: Implausible sense data ({}), integrating at full scale",
+                    self.name, fault
+                );
+                pinned = self.full_scale(buf, stats.clipped());
+                stats = SenseStats::analyze(&pinned, self.g.channels, self.vs_chan, self.is_chan);
+                stats.scale(self.vs_scale, self.is_scale);
+                &pinned
+            }
+            Some(fault) => {
+                debug!(
+                    "{}: Implausible sense data ({}), not integrating",
+                    self.name, fault
+                );
+                return Some(self.s.gain);
+            }
+        };
+
+        assert!(self.coeffs.sample_rate > 0.);
+
+        let mut temps = self.temps();
+        let t = &mut temps[..self.nodes.len()];
+        let (over_coil, over_magnet) = if self.g.deterministic {
+            self.integrate_fixed(buf, t)
+        } else if self.g.single_precision {
+            self.integrate_f32(buf, t)
+        } else {
+            self.integrate(buf, t)
+        };
+
+        self.set_temps(&temps);
+        self.check_emergency(over_coil, over_magnet, buf.len() / self.g.channels);
+        let s = &mut self.s;
+
+        // Slightly negative power is just rounding error, anything worse was caught above
+        let pwr_avg = stats.pwr_avg.max(0.0);
+        s.power = pwr_avg;
+        s.impedance = stats.impedance(self.vs_scale, self.is_scale);
+
+        s.t_coil_hyst = s
+            .t_coil_hyst
+            .max(s.t_coil as f32)
+            .min(s.t_coil as f32 + self.g.t_hysteresis);
+        s.t_magnet_hyst = s
+            .t_magnet_hyst
+            .max(s.t_magnet as f32)
+            .min(s.t_magnet as f32 + self.g.t_hysteresis);
+
+        let window = if self.boost {
+            BOOST_WINDOW
+        } else {
+            self.g.t_window
+        };
+        // Whichever is deeper into its window
+        let reduction = ((s.t_coil_hyst - (self.t_limit - window)) / window)
+            .max((s.t_magnet_hyst - (self.t_limit_magnet - window)) / window);
+        let gain = s.min_gain * reduction.max(0.);
+
+        s.gain = gain;
+
+        if s.gain > -0.01 {
+            s.gain = 0.;
+        }
+        match self.emergency {
+            Some(em) if em.muted => s.gain = MUTE_GAIN,
+            Some(_) => s.gain = s.min_gain,
+            None => (),
+        }
+
+        debug!(
+            "{:>15}: Coil {:>6.2} °C Magnet {:>6.2} °C Power {:>5.2} W Gain {:>6.2} dB",
+            self.name, s.t_coil, s.t_magnet, pwr_avg, s.gain
+        );
+
+        Some(s.gain)
+    }
+
+    /**
+        `buf` with our sense channels that are clipped (VSENSE, ISENSE)
+        pinned to full scale, in phase with the other one so every frame
+        adds heat.
+    */
+    fn full_scale(&self, buf: &[i16], (v_clipped, i_clipped): (bool, bool)) -> Vec<i16> {
+        let full = |other: i16| if other < 0 { -i16::MAX } else { i16::MAX };
+        let mut pinned = buf.to_vec();
+        for frame in pinned.chunks_mut(self.g.channels) {
+            if v_clipped {
+                frame[self.vs_chan] = full(frame[self.is_chan]);
+            }
+            if i_clipped {
+                frame[self.is_chan] = full(frame[self.vs_chan]);
+            }
+        }
+        pinned
+    }
+
+    /**
+        The filter coefficients for a step of `frames` frames. Only the last
+        step of a period comes up short of Globals/decimation, by as much as
+        set_sample_rate() expected unless the period is short itself, and
+        only then does this compute anything.
+    */
+    fn step_coeffs(&self, frames: usize) -> Step {
+        let c = &self.coeffs;
+        match c.tail {
+            _ if frames == self.g.decimation => c.full,
+            Some((tail, step)) if frames == tail => step,
+            _ => self.step(frames, c.sample_rate),
+        }
+    }
+
+    /**
+        Run the node temperatures `t` over the sense data in `buf`. Returns
+        how far past the hard limits the coil and magnet got (°C).
+
+        Each step covers Globals/decimation frames, on their mean power.
+        The heat going in is the same as frame by frame, and the coefficients
+        are for the longer step, so the temperatures only lose the ripple
+        within a step, far faster than any node's time constant.
+    */
+    fn integrate(&self, buf: &[i16], t: &mut [f64]) -> (f64, f64) {
+        let mut over_coil = f64::NEG_INFINITY;
+        let mut over_magnet = f64::NEG_INFINITY;
+        // Full scale is 32768, a power of two, so this rounds the same as dividing each sample
+        let vs_scale = self.vs_scale / 32768.0;
+        let is_scale = self.is_scale / 32768.0;
+
+        for step in buf.chunks(self.g.channels * self.g.decimation) {
+            assert!(step.len() % self.g.channels == 0);
+            let coeffs = self.step_coeffs(step.len() / self.g.channels);
+
+            let p = step
+                .chunks(self.g.channels)
+                .map(|sample| {
+                    let v = sample[self.vs_chan] as f32 * vs_scale;
+                    let i = sample[self.is_chan] as f32 * is_scale;
+                    v * i
+                })
+                .sum::<f32>()
+                * coeffs.inv_frames;
+
+            // Each node heads for the next one out plus its own rise, the last one for ambient
+            for (k, (node, alpha)) in self.nodes.iter().zip(coeffs.alpha).enumerate() {
+                let base = t.get(k + 1).copied().unwrap_or(self.ambient());
+                let target = base + (p * node.tr) as f64;
+                t[k] = target * alpha + t[k] * (1. - alpha);
+            }
+
+            // The outer nodes can't get hotter than the magnet
+            over_coil = over_coil.max(t[0] - (self.t_limit + self.t_headroom) as f64);
+            over_magnet =
+                over_magnet.max(t[1] - (self.t_limit_magnet + self.t_headroom_magnet) as f64);
+        }
+
+        (over_coil, over_magnet)
+    }
+
+    /**
+        integrate() in single precision only (Globals/single_precision),
+        for machines where f64 arithmetic costs more than f32. Measure
+        first (see benches/model.rs), on a 64-bit FPU it is about even.
+
+        An f32 has 24 bits, so at the temperatures we deal with a step moves
+        a slow node by less than its rounding error, and plain f32 steps
+        would leave the magnet stuck. Each node carries the error of its
+        last step in `err` and takes it back out on the next (Kahan
+        summation), which keeps it within a ten-thousandth of a degree of
+        integrate() over long runs (see tests/props.rs). In between periods the
+        state stays f64, error included, so nothing is lost there either.
+    */
+    fn integrate_f32(&self, buf: &[i16], t: &mut [f64]) -> (f64, f64) {
+        let mut tf = [0f32; MAX_NODES];
+        let mut err = [0f32; MAX_NODES];
+        for ((tf, err), t) in tf.iter_mut().zip(err.iter_mut()).zip(t.iter()) {
+            *tf = *t as f32;
+            *err = (*tf as f64 - *t) as f32;
+        }
+        let tf = &mut tf[..t.len()];
+        let ambient = self.ambient() as f32;
+        let limit_coil = self.t_limit + self.t_headroom;
+        let limit_magnet = self.t_limit_magnet + self.t_headroom_magnet;
+        let vs_scale = self.vs_scale / 32768.0;
+        let is_scale = self.is_scale / 32768.0;
+        let mut over_coil = f32::NEG_INFINITY;
+        let mut over_magnet = f32::NEG_INFINITY;
+
+        for step in buf.chunks(self.g.channels * self.g.decimation) {
+            assert!(step.len() % self.g.channels == 0);
+            let coeffs = self.step_coeffs(step.len() / self.g.channels);
+
+            let p = step
+                .chunks(self.g.channels)
+                .map(|sample| {
+                    let v = sample[self.vs_chan] as f32 * vs_scale;
+                    let i = sample[self.is_chan] as f32 * is_scale;
+                    v * i
+                })
+                .sum::<f32>()
+                * coeffs.inv_frames;
+
+            for (k, (node, alpha)) in self.nodes.iter().zip(coeffs.alpha_f).enumerate() {
+                let base = tf.get(k + 1).copied().unwrap_or(ambient);
+                let delta = (base + p * node.tr - tf[k]) * alpha - err[k];
+                let next = tf[k] + delta;
+                err[k] = (next - tf[k]) - delta;
+                tf[k] = next;
+            }
+
+            over_coil = over_coil.max(tf[0] - limit_coil);
+            over_magnet = over_magnet.max(tf[1] - limit_magnet);
+        }
+
+        for ((t, tf), err) in t.iter_mut().zip(tf.iter()).zip(err.iter()) {
+            *t = *tf as f64 - *err as f64;
+        }
+        (over_coil as f64, over_magnet as f64)
+    }
+
+    /**
+        integrate() in fixed point, for deterministic mode. The power is
+        the raw product of the sense samples, the temperatures are in units
+        of 1/FIXED_ONE °C and every step rounds down (the mean power of a
+        step too), so the same sense data gives the same bits on any
+        machine and with any compiler, which the float version can't
+        promise.
+    */
+    fn integrate_fixed(&self, buf: &[i16], t: &mut [f64]) -> (f64, f64) {
+        let fixed = |t: f64| (t * FIXED_ONE).round() as i64;
+        let mut tq = [0i64; MAX_NODES];
+        for (tq, t) in tq.iter_mut().zip(t.iter()) {
+            *tq = fixed(*t);
+        }
+        let tq = &mut tq[..t.len()];
+        let ambient = fixed(self.ambient());
+        let limit_coil = fixed((self.t_limit + self.t_headroom) as f64);
+        let limit_magnet = fixed((self.t_limit_magnet + self.t_headroom_magnet) as f64);
+        let mut over_coil = i64::MIN;
+        let mut over_magnet = i64::MIN;
+
+        for step in buf.chunks(self.g.channels * self.g.decimation) {
+            assert!(step.len() % self.g.channels == 0);
+            let frames = step.len() / self.g.channels;
+            let alphas = self.step_coeffs(frames).alpha_q;
+
+            let p = step
+                .chunks(self.g.channels)
+                .map(|sample| sample[self.vs_chan] as i128 * sample[self.is_chan] as i128)
+                .sum::<i128>()
+                .div_euclid(frames as i128);
+            for (k, (node, alpha_q)) in self.nodes.iter().zip(alphas).enumerate() {
+                let base = tq.get(k + 1).copied().unwrap_or(ambient);
+                let target = base + ((p * node.rise_q as i128) >> FIXED_POWER_SHIFT) as i64;
+                tq[k] += (((target - tq[k]) as i128 * alpha_q as i128) >> 32) as i64;
+            }
+
+            over_coil = over_coil.max(tq[0] - limit_coil);
+            over_magnet = over_magnet.max(tq[1] - limit_magnet);
+        }
+
+        for (t, tq) in t.iter_mut().zip(tq.iter()) {
+            *t = *tq as f64 / FIXED_ONE;
+        }
+        let over = |o: i64| match o {
+            i64::MIN => f64::NEG_INFINITY,
+            o => o as f64 / FIXED_ONE,
+        };
+        (over(over_coil), over(over_magnet))
+    }
+
+    /**
+        Enter, track and leave the over temperature emergency, given how far
+        past the hard limits the coil and magnet got over `frames`.
+    */
+    fn check_emergency(&mut self, over_coil: f64, over_magnet: f64, frames: usize) {
+        let over = over_coil.max(over_magnet);
+        let temp = self.s.t_coil.max(self.s.t_magnet);
+
+        match self.emergency.as_mut() {
+            None if over > 0. => {
+                let (what, temp, limit) = if over_coil >= over_magnet {
+                    ("Coil", self.s.t_coil, self.t_limit)
+                } else {
+                    ("Magnet", self.s.t_magnet, self.t_limit_magnet)
+                };
+                let reason = format!(
+                    "{}: {} temperature limit exceeded ({:.2} > {:.1})",
+                    self.name, what, temp, limit
+                );
+                let mut em = Emergency {
+                    peak: temp,
+                    over: 0.,
+                    muted: false,
+                };
+                if self.g.emergency_time <= 0. {
+                    self.give_up(&mut em, &reason);
+                } else {
+                    warn!("{}, holding at min gain", reason);
+                }
+                self.emergency = Some(em);
+            }
+            None => (),
+            Some(em) if em.muted => {
+                // Stay muted until properly cool again
+                let cool = self.margin(self.g.t_hysteresis) > 0.;
+                if cool {
+                    info!("{}: Cooled down, unmuting", self.name);
+                    self.emergency = None;
+                }
+            }
+            Some(em) => {
+                let mut em = *em;
+                em.peak = em.peak.max(temp);
+                if over > 0. {
+                    em.over += frames as f32 * self.sample_time;
+                    if em.over > self.g.emergency_time {
+                        let reason = format!(
+                            "{}: Still over the limits at min gain after {:.1} s (peak {:.2} °C)",
+                            self.name, em.over, em.peak
+                        );
+                        self.give_up(&mut em, &reason);
+                    }
+                } else {
+                    em.over = 0.;
+                }
+                // Back to normal once under the limits proper
+                if !em.muted && self.margin(0.) > 0. {
+                    info!("{}: Temperature back under the limits", self.name);
+                    self.emergency = None;
+                } else {
+                    self.emergency = Some(em);
+                }
+            }
+        }
+    }
+
+    /// Min gain isn't enough, so apply the over limit policy
+    fn give_up(&self, em: &mut Emergency, reason: &str) {
+        match self.g.over_limit {
+            OverLimitPolicy::Panic => exit::fail(Failure::Overtemperature, reason.into()),
+            OverLimitPolicy::Mute => {
+                warn!("{}, muting the group", reason);
+                em.muted = true;
+            }
+        }
+    }
+
+    /// Whether the group is muted after a sustained limit violation
+    pub fn muted(&self) -> bool {
+        self.emergency.is_some_and(|em| em.muted)
+    }
+
+    /// Whether the speaker is held at min gain after exceeding the hard limits
+    pub fn in_emergency(&self) -> bool {
+        self.emergency.is_some()
+    }
+
+    pub fn skip_model(&mut self, time: f64) {
+        let ambient = self.ambient();
+        let mut t = self.temps();
+
+        if self.nodes.len() == 2 {
+            let t_coil = t[0] - ambient;
+            let t_magnet = t[1] - ambient;
+
+            let tau_coil = self.nodes[0].tau;
+            let tau_magnet = self.nodes[1].tau;
+            let eta = 1f64 / (1f64 - (tau_coil / tau_magnet) as f64);
+            // The libm exp() isn't the same everywhere
+            let exp = match self.g.deterministic {
+                true => exp_fixed,
+                false => f64::exp,
+            };
+            let a = exp(-time / tau_coil as f64) * (t_coil - eta * t_magnet);
+            let b = exp(-time / tau_magnet as f64) * t_magnet;
+
+            t[0] = ambient + a + b * eta;
+            t[1] = ambient + b;
+        } else if time > SKIP_SETTLED * self.nodes.iter().map(|n| n.tau as f64).sum::<f64>() {
+            t.fill(ambient);
+        } else {
+            // No closed form worth having, so step through the idle decay
+            let tau_min = self
+                .nodes
+                .iter()
+                .map(|n| n.tau)
+                .fold(f32::INFINITY, f32::min);
+            let steps = (time / (tau_min as f64 / 10.)).ceil().max(1.);
+            let step = time / steps;
+            let t = &mut t[..self.nodes.len()];
+            for _ in 0..steps as usize {
+                for (k, node) in self.nodes.iter().enumerate() {
+                    let target = t.get(k + 1).copied().unwrap_or(ambient);
+                    t[k] += (target - t[k]) * step / (node.tau as f64 + step);
+                }
+            }
+        }
+
+        if self.g.deterministic {
+            t.iter_mut()
+                .for_each(|t| *t = (*t * FIXED_ONE).round() / FIXED_ONE);
+        }
+        self.set_temps(&t);
+        debug!(
+            "{}: SKIP: Coil {:.2} °C Magnet {:.2} °C ({:.2} seconds)",
+            self.name, self.s.t_coil, self.s.t_magnet, time
+        );
+    }
+
+    /// The ambient the outermost node heads for, with the neighbours' heat
+    fn ambient(&self) -> f64 {
+        self.g.t_ambient as f64 + self.t_neighbors
+    }
+
+    /// The node temperatures, coil first
+    fn temps(&self) -> [f64; MAX_NODES] {
+        let mut t = [0.; MAX_NODES];
+        t[0] = self.s.t_coil;
+        t[1] = self.s.t_magnet;
+        t[2..].copy_from_slice(&self.s.t_outer);
+        t
+    }
+
+    fn set_temps(&mut self, t: &[f64; MAX_NODES]) {
+        self.s.t_coil = t[0];
+        self.s.t_magnet = t[1];
+        self.s.t_outer.copy_from_slice(&t[2..]);
+    }
+
+    /**
+        Scale min_gain to the user volume. Turned down, the worst case output
+        is that much lower, so we don't need to limit as hard. Returns
+        whether min_gain changed.
+    */
+    pub fn track_volume(&mut self, handle: &C::Handle) -> bool {
+        let volume = match self.controls.as_mut().and_then(|c| c.volume(handle)) {
+            Some(volume) => volume,
+            None => return false,
+        };
+
+        let min_gain = (self.min_gain_full - volume).min(0.);
+        if min_gain == self.s.min_gain {
+            return false;
+        }
+
+        debug!(
+            "{}: Volume {:.2} dB, min gain {:.2} dB",
+            self.name, volume, min_gain
+        );
+        self.s.min_gain = min_gain;
+        true
+    }
+
+    /**
+        Poll the amp fault register, if there is one. Returns true if the
+        fault state changed since the last poll.
+    */
+    pub fn check_amp_fault(&mut self, handle: &C::Handle) -> bool {
+        let fault = match self.controls.as_mut().and_then(|c| c.fault(handle)) {
+            Some(fault) => fault,
+            None => return false,
+        };
+
+        if fault == self.s.amp_fault {
+            return false;
+        }
+
+        if fault != 0 {
+            warn!("{}: Amp fault: 0x{:x}", self.name, fault);
+        } else {
+            info!("{}: Amp fault cleared", self.name);
+        }
+        self.s.amp_fault = fault;
+        true
+    }
+
+    /// The parsed model parameters, for the record
+    pub fn params(&self) -> SpeakerParams {
+        SpeakerParams {
+            name: self.name.clone(),
+            group: self.group,
+            group_name: self.group_name.clone(),
+            tau_coil: self.nodes[0].tau,
+            tau_magnet: self.nodes[1].tau,
+            tr_coil: self.nodes[0].tr,
+            tr_magnet: self.nodes[1].tr,
+            nodes: self.nodes.iter().map(|n| [n.tau, n.tr]).collect(),
+            t_limit: self.t_limit,
+            t_headroom: self.t_headroom,
+            t_limit_magnet: self.t_limit_magnet,
+            t_headroom_magnet: self.t_headroom_magnet,
+            z_nominal: self.z_nominal,
+            a_rdc: self.a_rdc,
+            is_scale: self.is_scale,
+            vs_scale: self.vs_scale,
+            is_chan: self.is_chan,
+            vs_chan: self.vs_chan,
+            t_ambient: self.g.t_ambient,
+            coupling: self.coupling.iter().cloned().collect(),
+            t_window: self.g.t_window,
+            t_hysteresis: self.g.t_hysteresis,
+        }
+    }
+
+    /// The (vs_chan, is_chan) pair
+    pub fn sense_chans(&self) -> (usize, usize) {
+        (self.vs_chan, self.is_chan)
+    }
+
+    /// RMS voltage and current over the last period (V, A)
+    pub fn rms(&self) -> (f32, f32) {
+        self.rms
+    }
+
+    pub fn z_nominal(&self) -> f32 {
+        self.z_nominal
+    }
+
+    /**
+        How far the coil and magnet are from `offset` under their limits, for
+        whichever is closer (°C, negative once past)
+    */
+    pub fn margin(&self, offset: f32) -> f32 {
+        (self.t_limit - offset - self.s.t_coil as f32)
+            .min(self.t_limit_magnet - offset - self.s.t_magnet as f32)
+    }
+
+    /// How far the coil and the magnet are from their hard limits (°C)
+    pub fn margins(&self) -> (f32, f32) {
+        (
+            self.t_limit - self.s.t_coil as f32,
+            self.t_limit_magnet - self.s.t_magnet as f32,
+        )
+    }
+
+    /// Temperature margin before the limiter engages (negative while limiting)
+    pub fn headroom(&self) -> f32 {
+        self.margin(self.g.t_window)
+    }
+
+    /**
+        Coil resistance at `t_coil`, relative to T_RDC_REF. Without a_rdc,
+        we pretend it's constant.
+    */
+    fn rdc_scale(&self, t_coil: f64) -> f64 {
+        match self.a_rdc {
+            Some(a) => 1. + a as f64 * (t_coil - T_RDC_REF),
+            None => 1.,
+        }
+    }
+
+    /**
+        Run the model ahead at a constant drive level that currently gives
+        `power`, and return when the coil or magnet would get within `offset`
+        of its limit, if that happens within `horizon` (s). The measured power already
+        reflects the coil resistance, but ahead of time the power drops as
+        the coil heats up and its resistance rises, which a_rdc accounts for.
+    */
+    fn predict(&self, power: f64, offset: f32, horizon: f64) -> Option<f64> {
+        let ambient = self.ambient();
+        let coil_threshold = (self.t_limit - offset) as f64;
+        let magnet_threshold = (self.t_limit_magnet - offset) as f64;
+
+        // Bail if where they settle is still fine
+        let tr_total: f64 = self.nodes.iter().map(|n| n.tr as f64).sum();
+        let tr_magnet = tr_total - self.nodes[0].tr as f64;
+        if ambient + power * tr_total <= coil_threshold
+            && ambient + power * tr_magnet <= magnet_threshold
+        {
+            return None;
+        }
+
+        let tau_min = self
+            .nodes
+            .iter()
+            .map(|n| n.tau)
+            .fold(f32::INFINITY, f32::min);
+        let step = tau_min as f64 / 2.;
+        let mut temps = self.temps();
+        let t = &mut temps[..self.nodes.len()];
+        let rdc_now = self.rdc_scale(t[0]);
+        let mut time = 0.;
+
+        while time <= horizon {
+            if t[0] > coil_threshold || t[1] > magnet_threshold {
+                return Some(time);
+            }
+            let power = power * rdc_now / self.rdc_scale(t[0]);
+            for (k, node) in self.nodes.iter().enumerate() {
+                let target = t.get(k + 1).copied().unwrap_or(ambient) + power * node.tr as f64;
+                t[k] += (target - t[k]) * step / (node.tau as f64 + step);
+            }
+            time += step;
+        }
+
+        None
+    }
+
+    /**
+        Estimate how long until the limiter engages if the current power
+        keeps up (s). Some(0) while limiting, None if it won't happen within
+        TTL_HORIZON, or at all.
+    */
+    pub fn time_to_limit(&self) -> Option<f32> {
+        if !self.enabled {
+            return None;
+        }
+        if self.s.gain < 0. {
+            return Some(0.);
+        }
+
+        self.predict(self.s.power as f64, self.g.t_window, TTL_HORIZON)
+            .map(|t| t as f32)
+    }
+
+    /**
+        Whether there is the thermal headroom for a boost of `seconds`: We
+        must not be recovering from limiting still, and worst case power for
+        the whole time must keep us within the boost limits.
+    */
+    pub fn boost_allowed(&self, seconds: f32) -> bool {
+        if !self.enabled {
+            // Held at min gain regardless
+            return true;
+        }
+
+        let window = self.g.t_window;
+        if self.s.t_coil_hyst > self.t_limit - window
+            || self.s.t_magnet_hyst > self.t_limit_magnet - window
+        {
+            return false;
+        }
+
+        // Worst-case RMS power is half the peak power, which is for a cool coil
+        let worst = (self.peak_pwr / 2.) as f64 / self.rdc_scale(self.s.t_coil).max(1.);
+        self.predict(worst, BOOST_WINDOW, seconds as f64).is_none()
+    }
+
+    /**
+        Move the limiter up to BOOST_WINDOW under t_limit while boosted. Once
+        the boost is over, the regular limiter brings the temperature back
+        down.
+    */
+    pub fn set_boost(&mut self, boost: bool) {
+        self.boost = boost;
+    }
+
+    /// Hold the speaker at min gain regardless of the model, or stop doing so
+    pub fn set_parked(&mut self, parked: bool) {
+        self.parked = parked;
+    }
+
+    /// Min gain with the user volume at 0 dB, what a held speaker is set to
+    pub fn min_gain_full(&self) -> f32 {
+        self.min_gain_full
+    }
+
+    /// Steady state power that takes the coil or the magnet to its limit (W)
+    pub fn max_power(&self) -> f32 {
+        self.max_pwr
+    }
+
+    /// Worst case peak power at full scale (W)
+    pub fn peak_power(&self) -> f32 {
+        self.peak_pwr
+    }
+
+    /// Whether the named control is one of this speaker's controls
+    pub fn owns_control(&self, name: &str) -> bool {
+        self.controls.as_ref().is_some_and(|c| c.owns(name))
+    }
+
+    /**
+        Check that nobody else changed our controls behind our back, and
+        apply the configured tamper policy if they did.
+    */
+    pub fn check_tamper(&mut self, handle: &C::Handle) {
+        let Some(controls) = self.controls.as_mut() else {
+            return;
+        };
+        if controls.verify(handle) {
+            return;
+        }
+
+        self.tamper_count += 1;
+        warn!("{}: Controls changed externally!", self.name);
+
+        if self.g.tamper_policy == TamperPolicy::Panic {
+            panic!("{}: Controls tampered with", self.name);
+        }
+        if !controls.restore(handle) {
+            panic!("{}: Failed to restore controls after tampering", self.name);
+        }
+        warn!("{}: Controls restored", self.name);
+    }
+
+    /// Cross-check one period's sense data against the configured scales
+    fn check_scales(&mut self, stats: &SenseStats) {
+        let Some(check) = self.scale_check.as_mut() else {
+            return;
+        };
+        match check.update(stats, self.level) {
+            Some(Err(mismatch)) => {
+                self.scale_mismatches += 1;
+                if !self.scale_mismatch {
+                    warn!("{}: Calibration: {}", self.name, mismatch);
+                    warn!(
+                        "{}: The model may be wrong about this speaker's temperature",
+                        self.name
+                    );
+                }
+                self.scale_mismatch = true;
+            }
+            Some(Ok(())) => self.scale_mismatch = false,
+            None => {}
+        }
+    }
+
+    pub fn update(&mut self, handle: &C::Handle, gain: f32) {
+        let hold = !self.enabled || self.parked || (self.g.fault_min_gain && self.s.amp_fault != 0);
+        // Don't count on the user volume staying down while we're not watching
+        let gain = if hold { self.min_gain_full } else { gain };
+        self.level = gain;
+        if let Some(controls) = self.controls.as_mut() {
+            controls.set_level(handle, gain);
+            self.level_pending = true;
+            self.check_level(handle);
+        }
+        self.check_tamper(handle);
+    }
+
+    /**
+        Read back the level last set, once it has reached the control. That
+        may take a period or more if writes go through a thread, so call
+        this every period. Catches quantization bugs in the driver, and
+        anyone else writing to it.
+    */
+    pub fn check_level(&mut self, handle: &C::Handle) {
+        let Some(controls) = self.controls.as_mut() else {
+            return;
+        };
+        if !self.level_pending || !controls.level_settled() {
+            return;
+        }
+        self.level_pending = false;
+
+        let applied = controls.check_level(handle, self.level);
+        if let Some(applied) = applied {
+            self.level_mismatches += 1;
+            if !self.level_mismatch {
+                warn!(
+                    "{}: Level set to {:.2} dB, but reads back as {:.2} dB",
+                    self.name, self.level, applied
+                );
+            }
+        }
+        self.level_mismatch = applied.is_some();
+    }
+}
+
+/**
+    Look up the neighbours each speaker of a group is coupled to. Call once
+    the group is complete, before couple().
+*/
+pub fn resolve_coupling<C: Controls>(speakers: &mut [Speaker<C>]) {
+    let names: Vec<String> = speakers.iter().map(|s| s.name.clone()).collect();
+    for spk in speakers.iter_mut() {
+        spk.neighbors = spk
+            .coupling
+            .iter()
+            .map(|(name, k)| {
+                let idx = names
+                    .iter()
+                    .position(|n| n == name && *n != spk.name)
+                    .unwrap_or_else(|| {
+                        panic!(
+                            "Speaker/{}/coupling: No other speaker {} in group {}",
+                            spk.name, name, spk.group
+                        )
+                    });
+                (idx, *k)
+            })
+            .collect();
+    }
+}
+
+/**
+    Let the speakers of a group heat each other: each one's ambient goes up
+    by its share of its enabled neighbours' magnet rise, as of the last
+    period. Call before running the model on every period.
+*/
+pub fn couple<C: Controls>(speakers: &mut [Speaker<C>]) {
+    for i in 0..speakers.len() {
+        let rise: f64 = speakers[i]
+            .neighbors
+            .iter()
+            .map(|&(j, k)| (&speakers[j], k))
+            .filter(|(n, _)| n.enabled)
+            .map(|(n, k)| k as f64 * (n.s.t_magnet - n.g.t_ambient as f64).max(0.))
+            .sum();
+        speakers[i].t_neighbors = rise;
+        speakers[i].s.t_ambient = speakers[i].ambient() as f32;
+    }
+}
+
+/*
+ * Regression cases from fuzzing the config loader, see fuzz/. A bad config
+ * may stop the daemon, but only with a panic naming the offending key.
+ */
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::config;
+
+    /// Load a config the way the daemon does, and run every speaker for a period
+    pub(crate) fn load_config(text: &str) {
+        let mut cfg = Ini::new_cs();
+        cfg.read(text.to_string()).unwrap();
+        config::migrate(&mut cfg);
+        let globals = Globals::parse(&cfg);
+
+        let buf = vec![0i16; globals.period * globals.channels];
+        for section in cfg.sections() {
+            let Some(name) = section.strip_prefix("Speaker/") else {
+                continue;
+            };
+            let mut spk: Speaker = Speaker::offline(&globals, name, &cfg, 15.);
+            spk.set_sample_rate(48000.);
+            spk.run_model(&buf);
+            spk.skip_model(1.);
+        }
+    }
+
+    /// The j274 config with one value changed
+    pub(crate) fn patched(section: &str, key: &str, value: &str) -> String {
+        let mut cfg = Ini::new_cs();
+        cfg.load(concat!(env!("CARGO_MANIFEST_DIR"), "/../conf/apple/j274.conf"))
+            .unwrap();
+        cfg.set(section, key, Some(value.to_string()));
+        cfg.writes()
+    }
+
+    #[test]
+    #[should_panic(expected = "Globals/period: Out of bounds")]
+    fn huge_period() {
+        load_config(&patched("Globals", "period", "99999999999"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Globals/channels: Out of bounds")]
+    fn no_channels() {
+        load_config(&patched("Globals", "channels", "0"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Globals/channels: Out of bounds")]
+    fn negative_channels() {
+        load_config(&patched("Globals", "channels", "-1"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Globals/t_ambient: Invalid value")]
+    fn infinite_float() {
+        load_config(&patched("Globals", "t_ambient", "1e40"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Speaker/Mono/vs_chan: Out of bounds")]
+    fn sense_channel_out_of_range() {
+        load_config(&patched("Speaker/Mono", "vs_chan", "2"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Speaker/Mono/t_limit: Not above t_ambient + t_window")]
+    fn limit_below_window() {
+        load_config(&patched("Speaker/Mono", "t_limit", "50"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Speaker/Mono/tau_coil: Out of bounds")]
+    fn zero_time_constant() {
+        load_config(&patched("Speaker/Mono", "tau_coil", "0"));
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Speaker/Mono/expected_amp_gain_db: Amp gain is 15.00 dB, not 18.00 dB"
+    )]
+    fn unexpected_amp_gain() {
+        load_config(&patched("Speaker/Mono", "expected_amp_gain_db", "18"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Speaker/Mono/expected_min_gain_range: Invalid value")]
+    fn backwards_min_gain_range() {
+        load_config(&patched(
+            "Speaker/Mono",
+            "expected_min_gain_range",
+            "0, -10",
+        ));
+    }
+
+    /// Min gain holding the speaker level, but past the limits, must still give up
+    #[test]
+    fn flat_over_limit_gives_up() {
+        let mut cfg = Ini::new_cs();
+        cfg.read(patched("Globals", "over_limit", "mute")).unwrap();
+        cfg.set("Globals", "emergency_time", Some("2".into()));
+        config::migrate(&mut cfg);
+        let globals = Globals::parse(&cfg);
+        let mut spk: Speaker = Speaker::offline(&globals, "Mono", &cfg, 15.);
+        spk.set_sample_rate(48000.);
+
+        spk.s.t_coil = (spk.t_limit + spk.t_headroom + 5.) as f64;
+        let frames = globals.period;
+        let periods = (3. * 48000. / frames as f32) as usize;
+        for _ in 0..periods {
+            spk.check_emergency(5., f64::NEG_INFINITY, frames);
+        }
+        assert!(spk.muted());
+    }
+}
@@ -0,0 +1,542 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    Property tests for the thermal model. Each property is checked against
+    generated speakers, two-node ones and longer ladders, fed with random
+    amounts of power:
+
+    - No node ever gets colder than ambient.
+    - Skipping a + b seconds lands where skipping a, then b does.
+    - Without power, the hottest node only ever cools down, and everything
+      settles at ambient.
+    - The gain never goes up with the temperature.
+    - Time steps that aren't positive (or aren't numbers) are clamped, and
+      running the model for them doesn't break it.
+    - Decimating the model (Globals/decimation) keeps it within a fraction
+      of a percent of the rise of the full rate one, including for periods
+      that don't divide into whole steps.
+    - Switching the sample rate away and back lands on the same bits as
+      never switching.
+    - The single precision model (Globals/single_precision) stays within
+      F32_TOLERANCE of the f64 one over twenty minutes of playback. That
+      takes minutes, so it's an ignored test, with a short run by default.
+    - Clipped sense data heats the model at least as much as the loudest
+      playback the sense path can measure, until the speaker is
+      quarantined.
+
+    PROPTEST_CASES sets the number of cases per property, instead of CASES.
+*/
+use std::f32::consts::PI;
+
+use configparser::ini::Ini;
+use proptest::prelude::*;
+
+use speakersafetyd_core::helpers::clamp_dt;
+use speakersafetyd_core::types::{Globals, Speaker};
+
+const CASES: u32 = 100;
+const PERIOD: usize = 1024;
+const SAMPLE_RATE: f32 = 48000.;
+/// Allowed rounding error (°C)
+const EPSILON: f64 = 1e-6;
+/// Allowed divergence of the single precision model (°C)
+const F32_TOLERANCE: f64 = 1e-4;
+/// Twenty minutes of periods, see single_precision_tracks_f64_long
+const LONG_RUN: usize = 1200 * SAMPLE_RATE as usize / PERIOD;
+/// Periods between changes of what's playing in a long run
+const PASSAGE: usize = 200;
+/// The same for the short run everyone gets, about four seconds
+const SHORT_RUN: usize = 200;
+const SHORT_PASSAGE: usize = 25;
+
+/// A random speaker on channels 0 (ISENSE) and 1 (VSENSE)
+#[derive(Debug)]
+struct Case {
+    globals: Globals,
+    config: Ini,
+    nodes: usize,
+    t_ambient: f64,
+    /// Coil and magnet limits, with their headroom
+    hard_limit: (f32, f32),
+}
+
+prop_compose! {
+    /**
+        A speaker with a ladder of 2 to 6 nodes, slower and slower from the
+        coil outwards. Two nodes are as often given as the coil and the
+        magnet proper.
+    */
+    fn case()(
+        globals in (20f32..60., 5f32..30., 0f32..10.),
+        ladder in prop::collection::vec((0.5f32..100., 5f32..100.), 2..7),
+        coil_magnet in prop::option::of(((0.5f32..10., 5f32..100.), (20f32..300., 5f32..100.))),
+        limits in (10f32..100., 10f32..100., 1f32..20.),
+        z_nominal in 2f32..8.,
+    ) -> Case {
+        let mut config = Ini::new_cs();
+        let mut set = |section: &str, key: &str, value: String| {
+            config.set(section, key, Some(value));
+        };
+
+        let (t_ambient, t_window, t_hysteresis) = globals;
+        set("Globals", "visense_pcm", "0".into());
+        set("Globals", "channels", "2".into());
+        set("Globals", "period", PERIOD.to_string());
+        set("Globals", "t_ambient", t_ambient.to_string());
+        set("Globals", "t_window", t_window.to_string());
+        set("Globals", "t_hysteresis", t_hysteresis.to_string());
+        for key in ["vsense", "isense", "amp_gain", "volume"] {
+            set("Controls", key, key.into());
+        }
+
+        let spk = "Speaker/Test";
+        let nodes = ladder.len();
+        if let Some(((tau_coil, tr_coil), (tau_magnet, tr_magnet))) =
+            coil_magnet.filter(|_| nodes == 2)
+        {
+            set(spk, "tau_coil", tau_coil.to_string());
+            set(spk, "tr_coil", tr_coil.to_string());
+            set(spk, "tau_magnet", tau_magnet.to_string());
+            set(spk, "tr_magnet", tr_magnet.to_string());
+        } else {
+            let mut tau = 0.;
+            let ladder: Vec<String> = ladder
+                .iter()
+                .map(|(dtau, tr)| {
+                    tau += dtau;
+                    format!("{}:{}", tau, tr)
+                })
+                .collect();
+            set(spk, "nodes", ladder.join(","));
+        }
+
+        let (over_coil, over_magnet, t_headroom) = limits;
+        let t_limit = t_ambient + t_window + over_coil;
+        let t_limit_magnet = t_ambient + t_window + over_magnet;
+        set(spk, "group", "0".into());
+        set(spk, "t_limit", t_limit.to_string());
+        set(spk, "t_headroom", t_headroom.to_string());
+        set(spk, "t_limit_magnet", t_limit_magnet.to_string());
+        set(spk, "z_nominal", z_nominal.to_string());
+        set(spk, "is_scale", "3.75".into());
+        set(spk, "vs_scale", "14".into());
+        set(spk, "is_chan", "0".into());
+        set(spk, "vs_chan", "1".into());
+
+        Case {
+            globals: Globals::parse(&config),
+            config,
+            nodes,
+            t_ambient: t_ambient as f64,
+            hard_limit: (t_limit + t_headroom, t_limit_magnet + t_headroom),
+        }
+    }
+}
+
+/// CASES per property, unless PROPTEST_CASES says otherwise
+fn config(cases: u32) -> ProptestConfig {
+    let mut config = ProptestConfig::default();
+    if std::env::var_os("PROPTEST_CASES").is_none() {
+        config.cases = cases;
+    }
+    config
+}
+
+/// Peak volts and frequency of a period of a sine
+fn tone() -> impl Strategy<Value = (f32, f32)> {
+    (0f32..10., 20f32..20000.)
+}
+
+impl Case {
+    fn speaker(&self) -> Speaker {
+        self.speaker_with(&self.globals)
+    }
+
+    fn speaker_with(&self, globals: &Globals) -> Speaker {
+        let mut spk = Speaker::offline(globals, "Test", &self.config, 15.);
+        spk.set_sample_rate(SAMPLE_RATE);
+        spk
+    }
+
+    /// One period of a sine with `amp` peak volts into `z` ohms
+    fn sense(&self, amp: f32, z: f32, freq: f32) -> Vec<i16> {
+        let mut buf = vec![0i16; PERIOD * 2];
+        for (n, frame) in buf.chunks_mut(2).enumerate() {
+            let v = amp * (2. * PI * freq * n as f32 / SAMPLE_RATE).sin();
+            frame[1] = (v / 14. * 32768.) as i16;
+            frame[0] = (v / z / 3.75 * 32768.) as i16;
+        }
+        buf
+    }
+
+    /// All node temperatures, coil first
+    fn temps(&self, spk: &Speaker) -> Vec<f64> {
+        let mut t = vec![spk.s.t_coil, spk.s.t_magnet];
+        t.extend_from_slice(&spk.s.t_outer[..self.nodes - 2]);
+        t
+    }
+
+    /// Whether the speaker is past its hard limits, where the model gives up
+    fn too_hot(&self, spk: &Speaker) -> bool {
+        spk.s.t_coil as f32 > self.hard_limit.0 || spk.s.t_magnet as f32 > self.hard_limit.1
+    }
+}
+
+proptest! {
+    #![proptest_config(config(CASES))]
+
+    #[test]
+    fn never_below_ambient(
+        c in case(),
+        z in 2f32..8.,
+        periods in prop::collection::vec((tone(), prop::option::weighted(0.25, 0f64..100.)), 50),
+    ) {
+        let mut spk = c.speaker();
+
+        for (period, ((amp, freq), skip)) in periods.into_iter().enumerate() {
+            spk.run_model(&c.sense(amp, z, freq));
+            if c.too_hot(&spk) {
+                break;
+            }
+            if let Some(skip) = skip {
+                spk.skip_model(skip);
+            }
+            for t in c.temps(&spk) {
+                prop_assert!(
+                    t >= c.t_ambient - EPSILON,
+                    "Period {}: {:.3} °C below ambient {:.3} °C",
+                    period,
+                    t,
+                    c.t_ambient
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn skip_is_additive(c in case(), a in 0f64..60., b in 0f64..60.) {
+        let mut once = c.speaker();
+        let mut twice = c.speaker();
+        // The longer ladders are stepped numerically, so allow 1% of the rise there
+        let tolerance = match c.nodes {
+            2 => 1e-6,
+            _ => (once.s.t_coil - c.t_ambient) * 0.01,
+        };
+        once.skip_model(a + b);
+        twice.skip_model(a);
+        twice.skip_model(b);
+
+        for (t1, t2) in c.temps(&once).iter().zip(c.temps(&twice)) {
+            prop_assert!(
+                (t1 - t2).abs() < tolerance,
+                "skip({:.2}) = {:.4} °C, skip({:.2}) + skip({:.2}) = {:.4} °C",
+                a + b,
+                t1,
+                a,
+                b,
+                t2
+            );
+        }
+    }
+
+    #[test]
+    fn zero_power_cools_down(
+        c in case(),
+        periods in prop::collection::vec(prop::option::of(0f64..10.), 50),
+    ) {
+        let mut spk = c.speaker();
+        let silence = vec![0i16; PERIOD * 2];
+
+        let hottest = |spk: &Speaker| c.temps(spk).into_iter().fold(f64::MIN, f64::max);
+        let mut last = hottest(&spk);
+        // Either run a period of silence, or skip ahead
+        for (period, skip) in periods.into_iter().enumerate() {
+            match skip {
+                None => {
+                    spk.run_model(&silence);
+                }
+                Some(t) => spk.skip_model(t),
+            }
+            let now = hottest(&spk);
+            prop_assert!(
+                now <= last + EPSILON,
+                "Period {}: Heated up from {:.4} to {:.4} °C without power",
+                period,
+                last,
+                now
+            );
+            last = now;
+        }
+
+        spk.skip_model(1e6);
+        prop_assert!(
+            (hottest(&spk) - c.t_ambient).abs() < 0.01,
+            "Settled at {:.3} °C, not ambient {:.3} °C",
+            hottest(&spk),
+            c.t_ambient
+        );
+    }
+
+    #[test]
+    fn gain_monotonic_in_temperature(
+        c in case(),
+        steps in prop::collection::vec(0.1f64..5., 100),
+    ) {
+        let silence = vec![0i16; PERIOD * 2];
+        let top = c.hard_limit.0.min(c.hard_limit.1) as f64 - 1.;
+
+        let mut last_gain = 0f32;
+        let mut t = c.t_ambient;
+        for step in steps.iter().cycle() {
+            if t >= top {
+                break;
+            }
+            let mut spk = c.speaker();
+            spk.s.t_coil = t;
+            spk.s.t_magnet = c.t_ambient + (t - c.t_ambient) / 2.;
+            let gain = spk.run_model(&silence).unwrap();
+            prop_assert!(
+                gain <= last_gain,
+                "Gain went up from {:.3} to {:.3} dB at {:.2} °C",
+                last_gain,
+                gain,
+                t
+            );
+            last_gain = gain;
+            t += step;
+        }
+    }
+
+    #[test]
+    fn bad_time_steps_clamped(
+        c in case(),
+        z in 2f32..8.,
+        periods in prop::collection::vec(
+            (
+                prop_oneof![
+                    Just(0.),
+                    Just(-0.),
+                    -10f64..0.,
+                    Just(f64::NAN),
+                    Just(f64::INFINITY),
+                    0.001f64..1.,
+                ],
+                tone(),
+            ),
+            50,
+        ),
+    ) {
+        let mut spk = c.speaker();
+
+        for (period, (raw, (amp, freq))) in periods.into_iter().enumerate() {
+            let (dt, anomaly) = clamp_dt(raw);
+            prop_assert!(
+                dt > 0. && dt.is_finite(),
+                "Period {}: {} s clamped to {} s",
+                period,
+                raw,
+                dt
+            );
+            prop_assert_eq!(
+                anomaly,
+                !(raw > 0. && raw.is_finite()),
+                "Period {}: {} s",
+                period,
+                raw
+            );
+            if !anomaly {
+                prop_assert_eq!(dt, raw, "Period {}: Valid step changed", period);
+            }
+
+            spk.skip_model(dt);
+            spk.run_model(&c.sense(amp, z, freq));
+            if c.too_hot(&spk) {
+                break;
+            }
+            for t in c.temps(&spk) {
+                prop_assert!(
+                    t.is_finite() && t >= c.t_ambient - EPSILON,
+                    "Period {}: {:.3} °C after a {} s step",
+                    period,
+                    t,
+                    raw
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn decimation_tracks_full_rate(
+        c in case(),
+        decimation in prop::sample::select(vec![2, 3, 4, 7, 16, 64]),
+        z in 2f32..8.,
+        periods in prop::collection::vec(tone(), 50),
+    ) {
+        let mut globals = c.globals.clone();
+        globals.decimation = decimation;
+
+        let mut full = c.speaker();
+        let mut decimated = c.speaker_with(&globals);
+
+        for (period, (amp, freq)) in periods.into_iter().enumerate() {
+            let buf = c.sense(amp, z, freq);
+            full.run_model(&buf);
+            decimated.run_model(&buf);
+            if c.too_hot(&full) || c.too_hot(&decimated) {
+                break;
+            }
+
+            for (t1, t2) in c.temps(&full).iter().zip(c.temps(&decimated)) {
+                let tolerance = ((t1 - c.t_ambient) * 0.005).max(1e-3);
+                prop_assert!(
+                    (t1 - t2).abs() < tolerance,
+                    "Period {}: {:.4} °C at full rate, {:.4} °C decimated by {}",
+                    period,
+                    t1,
+                    t2,
+                    decimation
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rate_switch_round_trips(
+        c in case(),
+        z in 2f32..8.,
+        periods in prop::collection::vec((tone(), 8000f32..192000.), 20),
+    ) {
+        let mut steady = c.speaker();
+        let mut switched = c.speaker();
+
+        for (period, ((amp, freq), new_rate)) in periods.into_iter().enumerate() {
+            // Via a rate it has seen before, and one it hasn't yet
+            for rate in [44100., SAMPLE_RATE, 96000., new_rate] {
+                switched.set_sample_rate(rate);
+            }
+            switched.set_sample_rate(SAMPLE_RATE);
+
+            let buf = c.sense(amp, z, freq);
+            steady.run_model(&buf);
+            switched.run_model(&buf);
+            if c.too_hot(&steady) {
+                break;
+            }
+            prop_assert_eq!(c.temps(&steady), c.temps(&switched), "Period {}", period);
+        }
+    }
+
+    #[test]
+    fn clipped_data_heats_up(
+        c in case(),
+        z in 2f32..8.,
+        freq in 20f32..20000.,
+        overdrive in 3f32..10.,
+    ) {
+        let mut clipped = c.speaker();
+        let mut loud = c.speaker();
+        // Just under full scale on both channels, and well past it
+        let full = 14f32.min(3.75 * z) * 0.99;
+        let measured = c.sense(full, z, freq);
+        let over = c.sense(full * overdrive, z, freq);
+
+        for period in 0..c.globals.sense_fault_periods - 1 {
+            loud.run_model(&measured);
+            prop_assert!(
+                clipped.run_model(&over).is_some(),
+                "Period {}: Quarantined early",
+                period
+            );
+            if c.too_hot(&clipped) {
+                break;
+            }
+            for (t1, t2) in c.temps(&clipped).iter().zip(c.temps(&loud)) {
+                prop_assert!(
+                    *t1 >= t2 - EPSILON,
+                    "Period {}: {:.4} °C clipped, {:.4} °C measured",
+                    period,
+                    t1,
+                    t2
+                );
+            }
+        }
+    }
+}
+
+/**
+    Run the single precision model next to the f64 one for `periods`,
+    playing the tone picked by `passages` for `passage` periods at a time,
+    and check they stay within F32_TOLERANCE of each other.
+*/
+fn check_single_precision(
+    c: &Case,
+    z: f32,
+    tones: &[(f32, f32)],
+    passages: &[usize],
+    periods: usize,
+    passage: usize,
+) -> Result<(), TestCaseError> {
+    let mut globals = c.globals.clone();
+    globals.single_precision = true;
+
+    let mut double = c.speaker();
+    let mut single = c.speaker_with(&globals);
+    // Loud and quiet passages, and silence
+    let bufs: Vec<Vec<i16>> = tones
+        .iter()
+        .enumerate()
+        .map(|(i, (amp, freq))| c.sense(amp * (i % 4) as f32, z, *freq))
+        .collect();
+    let mut buf = &bufs[0];
+
+    for period in 0..periods {
+        if period % passage == 0 {
+            buf = &bufs[passages[period / passage]];
+        }
+        // Nothing here turns the gain down, so cool off before the limits
+        let (coil, magnet) = c.hard_limit;
+        if double.s.t_coil as f32 > coil - 5. || double.s.t_magnet as f32 > magnet - 5. {
+            buf = &bufs[0];
+        }
+        double.run_model(buf);
+        single.run_model(buf);
+
+        for (t1, t2) in c.temps(&double).iter().zip(c.temps(&single)) {
+            prop_assert!(
+                (t1 - t2).abs() < F32_TOLERANCE,
+                "Period {}: {:.4} °C in f64, {:.4} °C in f32",
+                period,
+                t1,
+                t2
+            );
+        }
+    }
+    Ok(())
+}
+
+proptest! {
+    // Long runs, so fewer cases
+    #![proptest_config(config(CASES.div_ceil(50)))]
+
+    /// A few seconds of it, the twenty minutes are single_precision_tracks_f64_long
+    #[test]
+    fn single_precision_tracks_f64(
+        c in case(),
+        z in 2f32..8.,
+        tones in prop::collection::vec((0f32..4., 20f32..20000.), 8),
+        passages in prop::collection::vec(0..8usize, SHORT_RUN.div_ceil(SHORT_PASSAGE)),
+    ) {
+        check_single_precision(&c, z, &tones, &passages, SHORT_RUN, SHORT_PASSAGE)?;
+    }
+
+    #[test]
+    #[ignore = "takes a few minutes"]
+    fn single_precision_tracks_f64_long(
+        c in case(),
+        z in 2f32..8.,
+        tones in prop::collection::vec((0f32..4., 20f32..20000.), 8),
+        passages in prop::collection::vec(0..8usize, LONG_RUN.div_ceil(PASSAGE)),
+    ) {
+        check_single_precision(&c, z, &tones, &passages, LONG_RUN, PASSAGE)?;
+    }
+}
@@ -0,0 +1,72 @@
+/* SPDX-License-Identifier: MIT */
+/* (C) 2022 The Asahi Linux Contributors */
+/*
+ * C bindings for the speakersafetyd thermal model (libspeakersafetyd).
+ *
+ * A model is one speaker out of a regular speakersafetyd config file. Feed
+ * it the same interleaved V/ISENSE frames the daemon reads (as many
+ * channels as the config's Globals/channels) and it tracks the coil and
+ * magnet temperatures and the gain the speaker needs, exactly like the
+ * daemon does. Models start off from the daemon's cold boot assumption.
+ *
+ * Models are not thread safe, but separate models can be used from
+ * separate threads.
+ */
+#ifndef SPEAKERSAFETYD_H
+#define SPEAKERSAFETYD_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct SpeakerModel speaker_model;
+
+typedef struct {
+	/* Temperatures (°C) */
+	double t_coil;
+	double t_magnet;
+	/* Average power over the last period fed (W) */
+	float power;
+	/* Gain the speaker needs, and the least it can get (dB) */
+	float gain;
+	float min_gain;
+	/* Margin before the limiter engages (°C), negative while limiting */
+	float headroom;
+	/* Whether the speaker is in use (it is disabled once quarantined) */
+	int enabled;
+	/* Whether the speaker is past its hard limits, held at min gain */
+	int emergency;
+} speaker_state;
+
+/*
+ * Create the model for the speaker named `speaker` (e.g. "Left Woofer 1")
+ * out of the config text `config`, with the amp at `amp_gain` dBV and the
+ * sense data at `sample_rate` Hz. Returns NULL on an invalid config.
+ */
+speaker_model *speaker_model_new(const char *config, const char *speaker,
+				 float amp_gain, uint32_t sample_rate);
+
+void speaker_model_free(speaker_model *model);
+
+/*
+ * Run the model over `frames` frames of sense data. Stores the gain the
+ * speaker needs in `gain` (may be NULL) and returns 0, or returns -1 if the
+ * speaker was quarantined for implausible sense data or is past saving.
+ */
+int speaker_model_feed(speaker_model *model, const int16_t *buf,
+		       size_t frames, float *gain);
+
+/* Let `seconds` of silence pass without feeding any data */
+void speaker_model_skip(speaker_model *model, double seconds);
+
+void speaker_model_get_state(const speaker_model *model,
+			     speaker_state *state);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
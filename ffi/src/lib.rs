@@ -0,0 +1,236 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    C bindings for the thermal model, so anything that can call C can run
+    the exact model the daemon runs, e.g. to cross-validate another
+    implementation against it. See `include/speakersafetyd.h` for the API.
+
+    A model is one speaker out of a regular config file. It is fed the
+    same interleaved V/ISENSE frames the daemon reads, and starts off from
+    the cold boot assumption. Config errors, which panic in the daemon, make
+    speaker_model_new() return NULL instead; no panic ever crosses the FFI
+    boundary.
+*/
+use std::ffi::{c_char, c_int, CStr};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+use configparser::ini::Ini;
+use speakersafetyd_core::config;
+use speakersafetyd_core::types::{Globals, Speaker};
+
+pub struct SpeakerModel {
+    speaker: Speaker,
+    channels: usize,
+}
+
+/// The model state, see speaker_state in the header
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct SpeakerModelState {
+    pub t_coil: f64,
+    pub t_magnet: f64,
+    pub power: f32,
+    pub gain: f32,
+    pub min_gain: f32,
+    pub headroom: f32,
+    pub enabled: c_int,
+    pub emergency: c_int,
+}
+
+fn new_model(config: &str, speaker: &str, amp_gain: f32, sample_rate: u32) -> SpeakerModel {
+    let mut cfg = Ini::new_cs();
+    cfg.read(config.to_string())
+        .unwrap_or_else(|e| panic!("Failed to parse config: {}", e));
+    config::migrate(&mut cfg);
+    let globals = Globals::parse(&cfg);
+
+    let mut spk = Speaker::offline(&globals, speaker, &cfg, amp_gain);
+    spk.set_sample_rate(sample_rate as f32);
+
+    SpeakerModel {
+        speaker: spk,
+        channels: globals.channels,
+    }
+}
+
+/**
+    Create the model for `speaker` out of the config text `config`.
+
+    # Safety
+
+    `config` and `speaker` must be valid NUL terminated strings.
+*/
+#[no_mangle]
+pub unsafe extern "C" fn speaker_model_new(
+    config: *const c_char,
+    speaker: *const c_char,
+    amp_gain: f32,
+    sample_rate: u32,
+) -> *mut SpeakerModel {
+    if config.is_null() || speaker.is_null() || sample_rate == 0 {
+        return ptr::null_mut();
+    }
+    let (Ok(config), Ok(speaker)) = (
+        CStr::from_ptr(config).to_str(),
+        CStr::from_ptr(speaker).to_str(),
+    ) else {
+        return ptr::null_mut();
+    };
+
+    match catch_unwind(|| new_model(config, speaker, amp_gain, sample_rate)) {
+        Ok(model) => Box::into_raw(Box::new(model)),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/**
+    Free a model.
+
+    # Safety
+
+    `model` must come from speaker_model_new() and not be used afterwards.
+*/
+#[no_mangle]
+pub unsafe extern "C" fn speaker_model_free(model: *mut SpeakerModel) {
+    if !model.is_null() {
+        drop(Box::from_raw(model));
+    }
+}
+
+/**
+    Run the model over `frames` interleaved frames of sense data, with as
+    many channels as the config says. Stores the gain the speaker needs in
+    `gain` (if not NULL) and returns 0, or returns -1 if the speaker was
+    quarantined or past saving.
+
+    # Safety
+
+    `model` must be a live model and `buf` must hold `frames` frames.
+*/
+#[no_mangle]
+pub unsafe extern "C" fn speaker_model_feed(
+    model: *mut SpeakerModel,
+    buf: *const i16,
+    frames: usize,
+    gain: *mut f32,
+) -> c_int {
+    let Some(model) = model.as_mut() else {
+        return -1;
+    };
+    if buf.is_null() && frames > 0 {
+        return -1;
+    }
+    let data = match frames {
+        0 => &[][..],
+        _ => slice::from_raw_parts(buf, frames * model.channels),
+    };
+
+    match catch_unwind(AssertUnwindSafe(|| model.speaker.run_model(data))) {
+        Ok(Some(g)) => {
+            if !gain.is_null() {
+                *gain = g;
+            }
+            0
+        }
+        _ => -1,
+    }
+}
+
+/**
+    Let `seconds` of silence pass without feeding any data.
+
+    # Safety
+
+    `model` must be a live model.
+*/
+#[no_mangle]
+pub unsafe extern "C" fn speaker_model_skip(model: *mut SpeakerModel, seconds: f64) {
+    if let Some(model) = model.as_mut() {
+        model.speaker.skip_model(seconds.max(0.));
+    }
+}
+
+/**
+    Fill in `state` with the current model state.
+
+    # Safety
+
+    `model` must be a live model and `state` valid for writing.
+*/
+#[no_mangle]
+pub unsafe extern "C" fn speaker_model_get_state(
+    model: *const SpeakerModel,
+    state: *mut SpeakerModelState,
+) {
+    let (Some(model), Some(state)) = (model.as_ref(), state.as_mut()) else {
+        return;
+    };
+    let spk = &model.speaker;
+
+    *state = SpeakerModelState {
+        t_coil: spk.s.t_coil,
+        t_magnet: spk.s.t_magnet,
+        power: spk.s.power,
+        gain: spk.s.gain,
+        min_gain: spk.s.min_gain,
+        headroom: spk.headroom(),
+        enabled: spk.enabled as c_int,
+        emergency: spk.in_emergency() as c_int,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+
+    fn config() -> CString {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../conf/apple/j274.conf");
+        CString::new(std::fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn feed_and_skip() {
+        let speaker = CString::new("Mono").unwrap();
+        unsafe {
+            let model = speaker_model_new(config().as_ptr(), speaker.as_ptr(), 15., 48000);
+            assert!(!model.is_null());
+
+            let mut state = SpeakerModelState::default();
+            speaker_model_get_state(model, &mut state);
+            let t_start = state.t_coil;
+
+            // A few seconds of loud noise into the 4 ohm-ish Mono speaker
+            let mut buf = vec![0i16; 4096 * 2];
+            for (n, frame) in buf.chunks_mut(2).enumerate() {
+                let v = if n % 2 == 0 { 12000 } else { -12000 };
+                frame[0] = v / 4;
+                frame[1] = v;
+            }
+            let mut gain = 1.;
+            for _ in 0..50 {
+                assert_eq!(speaker_model_feed(model, buf.as_ptr(), 4096, &mut gain), 0);
+            }
+            speaker_model_get_state(model, &mut state);
+            assert!(state.t_coil > t_start);
+            assert!(gain <= 0.);
+            assert!(state.power > 0.);
+
+            speaker_model_skip(model, 1e6);
+            speaker_model_get_state(model, &mut state);
+            assert!(state.t_coil < t_start);
+            speaker_model_free(model);
+        }
+    }
+
+    #[test]
+    fn bad_config() {
+        let speaker = CString::new("Nonexistent").unwrap();
+        unsafe {
+            assert!(speaker_model_new(config().as_ptr(), speaker.as_ptr(), 15., 48000).is_null());
+            assert!(speaker_model_new(ptr::null(), speaker.as_ptr(), 15., 48000).is_null());
+        }
+    }
+}
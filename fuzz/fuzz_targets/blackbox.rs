@@ -0,0 +1,23 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    Fuzz target for the blackbox reader. Dumps come from disk, possibly
+    from an older or a crashed daemon, so anything malformed must come
+    back as an error or without a snapshot, never as a panic. Start from
+    the seed dump, or any from a blackbox directory:
+
+        cargo fuzz run blackbox fuzz/corpus/blackbox fuzz/seeds/blackbox
+*/
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use speakersafetyd_core::blackbox;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok((meta, data)) = blackbox::parse(data) else {
+        return;
+    };
+    let _ = blackbox::model_version(&meta);
+    let _ = blackbox::snapshot(&meta, &data);
+});
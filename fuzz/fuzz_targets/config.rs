@@ -0,0 +1,88 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    Fuzz target for the config loader. Configs go through the same code the
+    daemon runs them through, and every speaker is run for a period. A bad
+    config is allowed to stop the daemon, but only with a panic that names
+    the offending key (`Section/key: ...`), never with a stray assertion,
+    an arithmetic error or by running out of memory. Start from the shipped
+    configs:
+
+        cargo fuzz run config fuzz/corpus/config conf/apple
+
+    Anything it finds goes into the regression tests in core/src/types.rs.
+*/
+#![no_main]
+
+use std::cell::{Cell, RefCell};
+use std::panic::{self, AssertUnwindSafe};
+
+use configparser::ini::Ini;
+use libfuzzer_sys::fuzz_target;
+
+use speakersafetyd_core::config;
+use speakersafetyd_core::types::{Globals, Speaker};
+
+thread_local! {
+    /// Whether a panic is a config error to check, rather than a crash
+    static EXPECTED: Cell<bool> = const { Cell::new(false) };
+    static MESSAGE: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+/// Let the panics of a bad config through to be checked, libfuzzer aborts on any other
+fn expect_panics() {
+    let crash = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        if !EXPECTED.with(|e| e.get()) {
+            return crash(info);
+        }
+        let msg = info
+            .payload()
+            .downcast_ref::<String>()
+            .cloned()
+            .or_else(|| info.payload().downcast_ref::<&str>().map(|s| s.to_string()))
+            .unwrap_or_default();
+        MESSAGE.with(|m| *m.borrow_mut() = msg);
+    }));
+}
+
+/// Load a config the way the daemon does, and run every speaker for a period
+fn load_config(text: &str) {
+    let mut cfg = Ini::new_cs();
+    if cfg.read(text.to_string()).is_err() {
+        return;
+    }
+    config::migrate(&mut cfg);
+    let globals = Globals::parse(&cfg);
+
+    let buf = vec![0i16; globals.period * globals.channels];
+    for section in cfg.sections() {
+        let Some(name) = section.strip_prefix("Speaker/") else {
+            continue;
+        };
+        let mut spk: Speaker = Speaker::offline(&globals, name, &cfg, 15.);
+        spk.set_sample_rate(48000.);
+        spk.run_model(&buf);
+        spk.skip_model(1.);
+    }
+}
+
+/// Whether a config panic tells the user what to fix
+fn names_key(msg: &str) -> bool {
+    msg.split(": ")
+        .next()
+        .is_some_and(|loc| loc.contains('/') && !loc.contains("{}"))
+}
+
+fuzz_target!(init: expect_panics(), |data: &[u8]| {
+    let text = String::from_utf8_lossy(data);
+
+    EXPECTED.with(|e| e.set(true));
+    let ret = panic::catch_unwind(AssertUnwindSafe(|| load_config(&text)));
+    EXPECTED.with(|e| e.set(false));
+
+    if ret.is_err() {
+        let msg = MESSAGE.with(|m| m.take());
+        assert!(names_key(&msg), "Unhelpful panic '{}'", msg);
+    }
+});
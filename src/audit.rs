@@ -0,0 +1,289 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    Audit log of every control write, so what the daemon told the hardware
+    can be reconstructed after the fact. Entries go to an append-only file
+    (`--audit-log`) and/or the journal as structured entries
+    (`--audit-journal`, with SPEAKERSAFETYD_CONTROL, SPEAKERSAFETYD_VALUE
+    and SPEAKERSAFETYD_REPEATS fields).
+
+    Most writes repeat the last value (the unlock heartbeat, every period),
+    so those are counted and summarized at most once a minute, or as soon
+    as the value changes. On top of that, no more than MAX_PER_SECOND
+    entries are written per second, with a note of how many were dropped.
+
+    Everything is opened up front, so the log keeps working after dropping
+    privileges and inside the seccomp sandbox.
+
+    Regardless of any of that, the last RECENT_WRITES writes are always
+    kept in memory (reusing their buffers, so the loop doesn't allocate once
+    it has warmed up), for the blackbox to record when we panic.
+*/
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use speakersafetyd_core::blackbox::ControlWrite;
+
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+/// Longest a run of identical writes goes unreported
+const REPEAT_INTERVAL: Duration = Duration::from_secs(60);
+const MAX_PER_SECOND: usize = 100;
+/// Control writes kept for the panic context, see recent()
+const RECENT_WRITES: usize = 32;
+
+struct Last {
+    value: i64,
+    db: Option<f32>,
+    since: Instant,
+    repeats: u64,
+}
+
+struct Audit {
+    file: Option<File>,
+    journal: Option<UnixDatagram>,
+    last: HashMap<String, Last>,
+    window: Instant,
+    count: usize,
+    dropped: u64,
+}
+
+static AUDIT: Mutex<Option<Audit>> = Mutex::new(None);
+
+struct Recent {
+    writes: Vec<(Instant, String, i64)>,
+    /// Index of the oldest entry once `writes` is full
+    head: usize,
+}
+
+static RECENT: Mutex<Recent> = Mutex::new(Recent {
+    writes: Vec::new(),
+    head: 0,
+});
+
+impl Recent {
+    fn push(&mut self, control: &str, value: i64) {
+        let now = Instant::now();
+        if self.writes.len() < RECENT_WRITES {
+            self.writes.push((now, control.to_string(), value));
+            return;
+        }
+        let entry = &mut self.writes[self.head];
+        entry.0 = now;
+        entry.1.clear();
+        entry.1.push_str(control);
+        entry.2 = value;
+        self.head = (self.head + 1) % RECENT_WRITES;
+    }
+}
+
+/// The last control writes, oldest first, with how long ago (s)
+pub fn recent() -> Vec<ControlWrite> {
+    let mut writes = Vec::new();
+    let Ok(recent) = RECENT.lock() else {
+        return writes;
+    };
+    let now = Instant::now();
+    let (newer, older) = recent.writes.split_at(recent.head);
+    for (time, control, value) in older.iter().chain(newer) {
+        writes.push(ControlWrite {
+            control: control.clone(),
+            value: *value,
+            ago: (now - *time).as_secs_f64(),
+        });
+    }
+    writes
+}
+
+/// Start logging control writes to the file at `path` and/or the journal
+pub fn init(path: Option<&Path>, journal: bool) {
+    let file = path.and_then(|p| {
+        OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(0o640)
+            .open(p)
+            .map_err(|e| warn!("Failed to open audit log {:?}: {}", p, e))
+            .ok()
+    });
+    let journal = journal
+        .then(|| {
+            let sock = UnixDatagram::unbound()?;
+            sock.connect(JOURNAL_SOCKET)?;
+            Ok::<_, io::Error>(sock)
+        })
+        .and_then(|r| {
+            r.map_err(|e| warn!("Failed to connect to the journal: {}", e))
+                .ok()
+        });
+    if file.is_none() && journal.is_none() {
+        return;
+    }
+
+    if let Some(path) = path.filter(|_| file.is_some()) {
+        info!("Logging control writes to {:?}", path);
+    }
+    if journal.is_some() {
+        info!("Logging control writes to the journal");
+    }
+    *AUDIT.lock().unwrap() = Some(Audit {
+        file,
+        journal,
+        last: HashMap::new(),
+        window: Instant::now(),
+        count: 0,
+        dropped: 0,
+    });
+}
+
+fn format_value(value: i64, db: Option<f32>) -> String {
+    match db {
+        Some(db) => format!("{} ({:.2} dB)", value, db),
+        None => value.to_string(),
+    }
+}
+
+impl Audit {
+    fn emit(&mut self, control: &str, value: i64, db: Option<f32>, repeats: u64) {
+        let now = Instant::now();
+        if now - self.window >= Duration::from_secs(1) {
+            self.window = now;
+            self.count = 0;
+            if self.dropped > 0 {
+                let dropped = self.dropped;
+                self.dropped = 0;
+                self.write(&format!("{} entries dropped", dropped), &[]);
+            }
+        }
+        if self.count >= MAX_PER_SECOND {
+            self.dropped += 1;
+            return;
+        }
+        self.count += 1;
+
+        let value = format_value(value, db);
+        let message = match repeats {
+            0 => format!("{} = {}", control, value),
+            n => format!("{} = {} (written {} more times)", control, value, n),
+        };
+        self.write(
+            &message,
+            &[
+                ("SPEAKERSAFETYD_CONTROL", control),
+                ("SPEAKERSAFETYD_VALUE", &value),
+                ("SPEAKERSAFETYD_REPEATS", &repeats.to_string()),
+            ],
+        );
+    }
+
+    fn write(&mut self, message: &str, fields: &[(&str, &str)]) {
+        if let Some(file) = self.file.as_mut() {
+            let line = format!("{} {}\n", chrono::Local::now().to_rfc3339(), message);
+            if let Err(e) = file.write_all(line.as_bytes()) {
+                warn!("Failed to write audit log, giving up on it: {}", e);
+                self.file = None;
+            }
+        }
+        if let Some(journal) = self.journal.as_ref() {
+            let mut entry = format!(
+                "MESSAGE=Control write: {}\nPRIORITY=6\nSYSLOG_IDENTIFIER=speakersafetyd\n",
+                message
+            );
+            for (key, value) in fields {
+                entry += &format!("{}={}\n", key, value);
+            }
+            if let Err(e) = journal.send(entry.as_bytes()) {
+                warn!("Failed to log to the journal, giving up on it: {}", e);
+                self.journal = None;
+            }
+        }
+    }
+
+    /// `db` is only looked up for new values, as it may take an ioctl
+    fn record(&mut self, control: &str, value: i64, db: impl FnOnce() -> Option<f32>) {
+        let now = Instant::now();
+        let mut flush = None;
+        match self.last.get_mut(control) {
+            Some(last) if last.value == value => {
+                last.repeats += 1;
+                if now - last.since < REPEAT_INTERVAL {
+                    return;
+                }
+                flush = Some((last.value, last.db, last.repeats));
+                last.since = now;
+                last.repeats = 0;
+            }
+            Some(last) => {
+                if last.repeats > 0 {
+                    flush = Some((last.value, last.db, last.repeats));
+                }
+                *last = Last {
+                    value,
+                    db: db(),
+                    since: now,
+                    repeats: 0,
+                };
+            }
+            None => {
+                self.last.insert(
+                    control.to_string(),
+                    Last {
+                        value,
+                        db: db(),
+                        since: now,
+                        repeats: 0,
+                    },
+                );
+            }
+        }
+
+        let db = self.last[control].db;
+        match flush {
+            // A run of repeats ended in the same value, that's all there is to say
+            Some((v, old_db, n)) if v == value => self.emit(control, v, old_db, n),
+            Some((v, old_db, n)) => {
+                self.emit(control, v, old_db, n);
+                self.emit(control, value, db, 0);
+            }
+            None => self.emit(control, value, db, 0),
+        }
+    }
+}
+
+/// Log a successful write of `ev` to the control `name`
+pub fn record(card: &alsa::ctl::Ctl, ev: &alsa::ctl::ElemValue, name: &str) {
+    // The getters only answer for the element's own type
+    let value = ev
+        .get_boolean(0)
+        .map(i64::from)
+        .or_else(|| ev.get_integer(0).map(i64::from))
+        .or_else(|| ev.get_integer64(0));
+    let Some(value) = value else {
+        return;
+    };
+    if let Ok(mut recent) = RECENT.lock() {
+        recent.push(name, value);
+    }
+
+    let Ok(mut audit) = AUDIT.lock() else {
+        return;
+    };
+    let Some(audit) = audit.as_mut() else {
+        return;
+    };
+    // Only controls with a dB scale have one, the unlock control doesn't
+    let db = || {
+        let mut id = alsa::ctl::ElemId::new(alsa::ctl::ElemIface::Mixer);
+        id.set_name(&CString::new(name).ok()?);
+        card.convert_to_db(&id, value).ok().map(|db| db.to_db())
+    };
+
+    audit.record(name, value, db);
+}
@@ -0,0 +1,88 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    Fan-out of what the loop produces (events, telemetry) to a handful of
+    subscribers, each behind a bounded queue of its own. The loop only ever
+    try_send()s, so a subscriber that stops reading loses messages, counted
+    for it to report, instead of holding anyone up: not the loop, and not
+    the other subscribers.
+*/
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+
+use crate::shared::PiMutex;
+
+struct Subscriber<T> {
+    tx: SyncSender<T>,
+    dropped: Arc<AtomicU64>,
+}
+
+pub struct Broadcast<T> {
+    /// Taken by the loop every send, see shared.rs
+    subscribers: PiMutex<Vec<Subscriber<T>>>,
+    max_subscribers: usize,
+    queue_len: usize,
+}
+
+pub struct Subscription<T> {
+    rx: Receiver<T>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl<T: Clone> Broadcast<T> {
+    /// Up to `max_subscribers`, each with a queue of `queue_len`
+    pub fn new(max_subscribers: usize, queue_len: usize) -> Broadcast<T> {
+        Broadcast {
+            subscribers: PiMutex::new(Vec::new()),
+            max_subscribers,
+            queue_len,
+        }
+    }
+
+    /// A new subscription, if there is room for one
+    pub fn subscribe(&self) -> Option<Subscription<T>> {
+        let mut subscribers = self.subscribers.lock();
+        if subscribers.len() >= self.max_subscribers {
+            return None;
+        }
+        let (tx, rx) = mpsc::sync_channel(self.queue_len);
+        let dropped = Arc::new(AtomicU64::new(0));
+        subscribers.push(Subscriber {
+            tx,
+            dropped: Arc::clone(&dropped),
+        });
+        Some(Subscription { rx, dropped })
+    }
+
+    /// Whether anybody is listening, to skip preparing messages for nobody
+    pub fn is_empty(&self) -> bool {
+        self.subscribers.lock().is_empty()
+    }
+
+    /// Queue `msg` for every subscriber with room for it. Never blocks.
+    pub fn send(&self, msg: &T) {
+        self.subscribers
+            .lock()
+            .retain(|s| match s.tx.try_send(msg.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    s.dropped.fetch_add(1, Ordering::Relaxed);
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            });
+    }
+}
+
+impl<T> Subscription<T> {
+    /// The next message, None once the sending side is gone
+    pub fn recv(&self) -> Option<T> {
+        self.rx.recv().ok()
+    }
+
+    /// Messages dropped since the last call
+    pub fn take_dropped(&self) -> u64 {
+        self.dropped.swap(0, Ordering::Relaxed)
+    }
+}
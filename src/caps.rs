@@ -0,0 +1,129 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    What the card's controls can do, probed once at startup: how many
+    channels each has, and its dB and integer ranges. The speaker configs
+    rely on some of it without saying so, e.g. that the level control goes
+    down to min gain, or that the amp gain sits at the top of its range
+    (which the peak power, and so min gain, is computed from). A driver
+    change can quietly break those, so they're checked here and any
+    discrepancy is logged loudly.
+*/
+use std::collections::BTreeMap;
+
+use alsa::ctl::Ctl;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::types::Elem;
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ElemCaps {
+    pub count: u32,
+    /// (min, max) in dB, for controls with a dB scale
+    pub db_range: Option<(f32, f32)>,
+    /// The raw values the dB range maps to
+    pub int_range: Option<(i64, i64)>,
+}
+
+/// Also the `card` of the status reply
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CardCapabilities {
+    elems: BTreeMap<String, ElemCaps>,
+    discrepancies: Vec<String>,
+}
+
+impl CardCapabilities {
+    /// Probe `elem`, unless it already was (elements may be shared)
+    pub fn probe(&mut self, card: &Ctl, elem: &Elem) -> &ElemCaps {
+        self.elems
+            .entry(elem.name().to_string())
+            .or_insert_with(|| {
+                let range = card.get_db_range(elem.id()).ok();
+                let int = |(min, max)| {
+                    Some((
+                        card.convert_from_db(elem.id(), min, alsa::Round::Ceil)
+                            .ok()?,
+                        card.convert_from_db(elem.id(), max, alsa::Round::Floor)
+                            .ok()?,
+                    ))
+                };
+                ElemCaps {
+                    count: elem.count(),
+                    db_range: range.map(|(min, max)| (min.to_db(), max.to_db())),
+                    int_range: range.and_then(int),
+                }
+            })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ElemCaps> {
+        self.elems.get(name)
+    }
+
+    /// What the config assumes that the hardware doesn't do
+    pub fn discrepancies(&self) -> &[String] {
+        &self.discrepancies
+    }
+
+    fn discrepancy(&mut self, message: String) {
+        warn!("!!! {} !!!", message);
+        self.discrepancies.push(message);
+    }
+
+    /**
+        Check what the speaker `name` assumes of its `level` and `amp_gain`
+        controls: the model computed `min_gain` (at full volume) from the
+        amp being at `amp_gain` dB.
+    */
+    pub fn check_speaker(
+        &mut self,
+        name: &str,
+        level: &str,
+        amp_gain: &str,
+        min_gain: f32,
+        gain: f32,
+    ) {
+        match self.get(level).and_then(|c| c.db_range) {
+            None => self.discrepancy(format!("{}: {} has no dB scale", name, level)),
+            Some((min, _)) if min > min_gain + 0.01 => self.discrepancy(format!(
+                "{}: {} only goes down to {:.2} dB, min gain is {:.2} dB",
+                name, level, min, min_gain
+            )),
+            Some(_) => {}
+        }
+
+        match self.get(amp_gain).and_then(|c| c.db_range) {
+            None => self.discrepancy(format!("{}: {} has no dB scale", name, amp_gain)),
+            Some((_, max)) if (max - gain).abs() > 0.01 => self.discrepancy(format!(
+                "{}: {} is at {:.2} dB rather than the top of its range ({:.2} dB), peak power assumes the former",
+                name, amp_gain, gain, max
+            )),
+            Some(_) => {}
+        }
+    }
+
+    pub fn log(&self) {
+        info!("Card capabilities:");
+        for (name, caps) in self.elems.iter() {
+            match (caps.db_range, caps.int_range) {
+                (Some((min, max)), Some((imin, imax))) => info!(
+                    "  {}: {} channel(s), {:.2}..{:.2} dB ({}..{})",
+                    name, caps.count, min, max, imin, imax
+                ),
+                (Some((min, max)), None) => info!(
+                    "  {}: {} channel(s), {:.2}..{:.2} dB",
+                    name, caps.count, min, max
+                ),
+                _ => info!("  {}: {} channel(s)", name, caps.count),
+            }
+        }
+        if !self.discrepancies.is_empty() {
+            warn!(
+                "{} discrepancies between the config and the hardware, see above",
+                self.discrepancies.len()
+            );
+        }
+    }
+}
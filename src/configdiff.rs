@@ -0,0 +1,86 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    `speakersafetyd config-diff`, which shows how the config in effect
+    differs from the one packaged for the machine, key by key. Both are
+    migrated to the current schema first, and numbers are compared by value,
+    so only real differences show up.
+*/
+use configparser::ini::Ini;
+
+use crate::config;
+
+#[derive(Debug, PartialEq)]
+pub enum Change {
+    Changed(String, String),
+    Added(String),
+    Removed(String),
+}
+
+/// Whether two values mean the same, e.g. "1" and "1.0"
+fn same(a: &str, b: &str) -> bool {
+    match (a.trim().parse::<f64>(), b.trim().parse::<f64>()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a.trim() == b.trim(),
+    }
+}
+
+/// The changes from `defaults` to `effective`, as (section, key, change)
+pub fn diff(defaults: &Ini, effective: &Ini) -> Vec<(String, String, Change)> {
+    let (old, new) = (defaults.get_map_ref(), effective.get_map_ref());
+    let mut sections: Vec<&String> = old.keys().collect();
+    sections.extend(new.keys().filter(|s| !old.contains_key(*s)));
+
+    let mut changes = Vec::new();
+    for section in sections {
+        let (a, b) = (old.get(section), new.get(section));
+        let mut keys: Vec<&String> = a.iter().flat_map(|m| m.keys()).collect();
+        keys.extend(
+            b.iter()
+                .flat_map(|m| m.keys())
+                .filter(|k| !a.is_some_and(|a| a.contains_key(*k))),
+        );
+
+        for key in keys {
+            let change = match (defaults.get(section, key), effective.get(section, key)) {
+                (Some(x), Some(y)) if same(&x, &y) => continue,
+                (Some(x), Some(y)) => Change::Changed(x, y),
+                (None, Some(y)) => Change::Added(y),
+                (Some(x), None) => Change::Removed(x),
+                (None, None) => continue,
+            };
+            changes.push((section.clone(), key.clone(), change));
+        }
+    }
+
+    changes
+}
+
+/// Load and migrate a config file
+pub fn load(path: &std::path::Path) -> Result<Ini, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let mut cfg = Ini::new_cs();
+    cfg.read(text)?;
+    config::migrate(&mut cfg);
+    Ok(cfg)
+}
+
+pub fn print(changes: &[(String, String, Change)]) {
+    if changes.is_empty() {
+        println!("No differences");
+        return;
+    }
+
+    let mut last = None;
+    for (section, key, change) in changes {
+        if last != Some(section) {
+            println!("[{}]", section);
+            last = Some(section);
+        }
+        match change {
+            Change::Changed(old, new) => println!("  {} = {} (packaged: {})", key, new, old),
+            Change::Added(new) => println!("+ {} = {}", key, new),
+            Change::Removed(old) => println!("- {} (packaged: {})", key, old),
+        }
+    }
+}
@@ -0,0 +1,76 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    Limiting episodes, from the limiter engaging on a group to it letting
+    go again. The gain changes every few periods while limiting, which is
+    too much detail for the journal, so each episode gets one line when it
+    starts and a summary when it ends. That summary is what ends up in bug
+    reports about the speakers getting quiet.
+*/
+use std::time::Instant;
+
+use crate::types::Speaker;
+
+pub struct Episode {
+    start: Instant,
+    /// The speaker that asked for the reduction first
+    trigger: String,
+    /// The deepest reduction (dB)
+    min_gain: f32,
+    /// The hottest any coil got (°C), and whose
+    t_peak: f64,
+    hottest: String,
+}
+
+/// The enabled speaker of `speakers` asking for the most reduction
+fn limiting(speakers: &[Speaker]) -> Option<&Speaker> {
+    speakers
+        .iter()
+        .filter(|s| s.enabled)
+        .min_by(|a, b| a.s.gain.total_cmp(&b.s.gain))
+}
+
+impl Episode {
+    /// An episode starting `now` at `gain`, as asked for by `speakers`
+    pub fn new(now: Instant, gain: f32, speakers: &[Speaker]) -> Episode {
+        let trigger = limiting(speakers).map_or("?".into(), |s| s.name.clone());
+        let mut ep = Episode {
+            start: now,
+            trigger,
+            min_gain: gain,
+            t_peak: f64::NEG_INFINITY,
+            hottest: "?".into(),
+        };
+        ep.update(gain, speakers);
+        ep
+    }
+
+    /// The name of the speaker that started it
+    pub fn trigger(&self) -> &str {
+        &self.trigger
+    }
+
+    /// Note another period at `gain`
+    pub fn update(&mut self, gain: f32, speakers: &[Speaker]) {
+        self.min_gain = self.min_gain.min(gain);
+        for s in speakers.iter().filter(|s| s.enabled) {
+            if s.s.t_coil > self.t_peak {
+                self.t_peak = s.s.t_coil;
+                self.hottest = s.name.clone();
+            }
+        }
+    }
+
+    /// One line about the episode, ending `now`
+    pub fn summary(&self, label: &str, now: Instant) -> String {
+        format!(
+            "Speaker group {} limited for {:.1} s: down to {:.2} dB, started by {}, coil peaked at {:.1} °C ({})",
+            label,
+            (now - self.start).as_secs_f64(),
+            self.min_gain,
+            self.trigger,
+            self.t_peak,
+            self.hottest
+        )
+    }
+}
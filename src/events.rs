@@ -0,0 +1,83 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    ALSA control event handling. We keep a separate, non-blocking control
+    handle subscribed to events, which the reactor watches, and drain it
+    whenever it has any. This lets us react to sample rate changes, control
+    removal and writes from other clients as they happen, without reading
+    every element every period.
+*/
+use alsa::ctl::Ctl;
+use alsa::poll::{pollfd, Descriptors, Flags};
+use log::debug;
+
+#[derive(Debug)]
+pub enum CtlEvent {
+    /// The value of the named control changed (including our own writes)
+    Value(String),
+    /// The named control was removed
+    Removed(String),
+    /// The card went away altogether
+    Disconnected,
+}
+
+pub struct CtlEvents {
+    ctl: Ctl,
+}
+
+impl CtlEvents {
+    pub fn new(card: &str) -> CtlEvents {
+        let ctl = Ctl::new(card, true)
+            .unwrap_or_else(|e| panic!("{}: Could not open control events handle: {}", card, e));
+        ctl.subscribe_events(true)
+            .unwrap_or_else(|e| panic!("{}: Could not subscribe to control events: {}", card, e));
+
+        CtlEvents { ctl }
+    }
+
+    /// Drain all pending events without blocking
+    pub fn read(&self) -> Vec<CtlEvent> {
+        let mut events = Vec::new();
+
+        loop {
+            let ev = match self.ctl.read() {
+                Ok(Some(ev)) => ev,
+                Ok(None) => break,
+                Err(e) if e.errno() == libc::EAGAIN => break,
+                Err(e) if e.errno() == libc::ENODEV => {
+                    events.push(CtlEvent::Disconnected);
+                    break;
+                }
+                Err(e) => panic!("Failed to read control events: {}", e),
+            };
+
+            let mask = ev.get_mask();
+            let id = ev.get_id();
+            let name = id.get_name().unwrap_or("").to_string();
+
+            if mask.remove() {
+                events.push(CtlEvent::Removed(name));
+            } else if mask.value() {
+                events.push(CtlEvent::Value(name));
+            } else {
+                debug!("Ignoring control event {:?} for {}", mask, name);
+            }
+        }
+
+        events
+    }
+}
+
+impl Descriptors for CtlEvents {
+    fn count(&self) -> usize {
+        self.ctl.count()
+    }
+
+    fn fill(&self, fds: &mut [pollfd]) -> alsa::Result<usize> {
+        self.ctl.fill(fds)
+    }
+
+    fn revents(&self, fds: &[pollfd]) -> alsa::Result<Flags> {
+        self.ctl.revents(fds)
+    }
+}
@@ -0,0 +1,447 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    Offline fitting of the thermal model parameters. The voice coil
+    temperature can be measured from its resistance, which we get from the
+    V/ISENSE data at a low frequency pilot tone (where the coil inductance
+    doesn't matter). Fitting the model against that gives starting values
+    for tau_coil, tau_magnet, tr_coil and tr_magnet on new machines.
+*/
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use configparser::ini::Ini;
+
+use crate::blackbox::{self, Meta};
+use crate::config;
+use crate::helpers;
+use crate::types;
+
+/// Length of the windows the data is evaluated in (s)
+const WINDOW: f64 = 0.1;
+
+/// The resistance reference is taken over this range (s), skipping the start
+const REF_START: f64 = 1.;
+const REF_END: f64 = 2.;
+
+/// Q of the pilot band-pass filter
+const PILOT_Q: f64 = 2.;
+
+const FIT_ITERATIONS: usize = 2000;
+
+/// Model parameters, in the order they are fitted
+const PARAMS: [&str; 4] = ["tau_coil", "tau_magnet", "tr_coil", "tr_magnet"];
+
+/// RBJ band-pass biquad (0 dB peak gain)
+struct BandPass {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl BandPass {
+    fn new(f0: f64, fs: f64, q: f64) -> BandPass {
+        let w0 = 2. * std::f64::consts::PI * f0 / fs;
+        let alpha = w0.sin() / (2. * q);
+        let a0 = 1. + alpha;
+
+        BandPass {
+            b: [alpha / a0, 0., -alpha / a0],
+            a: [-2. * w0.cos() / a0, (1. - alpha) / a0],
+            x: [0.; 2],
+            y: [0.; 2],
+        }
+    }
+
+    fn run(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+/// Per-window data of one speaker
+struct Measurement {
+    /// Average power (W)
+    power: Vec<f64>,
+    /// Coil temperature derived from the resistance (°C), if there was enough pilot
+    temp: Vec<Option<f64>>,
+}
+
+struct Model {
+    dt: f64,
+    t_ambient: f64,
+    t_coil: f64,
+    t_magnet: f64,
+}
+
+impl Model {
+    /// Coil temperature per window for the given parameters
+    fn run(&self, p: &[f64], power: &[f64]) -> Vec<f64> {
+        let a_coil = 1. - (-self.dt / p[0]).exp();
+        let a_magnet = 1. - (-self.dt / p[1]).exp();
+        let mut t_coil = self.t_coil;
+        let mut t_magnet = self.t_magnet;
+
+        power
+            .iter()
+            .map(|pwr| {
+                t_coil += (t_magnet + pwr * p[2] - t_coil) * a_coil;
+                t_magnet += (self.t_ambient + pwr * p[3] - t_magnet) * a_magnet;
+                t_coil
+            })
+            .collect()
+    }
+
+    fn rms_error(&self, p: &[f64], m: &Measurement) -> f64 {
+        let (sum, n) = self
+            .run(p, &m.power)
+            .iter()
+            .zip(m.temp.iter())
+            .filter_map(|(model, meas)| Some((model - (*meas)?).powi(2)))
+            .fold((0., 0), |(s, n), e| (s + e, n + 1));
+
+        if n == 0 {
+            f64::INFINITY
+        } else {
+            (sum / n as f64).sqrt()
+        }
+    }
+}
+
+/// Minimize `f` with the Nelder-Mead simplex method, starting at `x0`
+fn nelder_mead(f: impl Fn(&[f64]) -> f64, x0: &[f64], step: f64, iters: usize) -> Vec<f64> {
+    let n = x0.len();
+    let mut simplex: Vec<(Vec<f64>, f64)> = (0..=n)
+        .map(|i| {
+            let mut x = x0.to_vec();
+            if i > 0 {
+                x[i - 1] += step;
+            }
+            let fx = f(&x);
+            (x, fx)
+        })
+        .collect();
+
+    for _ in 0..iters {
+        simplex.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+        let centroid: Vec<f64> = (0..n)
+            .map(|j| simplex[..n].iter().map(|p| p.0[j]).sum::<f64>() / n as f64)
+            .collect();
+        let towards = |t: f64| -> Vec<f64> {
+            (0..n)
+                .map(|j| centroid[j] + t * (simplex[n].0[j] - centroid[j]))
+                .collect()
+        };
+
+        let xr = towards(-1.);
+        let fr = f(&xr);
+        if fr < simplex[0].1 {
+            let xe = towards(-2.);
+            let fe = f(&xe);
+            simplex[n] = if fe < fr { (xe, fe) } else { (xr, fr) };
+        } else if fr < simplex[n - 1].1 {
+            simplex[n] = (xr, fr);
+        } else {
+            let xc = towards(0.5);
+            let fc = f(&xc);
+            if fc < simplex[n].1 {
+                simplex[n] = (xc, fc);
+            } else {
+                let best = simplex[0].0.clone();
+                for p in simplex[1..].iter_mut() {
+                    p.0 = (0..n).map(|j| best[j] + 0.5 * (p.0[j] - best[j])).collect();
+                    p.1 = f(&p.0);
+                }
+            }
+        }
+    }
+
+    simplex.sort_by(|a, b| a.1.total_cmp(&b.1));
+    simplex.swap_remove(0).0
+}
+
+/// The speakers in the order the daemon records them (by group, then config order)
+fn speaker_order(meta: &Meta, config: &Ini) -> Vec<String> {
+    if !meta.speakers.is_empty() {
+        return meta.speakers.iter().map(|s| s.name.clone()).collect();
+    }
+
+    let mut names: Vec<(usize, String)> = config
+        .sections()
+        .iter()
+        .filter_map(|s| s.strip_prefix("Speaker/"))
+        .map(|name| {
+            let group = helpers::parse_int(config, &("Speaker/".to_owned() + name), "group");
+            (group, name.to_string())
+        })
+        .collect();
+    names.sort_by_key(|a| a.0);
+    names.into_iter().map(|a| a.1).collect()
+}
+
+/// A run of consecutive blocks at the same sample rate
+struct Segment {
+    rate: f64,
+    /// Index of the first block
+    first: usize,
+    /// Position and length of its data (bytes)
+    offset: usize,
+    len: usize,
+}
+
+impl Segment {
+    fn duration(&self, channels: usize) -> f64 {
+        (self.len / 2 / channels) as f64 / self.rate
+    }
+}
+
+/**
+    Split the dump into runs of blocks at the same sample rate. The filters
+    and the model only work at a single rate, and rather than resampling
+    (and smearing the pilot tone), a rate change just starts a new segment.
+    Dumps without a block index are a single segment at the top level rate.
+*/
+fn segments(meta: &Meta, channels: usize, data_len: usize) -> Vec<Segment> {
+    let mut segments: Vec<Segment> = Vec::new();
+    let mut offset = 0;
+
+    for (i, block) in meta.blocks.iter().enumerate() {
+        let rate = block.sample_rate as f64;
+        let len = block.sample_count * channels * 2;
+        match segments.last_mut() {
+            Some(seg) if seg.rate == rate => seg.len += len,
+            _ => segments.push(Segment {
+                rate,
+                first: i,
+                offset,
+                len,
+            }),
+        }
+        offset += len;
+    }
+    if segments.is_empty() {
+        segments.push(Segment {
+            rate: meta.sample_rate as f64,
+            first: 0,
+            offset: 0,
+            len: data_len,
+        });
+    }
+
+    // Not any playback monitor data after the sense data either
+    let sense_len = meta
+        .monitor
+        .as_ref()
+        .and_then(|m| m.offset)
+        .map_or(data_len, |o| o.min(data_len));
+    for seg in segments.iter_mut() {
+        seg.len = seg.len.min(sense_len.saturating_sub(seg.offset));
+    }
+    segments.retain(|s| s.rate > 0. && s.len > 0);
+    segments
+}
+
+/**
+    Fit the model parameters of every speaker against the dump at `path` and
+    print suggested config values. The config embedded in the dump is used,
+    unless `config_path` is given (needed for old dumps).
+*/
+pub fn fit(path: &Path, config_path: Option<&Path>, pilot: f64) -> io::Result<()> {
+    let (meta, data) = blackbox::load(path)?;
+    // The states are where each segment starts from
+    let version = blackbox::model_version(&meta);
+    if version != types::MODEL_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "The dump is from model version {}, this is version {}",
+                version,
+                types::MODEL_VERSION
+            ),
+        ));
+    }
+
+    let config_text = match config_path {
+        Some(p) => fs::read_to_string(p)?,
+        None => meta.config.clone().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The dump has no embedded config, please specify one",
+            )
+        })?,
+    };
+    let mut config = Ini::new_cs();
+    config
+        .read(config_text)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    config::migrate(&mut config);
+
+    let channels = meta.channels;
+    if channels == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Missing channel count",
+        ));
+    }
+
+    // The longest stretch at a single rate is the one worth fitting
+    let segments = segments(&meta, channels, data.len());
+    let Some(seg) = segments
+        .iter()
+        .max_by(|a, b| a.duration(channels).total_cmp(&b.duration(channels)))
+    else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Missing sample rate",
+        ));
+    };
+    let fs = seg.rate;
+    if segments.len() > 1 {
+        println!(
+            "# The sample rate changes within the dump, fitting {:.1} s at {} Hz (of {:.1} s)",
+            seg.duration(channels),
+            fs,
+            segments.iter().map(|s| s.duration(channels)).sum::<f64>()
+        );
+    }
+
+    let samples: Vec<i16> = data[seg.offset..seg.offset + seg.len]
+        .chunks_exact(2)
+        .map(|b| i16::from_le_bytes([b[0], b[1]]))
+        .collect();
+    let window = (WINDOW * fs) as usize;
+    let t_ambient = meta.t_ambient.map_or(35., f64::from);
+
+    for (idx, name) in speaker_order(&meta, &config).iter().enumerate() {
+        let section = "Speaker/".to_owned() + name;
+        if config.get(&section, "nodes").is_some() {
+            println!("# {}: Only two-node models can be fitted, skipping", name);
+            continue;
+        }
+        let get = |key: &str| helpers::parse_float(&config, &section, key) as f64;
+        let vs_chan: usize = helpers::parse_int(&config, &section, "vs_chan");
+        let is_chan: usize = helpers::parse_int(&config, &section, "is_chan");
+        let (vs_scale, is_scale) = (get("vs_scale"), get("is_scale"));
+        let z_shunt = config
+            .getfloat(&section, "z_shunt")
+            .ok()
+            .flatten()
+            .unwrap_or(0.);
+        let alpha = get("a_t_35c");
+
+        let mut v_filt = BandPass::new(pilot, fs, PILOT_Q);
+        let mut i_filt = BandPass::new(pilot, fs, PILOT_Q);
+        let mut power = Vec::new();
+        let mut resistance = Vec::new();
+
+        for frames in samples
+            .chunks_exact(channels)
+            .collect::<Vec<_>>()
+            .chunks(window)
+        {
+            let (mut pwr, mut vv, mut vi) = (0., 0., 0.);
+            for frame in frames {
+                let v = frame[vs_chan] as f64 / 32768. * vs_scale;
+                let i = frame[is_chan] as f64 / 32768. * is_scale;
+                pwr += v * i;
+
+                let (v, i) = (v_filt.run(v), i_filt.run(i));
+                vv += v * v;
+                vi += v * i;
+            }
+            power.push(pwr / frames.len() as f64);
+            // No pilot, no measurement
+            resistance.push((vi > 1e-9).then(|| vv / vi));
+        }
+
+        if resistance.len() < (REF_END / WINDOW) as usize {
+            println!("# {}: Not enough data", name);
+            continue;
+        }
+        let refs: Vec<f64> = resistance[(REF_START / WINDOW) as usize..(REF_END / WINDOW) as usize]
+            .iter()
+            .flatten()
+            .copied()
+            .collect();
+        if refs.is_empty() {
+            println!("# {}: No pilot tone at {} Hz found", name, pilot);
+            continue;
+        }
+        let r_ref = refs.iter().sum::<f64>() / refs.len() as f64;
+
+        // The state at the end of the block before the segment is where it starts
+        let block = meta
+            .blocks
+            .get(seg.first.saturating_sub(1))
+            .and_then(|b| b.speakers.get(idx))
+            .cloned()
+            .unwrap_or_default();
+        // Older dumps don't record the ambient the model ran against
+        let t_ambient = block.t_ambient.map(f64::from).unwrap_or_else(|| {
+            config
+                .getfloat(&section, "t_ambient")
+                .ok()
+                .flatten()
+                .unwrap_or(t_ambient)
+        });
+        let model = Model {
+            dt: WINDOW,
+            t_ambient,
+            t_coil: Some(block.t_coil)
+                .filter(|t| !t.is_nan())
+                .unwrap_or(t_ambient),
+            t_magnet: Some(block.t_magnet)
+                .filter(|t| !t.is_nan())
+                .unwrap_or(t_ambient),
+        };
+        let t_ref = model.t_coil;
+
+        let m = Measurement {
+            power,
+            temp: resistance
+                .iter()
+                .enumerate()
+                .map(|(i, r)| {
+                    // The filters need to settle first
+                    if (i as f64) * WINDOW < REF_START {
+                        return None;
+                    }
+                    r.map(|r| ((r - z_shunt) / (r_ref - z_shunt) - 1.) / alpha + t_ref)
+                })
+                .collect(),
+        };
+
+        let current: Vec<f64> = PARAMS.iter().map(|p| get(p)).collect();
+        // Fit in log space, which keeps everything positive
+        let cost = |x: &[f64]| {
+            let p: Vec<f64> = x.iter().map(|a| a.exp()).collect();
+            model.rms_error(&p, &m)
+        };
+        let x0: Vec<f64> = current.iter().map(|a| a.ln()).collect();
+        let fitted: Vec<f64> = nelder_mead(cost, &x0, 0.5, FIT_ITERATIONS)
+            .iter()
+            .map(|a| a.exp())
+            .collect();
+
+        println!("[{}]", section);
+        for (key, value) in PARAMS.iter().zip(fitted.iter()) {
+            println!("{} = {:.4}", key, value);
+        }
+        println!(
+            "# R_ref = {:.3} ohm, RMS error {:.2} °C (currently {:.2} °C)",
+            r_ref,
+            model.rms_error(&fitted, &m),
+            model.rms_error(&current, &m)
+        );
+        println!();
+    }
+
+    Ok(())
+}
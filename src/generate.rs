@@ -0,0 +1,153 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    Config generation. Most machines use one of a handful of speaker drivers
+    and amps, just wired up differently, so instead of copying parameters
+    between their configs (and having them drift apart), a machine can be
+    described by a layout: which driver sits on which sense channels, plus
+    whatever is special about it. The driver and amp parameters come from a
+    database built into the binary.
+
+    A layout is an ini file with an optional `[Layout]` section naming the
+    `amp`, `[Globals]` and `[Controls]` overrides, and a `[Speaker/<name>]`
+    section per speaker with its `driver`, `group`, `is_chan` and `vs_chan`.
+    Any other speaker key overrides the driver's value. Other sections are
+    passed through as they are.
+*/
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use configparser::ini::Ini;
+
+use crate::config;
+
+const DATABASE: &str = include_str!("../conf/drivers.conf");
+
+/// An ordered list of keys and values
+type Section = Vec<(String, String)>;
+
+fn invalid(e: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+fn section(config: &Ini, name: &str) -> Section {
+    config
+        .get_map_ref()
+        .get(name)
+        .map(|s| {
+            s.iter()
+                .filter_map(|(k, v)| Some((k.clone(), v.clone()?)))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Override the keys of `base` in place, appending the new ones
+fn merge(base: &mut Section, over: Section) {
+    for (key, value) in over {
+        match base.iter_mut().find(|(k, _)| *k == key) {
+            Some(entry) => entry.1 = value,
+            None => base.push((key, value)),
+        }
+    }
+}
+
+fn write_section(out: &mut String, name: &str, section: &Section) {
+    if !out.is_empty() {
+        out.push('\n');
+    }
+    out.push_str(&format!("[{}]\n", name));
+    for (key, value) in section {
+        out.push_str(&format!("{} = {}\n", key, value));
+    }
+}
+
+/// Generate a machine config from the layout at `path`
+pub fn generate(path: &Path) -> io::Result<String> {
+    let mut db = Ini::new_cs();
+    db.read(DATABASE.to_string())
+        .expect("Failed to parse the built-in driver database");
+
+    let mut layout = Ini::new_cs();
+    layout.read(fs::read_to_string(path)?).map_err(invalid)?;
+
+    let amp = match layout.get("Layout", "amp") {
+        Some(amp) => {
+            let params = section(&db, &("Amp/".to_owned() + &amp));
+            if params.is_empty() {
+                return Err(invalid(format!("Unknown amp '{}'", amp)));
+            }
+            params
+        }
+        None => Section::new(),
+    };
+
+    let mut channels = 0;
+    let mut speakers = Vec::new();
+    for name in layout.sections() {
+        if !name.starts_with("Speaker/") {
+            continue;
+        }
+        let mut keys = section(&layout, &name);
+
+        let driver = match keys.iter().position(|(k, _)| k == "driver") {
+            Some(idx) => keys.remove(idx).1,
+            None => return Err(invalid(format!("{}: No driver given", name))),
+        };
+        let mut params = section(&db, &("Driver/".to_owned() + &driver));
+        if params.is_empty() {
+            return Err(invalid(format!("{}: Unknown driver '{}'", name, driver)));
+        }
+
+        for (key, value) in keys.iter() {
+            if key == "is_chan" || key == "vs_chan" {
+                let chan: usize = value
+                    .parse()
+                    .map_err(|_| invalid(format!("{}/{}: Invalid value", name, key)))?;
+                channels = channels.max(chan + 1);
+            }
+        }
+
+        // The group goes first, then what we know about the hardware
+        let mut spk = Section::new();
+        if let Some(idx) = keys.iter().position(|(k, _)| k == "group") {
+            spk.push(keys.remove(idx));
+        }
+        merge(&mut params, amp.clone());
+        merge(&mut spk, params);
+        merge(&mut spk, keys);
+        speakers.push((name, spk));
+    }
+    if speakers.is_empty() {
+        return Err(invalid("No speakers in the layout".into()));
+    }
+
+    let mut globals = vec![("schema_version".to_string(), config::SCHEMA.to_string())];
+    merge(&mut globals, section(&db, "Globals"));
+    merge(&mut globals, section(&layout, "Globals"));
+    for (key, value) in globals.iter_mut() {
+        if key == "channels" && value == "auto" {
+            *value = channels.to_string();
+        }
+    }
+
+    let mut controls = section(&db, "Controls");
+    merge(&mut controls, section(&layout, "Controls"));
+
+    let mut out = String::new();
+    write_section(&mut out, "Globals", &globals);
+    write_section(&mut out, "Controls", &controls);
+    for name in layout.sections() {
+        let passthrough = !["Layout", "Globals", "Controls"].contains(&name.as_str())
+            && !name.starts_with("Speaker/");
+        if passthrough {
+            write_section(&mut out, &name, &section(&layout, &name));
+        }
+    }
+    for (name, spk) in speakers.iter() {
+        write_section(&mut out, name, spk);
+    }
+
+    Ok(out)
+}
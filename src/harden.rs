@@ -0,0 +1,276 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    Process hardening. The safety loop must keep running under memory
+    pressure, none of our file descriptors should end up in processes we
+    spawn, and once the devices are open we don't need to be root anymore.
+*/
+use log::{info, warn};
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io;
+
+/**
+    Lock our current working set into memory, so page faults under memory
+    pressure can't stall the protection loop. We deliberately don't use
+    MCL_FUTURE: once we're running, all our big allocations exist already,
+    and future ones failing against RLIMIT_MEMLOCK would be worse than an
+    occasional page fault.
+*/
+pub fn lock_memory() {
+    if unsafe { libc::mlockall(libc::MCL_CURRENT) } != 0 {
+        warn!("Failed to lock memory: {}", io::Error::last_os_error());
+        return;
+    }
+
+    info!("Locked memory");
+}
+
+/**
+    Make sure no file descriptors beyond stdio leak into anything we might
+    spawn. Rust sets O_CLOEXEC itself, but that isn't guaranteed for fds
+    opened by C libraries.
+*/
+pub fn set_cloexec_all() {
+    let fds = match fs::read_dir("/proc/self/fd") {
+        Ok(fds) => fds,
+        Err(e) => {
+            warn!("Failed to list fds: {}", e);
+            return;
+        }
+    };
+
+    let fds: Vec<i32> = fds
+        .filter_map(|e| e.ok()?.file_name().to_str()?.parse().ok())
+        .filter(|&fd| fd > 2)
+        .collect();
+
+    for fd in fds {
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+        // The directory fd used for the listing is already gone
+        if flags < 0 || flags & libc::FD_CLOEXEC != 0 {
+            continue;
+        }
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) } != 0 {
+            warn!("Failed to set FD_CLOEXEC on fd {}", fd);
+        }
+    }
+}
+
+/// Look up a user's uid and primary gid
+fn lookup_user(user: &CStr) -> Option<(libc::uid_t, libc::gid_t)> {
+    let mut pwd: libc::passwd = unsafe { core::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut result: *mut libc::passwd = core::ptr::null_mut();
+
+    let ret = unsafe {
+        libc::getpwnam_r(
+            user.as_ptr(),
+            &mut pwd,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if ret != 0 || result.is_null() {
+        return None;
+    }
+
+    Some((pwd.pw_uid, pwd.pw_gid))
+}
+
+/**
+    Switch to the given user, with its primary and supplementary groups.
+    The user needs access to the sound devices (usually via the audio group)
+    so we can reopen the PCM, and write access to the blackbox and stats
+    paths.
+*/
+pub fn drop_privileges(user: &str) {
+    let cuser = CString::new(user).unwrap();
+    let (uid, gid) = lookup_user(&cuser).unwrap_or_else(|| panic!("Unknown user '{}'", user));
+
+    if unsafe { libc::initgroups(cuser.as_ptr(), gid) } != 0 {
+        panic!("initgroups() failed: {}", io::Error::last_os_error());
+    }
+    if unsafe { libc::setresgid(gid, gid, gid) } != 0 {
+        panic!("setresgid() failed: {}", io::Error::last_os_error());
+    }
+    if unsafe { libc::setresuid(uid, uid, uid) } != 0 {
+        panic!("setresuid() failed: {}", io::Error::last_os_error());
+    }
+
+    info!("Dropped privileges to {} ({}:{})", user, uid, gid);
+}
+
+pub fn set_no_new_privs() {
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        panic!("PR_SET_NO_NEW_PRIVS failed: {}", io::Error::last_os_error());
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc00000b7;
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000003e;
+
+/// Syscalls the daemon makes once it's running (besides ioctl)
+const SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_read,
+    libc::SYS_readv,
+    libc::SYS_pread64,
+    libc::SYS_write,
+    libc::SYS_writev,
+    libc::SYS_openat,
+    libc::SYS_close,
+    libc::SYS_fcntl,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_lseek,
+    libc::SYS_faccessat,
+    libc::SYS_faccessat2,
+    libc::SYS_readlinkat,
+    libc::SYS_getdents64,
+    libc::SYS_renameat,
+    libc::SYS_renameat2,
+    libc::SYS_linkat,
+    libc::SYS_unlinkat,
+    libc::SYS_fsync,
+    libc::SYS_ppoll,
+    libc::SYS_pselect6,
+    // The reactor, see reactor.rs
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_epoll_pwait2,
+    libc::SYS_timerfd_settime,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    libc::SYS_brk,
+    libc::SYS_futex,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_nanosleep,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_sigaltstack,
+    libc::SYS_accept4,
+    libc::SYS_socket,
+    libc::SYS_connect,
+    libc::SYS_recvfrom,
+    libc::SYS_sendto,
+    libc::SYS_getsockopt,
+    // Client timeouts, see status.rs and varlink.rs
+    libc::SYS_setsockopt,
+    libc::SYS_shutdown,
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_set_robust_list,
+    libc::SYS_rseq,
+    libc::SYS_prlimit64,
+    libc::SYS_getpid,
+    libc::SYS_getuid,
+    libc::SYS_geteuid,
+    libc::SYS_getgid,
+    libc::SYS_getegid,
+    libc::SYS_uname,
+    libc::SYS_gettid,
+    libc::SYS_tgkill,
+    libc::SYS_getrandom,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_poll,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_open,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_stat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_access,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_readlink,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_rename,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_unlink,
+];
+
+/// Allowed ioctl types: PCM ('A'), control ('U') and terminal ('T', which includes FIONBIO)
+const IOCTL_TYPES: &[u32] = &[b'A' as u32, b'U' as u32, b'T' as u32];
+
+fn stmt(code: u32, k: u32) -> libc::sock_filter {
+    jump(code, k, 0, 0)
+}
+
+fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+/**
+    Install a seccomp filter on all threads, allowing only the syscalls we
+    need in the main loop and only ALSA and terminal ioctls. Anything else
+    fails with EPERM, which ends up as a panic and leaves the blackbox
+    behind, rather than killing us outright.
+*/
+pub fn install_seccomp() {
+    let ld = |off: usize| stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, off as u32);
+    let jeq = |k: u32, jt: u8, jf: u8| jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, k, jt, jf);
+    let ret = |k: u32| stmt(libc::BPF_RET | libc::BPF_K, k);
+    let allow = ret(libc::SECCOMP_RET_ALLOW);
+    let deny = ret(libc::SECCOMP_RET_ERRNO | libc::EPERM as u32);
+
+    let mut filter = vec![
+        ld(core::mem::offset_of!(libc::seccomp_data, arch)),
+        jeq(AUDIT_ARCH, 1, 0),
+        deny,
+        ld(core::mem::offset_of!(libc::seccomp_data, nr)),
+    ];
+
+    for nr in SYSCALLS {
+        filter.push(jeq(*nr as u32, 0, 1));
+        filter.push(allow);
+    }
+
+    // ioctl, filtered on the type byte of the request (low half of args[1])
+    filter.push(jeq(libc::SYS_ioctl as u32, 1, 0));
+    filter.push(deny);
+    filter.push(ld(core::mem::offset_of!(libc::seccomp_data, args) + 8));
+    filter.push(stmt(libc::BPF_ALU | libc::BPF_AND | libc::BPF_K, 0xff00));
+    for t in IOCTL_TYPES {
+        filter.push(jeq(t << 8, 0, 1));
+        filter.push(allow);
+    }
+    filter.push(deny);
+
+    let prog = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_mut_ptr(),
+    };
+
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            libc::SECCOMP_FILTER_FLAG_TSYNC,
+            &prog,
+        )
+    };
+    if ret != 0 {
+        panic!(
+            "Failed to install seccomp filter: {}",
+            io::Error::last_os_error()
+        );
+    }
+
+    info!("Installed seccomp filter");
+}
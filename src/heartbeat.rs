@@ -0,0 +1,135 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    The unlock heartbeat. The kernel only lets the speakers go past its own
+    conservative limits for as long as we keep writing the unlock controls.
+    Whenever a write fails, or we hold one back (safe mode, a quarantined
+    group), the kernel's limits may kick in and cap the volume on their
+    own, which from the outside looks just like the daemon limiting.
+
+    So every interruption is counted, logged and recorded in the event
+    history, once when it starts and once when the heartbeat is back, and
+    the counts are kept across runs with the usage statistics. That answers
+    "did the kernel cap my volume or did the daemon?" in bug reports.
+*/
+use std::collections::BTreeSet;
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use speakersafetyd_core::history::{Event, History};
+
+use crate::types::Elem;
+use crate::writer;
+
+const UNLOCK_MAGIC: i32 = 0xdec1be15u32 as i32;
+
+/// Heartbeat interruptions, each counted once however long it lasted
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Interruptions {
+    /// The unlock write failed
+    pub failed: u64,
+    /// We held the unlock back on purpose
+    pub withheld: u64,
+}
+
+impl Interruptions {
+    pub fn add(self, other: Interruptions) -> Interruptions {
+        Interruptions {
+            failed: self.failed + other.failed,
+            withheld: self.withheld + other.withheld,
+        }
+    }
+}
+
+/// The heartbeat in the status reply, see Heartbeat::report()
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HeartbeatReport {
+    /// In this run
+    #[serde(flatten)]
+    pub counts: Interruptions,
+    /// Across runs, this one included
+    pub lifetime: Option<Interruptions>,
+    /// The unlock controls not being refreshed right now
+    pub interrupted: Vec<String>,
+}
+
+#[derive(Default)]
+pub struct Heartbeat {
+    /// Interruptions in this run
+    pub counts: Interruptions,
+    /// The controls the last heartbeat didn't refresh, by name
+    down: BTreeSet<String>,
+}
+
+impl Heartbeat {
+    /**
+        Refresh `unlock`, unless told to `withhold` it. Writes complete in
+        the background, so failures show up a period late.
+    */
+    pub fn beat(
+        &mut self,
+        writer: &writer::Handle,
+        unlock: &Elem,
+        withhold: bool,
+        history: &mut History,
+    ) {
+        let result = match withhold {
+            true => Err(None),
+            false => {
+                unlock.queue(writer, UNLOCK_MAGIC, true);
+                match writer.outcome(unlock.name()) {
+                    Some(result) => result.map_err(Some),
+                    // No news yet
+                    None => return,
+                }
+            }
+        };
+        let control = unlock.name();
+
+        match result {
+            Ok(()) => {
+                if self.down.remove(control) {
+                    info!("{}: Heartbeat restored", control);
+                    history.push(Event::HeartbeatRestored {
+                        control: control.into(),
+                    });
+                }
+            }
+            Err(_) if self.down.contains(control) => {}
+            Err(e) => {
+                match e {
+                    Some(e) => {
+                        self.counts.failed += 1;
+                        warn!(
+                            "{}: Heartbeat write failed ({}), the kernel's limits may be in effect",
+                            control, e
+                        );
+                    }
+                    None => {
+                        self.counts.withheld += 1;
+                        info!(
+                            "{}: Heartbeat withheld, the kernel's limits are in effect",
+                            control
+                        );
+                    }
+                }
+                history.push(Event::HeartbeatInterrupted {
+                    control: control.into(),
+                    failed: e.is_some(),
+                });
+                self.down.insert(control.into());
+            }
+        }
+    }
+
+    /// The counts for this run, over the `lifetime` if known, and what's down now
+    pub fn report(&self, lifetime: Option<Interruptions>) -> HeartbeatReport {
+        HeartbeatReport {
+            counts: self.counts,
+            lifetime,
+            interrupted: self.down.iter().cloned().collect(),
+        }
+    }
+}
@@ -1,20 +1,146 @@
 // SPDX-License-Identifier: MIT
 // (C) 2022 The Asahi Linux Contributors
 
+use std::fs;
+use std::thread;
+use std::time::{Duration, Instant};
+
 use alsa::mixer::MilliBel;
-use configparser::ini::Ini;
+use log::{info, warn};
+use speakersafetyd_core::exit::{self, Failure};
+
+use crate::audit;
+
+pub use speakersafetyd_core::helpers::{
+    clamp_dt, group_label, parse_float, parse_int, parse_opt_float, parse_opt_int,
+};
+
+/// Identifies config text, to tell whether what's on disk is what was loaded
+pub fn config_hash(text: &str) -> String {
+    format!(
+        "{:016x}",
+        speakersafetyd_core::helpers::fnv1a64(text.as_bytes())
+    )
+}
 
 pub fn open_card(card: &str) -> alsa::ctl::Ctl {
     let ctldev: alsa::ctl::Ctl = match alsa::ctl::Ctl::new(card, false) {
         Ok(ctldev) => ctldev,
         Err(e) => {
-            panic!("{}: Could not open sound card! Error: {}", card, e);
+            exit::fail(
+                Failure::CardMissing,
+                format!("{}: Could not open sound card! Error: {}", card, e),
+            );
         }
     };
 
     ctldev
 }
 
+/**
+    Wait for a card to show up, e.g. at boot or while the driver is being
+    rebound. Gives up after `timeout` and leaves it to open_card to fail.
+*/
+pub fn wait_for_card(card: &str, timeout: Duration) {
+    let start = Instant::now();
+    let mut waiting = false;
+
+    while alsa::ctl::Ctl::new(card, false).is_err() && start.elapsed() < timeout {
+        if !waiting {
+            info!("{}: Waiting for the card to appear", card);
+            waiting = true;
+        }
+        thread::sleep(Duration::from_millis(500));
+    }
+}
+
+/**
+    Wait for a card that went away to be gone, so we don't catch it on its
+    way out. It may well be back by the time we look, or have never left if
+    only some of its controls did, so this gives up after `timeout`.
+*/
+pub fn wait_for_card_gone(card: &str, timeout: Duration) {
+    let start = Instant::now();
+
+    while alsa::ctl::Ctl::new(card, false).is_ok() && start.elapsed() < timeout {
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/**
+    Whether any playback substream of the card is open, going by procfs.
+    Errs on the side of yes if we can't tell.
+*/
+pub fn playback_open(card: i32) -> bool {
+    let substreams = || -> std::io::Result<Vec<std::path::PathBuf>> {
+        let mut subs = Vec::new();
+        for pcm in fs::read_dir(format!("/proc/asound/card{}", card))? {
+            let pcm = pcm?;
+            let name = pcm.file_name().to_string_lossy().into_owned();
+            if !(name.starts_with("pcm") && name.ends_with('p')) {
+                continue;
+            }
+            for sub in fs::read_dir(pcm.path())? {
+                let sub = sub?;
+                if sub.file_name().to_string_lossy().starts_with("sub") {
+                    subs.push(sub.path().join("status"));
+                }
+            }
+        }
+        Ok(subs)
+    };
+
+    match substreams() {
+        Ok(subs) => subs
+            .iter()
+            .any(|s| fs::read_to_string(s).map_or(true, |st| st.trim() != "closed")),
+        Err(_) => true,
+    }
+}
+
+/**
+    Whether the machine is running off its battery, going by the power
+    supply class. Machines without a battery never are.
+*/
+pub fn on_battery() -> bool {
+    let Ok(supplies) = fs::read_dir("/sys/class/power_supply") else {
+        return false;
+    };
+    let read = |path: &std::path::Path, attr: &str| {
+        fs::read_to_string(path.join(attr))
+            .map(|s| s.trim().to_string())
+            .unwrap_or_default()
+    };
+
+    supplies.flatten().any(|s| {
+        let path = s.path();
+        read(&path, "type") == "Battery" && read(&path, "status") == "Discharging"
+    })
+}
+
+/**
+    Resolve a user-provided card specifier into an ALSA device string.
+    Anything that already looks like a device string (contains a ':') is
+    passed through untouched. Otherwise we look for a card whose name or
+    longname matches, and fall back to treating it as a card ID.
+*/
+pub fn resolve_card(card: &str) -> String {
+    if card.contains(':') {
+        return card.to_string();
+    }
+
+    for c in alsa::card::Iter::new().filter_map(|c| c.ok()) {
+        let name = c.get_name().unwrap_or_default();
+        let longname = c.get_longname().unwrap_or_default();
+        if name == card || longname == card {
+            info!("Card '{}' is card {}", card, c.get_index());
+            return format!("hw:{}", c.get_index());
+        }
+    }
+
+    format!("hw:{}", card)
+}
+
 pub fn open_pcm(dev: &str, chans: u32, mut sample_rate: u32) -> alsa::pcm::PCM {
     let pcm = alsa::pcm::PCM::new(dev, alsa::Direction::Capture, false).unwrap();
     {
@@ -36,61 +162,79 @@ pub fn open_pcm(dev: &str, chans: u32, mut sample_rate: u32) -> alsa::pcm::PCM {
         params.set_access(alsa::pcm::Access::RWInterleaved).unwrap();
         pcm.hw_params(&params).unwrap();
     }
+    {
+        // Keep capturing when we fall behind, so avail() counts what we missed
+        let params = pcm.sw_params_current().unwrap();
+        params
+            .set_stop_threshold(params.get_boundary().unwrap())
+            .unwrap();
+        pcm.sw_params(&params).unwrap();
+    }
+    // Rather than on the first read, which only happens once it polls readable
+    pcm.start().unwrap();
 
     pcm
 }
 
-/**
-    Wrapper around configparser::ini::Ini.getint()
-    to safely unwrap the Result<Option<i64>, E> returned by
-    it.
-*/
-pub fn parse_int<T: TryFrom<i64>>(config: &Ini, section: &str, key: &str) -> T
-where
-    <T as TryFrom<i64>>::Error: std::fmt::Debug,
-{
-    config
-        .getint(section, key)
-        .unwrap_or_else(|_| panic!("{}/{}: Invalid value", section, key))
-        .unwrap_or_else(|| panic!("{}/{}: Missing key", section, key))
-        .try_into()
-        .expect("{}/{}: Out of bounds")
-}
-
-pub fn parse_opt_int<T: TryFrom<i64>>(config: &Ini, section: &str, key: &str) -> Option<T>
-where
-    <T as TryFrom<i64>>::Error: std::fmt::Debug,
-{
-    config
-        .getint(section, key)
-        .unwrap_or_else(|_| panic!("{}/{}: Invalid value", section, key))
-        .map(|a| a.try_into().expect("{}/{}: Out of bounds"))
+/// Have the PCM poll readable once `frames` can be read in one go
+pub fn set_avail_min(pcm: &alsa::pcm::PCM, frames: usize) {
+    let params = pcm.sw_params_current().unwrap();
+    params.set_avail_min(frames as alsa::pcm::Frames).unwrap();
+    pcm.sw_params(&params).unwrap();
 }
 
 /**
-    Wrapper around configparser::ini::Ini.getfloat()
-    to safely unwrap the Result<Option<f64>, E> returned by
-    it.
+    Frames captured that we didn't read before they were overwritten. If
+    any were, the rest of the buffer is stale too, so skip all of it and
+    carry on from the latest data.
 */
-pub fn parse_float(config: &Ini, section: &str, key: &str) -> f32 {
-    let val = config
-        .getfloat(section, key)
-        .unwrap_or_else(|_| panic!("{}/{}: Invalid value", section, key))
-        .unwrap_or_else(|| panic!("{}/{}: Missing key", section, key)) as f32;
+pub fn lost_frames(pcm: &alsa::pcm::PCM) -> usize {
+    let Ok(avail) = pcm.avail() else {
+        return 0;
+    };
+    let buffer = pcm
+        .hw_params_current()
+        .and_then(|p| p.get_buffer_size())
+        .unwrap_or(avail);
+    if avail <= buffer {
+        return 0;
+    }
 
-    assert!(val.is_finite());
-    val
+    if let Err(e) = pcm.reset() {
+        warn!("Failed to skip {} lost frames: {}", avail, e);
+        return 0;
+    }
+    avail as usize
 }
 
 /**
-    Wrapper around configparser::ini::Ini.getfloat()
-    to safely unwrap the Result<Option<f64>, E> returned by
-    it.
+    Lets a warning that can recur every period through at most once per
+    `interval`, so a misbehaving stream can't flood the journal.
 */
-pub fn parse_string(config: &Ini, section: &str, key: &str) -> String {
-    config
-        .get(section, key)
-        .unwrap_or_else(|| panic!("{}/{}: Missing key", section, key))
+pub struct WarnLimit {
+    interval: Duration,
+    last: Option<Instant>,
+    held: u64,
+}
+
+impl WarnLimit {
+    pub fn new(interval: Duration) -> WarnLimit {
+        WarnLimit {
+            interval,
+            last: None,
+            held: 0,
+        }
+    }
+
+    /// Whether to log this time, and if so, how many were held back since the last one
+    pub fn check(&mut self, now: Instant) -> Option<u64> {
+        if self.last.is_some_and(|t| now - t < self.interval) {
+            self.held += 1;
+            return None;
+        }
+        self.last = Some(now);
+        Some(std::mem::take(&mut self.held))
+    }
 }
 
 /**
@@ -128,36 +272,23 @@ pub fn read_ev(card: &alsa::ctl::Ctl, ev: &mut alsa::ctl::ElemValue, name: &str)
     Wrapper for alsa::ctl::Ctl::elem_write().
 */
 pub fn write_ev(card: &alsa::ctl::Ctl, ev: &alsa::ctl::ElemValue, name: &str) {
-    match card.elem_write(ev) {
-        // alsa:Result<()>
-        Ok(val) => val,
-        Err(e) => {
-            panic!(
-                "Could not write elem value {}. alsa-lib error: {:?}",
-                name, e
-            );
-        }
-    };
+    if let Err(e) = try_write_ev(card, ev, name) {
+        panic!(
+            "Could not write elem value {}. alsa-lib error: {:?}",
+            name, e
+        );
+    }
 }
 
-/**
-    Wrapper for alsa::ctl::Ctl::elem_write().
-*/
-pub fn get_range_db(
+/// write_ev(), for writes that may fail
+pub fn try_write_ev(
     card: &alsa::ctl::Ctl,
-    el: &alsa::ctl::ElemId,
+    ev: &alsa::ctl::ElemValue,
     name: &str,
-) -> (MilliBel, MilliBel) {
-    match card.get_db_range(el) {
-        // alsa:Result<()>
-        Ok(val) => val,
-        Err(e) => {
-            panic!(
-                "Could not get elem db range {}. alsa-lib error: {:?}",
-                name, e
-            );
-        }
-    }
+) -> alsa::Result<()> {
+    card.elem_write(ev)?;
+    audit::record(card, ev, name);
+    Ok(())
 }
 
 /**
@@ -167,12 +298,35 @@ pub fn lock_el(card: &alsa::ctl::Ctl, el: &alsa::ctl::ElemId, name: &str) {
     let _val = match card.elem_lock(el) {
         // alsa:Result<()>
         Ok(val) => val,
+        Err(e) if e.errno() == libc::EBUSY => {
+            exit::fail(
+                Failure::ElementLocked,
+                format!(
+                    "Could not lock elem {}, another process holds it (is another speakersafetyd stuck? try --takeover)",
+                    name
+                ),
+            );
+        }
         Err(e) => {
             panic!("Could not lock elem {}. alsa-lib error: {:?}", name, e);
         }
     };
 }
 
+/**
+    Number of values (channels) of the element, 1 if that can't be found
+    out. Only the high level API has element info, so this is slow.
+*/
+pub fn elem_count(card: &alsa::ctl::Ctl, el: &alsa::ctl::ElemId) -> u32 {
+    let count = card.card_info().ok().and_then(|info| {
+        let hctl = alsa::HCtl::from_card(&info.get_card(), false).ok()?;
+        hctl.load().ok()?;
+        hctl.find_elem(el)?.info().ok().map(|i| i.get_count())
+    });
+
+    count.unwrap_or(1).max(1)
+}
+
 pub fn int_to_db(card: &alsa::ctl::Ctl, id: &alsa::ctl::ElemId, val: i32) -> MilliBel {
     
 
@@ -0,0 +1,70 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    Holdoff for restart storms. A daemon that fails during startup gets
+    restarted by systemd a second later, and unless whatever broke it went
+    away, it fails the same way again, filling the journal with the same
+    crash over and over. So a startup failure is recorded in a runtime
+    file, and if the next run fails the same way shortly after, it holds
+    off before exiting, logging one line about it.
+
+    By the time we get here the card has been closed, so the kernel's own
+    speaker limits are in place for the whole holdoff, as they are when
+    the daemon isn't running at all.
+*/
+use std::fs;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{debug, warn};
+use speakersafetyd_core::exit::Failure;
+
+/// The last startup failure, as "<status> <time>\n<message>"
+const FAILFILE: &str = "/run/speakersafetyd.failure";
+/// How long after a failure (plus the holdoff) another one counts as a repeat
+const REPEAT_WINDOW: Duration = Duration::from_secs(60);
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Whether the last recorded failure was `failure` with `message`, recently
+fn repeated(failure: Failure, message: &str, holdoff: Duration) -> bool {
+    let Ok(record) = fs::read_to_string(FAILFILE) else {
+        return false;
+    };
+    let Some((header, last_message)) = record.split_once('\n') else {
+        return false;
+    };
+    let mut header = header.split(' ').map(|f| f.parse::<u64>().ok());
+    let (Some(Some(status)), Some(Some(time))) = (header.next(), header.next()) else {
+        return false;
+    };
+
+    status == failure.code() as u64
+        && last_message == message
+        && now().saturating_sub(time) < (holdoff + REPEAT_WINDOW).as_secs()
+}
+
+/**
+    Startup failed with `failure`. If the previous run failed the same
+    way, wait out `holdoff` before letting the caller exit.
+*/
+pub fn startup_failed(failure: Failure, message: &str, holdoff: Duration) {
+    if !holdoff.is_zero() && repeated(failure, message, holdoff) {
+        warn!(
+            "Startup failed the same way as last time ({}), holding off for {:.0} s with the kernel's speaker limits in place",
+            failure,
+            holdoff.as_secs_f64()
+        );
+        thread::sleep(holdoff);
+    }
+
+    // Privileges may be dropped by now, in which case this is best effort
+    let record = format!("{} {}\n{}", failure.code(), now(), message);
+    if let Err(e) = fs::write(FAILFILE, record) {
+        debug!("Failed to record the failure in {}: {}", FAILFILE, e);
+    }
+}
@@ -0,0 +1,420 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    User hooks, to wire an indicator (a keyboard backlight, a LED, a
+    notification) up to the speakers' thermal state. Configured in the
+    `[Hooks]` section:
+
+    - `exec`: a program to run on every event, with the event name as its
+      only argument and the details in `SPEAKERSAFETYD_*` environment
+      variables.
+    - `led`: a sysfs LED (e.g. `/sys/class/leds/kbd_backlight`), lit at
+      full brightness while any group is limiting or any speaker is above
+      a temperature threshold.
+    - `thresholds`: coil temperatures (°C) to report crossing, comma
+      separated. They're considered crossed again on the way down once the
+      coil is t_hysteresis below them.
+    - `limit_threshold` and `limit_hold`: only report a group as limited
+      once its gain has been reduced by more than limit_threshold (dB) for
+      limit_hold (ms), so bass transients that touch the limiter for a few
+      milliseconds don't set off notifications. Both default to 0, i.e.
+      any reduction is reported right away.
+
+    Hooks run on a thread of their own, so a slow script can never hold up
+    the protection loop; if they fall behind, events are dropped.
+
+    The sandbox (`--user`) doesn't let the daemon spawn processes, so the
+    `exec` program is run by a helper process instead, this binary run as
+    `hook-helper`. It's started during setup, before the sandbox goes up,
+    drops to the same user but stays outside the seccomp filter, and gets
+    the events over a pipe, one JSON object per line.
+*/
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use configparser::ini::Ini;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::harden;
+use crate::helpers;
+use crate::schema;
+use crate::types::Globals;
+
+/// Number of events that may be pending before they're dropped
+const QUEUE_LEN: usize = 16;
+
+/// Events, by the name the exec program gets them as
+const EVENTS: &[&str] = &[
+    "limiter_engaged",
+    "limiter_released",
+    "temperature_above",
+    "temperature_below",
+];
+
+/// Environment variables the helper passes on, all others are dropped
+const ENV_PREFIX: &str = "SPEAKERSAFETYD_";
+
+/// An event for the helper, one per line
+#[derive(Serialize, Deserialize)]
+struct Message {
+    event: String,
+    env: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    LimiterEngaged {
+        group: usize,
+        name: Option<String>,
+        gain: f32,
+    },
+    LimiterReleased {
+        group: usize,
+        name: Option<String>,
+    },
+    TemperatureAbove {
+        speaker: String,
+        threshold: f32,
+        t_coil: f64,
+    },
+    TemperatureBelow {
+        speaker: String,
+        threshold: f32,
+        t_coil: f64,
+    },
+}
+
+impl Event {
+    fn name(&self) -> &'static str {
+        match self {
+            Event::LimiterEngaged { .. } => "limiter_engaged",
+            Event::LimiterReleased { .. } => "limiter_released",
+            Event::TemperatureAbove { .. } => "temperature_above",
+            Event::TemperatureBelow { .. } => "temperature_below",
+        }
+    }
+
+    fn env(&self) -> Vec<(&'static str, String)> {
+        match self {
+            Event::LimiterEngaged { group, name, gain } => {
+                let mut env = vec![
+                    ("SPEAKERSAFETYD_GROUP", group.to_string()),
+                    ("SPEAKERSAFETYD_GAIN", format!("{:.2}", gain)),
+                ];
+                env.extend(name.clone().map(|n| ("SPEAKERSAFETYD_GROUP_NAME", n)));
+                env
+            }
+            Event::LimiterReleased { group, name } => {
+                let mut env = vec![("SPEAKERSAFETYD_GROUP", group.to_string())];
+                env.extend(name.clone().map(|n| ("SPEAKERSAFETYD_GROUP_NAME", n)));
+                env
+            }
+            Event::TemperatureAbove {
+                speaker,
+                threshold,
+                t_coil,
+            }
+            | Event::TemperatureBelow {
+                speaker,
+                threshold,
+                t_coil,
+            } => vec![
+                ("SPEAKERSAFETYD_SPEAKER", speaker.clone()),
+                ("SPEAKERSAFETYD_THRESHOLD", threshold.to_string()),
+                ("SPEAKERSAFETYD_TEMPERATURE", format!("{:.1}", t_coil)),
+            ],
+        }
+    }
+}
+
+struct Led {
+    brightness: PathBuf,
+    max: String,
+    /// Limiting groups and (speaker, threshold) pairs above their threshold
+    active: BTreeSet<String>,
+}
+
+impl Led {
+    fn new(path: PathBuf) -> Option<Led> {
+        let max = fs::read_to_string(path.join("max_brightness"))
+            .map_err(|e| warn!("Hooks: Failed to open LED {:?}: {}", path, e))
+            .ok()?;
+
+        Some(Led {
+            brightness: path.join("brightness"),
+            max: max.trim().to_string(),
+            active: BTreeSet::new(),
+        })
+    }
+
+    fn update(&mut self, event: &Event) {
+        let was_on = !self.active.is_empty();
+        match event {
+            Event::LimiterEngaged { group, .. } => self.active.insert(format!("group {}", group)),
+            Event::LimiterReleased { group, .. } => self.active.remove(&format!("group {}", group)),
+            Event::TemperatureAbove {
+                speaker, threshold, ..
+            } => self.active.insert(format!("{} {}", speaker, threshold)),
+            Event::TemperatureBelow {
+                speaker, threshold, ..
+            } => self.active.remove(&format!("{} {}", speaker, threshold)),
+        };
+
+        let on = !self.active.is_empty();
+        if on != was_on {
+            let value = if on { self.max.as_str() } else { "0" };
+            if let Err(e) = fs::write(&self.brightness, value) {
+                warn!("Hooks: Failed to set LED: {}", e);
+            }
+        }
+    }
+}
+
+/// Run `exec` for one event. Errors are for it not running at all.
+fn exec_event<K, V>(exec: &str, name: &str, env: impl IntoIterator<Item = (K, V)>) -> io::Result<()>
+where
+    K: AsRef<std::ffi::OsStr>,
+    V: AsRef<std::ffi::OsStr>,
+{
+    let status = Command::new(exec).arg(name).envs(env).status()?;
+    if !status.success() {
+        warn!("Hooks: {} {} failed: {}", exec, name, status);
+    }
+    Ok(())
+}
+
+/**
+    Start the helper that runs `exec` for us, as `user` if given. It has
+    to be started before the sandbox goes up.
+*/
+fn spawn_helper(exec: &str, user: Option<&str>) -> io::Result<Child> {
+    let mut cmd = Command::new("/proc/self/exe");
+    cmd.arg("hook-helper").arg(exec).stdin(Stdio::piped());
+    if let Some(user) = user {
+        cmd.arg("--user").arg(user);
+    }
+    cmd.spawn()
+}
+
+fn run(rx: Receiver<Event>, mut helper: Option<Child>, mut led: Option<Led>) {
+    for event in rx {
+        debug!("Hook: {:?}", event);
+        if let Some(led) = led.as_mut() {
+            led.update(&event);
+        }
+        if let Some(stdin) = helper.as_mut().and_then(|h| h.stdin.as_mut()) {
+            let msg = Message {
+                event: event.name().into(),
+                env: event
+                    .env()
+                    .into_iter()
+                    .map(|(k, v)| (k.into(), v))
+                    .collect(),
+            };
+            if let Err(e) = writeln!(stdin, "{}", schema::dump(&msg)) {
+                warn!("Hooks: Lost the exec helper ({})", e);
+                helper = None;
+            }
+        }
+    }
+}
+
+/**
+    The hook helper: run `exec` for every event the daemon sends on stdin,
+    until it goes away. Only known events and our own environment
+    variables are passed on, whatever the daemon might have been made to
+    send. A program that can't be run at all (missing, not executable for
+    `user`) is given up on after saying so once, the rest of the events
+    are drained.
+*/
+pub fn run_helper(exec: &str, user: Option<&str>) {
+    if let Some(user) = user {
+        harden::drop_privileges(user);
+        harden::set_no_new_privs();
+    }
+
+    let mut broken = false;
+    for line in io::stdin().lock().lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        if broken {
+            continue;
+        }
+        let msg: Message = match serde_json::from_str(&line) {
+            Ok(msg) => msg,
+            Err(e) => {
+                warn!("Hook helper: Invalid message: {}", e);
+                continue;
+            }
+        };
+        if !EVENTS.contains(&msg.event.as_str()) {
+            warn!("Hook helper: Unknown event {:?}", msg.event);
+            continue;
+        }
+        let env = msg.env.iter().filter(|(k, _)| k.starts_with(ENV_PREFIX));
+        if let Err(e) = exec_event(exec, &msg.event, env) {
+            warn!("Hooks: Failed to run {}: {}, not running it again", exec, e);
+            broken = true;
+        }
+    }
+}
+
+pub struct Hooks {
+    tx: SyncSender<Event>,
+    thresholds: Vec<f32>,
+    t_hysteresis: f32,
+    /// Number of thresholds each speaker is above, by index
+    levels: Vec<usize>,
+    /// Gain reduction (dB) and how long it must last to count as limiting
+    limit_threshold: f32,
+    limit_hold: Duration,
+    /// Per group, since when it's been reduced past limit_threshold, and
+    /// whether that was reported
+    limited: BTreeMap<usize, (Option<Instant>, bool)>,
+}
+
+impl Hooks {
+    /**
+        Start the hooks configured in `config`, if any. `user` is who the
+        daemon will run as once sandboxed, and who `exec` runs as.
+    */
+    pub fn new(config: &Ini, globals: &Globals, user: Option<&str>) -> Option<Hooks> {
+        let exec = config.get("Hooks", "exec");
+        let led = config.get("Hooks", "led");
+        if exec.is_none() && led.is_none() {
+            return None;
+        }
+
+        let mut thresholds: Vec<f32> = config
+            .get("Hooks", "thresholds")
+            .map(|t| {
+                t.split(',')
+                    .map(|v| {
+                        v.trim()
+                            .parse::<f32>()
+                            .ok()
+                            .filter(|v| v.is_finite())
+                            .unwrap_or_else(|| panic!("Hooks/thresholds: Invalid value '{}'", v))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        thresholds.sort_by(f32::total_cmp);
+
+        let limit_threshold =
+            helpers::parse_opt_float(config, "Hooks", "limit_threshold").unwrap_or(0.);
+        if limit_threshold.is_nan() || limit_threshold < 0. {
+            panic!("Hooks/limit_threshold: Out of bounds");
+        }
+        let limit_hold = helpers::parse_opt_float(config, "Hooks", "limit_hold").unwrap_or(0.);
+        let limit_hold = Duration::try_from_secs_f32(limit_hold / 1000.)
+            .unwrap_or_else(|_| panic!("Hooks/limit_hold: Out of bounds"));
+
+        info!("Hooks:");
+        if let Some(exec) = exec.as_ref() {
+            match user {
+                Some(user) => info!("  Exec: {} (via the hook helper, as {})", exec, user),
+                None => info!("  Exec: {} (via the hook helper)", exec),
+            }
+        }
+        if let Some(led) = led.as_ref() {
+            info!("  LED: {}", led);
+        }
+        if !thresholds.is_empty() {
+            info!("  Thresholds: {:?} °C", thresholds);
+        }
+        if limit_threshold > 0. || !limit_hold.is_zero() {
+            info!(
+                "  Limiting: more than {} dB for {} ms",
+                limit_threshold,
+                limit_hold.as_millis()
+            );
+        }
+
+        let led = led.and_then(|l| Led::new(l.into()));
+        let helper = exec.and_then(|exec| {
+            spawn_helper(&exec, user)
+                .map_err(|e| warn!("Hooks: Failed to start the exec helper: {}", e))
+                .ok()
+        });
+        let (tx, rx) = mpsc::sync_channel(QUEUE_LEN);
+        thread::Builder::new()
+            .name("hooks".into())
+            .spawn(move || run(rx, helper, led))
+            .expect("Failed to start hooks thread");
+
+        Some(Hooks {
+            tx,
+            thresholds,
+            t_hysteresis: globals.t_hysteresis,
+            levels: Vec::new(),
+            limit_threshold,
+            limit_hold,
+            limited: BTreeMap::new(),
+        })
+    }
+
+    pub fn fire(&self, event: Event) {
+        match self.tx.try_send(event) {
+            Ok(_) => {}
+            Err(TrySendError::Full(ev)) => warn!("Hooks are behind, dropped {}", ev.name()),
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+
+    /// Report group `group`, `name`, as limiting or not, debounced
+    pub fn check_gain(&mut self, group: usize, name: Option<&str>, gain: f32, now: Instant) {
+        let (since, reported) = self.limited.entry(group).or_default();
+        let name = name.map(str::to_string);
+
+        let event = if gain < -self.limit_threshold {
+            let since = *since.get_or_insert(now);
+            if *reported || now - since < self.limit_hold {
+                return;
+            }
+            Event::LimiterEngaged { group, name, gain }
+        } else {
+            *since = None;
+            if !*reported {
+                return;
+            }
+            Event::LimiterReleased { group, name }
+        };
+        *reported = !*reported;
+        self.fire(event);
+    }
+
+    /// Report the thresholds crossed by speaker `idx`, `name`, now at `t_coil`
+    pub fn check_temperature(&mut self, idx: usize, name: &str, t_coil: f64) {
+        if self.levels.len() <= idx {
+            self.levels.resize(idx + 1, 0);
+        }
+        let mut level = self.levels[idx];
+
+        while level < self.thresholds.len() && t_coil >= self.thresholds[level] as f64 {
+            self.fire(Event::TemperatureAbove {
+                speaker: name.into(),
+                threshold: self.thresholds[level],
+                t_coil,
+            });
+            level += 1;
+        }
+        while level > 0 && t_coil < (self.thresholds[level - 1] - self.t_hysteresis) as f64 {
+            level -= 1;
+            self.fire(Event::TemperatureBelow {
+                speaker: name.into(),
+                threshold: self.thresholds[level],
+                t_coil,
+            });
+        }
+        self.levels[idx] = level;
+    }
+}
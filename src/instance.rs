@@ -0,0 +1,272 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    Single instance lock. Two daemons fighting over the same speakers end
+    up with one of them failing to lock the controls (EBUSY), which makes
+    for a confusing panic, so the second one bails out up front instead.
+
+    The lock is an flock() on a runtime file holding the owner's PID. The
+    kernel drops it when the owner exits, however that happens, so a lock
+    can't outlive its daemon; a PID left behind in the file by a daemon
+    that didn't shut down cleanly just gets overwritten.
+
+    A daemon that hangs (stuck in the kernel, stopped, or just wedged)
+    keeps both this lock and its control element locks though. With
+    `--takeover`, the holders are looked up via /proc and asked to exit,
+    then killed if they don't, and we carry on once the locks are free.
+    Only processes named speakersafetyd are ever signalled.
+*/
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+/// Our process name, the only one we ever take over from
+const NAME: &str = "speakersafetyd";
+/// How long to give a holder to exit after SIGTERM, and then SIGKILL
+const TERM_TIMEOUT: Duration = Duration::from_secs(5);
+const KILL_TIMEOUT: Duration = Duration::from_secs(5);
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+pub struct InstanceLock {
+    _file: File,
+}
+
+/// The PID recorded in the lock file, if it's a running process
+pub fn recorded_pid(file: &mut File) -> Option<i32> {
+    let mut pid = String::new();
+    file.rewind().ok()?;
+    file.read_to_string(&mut pid).ok()?;
+    let pid: i32 = pid.trim().parse().ok()?;
+
+    Path::new(&format!("/proc/{}", pid)).exists().then_some(pid)
+}
+
+/// Name of the process `pid`, for messages
+pub fn process_name(pid: i32) -> String {
+    fs::read_to_string(format!("/proc/{}/comm", pid))
+        .map(|c| c.trim().to_string())
+        .unwrap_or_else(|_| "?".into())
+}
+
+/// Process state from /proc/<pid>/stat (R, S, D, T, Z, ...)
+fn process_state(pid: i32) -> Option<char> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The name is in parentheses and may contain anything, so skip past it
+    stat.rsplit_once(')')?.1.trim_start().chars().next()
+}
+
+/// Processes other than us with `path` open
+pub fn holders(path: &Path) -> Vec<i32> {
+    let Ok(path) = path.canonicalize() else {
+        return Vec::new();
+    };
+    let me = std::process::id() as i32;
+    let Ok(procs) = fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+
+    procs
+        .filter_map(|e| e.ok()?.file_name().to_str()?.parse::<i32>().ok())
+        .filter(|pid| *pid != me)
+        .filter(|pid| {
+            fs::read_dir(format!("/proc/{}/fd", pid)).is_ok_and(|fds| {
+                fds.filter_map(|fd| fs::read_link(fd.ok()?.path()).ok())
+                    .any(|target| target == path)
+            })
+        })
+        .collect()
+}
+
+/// Signal `pid`, skipping anything that isn't a speakersafetyd
+fn signal(pid: i32, sig: libc::c_int) {
+    if process_name(pid) != NAME {
+        warn!("Not signalling PID {} ({})", pid, process_name(pid));
+        return;
+    }
+    if unsafe { libc::kill(pid, sig) } != 0 {
+        warn!(
+            "Failed to signal PID {}: {}",
+            pid,
+            io::Error::last_os_error()
+        );
+    }
+}
+
+fn wait_for(timeout: Duration, released: &mut impl FnMut() -> bool) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        if released() {
+            return true;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    released()
+}
+
+/**
+    Get the speakersafetyd processes among `pids` to let go of `what`:
+    SIGTERM first, SIGKILL if that doesn't do it. Returns whether
+    `released` says it's free now. A process stuck in the kernel may not
+    even die on SIGKILL, in which case there is nothing more we can do.
+*/
+pub fn take_over(what: &str, pids: &[i32], mut released: impl FnMut() -> bool) -> bool {
+    for &pid in pids {
+        warn!(
+            "Taking over {} from PID {} ({}, state {})",
+            what,
+            pid,
+            process_name(pid),
+            process_state(pid).unwrap_or('?')
+        );
+        signal(pid, libc::SIGTERM);
+        // A stopped process won't act on SIGTERM until it's continued
+        if process_state(pid) == Some('T') {
+            signal(pid, libc::SIGCONT);
+        }
+    }
+    if wait_for(TERM_TIMEOUT, &mut released) {
+        return true;
+    }
+
+    for &pid in pids {
+        warn!("PID {} didn't exit, killing it", pid);
+        signal(pid, libc::SIGKILL);
+    }
+    if wait_for(KILL_TIMEOUT, &mut released) {
+        return true;
+    }
+
+    for &pid in pids {
+        warn!(
+            "PID {} still holds {} (state {})",
+            pid,
+            what,
+            process_state(pid).unwrap_or('?')
+        );
+    }
+    false
+}
+
+/**
+    Make sure no other speakersafetyd has the control device of `ctl` open,
+    taking over from any that do. Other processes (mixers, PipeWire) may
+    have it open too, but only we lock elements.
+*/
+pub fn take_over_card(ctl: &alsa::ctl::Ctl) {
+    let Ok(card) = ctl.card_info().map(|i| i.get_card()) else {
+        return;
+    };
+    let path = format!("/dev/snd/controlC{}", card.get_index());
+    let ours = || -> Vec<i32> {
+        holders(Path::new(&path))
+            .into_iter()
+            .filter(|pid| process_name(*pid) == NAME && process_state(*pid) != Some('Z'))
+            .collect()
+    };
+
+    let pids = ours();
+    if !pids.is_empty() && !take_over(&path, &pids, || ours().is_empty()) {
+        warn!(
+            "Failed to take over {}, its controls may still be locked",
+            path
+        );
+    }
+}
+
+fn try_lock(file: &File) -> io::Result<bool> {
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+    match io::Error::last_os_error() {
+        e if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+        e => Err(e),
+    }
+}
+
+pub fn open(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o644)
+        .open(path)
+}
+
+impl InstanceLock {
+    /// Take the lock at `path`, or None if another instance holds it
+    pub fn acquire(path: &Path) -> io::Result<Option<InstanceLock>> {
+        let mut file = open(path)?;
+        if !try_lock(&file)? {
+            return Ok(None);
+        }
+
+        if let Some(pid) = recorded_pid(&mut file).filter(|p| *p != std::process::id() as i32) {
+            info!(
+                "Previous instance (PID {}, now {}) didn't shut down cleanly",
+                pid,
+                process_name(pid)
+            );
+        }
+        file.set_len(0)?;
+        file.rewind()?;
+        writeln!(file, "{}", std::process::id())?;
+
+        Ok(Some(InstanceLock { _file: file }))
+    }
+}
+
+/**
+    Take the instance lock, or exit with a clear message if another
+    instance has it (unless `takeover` is set and we can get it to exit).
+    Failing to create the lock file at all (e.g. no /run) isn't worth
+    refusing to protect the speakers over.
+*/
+pub fn lock_or_exit(path: &Path, takeover: bool) -> Option<InstanceLock> {
+    match InstanceLock::acquire(path) {
+        Ok(Some(lock)) => return Some(lock),
+        Ok(None) => {}
+        Err(e) => {
+            warn!("Failed to take the instance lock {:?}: {}", path, e);
+            return None;
+        }
+    }
+
+    let holder = open(path).ok().and_then(|mut f| recorded_pid(&mut f));
+    let pids = match holder {
+        Some(pid) => vec![pid],
+        None => holders(path),
+    };
+
+    if takeover {
+        let mut lock = None;
+        if take_over(&path.to_string_lossy(), &pids, || {
+            lock = InstanceLock::acquire(path).ok().flatten();
+            lock.is_some()
+        }) {
+            return lock;
+        }
+        eprintln!("Failed to take over from the running instance, giving up");
+        std::process::exit(1);
+    }
+
+    match pids.first() {
+        Some(pid) => eprintln!(
+            "speakersafetyd is already running (PID {}, {}), not starting another instance",
+            pid,
+            process_name(*pid)
+        ),
+        None => eprintln!(
+            "speakersafetyd is already running ({} is locked), not starting another instance",
+            path.display()
+        ),
+    }
+    eprintln!("If it is hung, --takeover stops it and starts over");
+    std::process::exit(1);
+}
@@ -7,31 +7,145 @@
     or a timeout, we panic and let the kernel put the speakers back into a safe
     state.
 */
+use std::any::Any;
 use std::collections::BTreeMap;
 use std::fs;
+use std::os::unix::io::AsRawFd;
 use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use clap_verbosity_flag::{InfoLevel, Verbosity};
 use configparser::ini::Ini;
+use serde::de::{DeserializeOwned, IgnoredAny};
 use log::{debug, info, warn};
 use simple_logger::SimpleLogger;
+use speakersafetyd_core::exit::{self, Failure};
+use speakersafetyd_core::{blackbox, config, history, schema, sense};
 
-mod blackbox;
+mod audit;
+mod broadcast;
+mod caps;
+mod configdiff;
+mod episode;
+mod events;
+mod fit;
+mod generate;
+mod harden;
+mod heartbeat;
 mod helpers;
+mod holdoff;
+mod hooks;
+mod instance;
+mod measure;
+mod monitor;
+mod period;
+mod pipewire;
+mod plot;
+mod reactor;
+#[cfg(test)]
+mod replay;
+mod rmslog;
+#[cfg(test)]
+mod sandbox;
+mod sched;
+mod selftest;
+mod shared;
+mod startup;
+mod stats;
+mod status;
+#[cfg(feature = "telemetry")]
+mod telemetry;
+mod top;
 mod types;
 mod uclamp;
+mod uevent;
+mod varlink;
+mod writer;
 
 const DEFAULT_CONFIG_PATH: &str = "share/speakersafetyd";
 
-const UNLOCK_MAGIC: i32 = 0xdec1be15u32 as i32;
-
 const FLAGFILE: &str = "/run/speakersafetyd.flag";
 
+const SOCKET: &str = "/run/speakersafetyd.sock";
+/// Varlink interface, named after it as is the convention
+const VARLINK_SOCKET: &str = "/run/org.asahilinux.speakersafetyd";
+/// Held by the running instance
+const LOCKFILE: &str = "/run/speakersafetyd.lock";
+/// Profile selected at runtime via the control interface
+const PROFILE_FILE: &str = "/var/lib/speakersafetyd/profile";
+/// Group whose members may request actions via the status socket
+const CONTROL_GROUP: &str = "speakersafetyd";
+/// Exit status asking the service manager to restart us (EX_TEMPFAIL)
+const EXIT_RESTART: i32 = 75;
+/// How long to wait for the card at startup (s)
+const CARD_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long to wait for a card that went away to be gone for good
+const CARD_GONE_TIMEOUT: Duration = Duration::from_secs(2);
+/// Sense data level (fraction of full scale) that means something is playing
+const ACTIVE_LEVEL: u16 = i16::MAX as u16 / 100;
+/// Periods of sense data without a sample rate before we give up
+const NO_RATE_PERIODS: usize = 16;
+/// Silence (s) after which we go idle, if no playback stream is open
+const IDLE_AFTER: f64 = 5.;
+/// Rate to time idle ticks by while there is none
+const IDLE_RATE: f64 = 48000.;
+/// How often to check whether we're on battery
+const POWER_POLL: Duration = Duration::from_secs(10);
+/// Minimum headroom (°C) on every speaker to batch reads on battery
+const BATTERY_HEADROOM: f32 = 15.;
+/// Minimum time between short read warnings
+const SHORT_READ_WARN: Duration = Duration::from_secs(10);
+/// Empty reads in a row after which the stream looks stuck
+const EMPTY_READS_WARN: usize = 100;
+/// Periods without sense data before we carry on without it
+const STALL_PERIODS: usize = 4;
+
+const CMDLINE_PREFIX: &str = "speakersafetyd.";
+const ENV_PREFIX: &str = "SPEAKERSAFETYD_";
+/// Number of recent events in a state dump
+const DUMP_EVENTS: usize = 16;
+
+/// What run_card() works from, the same every time the card is set up
+struct Setup {
+    args: Options,
+    cfg: Ini,
+    globals: types::Globals,
+    ctl_name: String,
+    pcm_name: String,
+    profile: Option<String>,
+    profiles: Vec<String>,
+    config_path: PathBuf,
+    config_text: String,
+    start: Instant,
+    sigquit: Arc<AtomicBool>,
+    sigusr2: Arc<AtomicBool>,
+    uevents: Option<uevent::Uevents>,
+}
+
+/**
+    What outlives the card when it's set up again. The sockets, the hook
+    helper and the logs can't be set up again once we're sandboxed, and
+    the counters and the modes should survive a driver rebind.
+*/
+#[derive(Default)]
+struct Carried {
+    /// The card was set up before
+    started: bool,
+    status: status::Status,
+    status_server: Option<status::StatusServer>,
+    stats: Option<stats::Stats>,
+    hooks: Option<hooks::Hooks>,
+    episodes: Option<blackbox::EpisodeTrigger>,
+    rms_log: Option<rmslog::RmsLog>,
+    hb: heartbeat::Heartbeat,
+    boost_until: Option<Instant>,
+    safe_mode: bool,
+}
+
 /// Simple program to greet a person
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -51,16 +165,557 @@ struct Options {
     /// Maximum gain reduction before panicing (for debugging)
     #[arg(short, long)]
     max_reduction: Option<f32>,
+
+    /// Config profile (loads <model>.<profile>.conf instead of <model>.conf).
+    /// Overrides any profile selected at runtime.
+    #[arg(short, long)]
+    profile: Option<String>,
+
+    /// Path to the usage statistics file
+    #[arg(short, long)]
+    stats_path: Option<PathBuf>,
+
+    /// Machine to load the config for, as <maker>,<model> (e.g. apple,j314),
+    /// instead of identifying it from the device tree or DMI
+    #[arg(long)]
+    machine: Option<String>,
+
+    /// ALSA card to use (e.g. hw:1, plughw:AppleJ314 or a card name/longname)
+    #[arg(short, long)]
+    device: Option<String>,
+
+    /// Drop privileges to this user and enable the seccomp sandbox after setup
+    #[arg(short, long)]
+    user: Option<String>,
+
+    /// Append a log of every control write to this file
+    #[arg(long)]
+    audit_log: Option<PathBuf>,
+
+    /// Log every control write to the journal, as structured entries
+    #[arg(long)]
+    audit_journal: bool,
+
+    /// Append per-period RMS voltage, current and impedance of each speaker
+    /// to this file (or FIFO), as CSV
+    #[arg(long)]
+    rms_log: Option<PathBuf>,
+
+    /// Take over from a hung previous instance, stopping it if need be
+    #[arg(long)]
+    takeover: bool,
+
+    /// Print what this build supports as JSON and exit
+    #[arg(long)]
+    capabilities: bool,
+
+    /// Give up if startup (up to the first sense data) takes longer than
+    /// this (s, 0 for no limit), overriding the config's startup_timeout
+    #[arg(long)]
+    startup_timeout: Option<f64>,
+
+    /// When startup fails the same way as in the previous run, wait this
+    /// long (s, 0 to never wait) before exiting and being restarted
+    #[arg(long, default_value_t = 30.)]
+    holdoff: f64,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Query the status of the running daemon
+    Status {
+        /// Print the raw JSON reply
+        #[arg(long)]
+        json: bool,
+        /// Also print the recent event history
+        #[arg(long)]
+        events: bool,
+    },
+    /// Follow the running daemon's events and telemetry, as JSON lines
+    Follow,
+    /// Show the limits the running daemon holds each speaker to
+    Limits {
+        /// Print the raw JSON reply
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show a live dashboard of the running daemon
+    Top {
+        /// Refresh interval (s)
+        #[arg(long, default_value_t = 0.1)]
+        interval: f64,
+    },
+    /// Estimate each speaker's coil resistance from normal playback, to
+    /// check z_nominal and the sense scales against
+    Measure {
+        /// Sampling interval (s)
+        #[arg(long, default_value_t = 0.5)]
+        interval: f64,
+        /// How often to print the estimates so far (s)
+        #[arg(long, default_value_t = 10.)]
+        report: f64,
+        /// Stop after this long (s, defaults to running until interrupted)
+        #[arg(long)]
+        duration: Option<f64>,
+        /// Only print the final estimates, as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Re-enable a speaker in the running daemon
+    Enable {
+        /// Speaker name, as in the config file
+        speaker: String,
+    },
+    /// Disable a speaker in the running daemon, holding it at min gain
+    Disable {
+        /// Speaker name, as in the config file
+        speaker: String,
+    },
+    /// Switch the running daemon to a config profile and restart it
+    Profile {
+        /// Profile name (omit for the default config)
+        name: Option<String>,
+    },
+    /// Save the running daemon's blackbox now
+    Blackbox,
+    /// Change the running daemon's log level
+    LogLevel {
+        /// off, error, warn, info, debug or trace
+        level: String,
+    },
+    /// Restart the running daemon, picking up config changes
+    Reload,
+    /// Temporarily relax the limiter (e.g. for an alarm), if there is the
+    /// thermal headroom for it
+    Boost {
+        /// Duration (s, up to 60)
+        seconds: f32,
+    },
+    /// Hold the speakers at min gain and leave them to the kernel's limits,
+    /// e.g. before trying out experimental audio software
+    Safe {
+        /// Go back to normal operation
+        #[arg(long)]
+        off: bool,
+    },
+    /// Publish the limiter headroom to PipeWire (run in the user session)
+    PipewireBridge,
+    /// Show how the config in effect differs from the packaged one
+    ConfigDiff {
+        /// Config file to compare (defaults to the one the running daemon
+        /// loaded, or would load)
+        config: Option<PathBuf>,
+        /// Packaged config to compare against (defaults to the machine's)
+        #[arg(long)]
+        packaged: Option<PathBuf>,
+    },
+    /// Plot a blackbox dump to SVG
+    Plot {
+        /// The dump (.bbox, or .fdr/.cvr for old dumps)
+        dump: PathBuf,
+        /// Output file (defaults to the dump name with .svg appended)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Fit the thermal model parameters to a blackbox dump
+    Fit {
+        /// The dump (.bbox, or .fdr/.cvr for old dumps)
+        dump: PathBuf,
+        /// Config file to use instead of the one embedded in the dump
+        #[arg(short, long)]
+        config: Option<PathBuf>,
+        /// Frequency of the pilot tone used to measure the coil resistance (Hz)
+        #[arg(long, default_value_t = 43.)]
+        pilot: f64,
+    },
+    /// Play test tones to check the sense channel mapping and scales
+    /// against the running daemon
+    Selftest {
+        /// Confirm that test tones may be played through the speakers
+        #[arg(long)]
+        confirm: bool,
+        /// PCM to play on (use the raw speaker device to test each amp)
+        #[arg(long, default_value = "default")]
+        pcm: String,
+        /// Number of playback channels (defaults to one per speaker)
+        #[arg(long)]
+        channels: Option<usize>,
+        /// Tone frequency (Hz)
+        #[arg(long, default_value_t = 1000.)]
+        freq: f64,
+        /// Tone level (dBFS, capped at -10)
+        #[arg(long, default_value_t = -30., allow_negative_numbers = true)]
+        level: f64,
+        /// Tone length per channel (s, 1 to 5)
+        #[arg(long, default_value_t = 2.)]
+        duration: f64,
+    },
+    /// Publish speaker temperatures and events to an HTTP endpoint
+    #[cfg(feature = "telemetry")]
+    Telemetry {
+        /// Endpoint to POST the reports to (http://host[:port]/path)
+        url: String,
+        /// Reporting interval (s)
+        #[arg(long, default_value_t = 10.)]
+        interval: f64,
+    },
+    /// Generate a machine config from a layout and the built-in driver database
+    GenerateConfig {
+        /// The machine layout
+        layout: PathBuf,
+        /// Output file (defaults to stdout)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Run the [Hooks] exec program for the daemon's events (see hooks.rs)
+    #[command(hide = true)]
+    HookHelper {
+        /// The program to run
+        exec: String,
+        /// Drop privileges to this user first
+        #[arg(long)]
+        user: Option<String>,
+    },
+}
+
+fn query_daemon<T: DeserializeOwned>(request: &str) -> T {
+    match status::query(Path::new(SOCKET), request) {
+        Ok(Ok(reply)) => reply,
+        Ok(Err(err)) => {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("Failed to query daemon at {}: {}", SOCKET, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Requests that only say whether they worked
+fn send_action(request: &str) {
+    query_daemon::<IgnoredAny>(request);
+}
+
+fn run_status(json: bool, events: bool) {
+    let reply: status::StatusReply = query_daemon("status");
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&reply).unwrap());
+    } else {
+        status::print_status(&reply);
+        if events {
+            status::print_events(&reply);
+        }
+    }
+}
+
+fn run_limits(json: bool) {
+    let reply: status::LimitsReply = query_daemon("limits");
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&reply).unwrap());
+    } else {
+        status::print_limits(&reply);
+    }
+}
+
+fn run_config_diff(machine: Option<&str>, effective: Option<PathBuf>, packaged: Option<PathBuf>) {
+    let packaged = packaged.unwrap_or_else(|| {
+        let (maker, model) = maker_model(machine);
+        default_config_base()
+            .join(maker)
+            .join(format!("{}.conf", model))
+    });
+    let effective = effective
+        .or_else(|| {
+            let reply: status::StatusReply = status::query(Path::new(SOCKET), "status").ok()?.ok()?;
+            Some(PathBuf::from(reply.config_path)).filter(|p| !p.as_os_str().is_empty())
+        })
+        .unwrap_or_else(|| machine_config_path(machine));
+
+    println!("Packaged: {}", packaged.display());
+    println!("In effect: {}", effective.display());
+    for name in ["config_path", "profile", "device"] {
+        if let Some(val) = get_override(name) {
+            println!("Override: {} = {}", name, val);
+        }
+    }
+    println!();
+
+    let load = |path: &Path| {
+        configdiff::load(path).unwrap_or_else(|e| {
+            eprintln!("Failed to load {:?}: {}", path, e);
+            std::process::exit(1);
+        })
+    };
+    configdiff::print(&configdiff::diff(&load(&packaged), &load(&effective)));
+}
+
+/**
+    What this build supports, for distro tooling and the installer to check
+    the kernel, configs and daemon against each other.
+*/
+fn capabilities() -> serde_json::Value {
+    serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "config_schema": {
+            "current": config::SCHEMA,
+            "migrates_from": 1,
+        },
+        "max_nodes": types::MAX_NODES,
+        "model_version": types::MODEL_VERSION,
+        "backends": ["alsa"],
+        "blackbox": {
+            "write": blackbox::VERSION,
+            "read": [1, blackbox::VERSION],
+        },
+        "stats_version": stats::STATS_VERSION,
+        "ipc": {
+            "socket": SOCKET,
+            "varlink": VARLINK_SOCKET,
+            "requests": status::REQUESTS,
+            "status_schema": schema::STATUS,
+            "limits_schema": schema::LIMITS,
+            "pipewire_metadata": pipewire::METADATA_KEY,
+            "pipewire_attenuation": pipewire::ATTENUATION_KEY,
+            "telemetry": cfg!(feature = "telemetry"),
+        },
+    })
+}
+
+/// Look up a setting override from the environment (SPEAKERSAFETYD_<NAME>)
+/// or, failing that, the kernel command line (speakersafetyd.<name>=<value>).
+/// CLI flags take precedence over both and are handled by the caller.
+fn get_override(name: &str) -> Option<String> {
+    let var = ENV_PREFIX.to_owned() + &name.to_ascii_uppercase();
+    if let Ok(val) = std::env::var(&var) {
+        if !val.is_empty() {
+            info!("Using {} from environment: {}", name, val);
+            return Some(val);
+        }
+    }
+
+    let cmdline = fs::read_to_string("/proc/cmdline").ok()?;
+    let key = CMDLINE_PREFIX.to_owned() + name + "=";
+    let val = cmdline
+        .split_whitespace()
+        .filter_map(|a| a.strip_prefix(&key))
+        .next_back()?
+        .to_string();
+    info!("Using {} from kernel cmdline: {}", name, val);
+    Some(val)
+}
+
+/**
+    Make up a machine name from DMI, for systems without a device tree (VMs,
+    x86 boxes). "Apple Inc." / "MacBookPro16,1" becomes apple,macbookpro16_1.
+*/
+fn dmi_machine() -> Option<String> {
+    let read = |name| fs::read_to_string(format!("/sys/class/dmi/id/{}", name)).ok();
+    let vendor = read("sys_vendor")?;
+    let product = read("product_name")?;
+
+    let maker: String = vendor
+        .split_whitespace()
+        .next()?
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    let model: String = product
+        .trim()
+        .chars()
+        .map(|c| match c {
+            c if c.is_ascii_alphanumeric() => c.to_ascii_lowercase(),
+            _ => '_',
+        })
+        .collect();
+
+    (!maker.is_empty() && !model.is_empty()).then(|| format!("{},{}", maker, model))
+}
+
+/**
+    Identify the machine as <maker>,<model>, which picks the config. Given
+    explicitly (--machine or the machine override), it's used as is.
+    Otherwise it comes from the device tree, or DMI if there is none.
+*/
+fn get_machine(machine: Option<&str>) -> String {
+    if let Some(machine) = machine
+        .map(str::to_string)
+        .or_else(|| get_override("machine"))
+    {
+        return machine;
+    }
+
+    match fs::read_to_string("/proc/device-tree/compatible") {
+        Ok(compatible) => compatible
+            .split_once("\0")
+            .expect("Unexpected compatible format")
+            .0
+            .trim_end_matches(|c: char| c.is_ascii_alphabetic())
+            .to_string(),
+        Err(e) => dmi_machine().unwrap_or_else(|| {
+            panic!(
+                "Could not read device tree compatible ({}) or DMI, use --machine",
+                e
+            )
+        }),
+    }
+}
+
+/// The machine's maker and model, e.g. ("apple", "j314")
+fn maker_model(machine: Option<&str>) -> (String, String) {
+    let machine = get_machine(machine);
+    let (maker, model) = machine
+        .split_once(",")
+        .expect("Unexpected machine name format");
+    (maker.to_string(), model.to_string())
+}
+
+/// The config file the daemon loads, given the overrides and profile
+fn machine_config_path(machine: Option<&str>) -> PathBuf {
+    let (maker, model) = maker_model(machine);
+    let base = get_override("config_path")
+        .map(PathBuf::from)
+        .unwrap_or_else(default_config_base)
+        .join(maker);
+    match selected_profile() {
+        Some(profile) => base.join(format!("{}.{}.conf", model, profile)),
+        None => base.join(format!("{}.conf", model)),
+    }
+}
+
+/**
+    The card, control and PCM devices to use. A device given on the command
+    line or via an override replaces the whole [Device] section. Otherwise,
+    the config may name the card and/or the exact ctl and PCM devices to
+    use. The PCM is left to the caller if not given.
+*/
+fn devices(
+    cfg: &Ini,
+    device: Option<String>,
+    maker: &str,
+    model: &str,
+) -> (String, String, Option<String>) {
+    let (device, ctl_name, pcm_name) = match device {
+        Some(device) => (device, None, None),
+        None => {
+            let device = cfg.get("Device", "card").unwrap_or_else(|| {
+                let maker_titlecase = maker[0..1].to_ascii_uppercase() + &maker[1..];
+                format!("hw:{}{}", maker_titlecase, model.to_ascii_uppercase())
+            });
+            (device, cfg.get("Device", "ctl"), cfg.get("Device", "pcm"))
+        }
+    };
+    let device = helpers::resolve_card(&device);
+
+    let ctl_name = ctl_name.unwrap_or_else(|| match device.strip_prefix("plug") {
+        Some(hw) => hw.to_string(),
+        None => device.clone(),
+    });
+    (device, ctl_name, pcm_name)
+}
+
+/**
+    Hold every speaker at min gain straight through the card, for when the
+    daemon isn't running. Without the daemon nobody unlocks the kernel's
+    limits either, so those stay in effect too. The controls are left
+    where we put them until the daemon starts again.
+*/
+fn park_speakers(machine: Option<&str>) {
+    let (maker, model) = maker_model(machine);
+    let config_path = machine_config_path(machine);
+    let cfg = configdiff::load(&config_path).unwrap_or_else(|e| {
+        eprintln!("Failed to load {:?}: {}", config_path, e);
+        std::process::exit(1);
+    });
+    let globals = types::Globals::parse(&cfg);
+    let (_, ctl_name, _) = devices(&cfg, get_override("device"), &maker, &model);
+
+    let ctl = helpers::open_card(&ctl_name);
+    let mut caps = caps::CardCapabilities::default();
+    for name in get_speakers(&cfg) {
+        let mut spk = types::new_speaker(&globals, &name, &cfg, &ctl, false, &mut caps, None);
+        spk.set_parked(true);
+        spk.update(&ctl, 0.);
+        println!("{}: Held at {:.2} dB", name, spk.min_gain_full());
+    }
+}
+
+fn run_safe(machine: Option<&str>, off: bool) {
+    let request = if off { "safe off" } else { "safe" };
+    match status::query::<IgnoredAny>(Path::new(SOCKET), request) {
+        Ok(Err(err)) => {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
+        Ok(Ok(_)) => {
+            if off {
+                println!("Safe mode off");
+            } else {
+                println!("Safe mode on, speakers held at min gain");
+            }
+        }
+        Err(e) if off => {
+            eprintln!("Failed to query daemon at {}: {}", SOCKET, e);
+            eprintln!("The speakers stay at min gain until it starts");
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("No daemon ({}), setting the controls directly", e);
+            park_speakers(machine);
+        }
+    }
+}
+
+/// Where the packaged configs are installed
+fn default_config_base() -> PathBuf {
+    let mut path = PathBuf::new();
+    path.push(option_env!("PREFIX").unwrap_or("/usr/local"));
+    path.push(DEFAULT_CONFIG_PATH);
+    path
 }
 
-fn get_machine() -> String {
-    fs::read_to_string("/proc/device-tree/compatible")
-        .expect("Could not read device tree compatible")
-        .split_once("\0")
-        .expect("Unexpected compatible format")
-        .0
-        .trim_end_matches(|c: char| c.is_ascii_alphabetic())
-        .to_string()
+/// The profile selected via an override or at runtime, if any
+fn selected_profile() -> Option<String> {
+    get_override("profile").or_else(|| {
+        let profile = fs::read_to_string(PROFILE_FILE).ok()?.trim().to_string();
+        (!profile.is_empty()).then_some(profile)
+    })
+}
+
+/// The profiles available for a model, i.e. the <model>.<profile>.conf files
+fn get_profiles(dir: &Path, model: &str) -> Vec<String> {
+    let prefix = model.to_owned() + ".";
+    let mut profiles: Vec<String> = fs::read_dir(dir)
+        .map(|d| {
+            d.filter_map(|e| e.ok()?.file_name().into_string().ok())
+                .filter_map(|n| Some(n.strip_prefix(&prefix)?.strip_suffix(".conf")?.to_string()))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    profiles.sort();
+    profiles
+}
+
+/// Persist the runtime profile selection (None for the default)
+fn save_profile(profile: Option<&str>) {
+    let ret = match profile {
+        Some(profile) => fs::write(PROFILE_FILE, profile),
+        None => match fs::remove_file(PROFILE_FILE) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            r => r,
+        },
+    };
+
+    if let Err(e) = ret {
+        warn!("Failed to save profile: {}", e);
+    }
 }
 
 fn get_speakers(config: &Ini) -> Vec<String> {
@@ -75,6 +730,13 @@ fn get_speakers(config: &Ini) -> Vec<String> {
 struct SpeakerGroup {
     speakers: Vec<types::Speaker>,
     gain: f32,
+    limiting: bool,
+    /// The limiting episode in progress, if any
+    episode: Option<episode::Episode>,
+    /// The group's own unlock control, if the kernel has one
+    unlock: Option<types::Elem>,
+    /// Human readable name, from group_name in the speakers' configs
+    name: Option<String>,
 }
 
 impl Default for SpeakerGroup {
@@ -82,66 +744,422 @@ impl Default for SpeakerGroup {
         Self {
             speakers: Default::default(),
             gain: f32::NAN,
+            limiting: false,
+            episode: None,
+            unlock: None,
+            name: None,
+        }
+    }
+}
+
+impl SpeakerGroup {
+    /// Whether we trust our model of every speaker in the group
+    fn healthy(&self) -> bool {
+        self.speakers.iter().all(|s| s.fault.is_none())
+    }
+
+    /// The group number and name, for messages
+    fn label(&self, idx: usize) -> String {
+        helpers::group_label(idx, self.name.as_deref())
+    }
+}
+
+/// The name of group `idx`, which all of its speakers that give one must agree on
+fn group_name(idx: usize, speakers: &[types::Speaker]) -> Option<String> {
+    let mut named = speakers
+        .iter()
+        .filter_map(|s| Some((&s.name, s.group_name.as_ref()?)));
+    let (first, name) = named.next()?;
+    if let Some((other, _)) = named.find(|(_, n)| *n != name) {
+        panic!(
+            "Speaker/{}/group_name: Conflicts with Speaker/{} for group {}",
+            other, first, idx
+        );
+    }
+    Some(name.clone())
+}
+
+/// The state of every speaker, by group
+fn group_states(groups: &BTreeMap<usize, SpeakerGroup>) -> Vec<Vec<types::SpeakerState>> {
+    groups
+        .values()
+        .map(|g| g.speakers.iter().map(|s| s.s).collect())
+        .collect()
+}
+
+/// Log the state of every group and speaker, and the recent events
+fn dump_state(groups: &BTreeMap<usize, SpeakerGroup>, history: &history::History) {
+    for (idx, group) in groups.iter() {
+        info!(
+            "  Group {}: Gain {:.2} dB{}{}",
+            group.label(*idx),
+            group.gain,
+            if group.limiting { ", limiting" } else { "" },
+            if group.healthy() { "" } else { ", unhealthy" }
+        );
+        for spk in group.speakers.iter() {
+            let s = &spk.s;
+            info!(
+                "    {}: Coil {:.2} °C Magnet {:.2} °C Power {:.3} W Gain {:.2} dB (min {:.2} dB) Headroom {:.1} °C",
+                spk.name, s.t_coil, s.t_magnet, s.power, s.gain, s.min_gain, spk.headroom()
+            );
+            info!(
+                "    {}: Enabled {} Fault {} Amp fault {:#x} Tampered {} times Level mismatches {} Scale mismatches {}",
+                spk.name,
+                spk.enabled,
+                spk.fault.map_or("none".into(), |f| f.to_string()),
+                s.amp_fault,
+                spk.tamper_count,
+                spk.level_mismatches,
+                spk.scale_mismatches
+            );
         }
     }
+    info!("  Recent events:");
+    for (time, event) in history.last(DUMP_EVENTS) {
+        info!("    {} {}", time.to_rfc3339(), event);
+    }
+}
+
+/**
+    Refresh the kernel's permission to go beyond its safe limits. Groups
+    with an unlock control of their own only get it while healthy, so a
+    quarantined speaker falls back to the kernel's protection without
+    taking the rest with it. In `safe_mode`, nothing gets it.
+
+    Runs every period, so it also picks up how the last period's control
+    writes went first.
+*/
+fn heartbeat(
+    writer: &writer::Writer,
+    hb: &mut heartbeat::Heartbeat,
+    unlock: Option<&mut types::Elem>,
+    groups: &mut BTreeMap<usize, SpeakerGroup>,
+    safe_mode: bool,
+    history: &mut history::History,
+) {
+    writer.check();
+    let handle = writer.handle();
+    if let Some(unlock) = unlock {
+        hb.beat(&handle, unlock, safe_mode, history);
+    }
+    for group in groups.values_mut() {
+        let healthy = group.healthy();
+        if let Some(unlock) = group.unlock.as_mut() {
+            hb.beat(&handle, unlock, safe_mode || !healthy, history);
+        }
+    }
+}
+
+/// Have the reactor wake us up once `pcm` has `frames` to read
+fn watch_pcm(reactor: &mut reactor::Reactor, pcm: &alsa::pcm::PCM, frames: usize) {
+    helpers::set_avail_min(pcm, frames);
+    reactor
+        .watch(reactor::Source::Pcm, pcm)
+        .unwrap_or_else(|e| panic!("Could not watch the sense PCM: {}", e));
 }
 
 fn main() {
     let args = Options::parse();
 
+    if args.capabilities {
+        println!("{}", serde_json::to_string_pretty(&capabilities()).unwrap());
+        return;
+    }
+
+    match args.command {
+        Some(Command::Status { json, events }) => return run_status(json, events),
+        Some(Command::Limits { json }) => return run_limits(json),
+        Some(Command::Follow) => {
+            if let Err(e) = status::follow(Path::new(SOCKET)) {
+                eprintln!("Failed to follow daemon at {}: {}", SOCKET, e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Top { interval }) => {
+            let interval = Duration::try_from_secs_f64(interval).unwrap_or_else(|_| {
+                eprintln!("Invalid interval: {}", interval);
+                std::process::exit(1);
+            });
+            top::run(Path::new(SOCKET), interval);
+            return;
+        }
+        Some(Command::Measure {
+            interval,
+            report,
+            duration,
+            json,
+        }) => {
+            let secs = |s: f64| {
+                Duration::try_from_secs_f64(s).unwrap_or_else(|_| {
+                    eprintln!("Invalid duration: {}", s);
+                    std::process::exit(1);
+                })
+            };
+            measure::run(
+                Path::new(SOCKET),
+                secs(interval),
+                secs(report),
+                duration.map(secs),
+                json,
+            );
+            return;
+        }
+        Some(Command::Enable { speaker }) => {
+            send_action(&format!("enable {}", speaker));
+            return;
+        }
+        Some(Command::Disable { speaker }) => {
+            send_action(&format!("disable {}", speaker));
+            return;
+        }
+        Some(Command::Profile { name }) => {
+            match name {
+                Some(name) => send_action(&format!("profile {}", name)),
+                None => send_action("profile"),
+            };
+            return;
+        }
+        Some(Command::Blackbox) => {
+            send_action("blackbox");
+            return;
+        }
+        Some(Command::LogLevel { level }) => {
+            send_action(&format!("loglevel {}", level));
+            return;
+        }
+        Some(Command::Reload) => {
+            send_action("reload");
+            return;
+        }
+        Some(Command::Boost { seconds }) => {
+            send_action(&format!("boost {}", seconds));
+            println!("Boost granted for {} s", seconds);
+            return;
+        }
+        Some(Command::Safe { off }) => return run_safe(args.machine.as_deref(), off),
+        Some(Command::PipewireBridge) => pipewire::run_bridge(Path::new(SOCKET)),
+        Some(Command::Plot { dump, output }) => {
+            let output = output.unwrap_or_else(|| {
+                let mut out = dump.clone().into_os_string();
+                out.push(".svg");
+                out.into()
+            });
+            if let Err(e) = plot::plot(&dump, &output) {
+                eprintln!("Failed to plot {:?}: {}", dump, e);
+                std::process::exit(1);
+            }
+            println!("Wrote {:?}", output);
+            return;
+        }
+        #[cfg(feature = "telemetry")]
+        Some(Command::Telemetry { url, interval }) => {
+            let interval = Duration::from_secs_f64(interval.max(1.));
+            if let Err(e) = telemetry::run_publisher(Path::new(SOCKET), &url, interval) {
+                eprintln!("Failed to publish telemetry to {}: {}", url, e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::ConfigDiff { config, packaged }) => {
+            return run_config_diff(args.machine.as_deref(), config, packaged)
+        }
+        Some(Command::GenerateConfig { layout, output }) => {
+            let config = generate::generate(&layout).unwrap_or_else(|e| {
+                eprintln!("Failed to generate a config from {:?}: {}", layout, e);
+                std::process::exit(1);
+            });
+            match output {
+                Some(output) => {
+                    if let Err(e) = fs::write(&output, config) {
+                        eprintln!("Failed to write {:?}: {}", output, e);
+                        std::process::exit(1);
+                    }
+                }
+                None => print!("{}", config),
+            }
+            return;
+        }
+        Some(Command::HookHelper { exec, user }) => {
+            SimpleLogger::new()
+                .with_level(log::LevelFilter::Info)
+                .without_timestamps()
+                .init()
+                .unwrap();
+            hooks::run_helper(&exec, user.as_deref());
+            return;
+        }
+        Some(Command::Fit {
+            dump,
+            config,
+            pilot,
+        }) => {
+            if let Err(e) = fit::fit(&dump, config.as_deref(), pilot) {
+                eprintln!("Failed to fit {:?}: {}", dump, e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Selftest {
+            confirm,
+            pcm,
+            channels,
+            freq,
+            level,
+            duration,
+        }) => {
+            if !confirm {
+                eprintln!("This plays test tones through the speakers, pass --confirm to proceed");
+                std::process::exit(1);
+            }
+            let tone = selftest::Tone {
+                freq,
+                level,
+                duration,
+            };
+            match selftest::selftest(Path::new(SOCKET), &pcm, channels, &tone) {
+                Ok(true) => return,
+                Ok(false) => std::process::exit(1),
+                Err(e) => {
+                    eprintln!("Self test failed: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => {}
+    }
+
+    // Failures anywhere in the daemon, including config parsing, exit with
+    // the status for their class (see core::exit)
+    let holdoff = Duration::from_secs_f64(args.holdoff.max(0.));
+    if let Err(e) = catch_unwind(AssertUnwindSafe(|| run_daemon(args))) {
+        let message = panic_message(&*e);
+        let failure = exit::class_of(&message);
+        if startup::failed() {
+            holdoff::startup_failed(failure, &message, holdoff);
+        }
+        warn!("Exiting: {} (status {})", failure, failure.code());
+        std::process::exit(failure.code());
+    }
+}
+
+fn panic_message(e: &(dyn Any + Send)) -> String {
+    if let Some(s) = e.downcast_ref::<&'static str>() {
+        (*s).into()
+    } else if let Some(s) = e.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "Unknown panic".into()
+    }
+}
+
+fn run_daemon(args: Options) {
+    let start = Instant::now();
     let sigquit = Arc::new(AtomicBool::new(false));
+    // SIGUSR2 asks for a state dump to the log, handled between periods
+    let sigusr2 = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGUSR2, Arc::clone(&sigusr2)).unwrap();
     signal_hook::flag::register(signal_hook::consts::SIGQUIT, Arc::clone(&sigquit)).unwrap();
+    // Everything the protection loop waits on goes through this, see reactor.rs
+    let mut reactor = reactor::Reactor::new()
+        .unwrap_or_else(|e| panic!("Failed to set up the event loop: {}", e));
+    reactor
+        .catch_signals(&[signal_hook::consts::SIGUSR2, signal_hook::consts::SIGQUIT])
+        .unwrap();
     // signal_hook insists on using SA_RESTART, which we don't want. Override it.
     unsafe {
         let mut act: libc::sigaction = core::mem::zeroed();
         assert!(libc::sigaction(signal_hook::consts::SIGQUIT, core::ptr::null(), &mut act) == 0);
         act.sa_flags &= !libc::SA_RESTART;
-        assert!(
-            libc::sigaction(
-                signal_hook::consts::SIGQUIT,
-                &mut act,
-                core::ptr::null_mut()
-            ) == 0
-        );
+        assert!(libc::sigaction(signal_hook::consts::SIGQUIT, &act, core::ptr::null_mut()) == 0);
     }
 
+    // Let the logger pass everything, so the level can be raised at runtime
     SimpleLogger::new()
-        .with_level(args.verbose.log_level_filter())
+        .with_level(log::LevelFilter::Trace)
         .without_timestamps()
         .init()
         .unwrap();
+    log::set_max_level(args.verbose.log_level_filter());
     info!("Starting up");
 
-    let mut config_path = args.config_path.unwrap_or_else(|| {
-        let mut path = PathBuf::new();
-        path.push(option_env!("PREFIX").unwrap_or("/usr/local"));
-        path.push(DEFAULT_CONFIG_PATH);
-        path
-    });
+    let _instance = instance::lock_or_exit(Path::new(LOCKFILE), args.takeover);
+    audit::init(args.audit_log.as_deref(), args.audit_journal);
+
+    let uevents = uevent::Uevents::new()
+        .map_err(|e| warn!("Failed to listen for uevents: {}", e))
+        .ok();
+    if let Some(u) = uevents.as_ref() {
+        reactor
+            .watch_fd(reactor::Source::Uevent, u.as_raw_fd())
+            .unwrap();
+    }
+
+    let mut config_path = args
+        .config_path
+        .clone()
+        .or_else(|| get_override("config_path").map(PathBuf::from))
+        .unwrap_or_else(default_config_base);
     info!("Config base: {:?}", config_path);
 
-    let machine: String = get_machine();
+    let machine: String = get_machine(args.machine.as_deref());
     info!("Machine: {}", machine);
 
     let (maker, model) = machine
         .split_once(",")
         .expect("Unexpected machine name format");
 
+    let profile = args.profile.clone().or_else(selected_profile);
+
     config_path.push(maker);
-    config_path.push(model);
-    config_path.set_extension("conf");
+    let profiles = get_profiles(&config_path, model);
+    match profile.as_ref() {
+        Some(profile) => {
+            info!("Profile: {}", profile);
+            config_path.push(format!("{}.{}.conf", model, profile));
+        }
+        None => config_path.push(format!("{}.conf", model)),
+    }
     info!("Config file: {:?}", config_path);
 
-    let maker_titlecase = maker[0..1].to_ascii_uppercase() + &maker[1..];
-
-    let device = format!("hw:{}{}", maker_titlecase, model.to_ascii_uppercase());
-    info!("Device: {}", device);
-
+    // Keep the text around, so the blackbox can record exactly what we parsed
+    let config_text = fs::read_to_string(&config_path).unwrap_or_else(|e| {
+        exit::fail(
+            Failure::Config,
+            format!("Failed to read config file: {}", e),
+        )
+    });
     let mut cfg: Ini = Ini::new_cs();
-    cfg.load(config_path).expect("Failed to read config file");
+    cfg.read(config_text.clone()).unwrap_or_else(|e| {
+        exit::fail(
+            Failure::Config,
+            format!("Failed to parse config file: {}", e),
+        )
+    });
+    config::migrate(&mut cfg);
 
     let globals = types::Globals::parse(&cfg);
 
+    let startup_timeout = args
+        .startup_timeout
+        .map_or(globals.startup_timeout as f64, |t| t.max(0.));
+    if startup_timeout > 0. {
+        startup::supervise(start, Duration::from_secs_f64(startup_timeout));
+    }
+
+    let (device, ctl_name, pcm_name) = devices(
+        &cfg,
+        args.device.clone().or_else(|| get_override("device")),
+        maker,
+        model,
+    );
+    info!("Device: {}", device);
+    info!("Control device: {}", ctl_name);
+
+    let pcm_name = pcm_name.unwrap_or_else(|| format!("{},{}", device, globals.visense_pcm));
+    info!("PCM device: {}", pcm_name);
+
     if globals.uclamp_min.is_some() || globals.uclamp_max.is_some() {
         uclamp::set_uclamp(
             globals.uclamp_min.unwrap_or(0).try_into().unwrap(),
@@ -149,243 +1167,1135 @@ fn main() {
         );
     }
 
-    let mut blackbox = args.blackbox_path.map(|p| {
+    let mut blackbox = args.blackbox_path.as_ref().and_then(|p| {
         info!("Enabling blackbox, path: {:?}", p);
-        blackbox::Blackbox::new(&machine, &p, &globals)
+        blackbox::Blackbox::new(&machine, p, &globals, &config_text, &config_path)
+            .map_err(|e| warn!("Failed to open blackbox directory: {}", e))
+            .ok()
     });
 
+    let setup = Setup {
+        args,
+        cfg,
+        globals,
+        ctl_name,
+        pcm_name,
+        profile,
+        profiles,
+        config_path,
+        config_text,
+        start,
+        sigquit,
+        sigusr2,
+        uevents,
+    };
+
+    let mut reactor_ref = AssertUnwindSafe(&mut reactor);
     let mut blackbox_ref = AssertUnwindSafe(&mut blackbox);
+    let mut history = history::History::default();
+    let mut history_ref = AssertUnwindSafe(&mut history);
     let result = catch_unwind(move || {
-        let speaker_names = get_speakers(&cfg);
-        let speaker_count = speaker_names.len();
-        info!("Found {} speakers", speaker_count);
+        let mut carried = Carried::default();
+        loop {
+            let reason = run_card(
+                &setup,
+                &mut carried,
+                &mut reactor_ref,
+                &mut blackbox_ref,
+                &mut history_ref,
+            );
+            // Its handles are closed, and with that gone from the poller
+            reactor_ref.forget(reactor::Source::Pcm);
+            reactor_ref.forget(reactor::Source::Ctl);
+            warn!("{}, reinitializing", reason);
+            history_ref.push(history::Event::Reinitialized { reason });
+            helpers::wait_for_card_gone(&setup.ctl_name, CARD_GONE_TIMEOUT);
+        }
+    });
+    if let Err(e) = result {
+        warn!("Panic!");
 
-        info!("Opening control device");
-        let ctl: alsa::ctl::Ctl = helpers::open_card(&device);
+        let reason = panic_message(&*e);
 
-        let flag_path = Path::new(FLAGFILE);
+        if let Some(bb) = blackbox.as_mut() {
+            bb.preserve(reason, &history, Some(audit::recent()));
+            bb.finish();
+        }
 
-        let cold_boot = match flag_path.try_exists() {
-            Ok(true) => {
-                info!("Startup mode: Warm boot");
-                false
-            }
-            Ok(false) => {
-                info!("Startup mode: Cold boot");
-                if fs::write(flag_path, b"started").is_err() {
-                    warn!("Failed to write flag file, continuing as warm boot");
-                    false
-                } else {
-                    true
-                }
-            }
-            Err(_) => {
-                warn!("Failed to test flag file, continuing as warm boot");
+        resume_unwind(e);
+    }
+}
+
+/**
+    Set up the card and run the protection loop on it, until it goes away
+    or changes under us, e.g. because the driver is being rebound. Our
+    element IDs and handles are stale then, so we drop them all and return
+    why, for the caller to wait for the card to come back and call us
+    again. The kernel holds the speakers safe meanwhile, since the
+    heartbeat stops.
+*/
+fn run_card(
+    setup: &Setup,
+    carried: &mut Carried,
+    reactor: &mut reactor::Reactor,
+    blackbox: &mut Option<blackbox::Blackbox>,
+    history: &mut history::History,
+) -> String {
+    let Setup {
+        args,
+        cfg,
+        globals,
+        ctl_name,
+        pcm_name,
+        profile,
+        profiles,
+        config_path,
+        config_text,
+        start,
+        sigquit,
+        sigusr2,
+        uevents,
+    } = setup;
+    let first = !std::mem::replace(&mut carried.started, true);
+    let Carried {
+        status,
+        status_server,
+        stats,
+        hooks,
+        episodes,
+        rms_log,
+        hb,
+        boost_until,
+        safe_mode,
+        ..
+    } = carried;
+
+    let speaker_names = get_speakers(cfg);
+    let speaker_count = speaker_names.len();
+    info!("Found {} speakers", speaker_count);
+
+    info!("Opening control device");
+    startup::step("opening the card");
+    helpers::wait_for_card(ctl_name, CARD_TIMEOUT);
+    let ctl = helpers::open_card(ctl_name);
+    let writer = writer::Writer::new(ctl_name);
+    if args.takeover && first {
+        instance::take_over_card(&ctl);
+    }
+
+    let flag_path = Path::new(FLAGFILE);
+
+    let cold_boot = match flag_path.try_exists() {
+        // Whatever was playing just now is still in the coils
+        _ if !first => {
+            info!("Startup mode: Warm boot (reinitializing)");
+            false
+        }
+        Ok(true) => {
+            info!("Startup mode: Warm boot");
+            false
+        }
+        Ok(false) => {
+            info!("Startup mode: Cold boot");
+            if fs::write(flag_path, b"started").is_err() {
+                warn!("Failed to write flag file, continuing as warm boot");
                 false
+            } else {
+                true
             }
-        };
+        }
+        Err(_) => {
+            warn!("Failed to test flag file, continuing as warm boot");
+            false
+        }
+    };
 
-        let mut groups: BTreeMap<usize, SpeakerGroup> = BTreeMap::new();
+    startup::step("setting up the controls");
+    let mut groups: BTreeMap<usize, SpeakerGroup> = BTreeMap::new();
+    let mut caps = caps::CardCapabilities::default();
 
-        for i in speaker_names {
-            let speaker: types::Speaker = types::Speaker::new(&globals, &i, &cfg, &ctl, cold_boot);
+    for i in speaker_names {
+        let speaker: types::Speaker = types::new_speaker(
+            globals,
+            &i,
+            cfg,
+            &ctl,
+            cold_boot,
+            &mut caps,
+            Some(writer.handle()),
+        );
 
-            groups
-                .entry(speaker.group)
-                .or_default()
+        groups
+            .entry(speaker.group)
+            .or_default()
+            .speakers
+            .push(speaker);
+    }
+
+    assert!(
+        groups
+            .values()
+            .map(|a| a.speakers.len())
+            .sum::<usize>()
+            == speaker_count
+    );
+    assert!(2 * speaker_count <= globals.channels);
+    caps.log();
+
+    for (idx, group) in groups.iter_mut() {
+        types::resolve_coupling(&mut group.speakers);
+        group.name = group_name(*idx, &group.speakers);
+        if let Some(name) = group.name.as_ref() {
+            info!("Speaker group {}: {}", idx, name);
+        }
+    }
+
+    if let Some(bb) = blackbox.as_mut() {
+        let params = groups
+            .values()
+            .flat_map(|g| g.speakers.iter())
+            .map(|s| s.params())
+            .collect();
+        bb.set_speakers(params);
+    }
+
+    // Subscribe before reading the initial sample rate, so we can't miss a change
+    let ctl_events = events::CtlEvents::new(ctl_name);
+    reactor
+        .watch(reactor::Source::Ctl, &ctl_events)
+        .unwrap_or_else(|e| panic!("{}: Could not watch control events: {}", ctl_name, e));
+
+    let mut sample_rate_elem = types::Elem::new(
+        "Speaker Sample Rate".to_string(),
+        &ctl,
+        alsa::ctl::ElemType::Integer,
+    );
+    let mut sample_rate = sample_rate_elem.read::<i32>(&ctl);
+
+    if sample_rate != 0 {
+        info!("Sample rate: {}", sample_rate);
+        for (_, group) in groups.iter_mut() {
+            group
                 .speakers
-                .push(speaker);
+                .iter_mut()
+                .for_each(|s| s.set_sample_rate(sample_rate as f32));
         }
+    }
 
-        assert!(
-            groups
-                .values()
-                .map(|a| a.speakers.len())
-                .sum::<usize>()
-                == speaker_count
-        );
-        assert!(2 * speaker_count <= globals.channels);
+    // Only pin the PCM rate if we're going to track rate changes
+    let pcm_rate = |rate: i32| if globals.reopen_pcm { rate as u32 } else { 0 };
 
-        let pcm_name = format!("{},{}", device, globals.visense_pcm);
-        // Set up PCM to buffer in V/ISENSE
-        let mut pcm: Option<alsa::pcm::PCM> =
-            Some(helpers::open_pcm(&pcm_name, globals.channels.try_into().unwrap(), 0));
-        let mut io = Some(pcm.as_ref().unwrap().io_i16().unwrap());
+    // Set up PCM to buffer in V/ISENSE
+    startup::step("opening the sense PCM");
+    let mut pcm: Option<alsa::pcm::PCM> = Some(helpers::open_pcm(
+        pcm_name,
+        globals.channels.try_into().unwrap(),
+        pcm_rate(sample_rate),
+    ));
+    let mut io = Some(pcm.as_ref().unwrap().io_i16().unwrap());
+    watch_pcm(reactor, pcm.as_ref().unwrap(), globals.period);
 
-        let mut sample_rate_elem = types::Elem::new(
-            "Speaker Sample Rate".to_string(),
-            &ctl,
-            alsa::ctl::ElemType::Integer,
-        );
-        let mut sample_rate = sample_rate_elem.read_int(&ctl);
+    // What is being played, only ever recorded into the blackbox
+    let mut monitor = globals
+        .monitor_pcm
+        .as_ref()
+        .filter(|_| blackbox.is_some())
+        .map(|dev| {
+            monitor::Monitor::new(
+                dev,
+                globals.monitor_channels,
+                globals.period * globals.battery_batch,
+                sample_rate,
+            )
+        });
 
-        let mut unlock_elem = types::Elem::new(
-            "Speaker Volume Unlock".to_string(),
-            &ctl,
+    // The writer thread refreshes the unlock controls, so they're locked on its handle
+    let writer_handle = writer.handle();
+    let writer_card = writer_handle.card();
+    for (idx, group) in groups.iter_mut() {
+        group.unlock = globals.ctl_group_unlock.get(idx).map(|name| {
+            info!("Speaker group {} unlock control: {}", idx, name);
+            types::Elem::new(name.clone(), &writer_card, alsa::ctl::ElemType::Integer)
+        });
+    }
+    // Only needed if some group doesn't have its own
+    let mut unlock_elem = (groups.values().any(|g| g.unlock.is_none())
+        || types::Elem::exists(&globals.ctl_unlock, &ctl))
+    .then(|| {
+        types::Elem::new(
+            globals.ctl_unlock.clone(),
+            &writer_card,
             alsa::ctl::ElemType::Integer,
-        );
+        )
+    });
+    drop(writer_card);
 
-        unlock_elem.write_int(&ctl, UNLOCK_MAGIC);
+    heartbeat(
+        &writer,
+        hb,
+        unlock_elem.as_mut(),
+        &mut groups,
+        *safe_mode,
+        history,
+    );
 
-        for (_idx, group) in groups.iter_mut() {
-            if cold_boot {
-                // Preset the gains to no reduction on cold boot
-                group.speakers.iter_mut().for_each(|s| s.update(&ctl, 0.0));
-                group.gain = 0.0;
-            } else {
-                // Leave the gains at whatever the kernel limit is, use anything
-                // random for group.gain so the gains will update on the first cycle.
-                group.gain = -999.0;
+    for (_idx, group) in groups.iter_mut() {
+        if cold_boot {
+            // Preset the gains to no reduction on cold boot
+            group.speakers.iter_mut().for_each(|s| s.update(&ctl, 0.0));
+            group.gain = 0.0;
+        } else {
+            // Leave the gains at whatever the kernel limit is, use anything
+            // random for group.gain so the gains will update on the first cycle.
+            group.gain = -999.0;
+        }
+        // A boost and safe mode outlast a reinitialization
+        group.speakers.iter_mut().for_each(|s| {
+            s.set_boost(boost_until.is_some());
+            s.set_parked(*safe_mode);
+        });
+    }
+
+    // The counters carry over, the rest describes the card as it is now
+    *status = status::Status {
+        profile: profile.clone(),
+        profiles: profiles.clone(),
+        sample_rate,
+        groups: groups
+            .iter()
+            .map(|(&group, g)| status::GroupStatus {
+                group,
+                name: g.name.clone(),
+                ..Default::default()
+            })
+            .collect(),
+        speakers: groups
+            .values()
+            .flat_map(|g| g.speakers.iter())
+            .map(|s| status::SpeakerStatus {
+                name: s.name.clone(),
+                group: s.group,
+                enabled: s.enabled,
+                fault: s.fault,
+                tamper_count: s.tamper_count,
+                level_mismatches: s.level_mismatches,
+                scale_mismatches: s.scale_mismatches,
+                state: s.s,
+                headroom: s.headroom(),
+                time_to_limit: s.time_to_limit(),
+                margins: s.margins(),
+                min_gain_full: s.min_gain_full(),
+                max_power: s.max_power(),
+                peak_power: s.peak_power(),
+                z_nominal: s.z_nominal(),
+                params: Some(s.params()),
+            })
+            .collect(),
+        config_path: config_path.to_string_lossy().to_string(),
+        config_hash: helpers::config_hash(config_text),
+        card: Some(caps.clone()),
+        short_reads: status.short_reads,
+        empty_reads: status.empty_reads,
+        timer_anomalies: status.timer_anomalies,
+        ..Default::default()
+    };
+
+    // None of these can be set up again once we're sandboxed, see harden.rs
+    if first {
+        *status_server =
+            status::StatusServer::new(Path::new(SOCKET), status, CONTROL_GROUP, reactor.waker())
+                .map_err(|e| warn!("Failed to start status server: {}", e))
+                .ok();
+        if let Some(server) = status_server.as_ref() {
+            if let Err(e) = server.serve_varlink(Path::new(VARLINK_SOCKET)) {
+                warn!("Failed to start varlink server: {}", e);
             }
         }
 
-        let mut last_update = Instant::now();
+        *stats = args.stats_path.as_ref().map(|p| stats::Stats::load(p));
+        *hooks = hooks::Hooks::new(cfg, globals, args.user.as_deref());
+        *episodes = blackbox::EpisodeTrigger::new(globals);
+        *rms_log = args.rms_log.as_deref().and_then(rmslog::RmsLog::new);
+    }
 
-        let mut buf = Vec::new();
-        buf.resize(globals.period * globals.channels, 0i16);
+    let mut last_update = Instant::now();
+    // Whether the PCM was (re)started since the last read, see below
+    let mut reopened = false;
 
-        let mut once_nominal = false;
+    let mut buf = vec![0i16; globals.period * globals.battery_batch * globals.channels];
+    // Periods per read, more than one only on battery while cool
+    let mut batch = 1;
+    let mut on_battery = false;
+    let mut power_checked: Option<Instant> = None;
 
-        loop {
-            if sigquit.load(Ordering::Relaxed) {
-                panic!("SIGQUIT received");
+    let mut once_nominal = false;
+
+    let started = Instant::now();
+
+    let mut short_read_warn = helpers::WarnLimit::new(SHORT_READ_WARN);
+    // Empty reads in a row
+    let mut empty_reads = 0;
+
+    let mut waiting_for_rate = false;
+    let mut no_rate_periods = 0;
+
+    let card_index = ctl.card_info().ok().map(|c| c.get_card().get_index());
+    let mut idle = false;
+    let mut silence = period::Silence::default();
+
+    let mut mapping_check = (globals.mapping_check != types::MappingPolicy::Off).then(|| {
+        let pairs: Vec<_> = groups
+            .values()
+            .flat_map(|g| g.speakers.iter())
+            .map(|s| {
+                let (vs_chan, is_chan) = s.sense_chans();
+                (s.name.clone(), vs_chan, is_chan)
+            })
+            .collect();
+        sense::MappingCheck::new(globals.channels, &pairs)
+    });
+
+    /*
+     * Do this last, so helper threads spawned during setup don't inherit
+     * the real-time policy. Those of a reinitialization do, but they only
+     * wait on the protection loop anyway.
+     */
+    if first {
+        if let Some(cpus) = globals.cpu_affinity.as_ref() {
+            sched::set_affinity(cpus);
+        }
+        if let Some(prio) = globals.sched_fifo {
+            sched::set_fifo(prio);
+        }
+
+        // Everything is open and allocated by now
+        harden::set_cloexec_all();
+        harden::lock_memory();
+
+        if let Some(user) = args.user.as_ref() {
+            harden::drop_privileges(user);
+            harden::set_no_new_privs();
+            harden::install_seccomp();
+        }
+    }
+
+    // When we last got to a period, with sense data or without
+    let mut last_tick = Instant::now();
+
+    startup::step("waiting for sense data");
+    loop {
+        /*
+         * The PCM paces us, but while idle we only tick along at the
+         * period rate to keep the heartbeat and the model going, with no
+         * sense data to read. Nor do we wait on a PCM that has nothing
+         * for a few periods, the controls and the heartbeat still need
+         * looking after. Anything else wakes us up in between.
+         */
+        let rate = if sample_rate > 0 {
+            sample_rate as f64
+        } else {
+            IDLE_RATE
+        };
+        let periods = if idle { 1 } else { STALL_PERIODS * batch };
+        let deadline =
+            last_tick + Duration::from_secs_f64((globals.period * periods) as f64 / rate);
+        let ready = reactor.wait(Some(deadline));
+
+        if sigquit.load(Ordering::Relaxed) {
+            panic!("SIGQUIT received");
+        }
+        if sigusr2.swap(false, Ordering::Relaxed) {
+            info!("State dump (SIGUSR2):");
+            info!("  Uptime: {:.0} s", started.elapsed().as_secs_f64());
+            info!(
+                "  Sample rate: {} Hz{}{}",
+                sample_rate,
+                if idle { ", idle" } else { "" },
+                if on_battery { ", on battery" } else { "" }
+            );
+            info!(
+                "  Period: {} samples, {} per read, last update {:.3} s ago",
+                globals.period,
+                batch,
+                last_update.elapsed().as_secs_f64()
+            );
+            info!(
+                "  Short reads: {} ({} empty)",
+                status.short_reads, status.empty_reads
+            );
+            if status.timer_anomalies > 0 {
+                info!("  Timer anomalies: {}", status.timer_anomalies);
+            }
+            if hb.counts != Default::default() {
+                info!(
+                    "  Heartbeat interruptions: {} failed, {} withheld",
+                    hb.counts.failed, hb.counts.withheld
+                );
             }
-            // Block while we're reading into the buffer
-            let read = io.as_ref().unwrap().readi(&mut buf);
+            if let Some(until) = *boost_until {
+                info!(
+                    "  Boost: {:.0} s left",
+                    until
+                        .saturating_duration_since(Instant::now())
+                        .as_secs_f32()
+                );
+            }
+            if *safe_mode {
+                info!("  Safe mode: on");
+            }
+            dump_state(&groups, history);
+        }
+        if let Some(uevents) = uevents.as_ref().filter(|_| ready.has(reactor::Source::Uevent)) {
+            for ev in uevents.read() {
+                debug!("Uevent: {} {}", ev.action, ev.devpath);
+                if card_index.is_some_and(|c| ev.card_removed(c)) {
+                    return "Card removed".into();
+                }
+            }
+        }
 
-            #[allow(unused_mut)]
+        let mut cur_sample_rate = sample_rate;
+        for ev in ctl_events.read() {
+            match ev {
+                events::CtlEvent::Value(name) if name == sample_rate_elem.name() => {
+                    cur_sample_rate = sample_rate_elem.read::<i32>(&ctl);
+                }
+                events::CtlEvent::Value(name) => {
+                    debug!("Control changed: {}", name);
+                    // This includes our own writes, which verify fine
+                    groups
+                        .values_mut()
+                        .flat_map(|g| g.speakers.iter_mut())
+                        .filter(|s| s.owns_control(&name))
+                        .for_each(|s| {
+                            let count = s.tamper_count;
+                            s.check_tamper(&ctl);
+                            if s.tamper_count != count {
+                                history.push(history::Event::ControlTampered {
+                                    speaker: s.name.clone(),
+                                });
+                            }
+                        });
+                }
+                events::CtlEvent::Removed(name) => {
+                    if name == sample_rate_elem.name()
+                        || unlock_elem.as_ref().is_some_and(|e| name == e.name())
+                        || groups
+                            .values()
+                            .any(|g| g.unlock.as_ref().is_some_and(|e| name == e.name()))
+                        || groups
+                            .values()
+                            .flat_map(|g| g.speakers.iter())
+                            .any(|s| s.owns_control(&name))
+                    {
+                        return format!("Control removed: {}", name);
+                    }
+                    warn!("Unrelated control removed: {}", name);
+                }
+                events::CtlEvent::Disconnected => {
+                    return "Card disconnected".into();
+                }
+            }
+        }
+
+        if cur_sample_rate != 0 && cur_sample_rate != sample_rate {
+            info!("Sample rate: {} -> {}", sample_rate, cur_sample_rate);
+            history.push(history::Event::SampleRateChange {
+                from: sample_rate,
+                to: cur_sample_rate,
+            });
+            sample_rate = cur_sample_rate;
+            for (_, group) in groups.iter_mut() {
+                group
+                    .speakers
+                    .iter_mut()
+                    .for_each(|s| s.set_sample_rate(sample_rate as f32));
+            }
+            if let Some(bb) = blackbox.as_mut() {
+                bb.reset()
+            }
+            if let Some(m) = monitor.as_mut() {
+                m.reopen(sample_rate);
+            }
             #[allow(unused_assignments)]
-            let read = match read {
-                Ok(a) => Ok(a),
-                Err(e) => {
-                    if sigquit.load(Ordering::Relaxed) {
-                        panic!("SIGQUIT received");
+            if globals.reopen_pcm && !idle {
+                /*
+                 * Any time lost while reopening is accounted for by the
+                 * wall clock on the next read, so the model stays
+                 * continuous.
+                 */
+                info!("Reopening PCM at {} Hz", sample_rate);
+                reactor.unwatch(reactor::Source::Pcm);
+                io = None;
+                pcm = None;
+                pcm = Some(helpers::open_pcm(
+                    pcm_name,
+                    globals.channels.try_into().unwrap(),
+                    pcm_rate(sample_rate),
+                ));
+                io = Some(pcm.as_ref().unwrap().io_i16().unwrap());
+                watch_pcm(reactor, pcm.as_ref().unwrap(), globals.period * batch);
+                reopened = true;
+            }
+        }
+
+        if let Some(server) = status_server.as_ref() {
+            for action in server.actions() {
+                let (name, enable) = match action {
+                    status::Action::Enable(name) => (name, true),
+                    status::Action::Disable(name) => (name, false),
+                    status::Action::TriggerBlackbox => {
+                        if let Some(bb) = blackbox.as_mut() {
+                            bb.preserve("Requested by client".into(), history, None);
+                        }
+                        continue;
+                    }
+                    status::Action::SetLogLevel(level) => {
+                        log::set_max_level(level);
+                        info!("Log level set to {}", level);
+                        continue;
                     }
-                    if e.errno() == libc::ESTRPIPE {
-                        warn!("Suspend detected!");
-                        /*
-                        // Resume handling
-                        loop {
-                            match pcm.resume() {
-                                Ok(_) => break Ok(0),
-                                Err(e) if e.errno() == Errno::EAGAIN => continue,
-                                Err(e) => break Err(e),
+                    status::Action::SetProfile(profile) => {
+                        save_profile(profile.as_deref());
+                        if let Some(stats) = stats.as_ref() {
+                            let _ = stats.save();
+                        }
+                        info!("Restarting with profile {:?}", profile);
+                        std::process::exit(EXIT_RESTART);
+                    }
+                    status::Action::Boost(seconds, reply) => {
+                        let lacking = groups
+                            .values()
+                            .flat_map(|g| g.speakers.iter())
+                            .find(|s| !s.boost_allowed(seconds));
+                        let ret = if *safe_mode {
+                            Err("Safe mode is on".to_string())
+                        } else if boost_until.is_some() {
+                            Err("A boost is already in effect".to_string())
+                        } else if let Some(s) = lacking {
+                            Err(format!("{}: Not enough thermal headroom", s.name))
+                        } else {
+                            info!("Boosting for {:.0} s", seconds);
+                            history.push(history::Event::BoostStarted { seconds });
+                            *boost_until = Some(Instant::now() + Duration::from_secs_f32(seconds));
+                            groups
+                                .values_mut()
+                                .flat_map(|g| g.speakers.iter_mut())
+                                .for_each(|s| s.set_boost(true));
+                            Ok(())
+                        };
+                        if let Err(e) = ret.as_ref() {
+                            info!("Boost denied: {}", e);
+                        }
+                        let _ = reply.send(ret);
+                        continue;
+                    }
+                    status::Action::Reload => {
+                        if let Some(stats) = stats.as_ref() {
+                            let _ = stats.save();
+                        }
+                        info!("Restarting to reload config");
+                        std::process::exit(EXIT_RESTART);
+                    }
+                    status::Action::SafeMode(on) => {
+                        if on != *safe_mode {
+                            if on {
+                                warn!("Safe mode on, holding all speakers at min gain");
+                            } else {
+                                info!("Safe mode off");
+                            }
+                            history.push(history::Event::SafeMode { on });
+                            *safe_mode = on;
+                            for group in groups.values_mut() {
+                                group.speakers.iter_mut().for_each(|s| s.set_parked(on));
+                                // Force the group gains to be rewritten
+                                group.gain = f32::NAN;
                             }
                         }
-                        .unwrap();
-                        warn!("Resume successful");
-                        */
-                        // Work around kernel issue: resume sometimes breaks visense
-                        warn!("Reinitializing PCM to work around kernel bug...");
-                        io = None;
-                        pcm = None;
-                        pcm = Some(helpers::open_pcm(&pcm_name, globals.channels.try_into().unwrap(), 0));
-                        io = Some(pcm.as_ref().unwrap().io_i16().unwrap());
                         continue;
                     }
-                    Err(e)
+                };
+                for (_, group) in groups.iter_mut() {
+                    if let Some(spk) = group.speakers.iter_mut().find(|s| s.name == name) {
+                        spk.set_enabled(enable);
+                        // Force the group gains to be rewritten
+                        group.gain = f32::NAN;
+                    }
                 }
             }
-            .unwrap();
+        }
+
+        let now = Instant::now();
+        let due = now >= deadline;
+        // Frames captured that we never got to read, None if unknown
+        let mut lost = None;
+        #[allow(unused_assignments)]
+        let read = if idle {
+            if !due {
+                continue;
+            }
+            // Anyone opening a playback stream wakes us up
+            if card_index.is_none_or(helpers::playback_open) {
+                info!("Playback opened, leaving idle");
+                idle = false;
+                io = None;
+                pcm = Some(helpers::open_pcm(
+                    pcm_name,
+                    globals.channels.try_into().unwrap(),
+                    pcm_rate(sample_rate),
+                ));
+                io = Some(pcm.as_ref().unwrap().io_i16().unwrap());
+                watch_pcm(reactor, pcm.as_ref().unwrap(), globals.period * batch);
+                reopened = true;
+                last_tick = now;
+                continue;
+            }
+            Ok(0)
+        } else if !ready.has(reactor::Source::Pcm) && !due {
+            continue;
+        } else {
+            let frames = globals.period * batch;
+            match pcm.as_ref().unwrap().avail_update() {
+                // Woken up early, it polls readable on errors too
+                Ok(avail) if (avail as usize) < frames && !due => continue,
+                // Stalled, see above
+                Ok(avail) if (avail as usize) < frames => Ok(0),
+                Err(e) => Err(e),
+                Ok(_) => {
+                    /*
+                     * Across a (re)start of the stream, there's no telling
+                     * how many frames went by from the PCM alone.
+                     */
+                    if !std::mem::take(&mut reopened) {
+                        lost = Some(helpers::lost_frames(pcm.as_ref().unwrap()));
+                    }
+                    // There's a read's worth, unless we just skipped what was lost
+                    let read = io
+                        .as_ref()
+                        .unwrap()
+                        .readi(&mut buf[..frames * globals.channels]);
+                    if let Some(bb) = blackbox.as_mut() {
+                        bb.record_read(match &read {
+                            Ok(n) => *n as i64,
+                            Err(e) => -(e.errno() as i64),
+                        });
+                    }
+                    read
+                }
+            }
+        };
+        last_tick = now;
 
-            if read != globals.period {
-                warn!("Expected {} samples, got {}", globals.period, read);
+        #[allow(unused_mut)]
+        #[allow(unused_assignments)]
+        let read = match read {
+            Ok(a) => Ok(a),
+            Err(e) => {
+                if sigquit.load(Ordering::Relaxed) {
+                    panic!("SIGQUIT received");
+                }
+                if e.errno() == libc::ENODEV {
+                    return "PCM device gone".into();
+                }
+                if e.errno() == libc::EPIPE {
+                    // Only if the stop threshold didn't take, see open_pcm
+                    warn!("Sense PCM overrun, restarting it");
+                    pcm.as_ref().unwrap().prepare().unwrap();
+                    pcm.as_ref().unwrap().start().unwrap();
+                    reopened = true;
+                    continue;
+                }
+                if e.errno() == libc::ESTRPIPE {
+                    warn!("Suspend detected!");
+                    history.push(history::Event::Suspend);
+                    /*
+                    // Resume handling
+                    loop {
+                        match pcm.resume() {
+                            Ok(_) => break Ok(0),
+                            Err(e) if e.errno() == Errno::EAGAIN => continue,
+                            Err(e) => break Err(e),
+                        }
+                    }
+                    .unwrap();
+                    warn!("Resume successful");
+                    */
+                    // Work around kernel issue: resume sometimes breaks visense
+                    warn!("Reinitializing PCM to work around kernel bug...");
+                    reactor.unwatch(reactor::Source::Pcm);
+                    io = None;
+                    pcm = None;
+                    pcm = Some(helpers::open_pcm(
+                        pcm_name,
+                        globals.channels.try_into().unwrap(),
+                        pcm_rate(sample_rate),
+                    ));
+                    io = Some(pcm.as_ref().unwrap().io_i16().unwrap());
+                    watch_pcm(reactor, pcm.as_ref().unwrap(), globals.period * batch);
+                    reopened = true;
+                    continue;
+                }
+                Err(e)
             }
+        }
+        .unwrap();
 
-            if sigquit.load(Ordering::Relaxed) {
-                panic!("SIGQUIT received");
+        let expected = globals.period * batch;
+        if !idle && read != expected {
+            if let Some(held) = short_read_warn.check(Instant::now()) {
+                warn!(
+                    "Expected {} samples, got {} ({} more short reads not logged)",
+                    expected, read, held
+                );
             }
+            status.short_reads += 1;
+            history.push(history::Event::ShortRead {
+                expected,
+                got: read,
+            });
+        }
+
+        if sigquit.load(Ordering::Relaxed) {
+            panic!("SIGQUIT received");
+        }
 
-            let buf_read = &buf[0..read * globals.channels];
+        if read > 0 || idle {
+            startup::done(*start);
+        }
+        if read != 0 || idle {
+            empty_reads = 0;
+        }
 
-            let cur_sample_rate = sample_rate_elem.read_int(&ctl);
+        let buf_read = &buf[0..read * globals.channels];
 
-            if cur_sample_rate != 0 && cur_sample_rate != sample_rate {
-                sample_rate = cur_sample_rate;
-                info!("Sample rate: {}", sample_rate);
-                if let Some(bb) = blackbox_ref.as_mut() { bb.reset() }
+        if read == 0 && !idle {
+            /*
+             * Nothing to integrate, the time is caught up next period.
+             * The controls and the heartbeat still need looking after,
+             * or a stream stuck returning nothing would leave the
+             * kernel to trip its failsafe without a word from us.
+             */
+            status.empty_reads += 1;
+            empty_reads += 1;
+            if empty_reads == EMPTY_READS_WARN {
+                warn!(
+                    "Sense PCM returned no data {} times in a row",
+                    empty_reads
+                );
             }
+            heartbeat(
+                &writer,
+                hb,
+                unlock_elem.as_mut(),
+                &mut groups,
+                *safe_mode,
+                history,
+            );
+            continue;
+        }
 
-            if sample_rate == 0 {
-                panic!("Invalid sample rate");
+        if sample_rate == 0 {
+            /*
+             * No stream has configured the DSP path yet, so nothing can
+             * be playing. Keep the heartbeat going and leave the model
+             * alone, the skip logic catches it up once a rate shows up.
+             * Sense data without a rate is another matter though.
+             */
+            if period::active(buf_read) {
+                no_rate_periods += 1;
+                if no_rate_periods > NO_RATE_PERIODS {
+                    exit::fail(
+                        Failure::SenseInvalid,
+                        "Sense data without a sample rate".into(),
+                    );
+                }
+            } else {
+                no_rate_periods = 0;
+            }
+            if !waiting_for_rate {
+                info!("No sample rate yet, waiting for playback");
+                waiting_for_rate = true;
             }
+            heartbeat(
+                &writer,
+                hb,
+                unlock_elem.as_mut(),
+                &mut groups,
+                *safe_mode,
+                history,
+            );
+            continue;
+        }
+        waiting_for_rate = false;
 
-            let now = Instant::now();
-            let dt = (now - last_update).as_secs_f64();
-            assert!(dt > 0f64);
+        let now = Instant::now();
+        let elapsed = (now - last_update).as_secs_f64();
+        let (dt, anomaly) = helpers::clamp_dt(elapsed);
+        if anomaly {
+            warn!(
+                "Timer anomaly: {} s since the last update, using {} s",
+                elapsed, dt
+            );
+            status.timer_anomalies += 1;
+            history.push(history::Event::TimerAnomaly { dt: elapsed });
+        }
 
-            let pt = globals.period as f64 / sample_rate as f64;
-            /* If we skipped at least 4 periods, run catchup for that minus one */
-            if dt > (4f64 * pt) {
-                let skip = dt - pt;
-                debug!("Skipping {:.2} seconds", skip);
-                for (_, group) in groups.iter_mut() {
-                    group.speakers.iter_mut().for_each(|s| s.skip_model(skip));
+        // Account for the frames actually read, not the nominal period
+        let pt = read as f64 / sample_rate as f64;
+        if let Some(skip) = period::catch_up(idle, lost, dt, read, expected, sample_rate) {
+            for (_, group) in groups.iter_mut() {
+                group.speakers.iter_mut().for_each(|s| s.skip_model(skip));
+            }
+            if let Some(bb) = blackbox.as_mut().filter(|_| !idle) {
+                bb.reset()
+            }
+        }
+
+        last_update = now;
+
+        // Recorded before the model runs, so it's there if the model panics
+        if let Some(bb) = blackbox.as_mut().filter(|_| !idle) {
+            let monitored = match monitor.as_mut() {
+                Some(m) => m.read(read),
+                None => &[],
+            };
+            bb.push(sample_rate, buf_read, monitored, group_states(&groups));
+        }
+
+        if let Some(check) = mapping_check.as_mut() {
+            for mismatch in check.update(buf_read) {
+                if globals.mapping_check == types::MappingPolicy::Panic {
+                    exit::fail(
+                        Failure::SenseInvalid,
+                        format!("Sense channel mapping mismatch: {}", mismatch),
+                    );
                 }
-                if let Some(bb) = blackbox_ref.as_mut() { bb.reset() }
+                warn!("!!! Sense channel mapping mismatch !!!");
+                warn!("!!! {}", mismatch);
+                warn!("!!! The config is likely wrong, this speaker may not be protected !!!");
+                history.push(history::Event::MappingMismatch {
+                    speaker: mismatch.speaker,
+                });
+            }
+            if check.done() {
+                info!("Sense channel mapping check complete");
+                mapping_check = None;
             }
+        }
 
-            last_update = now;
+        if boost_until.is_some_and(|until| now >= until) {
+            info!("Boost over");
+            history.push(history::Event::BoostEnded);
+            *boost_until = None;
+            groups
+                .values_mut()
+                .flat_map(|g| g.speakers.iter_mut())
+                .for_each(|s| s.set_boost(false));
+        }
 
-            if let Some(bb) = blackbox_ref.as_mut() {
-                let max_idx = *groups.iter().map(|g| g.0).max().unwrap();
-                let gstates = (0..=max_idx)
-                    .map(|i| groups[&i].speakers.iter().map(|s| s.s).collect())
-                    .collect();
-                bb.push(sample_rate, buf_read.to_vec(), gstates);
+        for (_, group) in groups.iter_mut() {
+            let mut changed = false;
+            for spk in group.speakers.iter_mut() {
+                // Picked up by the model on this period's run
+                spk.track_volume(&ctl);
+                spk.check_level(&ctl);
+                if spk.check_amp_fault(&ctl) {
+                    changed = true;
+                    history.push(history::Event::AmpFault {
+                        speaker: spk.name.clone(),
+                        fault: spk.s.amp_fault,
+                    });
+                }
+            }
+            if changed && globals.fault_min_gain {
+                // Force the group gains to be rewritten
+                group.gain = f32::NAN;
             }
+        }
 
-            let mut all_nominal = true;
-            for (idx, group) in groups.iter_mut() {
-                let gain = group
-                    .speakers
-                    .iter_mut()
-                    .map(|s| s.run_model(buf_read, sample_rate as f32))
-                    .reduce(f32::min)
-                    .unwrap();
-                if gain != group.gain {
-                    if gain == 0. {
-                        info!("Speaker group {} gain nominal", idx);
-                    } else {
-                        info!("Speaker group {} gain limited to {:.2} dBFS", idx, gain);
-                    }
-                    group.speakers.iter_mut().for_each(|s| s.update(&ctl, gain));
-                    group.gain = gain;
+        let mut all_nominal = true;
+        for (idx, group) in groups.iter_mut() {
+            let (gain, quarantined) = period::group_gain(&mut group.speakers, buf_read, history);
+            if quarantined {
+                // Force the group gains to be rewritten
+                group.gain = f32::NAN;
+                if group.unlock.is_some() {
+                    warn!(
+                        "Speaker group {} left to the kernel's protection",
+                        group.label(*idx)
+                    );
                 }
-                if gain != 0. {
-                    all_nominal = false;
+            }
+            if let Some(ep) = group.episode.as_mut() {
+                ep.update(gain, &group.speakers);
+            }
+            if gain != group.gain {
+                // Episodes are summed up in the journal, see episode.rs
+                if gain == 0. {
+                    debug!("Speaker group {} gain nominal", group.label(*idx));
+                } else {
+                    debug!(
+                        "Speaker group {} gain limited to {:.2} dBFS",
+                        group.label(*idx),
+                        gain
+                    );
                 }
-                if let Some(max_reduction) = args.max_reduction {
-                    if once_nominal && gain < -max_reduction {
-                        panic!("Gain reduction exceeded threshold");
+                group.speakers.iter_mut().for_each(|s| s.update(&ctl, gain));
+                group.gain = gain;
+
+                let name = group.name.clone();
+                if gain < 0. && !group.limiting {
+                    let ep = episode::Episode::new(now, gain, &group.speakers);
+                    info!(
+                        "Speaker group {} limiting, started by {}",
+                        group.label(*idx),
+                        ep.trigger()
+                    );
+                    group.episode = Some(ep);
+                    history.push(history::Event::LimiterEngaged {
+                        group: *idx,
+                        name,
+                        gain,
+                    });
+                } else if gain >= 0. && group.limiting {
+                    if let Some(ep) = group.episode.take() {
+                        info!("{}", ep.summary(&group.label(*idx), now));
                     }
+                    history.push(history::Event::LimiterReleased { group: *idx, name });
+                }
+                group.limiting = gain < 0.;
+            }
+            if gain != 0. {
+                all_nominal = false;
+            }
+            if let (Some(trigger), Some(bb)) = (episodes.as_mut(), blackbox.as_mut()) {
+                if let Some(reason) = trigger.update(&group.label(*idx), gain, now) {
+                    bb.preserve(reason, history, None);
                 }
             }
+            if let Some(max_reduction) = args.max_reduction {
+                if once_nominal && gain < -max_reduction {
+                    exit::fail(
+                        Failure::Overtemperature,
+                        "Gain reduction exceeded threshold".into(),
+                    );
+                }
+            }
+        }
 
-            if all_nominal {
-                once_nominal = true;
+        if all_nominal {
+            once_nominal = true;
+        }
+
+        if let Some(h) = hooks.as_mut() {
+            for (idx, group) in groups.iter() {
+                h.check_gain(*idx, group.name.as_deref(), group.gain, now);
+            }
+            for (i, s) in groups.values().flat_map(|g| g.speakers.iter()).enumerate() {
+                h.check_temperature(i, &s.name, s.s.t_coil);
             }
+        }
 
-            unlock_elem.write_int(&ctl, UNLOCK_MAGIC);
+        if let Some(bb) = blackbox.as_mut().filter(|_| !idle) {
+            bb.set_state(group_states(&groups));
         }
-    });
-    if let Err(e) = result {
-        warn!("Panic!");
 
-        let mut reason: String = "Unknown panic".into();
+        // On battery, read several periods at a time while it's safe to
+        if globals.battery_batch > 1 {
+            if power_checked.is_none_or(|t| now - t > POWER_POLL) {
+                let battery = helpers::on_battery();
+                if battery != on_battery {
+                    info!("Running on {}", if battery { "battery" } else { "AC" });
+                }
+                on_battery = battery;
+                power_checked = Some(now);
+            }
+            let speakers = groups.values().flat_map(|g| g.speakers.iter());
+            let new_batch = period::batch(globals, on_battery, all_nominal, speakers);
+            if new_batch != batch {
+                debug!("Reading {} periods at a time", new_batch);
+                batch = new_batch;
+                if let Some(pcm) = pcm.as_ref() {
+                    helpers::set_avail_min(pcm, globals.period * batch);
+                }
+            }
+        }
 
-        if let Some(s) = e.downcast_ref::<&'static str>() {
-            reason = (*s).into();
-        } else if let Some(s) = e.downcast_ref::<String>() {
-            reason = s.clone();
+        if globals.idle
+            && !idle
+            && silence.update(buf_read, pt)
+            && card_index.is_some_and(|c| !helpers::playback_open(c))
+        {
+            info!("No playback, going idle");
+            reactor.unwatch(reactor::Source::Pcm);
+            #[allow(unused_assignments)]
+            {
+                io = None;
+                pcm = None;
+            }
+            idle = true;
+            silence.reset();
         }
 
-        blackbox.as_mut().map(|bb| {
-            if bb.preserve(reason).is_err() {
-                warn!("Failed to write blackbox");
+        if let Some(log) = rms_log.as_mut().filter(|_| !idle) {
+            if !log.record(groups.values().flat_map(|g| g.speakers.iter())) {
+                *rms_log = None;
             }
-        });
+        }
 
-        resume_unwind(e);
+        if let Some(stats) = stats.as_mut() {
+            for spk in groups
+                .values()
+                .flat_map(|g| g.speakers.iter())
+                .filter(|s| s.enabled)
+            {
+                stats.update(&spk.name, &spk.s, spk.headroom(), spk.margin(0.), pt);
+            }
+            stats.set_heartbeat(hb.counts);
+            stats.save_periodic();
+        }
+
+        // In safe mode, the kernel's own limits kick back in on their own
+        heartbeat(
+            &writer,
+            hb,
+            unlock_elem.as_mut(),
+            &mut groups,
+            *safe_mode,
+            history,
+        );
+
+        for (st, group) in status.groups.iter_mut().zip(groups.values()) {
+            st.gain = group.gain;
+            st.histogram.add(group.gain, pt);
+        }
+
+        if let Some(server) = status_server.as_mut() {
+            status.sample_rate = sample_rate;
+            status.idle = idle;
+            status.boost = boost_until.map(|until| (until - now).as_secs_f32());
+            status.safe_mode = *safe_mode;
+            status.heartbeat = Some(hb.report(stats.as_ref().map(|s| s.heartbeat())));
+            if status.history.seq() != history.seq() {
+                status.history.clone_from(history);
+            }
+            status
+                .speakers
+                .iter_mut()
+                .zip(groups.values().flat_map(|g| g.speakers.iter()))
+                .for_each(|(st, s)| {
+                    st.enabled = s.enabled;
+                    st.fault = s.fault;
+                    st.tamper_count = s.tamper_count;
+                    st.level_mismatches = s.level_mismatches;
+                    st.scale_mismatches = s.scale_mismatches;
+                    st.state = s.s;
+                    st.headroom = s.headroom();
+                    st.time_to_limit = s.time_to_limit();
+                    st.margins = s.margins();
+                });
+            server.publish(status);
+            server.broadcast(status, now);
+        }
     }
 }
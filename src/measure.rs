@@ -0,0 +1,310 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    `speakersafetyd measure`, a running estimate of each speaker's coil
+    resistance from the sense data of normal playback, for sanity checking
+    z_nominal, is_scale and vs_scale of a config on the real machine. Like
+    `top`, it's purely a client of the status socket, sampling the apparent
+    impedance (V/I over a period) the daemon reports.
+
+    The apparent impedance rises with the coil temperature, so every sample
+    is taken back to the speaker's ambient temperature. With a_rdc in the
+    config that uses the configured coefficient, without it the slope of the
+    samples against the modeled coil temperature, once the coil has been
+    through enough of a temperature range to tell.
+
+    Apparent impedance is at least the DC resistance, and more the more of
+    the signal is up where the coil inductance matters, so the minimum is
+    the best bound on Re and the mean is what z_nominal gets compared to.
+    A config whose z_nominal is way off either has the wrong value or
+    wrong sense scales, and both are worth a look.
+*/
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::status::{self, SpeakerReport, StatusReply};
+
+/// Samples below this power (W) are mostly noise and get dropped
+const MIN_POWER: f64 = 0.05;
+/// Coil temperature range (°C) needed to fit the temperature coefficient
+const MIN_T_SPAN: f64 = 5.;
+/// Time constant of the current estimate, and length of the baseline (s)
+const DRIFT_WINDOW: f64 = 60.;
+/// z_nominal this far off the ambient estimate (relative) gets flagged
+const Z_MISMATCH: f64 = 0.15;
+/// Reference temperature of a_rdc (°C), as in the model
+const T_RDC_REF: f64 = 35.;
+
+/// The final estimates with `--json`, one per speaker
+#[derive(Serialize)]
+struct EstimateReport {
+    name: String,
+    samples: usize,
+    /// Only once there were samples
+    #[serde(flatten)]
+    fit: Option<Fit>,
+}
+
+#[derive(Serialize)]
+struct Fit {
+    active: f64,
+    z_nominal: f64,
+    z_min: f64,
+    z_mean: f64,
+    re_ambient: f64,
+    t_ambient: f64,
+    t_coil_min: f64,
+    t_coil_max: f64,
+    a_rdc: Option<f64>,
+    a_rdc_fitted: bool,
+    drift: Option<f64>,
+}
+
+#[derive(Default)]
+struct Estimate {
+    name: String,
+    z_nominal: f64,
+    a_rdc: Option<f64>,
+    t_ambient: f64,
+    samples: usize,
+    active: f64,
+    z_min: f64,
+    z_sum: f64,
+    // Linear fit of the impedance against the coil temperature
+    t_sum: f64,
+    tt_sum: f64,
+    tz_sum: f64,
+    t_min: f64,
+    t_max: f64,
+    // (impedance, coil temperature) over the first DRIFT_WINDOW and as of late
+    baseline: (f64, f64),
+    baseline_samples: usize,
+    recent: Option<(f64, f64)>,
+}
+
+impl Estimate {
+    fn new(spk: &SpeakerReport) -> Estimate {
+        Estimate {
+            name: spk.name.clone(),
+            z_nominal: spk.z_nominal as f64,
+            a_rdc: spk.params.as_ref().and_then(|p| p.a_rdc).map(f64::from),
+            t_ambient: spk.t_ambient as f64,
+            z_min: f64::INFINITY,
+            t_min: f64::INFINITY,
+            t_max: f64::NEG_INFINITY,
+            ..Default::default()
+        }
+    }
+
+    /// The temperature coefficient of the coil resistance, relative to T_RDC_REF
+    fn a_rdc(&self) -> Option<f64> {
+        if let Some(a) = self.a_rdc {
+            return Some(a);
+        }
+        if self.t_max - self.t_min < MIN_T_SPAN {
+            return None;
+        }
+        let n = self.samples as f64;
+        let slope = (n * self.tz_sum - self.t_sum * self.z_sum)
+            / (n * self.tt_sum - self.t_sum * self.t_sum);
+        let at_ref = (self.z_sum - slope * self.t_sum) / n + slope * T_RDC_REF;
+        Some(slope / at_ref)
+    }
+
+    /// Impedance `z` at coil temperature `t_coil`, taken back to ambient
+    fn at_ambient(&self, z: f64, t_coil: f64) -> f64 {
+        match self.a_rdc() {
+            Some(a) => {
+                z * (1. + a * (self.t_ambient - T_RDC_REF)) / (1. + a * (t_coil - T_RDC_REF))
+            }
+            None => z,
+        }
+    }
+
+    fn add(&mut self, spk: &SpeakerReport, dt: f64) {
+        let z = spk.impedance as f64;
+        let t = spk.t_coil;
+        let power = spk.power as f64;
+        // Unknown (null) power counts as none
+        if !z.is_finite() || !t.is_finite() || power.is_nan() || power < MIN_POWER {
+            return;
+        }
+        self.t_ambient = spk.t_ambient as f64;
+
+        self.samples += 1;
+        self.active += dt;
+        self.z_min = self.z_min.min(z);
+        self.z_sum += z;
+        self.t_sum += t;
+        self.tt_sum += t * t;
+        self.tz_sum += t * z;
+        self.t_min = self.t_min.min(t);
+        self.t_max = self.t_max.max(t);
+
+        if self.active <= DRIFT_WINDOW {
+            self.baseline.0 += z;
+            self.baseline.1 += t;
+            self.baseline_samples += 1;
+        }
+        let k = (dt / DRIFT_WINDOW).min(1.);
+        self.recent = Some(
+            self.recent
+                .map_or((z, t), |(rz, rt)| (rz + k * (z - rz), rt + k * (t - rt))),
+        );
+    }
+
+    fn z_mean(&self) -> f64 {
+        self.z_sum / self.samples as f64
+    }
+
+    /// The mean impedance, at ambient
+    fn re_ambient(&self) -> f64 {
+        self.at_ambient(self.z_mean(), self.t_sum / self.samples as f64)
+    }
+
+    /// How far the recent estimate moved from the baseline (relative)
+    fn drift(&self) -> Option<f64> {
+        if self.active <= DRIFT_WINDOW {
+            return None;
+        }
+        // Both go back to ambient with the latest coefficient, so a fitted
+        // one showing up doesn't read as drift
+        let n = self.baseline_samples as f64;
+        let baseline = self.at_ambient(self.baseline.0 / n, self.baseline.1 / n);
+        self.recent
+            .map(|(z, t)| self.at_ambient(z, t) / baseline - 1.)
+    }
+
+    fn report(&self) -> EstimateReport {
+        EstimateReport {
+            name: self.name.clone(),
+            samples: self.samples,
+            fit: (self.samples > 0).then(|| Fit {
+                active: self.active,
+                z_nominal: self.z_nominal,
+                z_min: self.z_min,
+                z_mean: self.z_mean(),
+                re_ambient: self.re_ambient(),
+                t_ambient: self.t_ambient,
+                t_coil_min: self.t_min,
+                t_coil_max: self.t_max,
+                a_rdc: self.a_rdc(),
+                a_rdc_fitted: self.a_rdc.is_none() && self.a_rdc().is_some(),
+                drift: self.drift(),
+            }),
+        }
+    }
+
+    fn print(&self) {
+        if self.samples == 0 {
+            println!("{:<16} no playback yet", self.name);
+            return;
+        }
+
+        let re = self.re_ambient();
+        let mut line = format!(
+            "{:<16} {:>5.0} s  Re {:>6.3} Ω at {:>4.1} °C  min {:>6.3} Ω  z_nominal {:>6.3} Ω ({:+.1}%)",
+            self.name,
+            self.active,
+            re,
+            self.t_ambient,
+            self.z_min,
+            self.z_nominal,
+            100. * (self.z_nominal / re - 1.),
+        );
+        match (self.a_rdc, self.a_rdc()) {
+            (None, Some(a)) => line += &format!("  a_rdc ~{:.4}", a),
+            (None, None) => line += "  (uncorrected, coil too steady)",
+            _ => {}
+        }
+        if let Some(drift) = self.drift() {
+            line += &format!("  drift {:+.2}%", 100. * drift);
+        }
+        if (self.z_nominal / re - 1.).abs() > Z_MISMATCH {
+            line += "  <- check z_nominal and the sense scales";
+        }
+        println!("{}", line);
+    }
+}
+
+/**
+    Sample the daemon at `socket` every `interval`, printing the estimates
+    every `report` and once more on the way out, after `duration` if given
+    or when interrupted. With `json`, only the final estimates are printed.
+*/
+pub fn run(
+    socket: &Path,
+    interval: Duration,
+    report: Duration,
+    duration: Option<Duration>,
+    json: bool,
+) {
+    let quit = Arc::new(AtomicBool::new(false));
+    for sig in [signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM] {
+        signal_hook::flag::register(sig, Arc::clone(&quit)).unwrap();
+    }
+
+    let start = Instant::now();
+    let mut last = start;
+    let mut last_report = start;
+    let mut speakers: Vec<Estimate> = Vec::new();
+
+    if !json {
+        println!(
+            "Measuring coil resistance during playback, Ctrl-C to stop. Apparent impedance is above Re, the more so the more treble there is."
+        );
+    }
+
+    while !quit.load(Ordering::Relaxed) && duration.is_none_or(|d| start.elapsed() < d) {
+        thread::sleep(interval);
+        let now = Instant::now();
+        let dt = (now - last).as_secs_f64();
+        last = now;
+
+        // Keep going across daemon restarts, the daemon comes back
+        let reply: StatusReply = match status::query(socket, "status") {
+            Ok(Ok(reply)) => reply,
+            Ok(Err(e)) => {
+                eprintln!("Error: {}", e);
+                continue;
+            }
+            Err(e) => {
+                eprintln!("Failed to query daemon at {:?}: {}", socket, e);
+                continue;
+            }
+        };
+        if reply.idle {
+            continue;
+        }
+
+        for spk in reply.speakers.iter() {
+            let est = match speakers.iter().position(|e| e.name == spk.name) {
+                Some(i) => &mut speakers[i],
+                None => {
+                    speakers.push(Estimate::new(spk));
+                    speakers.last_mut().unwrap()
+                }
+            };
+            est.add(spk, dt);
+        }
+
+        if !json && now - last_report >= report {
+            last_report = now;
+            println!();
+            speakers.iter().for_each(Estimate::print);
+        }
+    }
+
+    if json {
+        let out: Vec<EstimateReport> = speakers.iter().map(Estimate::report).collect();
+        println!("{}", serde_json::to_string_pretty(&out).unwrap());
+    } else {
+        println!("\nFinal estimates:");
+        speakers.iter().for_each(Estimate::print);
+    }
+}
@@ -0,0 +1,105 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    Capture of what is being played (a loopback or monitor PCM), recorded
+    into the blackbox next to the sense data. The sense data shows what the
+    speakers did, this shows what they were asked to do, which is what it
+    takes to tell a limiter bug from a loud track.
+
+    This is purely diagnostic, so it must never get in the way: the PCM is
+    read without blocking, taking whatever has arrived since the last
+    period, and if anything goes wrong it is simply left out.
+*/
+use alsa::pcm::{Access, Format, HwParams, PCM};
+use alsa::{Direction, ValueOr};
+use log::{debug, info, warn};
+
+/// Rate to open at before anything set one
+const DEFAULT_RATE: u32 = 48000;
+
+/// Buffer this many periods, so a slow period doesn't overrun right away
+const BUFFER_PERIODS: usize = 4;
+
+pub struct Monitor {
+    device: String,
+    channels: usize,
+    period: usize,
+    pcm: Option<PCM>,
+    buf: Vec<i16>,
+}
+
+fn open(device: &str, channels: usize, rate: u32, period: usize) -> alsa::Result<PCM> {
+    let pcm = PCM::new(device, Direction::Capture, true)?;
+    {
+        let params = HwParams::any(&pcm)?;
+        params.set_channels(channels as u32)?;
+        params.set_rate(rate, ValueOr::Nearest)?;
+        params.set_format(Format::s16())?;
+        params.set_access(Access::RWInterleaved)?;
+        params.set_buffer_size_near((period * BUFFER_PERIODS) as alsa::pcm::Frames)?;
+        pcm.hw_params(&params)?;
+    }
+    pcm.start()?;
+    Ok(pcm)
+}
+
+impl Monitor {
+    pub fn new(device: &str, channels: usize, period: usize, sample_rate: i32) -> Monitor {
+        let mut monitor = Monitor {
+            device: device.to_string(),
+            channels,
+            period,
+            pcm: None,
+            buf: vec![0; period * BUFFER_PERIODS * channels],
+        };
+        monitor.reopen(sample_rate);
+        monitor
+    }
+
+    /// (Re)open the PCM at `sample_rate`, to follow the sense data
+    pub fn reopen(&mut self, sample_rate: i32) {
+        self.pcm = None;
+        let rate = if sample_rate > 0 {
+            sample_rate as u32
+        } else {
+            DEFAULT_RATE
+        };
+
+        match open(&self.device, self.channels, rate, self.period) {
+            Ok(pcm) => {
+                info!("Monitoring playback from {} at {} Hz", self.device, rate);
+                self.pcm = Some(pcm);
+            }
+            Err(e) => warn!("Failed to open monitor PCM {}: {}", self.device, e),
+        }
+    }
+
+    /// Whatever arrived since the last call, up to `frames` frames
+    pub fn read(&mut self, frames: usize) -> &[i16] {
+        let Some(pcm) = self.pcm.as_ref() else {
+            return &[];
+        };
+        let frames = frames.min(self.buf.len() / self.channels);
+
+        let ret = pcm
+            .io_i16()
+            .and_then(|io| io.readi(&mut self.buf[..frames * self.channels]));
+        match ret {
+            Ok(n) => &self.buf[..n * self.channels],
+            Err(e) if e.errno() == libc::EAGAIN => &[],
+            Err(e) if e.errno() == libc::EPIPE => {
+                debug!("Monitor PCM overrun");
+                if pcm.prepare().and_then(|_| pcm.start()).is_err() {
+                    warn!("Failed to restart the monitor PCM, not monitoring");
+                    self.pcm = None;
+                }
+                &[]
+            }
+            Err(e) => {
+                warn!("Monitor PCM read failed, not monitoring: {}", e);
+                self.pcm = None;
+                &[]
+            }
+        }
+    }
+}
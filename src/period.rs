@@ -0,0 +1,150 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    The model's side of a period of the protection loop: catching it up on
+    time it missed, running it to get the group gains, and working out how
+    to read the next period. None of it touches the card, run_card() does
+    the I/O around it, so the replay tests can put their sense data through
+    the very same steps.
+*/
+use log::debug;
+
+use speakersafetyd_core::history::{self, History};
+
+use crate::types::{self, Globals, Speaker};
+use crate::{ACTIVE_LEVEL, BATTERY_HEADROOM, IDLE_AFTER};
+
+/// Whether anything is playing in `buf`, going by the sense data
+pub fn active(buf: &[i16]) -> bool {
+    buf.iter().any(|v| v.unsigned_abs() > ACTIVE_LEVEL)
+}
+
+/**
+    How far to skip the model ahead before running it on the `read` frames
+    we got, `dt` seconds after the last period, if at all. While `idle`,
+    there's no data, so the model decays over the whole time. Otherwise it
+    goes by the frames the card `lost` if we know, or by the wall clock if
+    that's more than 4 reads of `expected` frames behind.
+*/
+pub fn catch_up(
+    idle: bool,
+    lost: Option<usize>,
+    dt: f64,
+    read: usize,
+    expected: usize,
+    sample_rate: i32,
+) -> Option<f64> {
+    let rate = sample_rate as f64;
+    if idle {
+        return Some(dt);
+    }
+    match lost {
+        /*
+         * Going by the frames the card captured rather than the wall
+         * clock, so scheduling delays, stopping in a debugger or the
+         * clock being stepped don't count as missed audio unless the
+         * buffer actually overran.
+         */
+        Some(0) => None,
+        Some(lost) => {
+            let skip = lost as f64 / rate;
+            debug!("Skipping {:.2} seconds ({} frames lost)", skip, lost);
+            Some(skip)
+        }
+        // If we skipped at least 4 periods, run catchup for that minus what we read
+        None if dt > 4. * expected as f64 / rate => {
+            let skip = dt - read as f64 / rate;
+            debug!("Skipping {:.2} seconds", skip);
+            Some(skip)
+        }
+        None => None,
+    }
+}
+
+/**
+    Run the models of a group's speakers on a period of sense data, and
+    return the gain for the group, the lowest any of them asks for, and
+    whether one got quarantined on the way. Anything else that happened to
+    the speakers goes into the `history`.
+*/
+pub fn group_gain(speakers: &mut [Speaker], buf: &[i16], history: &mut History) -> (f32, bool) {
+    let mut quarantined = false;
+    types::couple(speakers);
+    // Disabled speakers don't participate, a fully disabled group is left at min gain
+    let gain = speakers
+        .iter_mut()
+        .filter(|s| s.enabled)
+        .filter_map(|s| {
+            let (emergency, muted) = (s.in_emergency(), s.muted());
+            let gain = s.run_model(buf);
+            if s.in_emergency() && !emergency {
+                history.push(history::Event::OverTemperature {
+                    speaker: s.name.clone(),
+                });
+            }
+            if s.muted() != muted {
+                let speaker = s.name.clone();
+                history.push(if muted {
+                    history::Event::Unmuted { speaker }
+                } else {
+                    history::Event::Muted { speaker }
+                });
+            }
+            if gain.is_none() {
+                quarantined = true;
+                history.push(history::Event::Quarantined {
+                    speaker: s.name.clone(),
+                    fault: s.fault.unwrap(),
+                });
+            }
+            gain
+        })
+        .reduce(f32::min)
+        .unwrap_or(0.);
+    (gain, quarantined)
+}
+
+/**
+    Periods to read at a time next. On battery, several while every speaker
+    is well clear of its limit, to cut down on wakeups. Anything getting
+    warm, or the limiter engaging, gets us back to reacting every period.
+*/
+pub fn batch<'a>(
+    globals: &Globals,
+    on_battery: bool,
+    all_nominal: bool,
+    speakers: impl IntoIterator<Item = &'a Speaker>,
+) -> usize {
+    let cool = all_nominal
+        && speakers
+            .into_iter()
+            .filter(|s| s.enabled)
+            .all(|s| s.headroom() > BATTERY_HEADROOM);
+    if on_battery && cool {
+        globals.battery_batch
+    } else {
+        1
+    }
+}
+
+/// How long nothing has been playing for, to go idle after a while
+#[derive(Default)]
+pub struct Silence {
+    time: f64,
+}
+
+impl Silence {
+    /// Account for `time` seconds of sense data in `buf`, true once it's been quiet long enough
+    pub fn update(&mut self, buf: &[i16], time: f64) -> bool {
+        if active(buf) {
+            self.time = 0.;
+        } else {
+            self.time += time;
+        }
+        self.time > IDLE_AFTER
+    }
+
+    pub fn reset(&mut self) {
+        self.time = 0.;
+    }
+}
@@ -0,0 +1,148 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    PipeWire bridge. The daemon runs as a system service and can't reach the
+    users' PipeWire instances, so this runs in the user session instead. It
+    polls the daemon and publishes the limiter headroom on the default
+    metadata object, where the session manager or a UI can pick it up and
+    back off before the hardware limiter has to.
+
+    It also publishes the attenuation actually applied to each speaker
+    group, for the DSP chain (asahi-audio's filter-chain) to compensate
+    perceptually while limiting, e.g. by easing off its bass boost for the
+    woofers that are being turned down anyway. Groups are identified by
+    number and name as in the config, so a filter-chain script can tell
+    woofers from tweeters. While anything is limiting, this is published
+    more often, so the compensation can follow the limiter.
+*/
+use std::io;
+use std::path::Path;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::schema;
+use crate::status::{self, StatusReply};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Poll interval while limiting
+const LIMITING_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Metadata key on subject 0 (global) of the "default" metadata object
+pub const METADATA_KEY: &str = "speakersafetyd.headroom";
+/// Metadata key for the attenuation applied per group, on the same object
+pub const ATTENUATION_KEY: &str = "speakersafetyd.attenuation";
+
+/// Round to 0.1, so we don't republish on every bit of noise
+fn round(v: Option<f32>) -> Option<f32> {
+    v.filter(|v| v.is_finite()).map(|v| (v * 10.).round() / 10.)
+}
+
+/// The headroom metadata, see run_bridge()
+#[derive(Serialize)]
+struct Headroom {
+    headroom: Option<f32>,
+    gain: Option<f32>,
+}
+
+/// The attenuation metadata, see run_bridge()
+#[derive(Serialize)]
+struct Attenuation {
+    limiting: bool,
+    groups: Vec<GroupAttenuation>,
+}
+
+#[derive(Serialize)]
+struct GroupAttenuation {
+    group: usize,
+    name: Option<String>,
+    gain: Option<f32>,
+}
+
+fn pw_metadata(args: &[&str]) -> io::Result<()> {
+    let ret = Command::new("pw-metadata").args(args).output()?;
+
+    if !ret.status.success() {
+        return Err(io::Error::other(format!(
+            "pw-metadata failed: {}",
+            ret.status
+        )));
+    }
+
+    Ok(())
+}
+
+/// The attenuation applied to each group of the status reply `st`
+fn attenuation(st: &StatusReply) -> Attenuation {
+    Attenuation {
+        limiting: st.gain.is_some_and(|g| g < 0.),
+        groups: st
+            .groups
+            .iter()
+            .map(|grp| GroupAttenuation {
+                group: grp.group,
+                name: grp.name.clone(),
+                gain: round(Some(grp.gain)),
+            })
+            .collect(),
+    }
+}
+
+/// Publish `value` under `key`, or remove the key if there is none
+fn publish(key: &str, value: Option<&String>) -> io::Result<()> {
+    match value {
+        Some(v) => pw_metadata(&["0", key, v, "Spa:String:JSON"]),
+        None => pw_metadata(&["-d", "0", key]),
+    }
+}
+
+/**
+    Publish the headroom and attenuation until killed. The headroom is a
+    JSON object with `headroom`, the smallest temperature margin before
+    limiting (°C), and `gain`, the strongest gain reduction currently
+    applied (dB). The attenuation has `limiting`, whether any group is
+    being turned down, and `groups`, with the `group` number, its `name`
+    (if any) and the `gain` applied to it (dB) for each. Both keys are
+    removed while the daemon is unreachable.
+*/
+pub fn run_bridge(socket: &Path) -> ! {
+    let mut last: [Option<String>; 2] = [None, None];
+
+    loop {
+        let st = status::query::<StatusReply>(socket, "status")
+            .ok()
+            .and_then(|r| r.ok());
+        let values = [
+            st.as_ref().map(|st| {
+                schema::dump(&Headroom {
+                    headroom: round(st.headroom),
+                    gain: round(st.gain),
+                })
+            }),
+            st.as_ref().map(|st| schema::dump(&attenuation(st))),
+        ];
+
+        for ((key, value), last) in [METADATA_KEY, ATTENUATION_KEY]
+            .into_iter()
+            .zip(values)
+            .zip(last.iter_mut())
+        {
+            if value == *last {
+                continue;
+            }
+            match publish(key, value.as_ref()) {
+                Ok(_) => *last = value,
+                Err(e) => eprintln!("Failed to update PipeWire metadata: {}", e),
+            }
+        }
+
+        let limiting = st.is_some_and(|st| st.gain.is_some_and(|g| g < 0.));
+        thread::sleep(if limiting {
+            LIMITING_POLL_INTERVAL
+        } else {
+            POLL_INTERVAL
+        });
+    }
+}
@@ -0,0 +1,270 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    Plotting of blackbox dumps. Renders the model temperatures, power and
+    gain of each speaker over time into a self-contained SVG, so a dump can
+    be looked at without the analysis scripts and their dependencies.
+*/
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::blackbox;
+
+const WIDTH: f64 = 1200.;
+const PANEL_HEIGHT: f64 = 240.;
+const PANEL_GAP: f64 = 60.;
+const MARGIN_LEFT: f64 = 70.;
+const MARGIN_RIGHT: f64 = 190.;
+const MARGIN_TOP: f64 = 60.;
+const MARGIN_BOTTOM: f64 = 40.;
+
+const COLORS: [&str; 8] = [
+    "#1f77b4", "#ff7f0e", "#2ca02c", "#d62728", "#9467bd", "#8c564b", "#e377c2", "#17becf",
+];
+
+struct Series {
+    name: String,
+    color: &'static str,
+    dash: Option<&'static str>,
+    points: Vec<(f64, f64)>,
+}
+
+struct Panel {
+    title: &'static str,
+    series: Vec<Series>,
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Tick positions at a round step covering [min, max]
+fn ticks(min: f64, max: f64, count: usize) -> Vec<f64> {
+    let raw = (max - min) / count as f64;
+    let mag = 10f64.powf(raw.log10().floor());
+    let step = [1., 2., 5., 10.]
+        .iter()
+        .map(|m| m * mag)
+        .find(|&s| s >= raw)
+        .unwrap();
+
+    let mut ticks = Vec::new();
+    let mut t = (min / step).ceil() * step;
+    while t <= max + step * 1e-6 {
+        ticks.push(t);
+        t += step;
+    }
+    ticks
+}
+
+fn render_panel(out: &mut String, panel: &Panel, top: f64, t_max: f64) {
+    let left = MARGIN_LEFT;
+    let right = WIDTH - MARGIN_RIGHT;
+    let bottom = top + PANEL_HEIGHT;
+
+    let values = || {
+        panel
+            .series
+            .iter()
+            .flat_map(|s| s.points.iter().map(|p| p.1))
+    };
+    let mut min = values().fold(f64::INFINITY, f64::min);
+    let mut max = values().fold(f64::NEG_INFINITY, f64::max);
+    if !min.is_finite() || !max.is_finite() {
+        min = 0.;
+        max = 1.;
+    }
+    if max - min < 1e-3 {
+        min -= 0.5;
+        max += 0.5;
+    }
+    let pad = (max - min) * 0.05;
+    min -= pad;
+    max += pad;
+
+    let x = |t: f64| left + t / t_max * (right - left);
+    let y = |v: f64| bottom - (v - min) / (max - min) * PANEL_HEIGHT;
+
+    let _ = writeln!(
+        out,
+        r#"<text x="{}" y="{}" font-weight="bold">{}</text>"#,
+        left,
+        top - 10.,
+        panel.title
+    );
+    let _ = writeln!(
+        out,
+        r##"<rect x="{}" y="{}" width="{}" height="{}" fill="none" stroke="#000"/>"##,
+        left,
+        top,
+        right - left,
+        PANEL_HEIGHT
+    );
+
+    for v in ticks(min, max, 5) {
+        let _ = writeln!(
+            out,
+            r##"<line x1="{l}" y1="{y:.1}" x2="{r}" y2="{y:.1}" stroke="#ddd"/><text x="{tx}" y="{y:.1}" text-anchor="end" dominant-baseline="middle">{v}</text>"##,
+            l = left,
+            r = right,
+            y = y(v),
+            tx = left - 6.,
+            v = (v * 1000.).round() / 1000.,
+        );
+    }
+    for t in ticks(0., t_max, 10) {
+        let _ = writeln!(
+            out,
+            r##"<line x1="{x:.1}" y1="{t}" x2="{x:.1}" y2="{b}" stroke="#ddd"/><text x="{x:.1}" y="{ty}" text-anchor="middle">{v}</text>"##,
+            x = x(t),
+            t = top,
+            b = bottom,
+            ty = bottom + 16.,
+            v = (t * 1000.).round() / 1000.,
+        );
+    }
+
+    for (i, series) in panel.series.iter().enumerate() {
+        let mut points = String::new();
+        for &(t, v) in series.points.iter().filter(|p| p.1.is_finite()) {
+            let _ = write!(points, "{:.1},{:.1} ", x(t), y(v));
+        }
+        let dash = series
+            .dash
+            .map(|d| format!(r#" stroke-dasharray="{}""#, d))
+            .unwrap_or_default();
+        let _ = writeln!(
+            out,
+            r#"<polyline points="{}" fill="none" stroke="{}" stroke-width="1.5"{}/>"#,
+            points, series.color, dash
+        );
+
+        let ly = top + 10. + i as f64 * 18.;
+        let _ = writeln!(
+            out,
+            r#"<line x1="{}" y1="{ly}" x2="{}" y2="{ly}" stroke="{}" stroke-width="2"{}/><text x="{}" y="{ly}" dominant-baseline="middle">{}</text>"#,
+            right + 10.,
+            right + 35.,
+            series.color,
+            dash,
+            right + 40.,
+            escape(&series.name),
+        );
+    }
+}
+
+/// Plot the dump at `input` into an SVG file at `output`
+pub fn plot(input: &Path, output: &Path) -> io::Result<()> {
+    let (meta, _) = blackbox::load(input)?;
+
+    let count = meta.blocks.first().map_or(0, |b| b.speakers.len());
+    let name = |i: usize| {
+        meta.speakers
+            .get(i)
+            .map(|s| s.name.clone())
+            .unwrap_or_else(|| format!("Speaker {}", i))
+    };
+
+    let mut temp = Panel {
+        title: "Temperature (°C)",
+        series: Vec::new(),
+    };
+    let mut power = Panel {
+        title: "Power (W)",
+        series: Vec::new(),
+    };
+    let mut gain = Panel {
+        title: "Gain (dB)",
+        series: Vec::new(),
+    };
+
+    for i in 0..count {
+        let color = COLORS[i % COLORS.len()];
+        let series = |suffix: &str, dash| Series {
+            name: name(i) + suffix,
+            color,
+            dash,
+            points: Vec::new(),
+        };
+
+        temp.series.push(series(" coil", None));
+        temp.series.push(series(" magnet", Some("6,3")));
+        power.series.push(series("", None));
+        gain.series.push(series("", None));
+    }
+
+    let mut t = 0.;
+    for block in meta.blocks.iter() {
+        for (i, spk) in block.speakers.iter().enumerate().take(count) {
+            temp.series[2 * i].points.push((t, spk.t_coil));
+            temp.series[2 * i + 1].points.push((t, spk.t_magnet));
+            power.series[i].points.push((t, spk.power.into()));
+            gain.series[i].points.push((t, spk.gain.into()));
+        }
+        if block.sample_rate > 0 {
+            t += block.sample_count as f64 / block.sample_rate as f64;
+        }
+    }
+
+    // The thermal limits, where we know them
+    for (i, params) in meta.speakers.iter().enumerate().take(count) {
+        let limit = params.t_limit as f64;
+        temp.series.push(Series {
+            name: name(i) + " limit",
+            color: COLORS[i % COLORS.len()],
+            dash: Some("2,3"),
+            points: vec![(0., limit), (t, limit)],
+        });
+        if params.t_limit_magnet != params.t_limit {
+            let limit = params.t_limit_magnet as f64;
+            temp.series.push(Series {
+                name: name(i) + " magnet limit",
+                color: COLORS[i % COLORS.len()],
+                dash: Some("1,4"),
+                points: vec![(0., limit), (t, limit)],
+            });
+        }
+    }
+
+    let t_max = if t > 0. { t } else { 1. };
+    let panels = [temp, power, gain];
+    let height = MARGIN_TOP + panels.len() as f64 * (PANEL_HEIGHT + PANEL_GAP) + MARGIN_BOTTOM;
+
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" font-family="sans-serif" font-size="12">"#,
+        WIDTH, height
+    );
+    let _ = writeln!(out, r#"<rect width="100%" height="100%" fill="white"/>"#);
+    let _ = writeln!(
+        out,
+        r#"<text x="{}" y="24" font-size="16" font-weight="bold">{}: {}</text>"#,
+        MARGIN_LEFT,
+        escape(if meta.machine.is_empty() {
+            "unknown"
+        } else {
+            &meta.machine
+        }),
+        escape(&meta.message),
+    );
+
+    for (i, panel) in panels.iter().enumerate() {
+        let top = MARGIN_TOP + i as f64 * (PANEL_HEIGHT + PANEL_GAP);
+        render_panel(&mut out, panel, top, t_max);
+    }
+    let _ = writeln!(
+        out,
+        r#"<text x="{}" y="{}" text-anchor="middle">Time (s)</text>"#,
+        (MARGIN_LEFT + WIDTH - MARGIN_RIGHT) / 2.,
+        height - 8.
+    );
+    out.push_str("</svg>\n");
+
+    fs::write(output, out)
+}
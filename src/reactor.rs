@@ -0,0 +1,169 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    The event loop of the daemon. Everything the protection loop waits on
+    is a source registered with one poller: the sense PCM, control events,
+    signals and uevents, and client actions wake it up too. Each wait has a
+    deadline for whatever is due next, such as the next tick while idle, so
+    the loop acts on any of them as they happen rather than once the next
+    period of sense data is in.
+
+    The poller is set up before we're sandboxed. Afterwards, the loop only
+    registers the descriptors of the handles it opens again and waits, see
+    harden.rs for the syscalls that takes.
+*/
+use std::collections::BTreeMap;
+use std::io::{self, Read};
+use std::os::unix::io::{AsRawFd, BorrowedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use std::time::Instant;
+
+use alsa::poll::Descriptors;
+use polling::{Event, Events, PollMode, Poller};
+
+/// What the loop waits on, besides being woken up
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Source {
+    /// The sense PCM has a read's worth of frames, or an error
+    Pcm,
+    /// Control events are pending
+    Ctl,
+    /// A signal came in
+    Signal,
+    /// A uevent came in, see uevent.rs
+    Uevent,
+}
+
+/// The sources that were ready after a wait
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Ready(u32);
+
+impl Ready {
+    pub fn has(&self, source: Source) -> bool {
+        self.0 & (1 << source as u32) != 0
+    }
+}
+
+/// Wakes the loop up from another thread, e.g. for a client's action
+#[derive(Clone)]
+pub struct Waker(Arc<Poller>);
+
+impl Waker {
+    pub fn wake(&self) {
+        // Only fails if the poller is gone, and the loop with it
+        let _ = self.0.notify();
+    }
+}
+
+pub struct Reactor {
+    poller: Arc<Poller>,
+    events: Events,
+    /// What's registered for each source
+    fds: BTreeMap<Source, Vec<RawFd>>,
+    /// The read end of the pipe caught signals are written to
+    signals: Option<UnixStream>,
+}
+
+impl Reactor {
+    pub fn new() -> io::Result<Reactor> {
+        Ok(Reactor {
+            poller: Arc::new(Poller::new()?),
+            events: Events::new(),
+            fds: BTreeMap::new(),
+            signals: None,
+        })
+    }
+
+    pub fn waker(&self) -> Waker {
+        Waker(Arc::clone(&self.poller))
+    }
+
+    /**
+        Wake up on `signals` too. Whatever else they are registered for
+        still happens, this only makes sure the loop gets to it right away.
+    */
+    pub fn catch_signals(&mut self, signals: &[libc::c_int]) -> io::Result<()> {
+        let (read, write) = UnixStream::pair()?;
+        read.set_nonblocking(true)?;
+        write.set_nonblocking(true)?;
+        for &signal in signals {
+            signal_hook::low_level::pipe::register(signal, write.try_clone()?)?;
+        }
+
+        self.watch_fd(Source::Signal, read.as_raw_fd())?;
+        self.signals = Some(read);
+        Ok(())
+    }
+
+    /// Watch `fd` for `source`, instead of whatever was watched for it before
+    pub fn watch_fd(&mut self, source: Source, fd: RawFd) -> io::Result<()> {
+        self.unwatch(source);
+        self.add(source, fd)
+    }
+
+    /// Watch an ALSA handle (a PCM or control handle) for `source`, going by its poll descriptors
+    pub fn watch(&mut self, source: Source, handle: &dyn Descriptors) -> io::Result<()> {
+        self.unwatch(source);
+        let pfds = handle
+            .get()
+            .map_err(|e| io::Error::from_raw_os_error(e.errno()))?;
+        for pfd in pfds {
+            self.add(source, pfd.fd)?;
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, source: Source, fd: RawFd) -> io::Result<()> {
+        // Safety: the owner unwatches the fd before closing it, see unwatch()
+        unsafe {
+            self.poller
+                .add_with_mode(fd, Event::readable(source as usize), PollMode::Level)?
+        };
+        self.fds.entry(source).or_default().push(fd);
+        Ok(())
+    }
+
+    /**
+        Stop watching `source`. This must happen before its descriptors are
+        closed, or we might remove whatever gets the same numbers next.
+    */
+    pub fn unwatch(&mut self, source: Source) {
+        for fd in self.fds.remove(&source).unwrap_or_default() {
+            let _ = self.poller.delete(unsafe { BorrowedFd::borrow_raw(fd) });
+        }
+    }
+
+    /**
+        Forget about `source`, whose descriptors are closed already. The
+        poller dropped them itself when they were.
+    */
+    pub fn forget(&mut self, source: Source) {
+        self.fds.remove(&source);
+    }
+
+    /// Wait until a source is ready, we're woken up or `deadline` passes
+    pub fn wait(&mut self, deadline: Option<Instant>) -> Ready {
+        let timeout = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+
+        self.events.clear();
+        match self.poller.wait(&mut self.events, timeout) {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => panic!("Failed to wait for events: {}", e),
+        }
+
+        let mut ready = Ready::default();
+        for ev in self.events.iter() {
+            ready.0 |= 1 << ev.key;
+        }
+
+        // The signals are picked up from their flags, this only needs emptying
+        if let Some(mut signals) = self.signals.as_ref().filter(|_| ready.has(Source::Signal)) {
+            let mut buf = [0u8; 16];
+            while matches!(signals.read(&mut buf), Ok(n) if n > 0) {}
+        }
+
+        ready
+    }
+}
@@ -0,0 +1,726 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    Replay tests. Each fixture in `testing/fixtures` names a machine config
+    and describes what was played through it, as segments of a sine at a
+    given level. The fixtures are synthetic, not captures: the sense data is
+    made up from the signal and each speaker's nominal impedance, the way an
+    ideal amp would report it. It is replayed through the same period steps
+    as the main loop runs (see period.rs), with the group gains fed back
+    into the output. The resulting gain trajectories are checked against the
+    fixture's expectations, and every run is checked for the things that
+    must never happen: quarantine, the hard limits, or gains below min gain.
+
+    Sense data that doesn't match the configured scales must be noticed by
+    the scale check, and sense data that does must not be.
+
+    In deterministic mode, a replay must also come out the same to the
+    bit every time, and close to what the float model does.
+
+    A replay can also resume from the model state snapshot of a blackbox
+    dump taken because of a panic, to carry on from the failing state.
+*/
+use std::collections::BTreeMap;
+use std::f32::consts::{PI, SQRT_2};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use configparser::ini::Ini;
+use serde::Deserialize;
+
+use crate::types::{self, Globals, Speaker, SpeakerState};
+use crate::{blackbox, config, helpers, history, period};
+
+/// Sample rate the fixtures are replayed at
+const SAMPLE_RATE: f32 = 48000.;
+
+/// See replay()
+#[derive(Deserialize)]
+struct Fixture {
+    config: String,
+    amp_gain: f32,
+    #[serde(default)]
+    globals: BTreeMap<String, String>,
+    #[serde(default)]
+    battery: bool,
+    signal: Vec<Segment>,
+    expect: Vec<Expectation>,
+}
+
+#[derive(Deserialize)]
+struct Segment {
+    seconds: f32,
+    level: Option<f32>,
+    freq: Option<f32>,
+    lost: Option<f32>,
+}
+
+#[derive(Deserialize)]
+struct Expectation {
+    group: usize,
+    at: f32,
+    min: f32,
+    max: f32,
+}
+
+fn fixture_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("testing/fixtures")
+}
+
+/// The part of a speaker's config the sense data depends on
+struct Wiring {
+    z_nominal: f32,
+    vs_scale: f32,
+    is_scale: f32,
+    vs_chan: usize,
+    is_chan: usize,
+}
+
+impl Wiring {
+    fn parse(config: &Ini, section: &str) -> Wiring {
+        Wiring {
+            z_nominal: helpers::parse_float(config, section, "z_nominal"),
+            vs_scale: helpers::parse_float(config, section, "vs_scale"),
+            is_scale: helpers::parse_float(config, section, "is_scale"),
+            vs_chan: helpers::parse_int(config, section, "vs_chan"),
+            is_chan: helpers::parse_int(config, section, "is_chan"),
+        }
+    }
+}
+
+fn to_sample(x: f32, scale: f32) -> i16 {
+    (x / scale * 32768.).round().clamp(-32768., 32767.) as i16
+}
+
+struct Group {
+    speakers: Vec<Speaker>,
+    /// How each of the speakers is wired up, in the same order
+    wiring: Vec<Wiring>,
+    gain: f32,
+    /// (time, gain) after every read
+    trajectory: Vec<(f32, f32)>,
+}
+
+struct Machine {
+    globals: Globals,
+    groups: BTreeMap<usize, Group>,
+    history: history::History,
+    on_battery: bool,
+    /// Periods per read, see period::batch()
+    batch: usize,
+    idle: bool,
+    silence: period::Silence,
+    /// Reads of more than one period, periods spent idle, and frames lost
+    batched: usize,
+    idle_periods: usize,
+    lost: usize,
+}
+
+impl Machine {
+    fn new(conf: &str, amp_gain: f32) -> Machine {
+        Machine::with_config(conf, amp_gain, |_| {})
+    }
+
+    /// A machine with its config adjusted by `tweak`
+    fn with_config(conf: &str, amp_gain: f32, tweak: impl FnOnce(&mut Ini)) -> Machine {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("conf")
+            .join(conf);
+        let mut cfg = Ini::new_cs();
+        cfg.load(&path)
+            .unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+        config::migrate(&mut cfg);
+        tweak(&mut cfg);
+        let globals = Globals::parse(&cfg);
+
+        let mut groups: BTreeMap<usize, Group> = BTreeMap::new();
+        let mut names: Vec<String> = cfg
+            .sections()
+            .into_iter()
+            .filter(|s| s.starts_with("Speaker/"))
+            .collect();
+        names.sort();
+        for section in names {
+            let name = section.strip_prefix("Speaker/").unwrap();
+            let mut spk = Speaker::offline(&globals, name, &cfg, amp_gain);
+            spk.set_sample_rate(SAMPLE_RATE);
+            let wiring = Wiring::parse(&cfg, &section);
+            let group = groups.entry(spk.group).or_insert_with(|| Group {
+                speakers: Vec::new(),
+                wiring: Vec::new(),
+                gain: 0.,
+                trajectory: Vec::new(),
+            });
+            group.speakers.push(spk);
+            group.wiring.push(wiring);
+        }
+        for group in groups.values_mut() {
+            types::resolve_coupling(&mut group.speakers);
+        }
+
+        Machine {
+            globals,
+            groups,
+            history: Default::default(),
+            on_battery: false,
+            batch: 1,
+            idle: false,
+            silence: Default::default(),
+            batched: 0,
+            idle_periods: 0,
+            lost: 0,
+        }
+    }
+
+    /// The sense data for a read of a sine at `level` dBFS, or silence
+    fn sense(&self, amp_gain: f32, level: Option<f32>, freq: f32, start: usize) -> Vec<i16> {
+        let channels = self.globals.channels;
+        let mut buf = vec![0i16; self.globals.period * self.batch * channels];
+
+        let Some(level) = level else {
+            return buf;
+        };
+        for group in self.groups.values() {
+            // Full scale is amp_gain dBV RMS
+            let peak = SQRT_2 * 10f32.powf((amp_gain + level + group.gain) / 20.);
+            for w in group.wiring.iter() {
+                for (n, frame) in buf.chunks_mut(channels).enumerate() {
+                    let t = (start + n) as f32 / SAMPLE_RATE;
+                    let v = peak * (2. * PI * freq * t).sin();
+                    frame[w.vs_chan] = to_sample(v, w.vs_scale);
+                    frame[w.is_chan] = to_sample(v / w.z_nominal, w.is_scale);
+                }
+            }
+        }
+
+        buf
+    }
+
+    /// Pick up the model state of every speaker from a blackbox snapshot
+    fn resume(&mut self, snapshot: &[(String, usize, SpeakerState)]) {
+        for (name, idx, state) in snapshot {
+            let group = self
+                .groups
+                .get_mut(idx)
+                .unwrap_or_else(|| panic!("{}: No group {}", name, idx));
+            let spk = group
+                .speakers
+                .iter_mut()
+                .find(|s| s.name == *name)
+                .unwrap_or_else(|| panic!("{}: Not in group {}", name, idx));
+            spk.s = *state;
+        }
+        for group in self.groups.values_mut() {
+            group.gain = group.speakers.iter().map(|s| s.s.gain).fold(0., f32::min);
+        }
+    }
+
+    /// Start the coils in the limiter's window, rather than playing until they get there
+    fn preheat(&mut self) {
+        let window = self.globals.t_window;
+        for spk in self.groups.values_mut().flat_map(|g| g.speakers.iter_mut()) {
+            spk.s.t_coil += (spk.margins().0 - window * 0.75) as f64;
+        }
+    }
+
+    fn states(&self) -> Vec<Vec<SpeakerState>> {
+        self.groups
+            .values()
+            .map(|g| g.speakers.iter().map(|s| s.s).collect())
+            .collect()
+    }
+
+    /**
+        Play a read's worth of a sine at `level` dBFS (or silence) from
+        frame `frames` on, after `lost` frames that never got read, and take
+        it through the period steps the way run_card() does. Returns the
+        sense data, and moves `frames` on past it.
+    */
+    fn play(
+        &mut self,
+        amp_gain: f32,
+        level: Option<f32>,
+        freq: f32,
+        frames: &mut usize,
+        lost: usize,
+    ) -> Vec<i16> {
+        // Playback opening wakes us up, and we can't know what we missed
+        let lost = if self.idle && level.is_some() {
+            self.idle = false;
+            None
+        } else {
+            Some(lost).filter(|_| !self.idle)
+        };
+        *frames += lost.unwrap_or(0);
+        self.lost += lost.unwrap_or(0);
+        let buf = if self.idle {
+            Vec::new()
+        } else {
+            self.sense(amp_gain, level, freq, *frames)
+        };
+
+        // No wall clock here: a read takes as long as it's got frames, an idle tick a period
+        let read = buf.len() / self.globals.channels;
+        let ticked = if self.idle { self.globals.period } else { read };
+        *frames += ticked;
+        let time = *frames as f32 / SAMPLE_RATE;
+        let dt = (ticked + lost.unwrap_or(0)) as f64 / SAMPLE_RATE as f64;
+        let expected = self.globals.period * self.batch;
+        if self.batch > 1 {
+            self.batched += 1;
+        }
+        if self.idle {
+            self.idle_periods += 1;
+        }
+
+        let rate = SAMPLE_RATE as i32;
+        if let Some(skip) = period::catch_up(self.idle, lost, dt, read, expected, rate) {
+            for spk in self.groups.values_mut().flat_map(|g| g.speakers.iter_mut()) {
+                spk.skip_model(skip);
+            }
+        }
+        self.step(&buf, time);
+
+        let all_nominal = self.groups.values().all(|g| g.gain == 0.);
+        let speakers = self.groups.values().flat_map(|g| g.speakers.iter());
+        self.batch = period::batch(&self.globals, self.on_battery, all_nominal, speakers);
+
+        // Nothing is playing during a silence, so nothing holds us up from going idle
+        let pt = read as f64 / SAMPLE_RATE as f64;
+        if self.globals.idle && !self.idle && self.silence.update(&buf, pt) && level.is_none() {
+            self.idle = true;
+            self.silence.reset();
+        }
+
+        buf
+    }
+
+    /// Run a read through the models and update the group gains
+    fn step(&mut self, buf: &[i16], time: f32) {
+        for (idx, group) in self.groups.iter_mut() {
+            let (gain, quarantined) =
+                period::group_gain(&mut group.speakers, buf, &mut self.history);
+            assert!(!quarantined, "Group {}: Quarantined at {:.1} s", idx, time);
+            for s in group.speakers.iter() {
+                assert!(
+                    !s.in_emergency(),
+                    "{}: Past the hard limits at {:.1} s",
+                    s.name,
+                    time
+                );
+                assert!(
+                    gain >= s.s.min_gain - 0.01,
+                    "Group {}: Gain {:.2} dB below min gain {:.2} dB at {:.1} s",
+                    idx,
+                    gain,
+                    s.s.min_gain,
+                    time
+                );
+            }
+            group.gain = gain;
+            group.trajectory.push((time, gain));
+        }
+    }
+}
+
+/**
+    Replay a fixture and check its expectations. A fixture has the machine
+    `config` (relative to `conf/`), the `amp_gain` in dBV, the `signal` as a
+    list of segments with their length in `seconds`, `level` in dBFS and
+    `freq` in Hz (silence without a level), and a list of gain expectations
+    for a `group` `at` some point in seconds, between `min` and `max` dB.
+
+    Optionally, `globals` overrides keys in the config's `[Globals]`,
+    `battery` has us run on battery, and a segment can start with `lost`
+    seconds of it that were captured but never read.
+*/
+fn replay(fixture: &str) -> Machine {
+    let path = fixture_dir().join(fixture);
+    let text = fs::read_to_string(&path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+    let fx: Fixture =
+        serde_json::from_str(&text).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+
+    let amp_gain = fx.amp_gain;
+    let mut machine = Machine::with_config(&fx.config, amp_gain, |cfg| {
+        for (key, value) in fx.globals.iter() {
+            cfg.set("Globals", key, Some(value.clone()));
+        }
+    });
+    machine.on_battery = fx.battery;
+
+    let mut frames = 0;
+    for seg in fx.signal.iter() {
+        let mut lost = (seg.lost.unwrap_or(0.) * SAMPLE_RATE) as usize;
+        let end = frames + lost + (seg.seconds * SAMPLE_RATE) as usize;
+        let level = seg.level;
+        let freq = seg.freq.unwrap_or(1000.);
+        while frames < end {
+            machine.play(amp_gain, level, freq, &mut frames, lost);
+            lost = 0;
+        }
+    }
+
+    for exp in fx.expect.iter() {
+        let (idx, at, min, max) = (exp.group, exp.at, exp.min, exp.max);
+
+        let group = &machine.groups[&idx];
+        let &(t, gain) = group
+            .trajectory
+            .iter()
+            .find(|(t, _)| *t >= at)
+            .unwrap_or_else(|| panic!("{}: Nothing played at {:.1} s", fixture, at));
+        assert!(
+            (min..=max).contains(&gain),
+            "{}: Group {} gain {:.2} dB at {:.1} s, expected {:.2}..{:.2} dB",
+            fixture,
+            idx,
+            gain,
+            t,
+            min,
+            max
+        );
+    }
+
+    machine
+}
+
+#[test]
+fn j274_mono() {
+    replay("j274-mono.json");
+}
+
+#[test]
+fn j314_woofers_and_tweeters() {
+    replay("j314-full-scale.json");
+}
+
+#[test]
+fn j413_woofers_and_tweeters() {
+    replay("j413-full-scale.json");
+}
+
+/// On battery, cool reads get batched, silences go idle, and lost frames get skipped
+#[test]
+fn j314_battery() {
+    let m = replay("j314-battery.json");
+    assert!(m.batched > 0, "Never read more than a period");
+    assert!(m.idle_periods > 0, "Never went idle");
+    assert!(m.lost > 0, "Never lost anything");
+}
+
+/// Skipping the model over a silence must land where running it does
+#[test]
+fn skip_matches_silence() {
+    let mut run = Machine::new("apple/j314.conf", 15.);
+    let mut skipped = Machine::new("apple/j314.conf", 15.);
+    let buf = vec![0i16; run.globals.period * run.globals.channels];
+    let periods = 200;
+    let seconds = (periods * run.globals.period) as f64 / SAMPLE_RATE as f64;
+
+    let speakers = run.groups.values_mut().flat_map(|g| g.speakers.iter_mut());
+    let others = skipped
+        .groups
+        .values_mut()
+        .flat_map(|g| g.speakers.iter_mut());
+    for (spk, other) in speakers.zip(others) {
+        for _ in 0..periods {
+            spk.run_model(&buf);
+        }
+        other.skip_model(seconds);
+        assert!(
+            (spk.s.t_coil - other.s.t_coil).abs() < 0.1,
+            "{}: Coil {:.2} °C run, {:.2} °C skipped",
+            spk.name,
+            spk.s.t_coil,
+            other.s.t_coil
+        );
+        assert!(
+            (spk.s.t_magnet - other.s.t_magnet).abs() < 0.1,
+            "{}: Magnet {:.2} °C run, {:.2} °C skipped",
+            spk.name,
+            spk.s.t_magnet,
+            other.s.t_magnet
+        );
+    }
+}
+
+/// A replay resumed from a panic dump's snapshot must carry on exactly as the original
+#[test]
+fn resume_from_snapshot() {
+    let amp_gain = 15.;
+    let mut run = Machine::new("apple/j314.conf", amp_gain);
+    let dir = std::env::temp_dir().join(format!("speakersafetyd-replay-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    let mut bb = blackbox::Blackbox::new("test", &dir, &run.globals, "", Path::new("")).unwrap();
+    let params = run
+        .groups
+        .values()
+        .flat_map(|g| g.speakers.iter())
+        .map(|s| s.params())
+        .collect();
+    bb.set_speakers(params);
+
+    run.preheat();
+
+    // Just enough to have a dump with the limiter in play
+    let mut frames = 0;
+    let play = |m: &mut Machine, frames: &mut usize| m.play(amp_gain, Some(0.), 1000., frames, 0);
+    for _ in 0..10 {
+        let buf = play(&mut run, &mut frames);
+        bb.push(SAMPLE_RATE as i32, &buf, &[], run.states());
+    }
+    bb.preserve(
+        "Test".into(),
+        &history::History::default(),
+        Some(Vec::new()),
+    );
+    bb.finish();
+
+    let dump = fs::read_dir(&dir).unwrap().next().unwrap().unwrap().path();
+    let (meta, data) = blackbox::load(&dump).unwrap();
+    let snapshot = blackbox::snapshot(&meta, &data).expect("No snapshot");
+    fs::remove_dir_all(&dir).unwrap();
+
+    let mut resumed = Machine::new("apple/j314.conf", amp_gain);
+    resumed.resume(&snapshot);
+    let mut resumed_frames = frames;
+    for _ in 0..10 {
+        play(&mut run, &mut frames);
+        play(&mut resumed, &mut resumed_frames);
+    }
+
+    for (idx, group) in run.groups.iter() {
+        let other = &resumed.groups[idx];
+        let expected = group.trajectory.last().unwrap().1;
+        let gain = other.trajectory.last().unwrap().1;
+        assert!(
+            expected < 0.,
+            "Group {}: Never limited, nothing to resume",
+            idx
+        );
+        assert_eq!(gain, expected, "Group {}: Diverged after resuming", idx);
+    }
+}
+
+/// A snapshot from another model version must not be resumed from
+#[test]
+fn snapshot_from_other_model_refused() {
+    let mut meta = blackbox::Meta {
+        snapshot: Some(blackbox::SnapshotIndex {
+            offset: 0,
+            speakers: vec![blackbox::SnapshotSpeaker {
+                name: "Test".into(),
+                group: 0,
+            }],
+        }),
+        ..Default::default()
+    };
+    let data = vec![0u8; blackbox::SNAPSHOT_SIZE];
+    assert!(
+        blackbox::snapshot(&meta, &data).is_some(),
+        "Untagged dump refused"
+    );
+
+    meta.model = Some(blackbox::ModelInfo {
+        version: types::MODEL_VERSION + 1,
+        ..Default::default()
+    });
+    assert!(blackbox::snapshot(&meta, &data).is_none());
+}
+
+/// A speaker coupled to a neighbour runs hotter than it would on its own
+#[test]
+fn coupled_speakers_heat_each_other() {
+    let amp_gain = 15.;
+    let run = |coupling: Option<&str>| {
+        let mut m = Machine::with_config("apple/j314.conf", amp_gain, |cfg| {
+            if let Some(c) = coupling {
+                cfg.set("Speaker/Left Woofer 1", "coupling", Some(c.into()));
+            }
+        });
+        let mut frames = 0;
+        for _ in 0..300 {
+            m.play(amp_gain, Some(-10.), 1000., &mut frames, 0);
+        }
+        let spk = m.groups[&1]
+            .speakers
+            .iter()
+            .find(|s| s.name == "Left Woofer 1")
+            .unwrap();
+        (spk.s.t_coil, spk.s.t_ambient, m.globals.t_ambient)
+    };
+
+    let (alone, ambient, t_ambient) = run(None);
+    assert_eq!(ambient, t_ambient);
+    let (coupled, ambient, _) = run(Some("Left Woofer 2: 0.5"));
+    assert!(ambient > t_ambient, "Ambient {:.2} °C not raised", ambient);
+    assert!(
+        coupled > alone,
+        "Coil {:.3} °C coupled, {:.3} °C alone",
+        coupled,
+        alone
+    );
+}
+
+#[test]
+#[should_panic(
+    expected = "Speaker/Left Woofer 1/coupling: No other speaker Left Tweeter in group 1"
+)]
+fn coupling_across_groups() {
+    Machine::with_config("apple/j314.conf", 15., |cfg| {
+        cfg.set(
+            "Speaker/Left Woofer 1",
+            "coupling",
+            Some("Left Tweeter:0.1".into()),
+        );
+    });
+}
+
+/**
+    Play loud enough for the scale check to judge every speaker, with the
+    sense data of Left Woofer 1 actually scaled by `actual` (vs, is) times
+    what its config says, and return the mismatches of each speaker.
+*/
+fn scale_mismatches(actual: (f32, f32)) -> BTreeMap<String, u64> {
+    let amp_gain = 15.;
+    let mut m = Machine::new("apple/j314.conf", amp_gain);
+    let group = m.groups.get_mut(&1).unwrap();
+    let idx = group
+        .speakers
+        .iter()
+        .position(|s| s.name == "Left Woofer 1")
+        .unwrap();
+    let wiring = &mut group.wiring[idx];
+    wiring.vs_scale *= actual.0;
+    wiring.is_scale *= actual.1;
+
+    let mut frames = 0;
+    for _ in 0..300 {
+        m.play(amp_gain, Some(0.), 1000., &mut frames, 0);
+    }
+    m.groups
+        .values()
+        .flat_map(|g| g.speakers.iter())
+        .map(|s| (s.name.clone(), s.scale_mismatches))
+        .collect()
+}
+
+/// Sense data that doesn't add up with the configured scales gets noticed
+#[test]
+fn sense_scales_checked() {
+    let ok = scale_mismatches((1., 1.));
+    assert!(ok.values().all(|n| *n == 0), "False alarms: {:?}", ok);
+
+    // vs_scale three times too high reads more than the amp can put out
+    // (with is_scale as far off the other way, so the power still adds up)
+    let vs = scale_mismatches((1. / 3., 3.));
+    assert!(vs["Left Woofer 1"] > 0, "Missed vs_scale: {:?}", vs);
+    assert_eq!(
+        vs.values().filter(|n| **n > 0).count(),
+        1,
+        "Spread: {:?}",
+        vs
+    );
+
+    // is_scale four times too low makes the impedance implausible
+    let is = scale_mismatches((1., 4.));
+    assert!(is["Left Woofer 1"] > 0, "Missed is_scale: {:?}", is);
+}
+
+/// The fixed point model repeats itself exactly, and tracks the float one
+#[test]
+fn deterministic_replay() {
+    check_deterministic(30, true);
+}
+
+/// The same over minutes of playback, heating up from cold
+#[test]
+#[ignore = "takes about a minute"]
+fn deterministic_replay_full() {
+    check_deterministic(500, false);
+}
+
+/**
+    Play loud, silence and quieter for `periods` each, in deterministic mode
+    twice and in floating point once, and compare them. Unless `preheat`,
+    the speakers start cold, so the limiter takes a few hundred periods to
+    come in.
+*/
+fn check_deterministic(periods: usize, preheat: bool) {
+    let amp_gain = 15.;
+    let run = |deterministic: bool| {
+        let mut m = Machine::with_config("apple/j314.conf", amp_gain, |cfg| {
+            cfg.set("Globals", "deterministic", Some(deterministic.to_string()));
+        });
+        if preheat {
+            m.preheat();
+        }
+        let mut frames = 0;
+        for level in [Some(0.), None, Some(-6.)] {
+            for _ in 0..periods {
+                m.play(amp_gain, level, 1000., &mut frames, 0);
+            }
+            for spk in m.groups.values_mut().flat_map(|g| g.speakers.iter_mut()) {
+                spk.skip_model(5.);
+            }
+        }
+        m
+    };
+
+    let float = run(false);
+    let once = run(true);
+    let twice = run(true);
+    for (idx, group) in once.groups.iter() {
+        let other = &twice.groups[idx];
+        for (a, b) in group.trajectory.iter().zip(other.trajectory.iter()) {
+            assert_eq!(
+                a.1.to_bits(),
+                b.1.to_bits(),
+                "Group {}: Diverged at {:.1} s",
+                idx,
+                a.0
+            );
+        }
+        for (spk, other) in group.speakers.iter().zip(other.speakers.iter()) {
+            assert_eq!(
+                spk.s.t_coil.to_bits(),
+                other.s.t_coil.to_bits(),
+                "{}",
+                spk.name
+            );
+            assert_eq!(
+                spk.s.t_magnet.to_bits(),
+                other.s.t_magnet.to_bits(),
+                "{}",
+                spk.name
+            );
+        }
+
+        let reference = &float.groups[idx];
+        assert!(
+            reference.trajectory.iter().any(|(_, g)| *g < 0.),
+            "Group {}: Never limited",
+            idx
+        );
+        for (a, b) in group.trajectory.iter().zip(reference.trajectory.iter()) {
+            assert!(
+                (a.1 - b.1).abs() < 0.05,
+                "Group {}: {:.3} dB fixed, {:.3} dB float at {:.1} s",
+                idx,
+                a.1,
+                b.1,
+                a.0
+            );
+        }
+        for (spk, other) in group.speakers.iter().zip(reference.speakers.iter()) {
+            assert!(
+                (spk.s.t_coil - other.s.t_coil).abs() < 0.05,
+                "{}: Coil {:.3} °C fixed, {:.3} °C float",
+                spk.name,
+                spk.s.t_coil,
+                other.s.t_coil
+            );
+        }
+    }
+}
@@ -0,0 +1,123 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    Per-period log of the sense data (`--rms-log`), for checking the model
+    against a measurement or tracking down a bad sense scale without
+    recording the sense stream itself. Every period, each enabled speaker
+    gets one CSV line:
+
+        time,speaker,v_rms,i_rms,impedance,power,t_coil,gain
+
+    with the wall clock time in seconds, RMS voltage (V) and current (A),
+    apparent impedance (ohms, empty while idle), average power (W), coil
+    temperature (°C) and gain (dB). That is a few dozen bytes per speaker
+    and period, a tiny fraction of the full rate data.
+
+    The path can be a FIFO, to stream it into something else. It is opened
+    up front, so it keeps working after dropping privileges and inside the
+    seccomp sandbox, and written from a thread of its own, so a slow or
+    stalled reader can't hold up the protection loop. Periods that don't
+    fit in the queue are dropped, and counted in the log.
+*/
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+
+use crate::types::Speaker;
+
+/// Periods queued for the writer before dropping
+const QUEUE_LEN: usize = 256;
+const HEADER: &str = "time,speaker,v_rms,i_rms,impedance,power,t_coil,gain";
+
+pub struct RmsLog {
+    tx: SyncSender<String>,
+    /// Periods dropped since the last one that made it
+    dropped: u64,
+}
+
+fn open(path: &Path) -> std::io::Result<File> {
+    let fifo = path.metadata().is_ok_and(|m| m.file_type().is_fifo());
+    match fifo {
+        // Opening a FIFO for writing alone blocks until there is a reader,
+        // with read access too it doesn't
+        true => OpenOptions::new().read(true).write(true).open(path),
+        false => OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(0o640)
+            .open(path),
+    }
+}
+
+fn run(rx: Receiver<String>, file: File) {
+    let mut out = BufWriter::new(file);
+    let result = writeln!(out, "{}", HEADER)
+        .and_then(|_| out.flush())
+        .and_then(|_| {
+            for lines in rx.iter() {
+                out.write_all(lines.as_bytes())?;
+                out.flush()?;
+            }
+            Ok(())
+        });
+    if let Err(e) = result {
+        warn!("Failed to write the RMS log, giving up on it: {}", e);
+    }
+}
+
+impl RmsLog {
+    pub fn new(path: &Path) -> Option<RmsLog> {
+        let file = open(path)
+            .map_err(|e| warn!("Failed to open RMS log {:?}: {}", path, e))
+            .ok()?;
+
+        let (tx, rx) = mpsc::sync_channel(QUEUE_LEN);
+        thread::Builder::new()
+            .name("rmslog".into())
+            .spawn(move || run(rx, file))
+            .expect("Failed to start RMS log thread");
+
+        info!("Logging per-period sense data to {:?}", path);
+        Some(RmsLog { tx, dropped: 0 })
+    }
+
+    /// Log one period of `speakers`. Returns false once the log is gone.
+    pub fn record<'a>(&mut self, speakers: impl Iterator<Item = &'a Speaker>) -> bool {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+
+        let mut lines = String::new();
+        if self.dropped > 0 {
+            writeln!(lines, "# {} periods dropped", self.dropped).unwrap();
+        }
+        for s in speakers.filter(|s| s.enabled) {
+            let (v_rms, i_rms) = s.rms();
+            let impedance = match s.s.impedance.is_finite() {
+                true => format!("{:.4}", s.s.impedance),
+                false => String::new(),
+            };
+            writeln!(
+                lines,
+                "{:.3},{},{:.4},{:.4},{},{:.4},{:.2},{:.2}",
+                time, s.name, v_rms, i_rms, impedance, s.s.power, s.s.t_coil, s.s.gain
+            )
+            .unwrap();
+        }
+
+        match self.tx.try_send(lines) {
+            Ok(_) => self.dropped = 0,
+            Err(TrySendError::Full(_)) => self.dropped += 1,
+            Err(TrySendError::Disconnected(_)) => return false,
+        }
+        true
+    }
+}
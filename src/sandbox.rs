@@ -0,0 +1,65 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    Sandbox tests. A seccomp filter applies to every thread of the process
+    for good, so each test runs its `_child` half in a process of its own:
+    the test binary again, told to run just that one. The halves are
+    ignored otherwise, and don't do anything outside of such a child.
+*/
+use std::fs;
+use std::process::Command;
+
+use crate::status::{self, Status, StatusReply, StatusServer};
+use crate::{harden, reactor};
+
+/// Set in the child processes, see run_child()
+const CHILD_ENV: &str = "SPEAKERSAFETYD_SANDBOX_CHILD";
+
+/// Run the test `name` in a child process and check it passed
+fn run_child(name: &str) {
+    let out = Command::new(std::env::current_exe().unwrap())
+        .args([name, "--exact", "--ignored", "--nocapture"])
+        .env(CHILD_ENV, "1")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(
+        out.status.success() && stdout.contains("1 passed"),
+        "{} failed:\n{}{}",
+        name,
+        stdout,
+        String::from_utf8_lossy(&out.stderr)
+    );
+}
+
+/// Clients must get their status with the filter installed, as the daemon runs
+#[test]
+fn status_under_seccomp() {
+    run_child("sandbox::status_under_seccomp_child");
+}
+
+#[test]
+#[ignore = "run by status_under_seccomp"]
+fn status_under_seccomp_child() {
+    if std::env::var_os(CHILD_ENV).is_none() {
+        return;
+    }
+
+    let dir = std::env::temp_dir().join(format!("speakersafetyd-sandbox-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("status.sock");
+
+    // Set up the way the daemon does, before it's sandboxed
+    let reactor = reactor::Reactor::new().unwrap();
+    let status = Status {
+        sample_rate: 48000,
+        ..Default::default()
+    };
+    let _server = StatusServer::new(&path, &status, crate::CONTROL_GROUP, reactor.waker()).unwrap();
+
+    harden::set_no_new_privs();
+    harden::install_seccomp();
+
+    let reply: StatusReply = status::query(&path, "status").unwrap().unwrap();
+    assert_eq!(reply.sample_rate, 48000);
+}
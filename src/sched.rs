@@ -0,0 +1,116 @@
+use log::{info, warn};
+use std::fs;
+use std::io;
+
+/// Raise the soft RLIMIT_RTPRIO to cover the given priority, if the hard limit allows
+fn raise_rtprio_limit(priority: u32) -> bool {
+    let mut lim: libc::rlimit = unsafe { core::mem::zeroed() };
+
+    if unsafe { libc::getrlimit(libc::RLIMIT_RTPRIO, &mut lim) } != 0 {
+        return false;
+    }
+    if lim.rlim_cur >= priority as libc::rlim_t {
+        return false;
+    }
+    if lim.rlim_max < priority as libc::rlim_t {
+        warn!(
+            "RLIMIT_RTPRIO hard limit ({}) is below the requested priority",
+            lim.rlim_max
+        );
+        return false;
+    }
+
+    lim.rlim_cur = priority as libc::rlim_t;
+    unsafe { libc::setrlimit(libc::RLIMIT_RTPRIO, &lim) == 0 }
+}
+
+fn try_set_fifo(priority: u32) -> io::Result<()> {
+    let param = libc::sched_param {
+        sched_priority: priority as i32,
+    };
+
+    if unsafe { libc::sched_setscheduler(0, libc::SCHED_FIFO, &param) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Switch the calling thread to SCHED_FIFO. Threads spawned later inherit this.
+pub fn set_fifo(priority: u32) {
+    let mut ret = try_set_fifo(priority);
+
+    if matches!(&ret, Err(e) if e.raw_os_error() == Some(libc::EPERM))
+        && raise_rtprio_limit(priority)
+    {
+        ret = try_set_fifo(priority);
+    }
+
+    match ret {
+        Ok(_) => info!("Set scheduling policy to SCHED_FIFO:{}", priority),
+        Err(e) => warn!("Failed to set SCHED_FIFO: {}", e),
+    }
+}
+
+/// Parse a CPU list like "0-3,6"
+fn parse_cpu_list(list: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+
+    for range in list.split(',').map(|a| a.trim()) {
+        match range.split_once('-') {
+            Some((a, b)) => cpus.extend(a.parse::<usize>().ok()?..=b.parse::<usize>().ok()?),
+            None => cpus.push(range.parse().ok()?),
+        }
+    }
+
+    Some(cpus)
+}
+
+/// The CPUs with the lowest capacity, i.e. the efficiency cores
+fn efficiency_cpus() -> Vec<usize> {
+    let online = fs::read_to_string("/sys/devices/system/cpu/online").unwrap_or_default();
+    let capacities: Vec<(usize, u32)> = parse_cpu_list(online.trim())
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|cpu| {
+            let path = format!("/sys/devices/system/cpu/cpu{}/cpu_capacity", cpu);
+            let cap = fs::read_to_string(path).ok()?.trim().parse().ok()?;
+            Some((cpu, cap))
+        })
+        .collect();
+
+    match capacities.iter().map(|a| a.1).min() {
+        Some(min) => capacities
+            .iter()
+            .filter(|a| a.1 == min)
+            .map(|a| a.0)
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Pin the calling thread to the given CPUs ("efficiency" or a CPU list)
+pub fn set_affinity(spec: &str) {
+    let cpus = if spec == "efficiency" {
+        efficiency_cpus()
+    } else {
+        parse_cpu_list(spec).unwrap_or_default()
+    };
+
+    if cpus.is_empty() {
+        warn!("No CPUs found for affinity '{}'", spec);
+        return;
+    }
+
+    let mut set: libc::cpu_set_t = unsafe { core::mem::zeroed() };
+    for cpu in cpus.iter() {
+        unsafe { libc::CPU_SET(*cpu, &mut set) };
+    }
+
+    if unsafe { libc::sched_setaffinity(0, core::mem::size_of::<libc::cpu_set_t>(), &set) } != 0 {
+        warn!("Failed to set CPU affinity: {}", io::Error::last_os_error());
+        return;
+    }
+
+    info!("Set CPU affinity to {:?}", cpus);
+}
@@ -0,0 +1,295 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    Speaker self test. Plays a bounded test tone on each output channel in
+    turn and watches what the running daemon sees on the sense channels, to
+    catch configs with the wrong vs_chan/is_chan mapping or scales before
+    they silently protect the wrong driver. The daemon keeps protecting the
+    speakers throughout, we only read its status, and we refuse to run (or
+    stop) if it isn't there or starts limiting.
+*/
+use std::f64::consts::PI;
+use std::io;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use alsa::pcm::{Access, Format, HwParams, PCM};
+use alsa::{Direction, ValueOr};
+
+use crate::status::{self, StatusReply};
+
+/// Upper bound on the tone level (dBFS), whatever the user asks for
+const MAX_LEVEL: f64 = -10.;
+/// Bounds on the tone length per channel (s)
+const MIN_DURATION: f64 = 1.;
+const MAX_DURATION: f64 = 5.;
+
+const SAMPLE_RATE: u32 = 48000;
+/// Length of each write, and so the status polling interval (s)
+const CHUNK: f64 = 0.1;
+/// Time for the output latency and the daemon's period to catch up (s)
+const SETTLE: f64 = 0.5;
+/// Fade in/out, to avoid clicks (s)
+const RAMP: f64 = 0.01;
+
+/// Average power (W) a speaker must see for it to count as excited
+const MIN_POWER: f32 = 0.001;
+/// How much stronger the speaker's own channel must be than any other
+const MIN_SEPARATION: f32 = 10.;
+/**
+    Plausible range of the measured impedance, relative to z_nominal. The
+    coil inductance pushes it up at the test frequency, so this is lopsided.
+    Anything outside is most likely a wrong vs_scale or is_scale.
+*/
+const Z_RANGE: (f32, f32) = (0.5, 3.);
+
+pub struct Tone {
+    pub freq: f64,
+    /// dBFS
+    pub level: f64,
+    /// Per channel (s)
+    pub duration: f64,
+}
+
+impl Tone {
+    fn level(&self) -> f64 {
+        self.level.min(MAX_LEVEL)
+    }
+
+    fn duration(&self) -> f64 {
+        self.duration.clamp(MIN_DURATION, MAX_DURATION)
+    }
+}
+
+fn open_playback(name: &str, channels: u32) -> alsa::Result<(PCM, u32)> {
+    let pcm = PCM::new(name, Direction::Playback, false)?;
+    let rate = {
+        let params = HwParams::any(&pcm)?;
+        params.set_channels(channels)?;
+        params.set_rate(SAMPLE_RATE, ValueOr::Nearest)?;
+        params.set_format(Format::s16())?;
+        params.set_access(Access::RWInterleaved)?;
+        // Keep the latency low, so we stop promptly if we have to
+        params.set_buffer_time_near(200_000, ValueOr::Nearest)?;
+        pcm.hw_params(&params)?;
+        params.get_rate()?
+    };
+
+    Ok((pcm, rate))
+}
+
+fn write_all(pcm: &PCM, buf: &[i16], channels: usize) -> alsa::Result<()> {
+    let io = pcm.io_i16()?;
+    let mut pos = 0;
+
+    while pos < buf.len() {
+        match io.writei(&buf[pos..]) {
+            Ok(frames) => pos += frames * channels,
+            Err(e) => pcm.try_recover(e, true)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Per speaker sums over the measurement window of one channel
+#[derive(Default, Clone)]
+struct Response {
+    power: f32,
+    impedance: f32,
+    polls: usize,
+    z_polls: usize,
+}
+
+impl Response {
+    fn power(&self) -> f32 {
+        if self.polls == 0 {
+            0.
+        } else {
+            self.power / self.polls as f32
+        }
+    }
+
+    fn impedance(&self) -> f32 {
+        if self.z_polls == 0 {
+            f32::NAN
+        } else {
+            self.impedance / self.z_polls as f32
+        }
+    }
+}
+
+/// Query the daemon and bail if it isn't in a state we can test in
+fn poll(socket: &Path) -> io::Result<StatusReply> {
+    let st: StatusReply = status::query(socket, "status")
+        .and_then(|r| r.map_err(io::Error::other))
+        .map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Daemon not reachable, refusing to play: {}", e),
+            )
+        })?;
+
+    if st.gain.is_some_and(|g| g < 0.) {
+        return Err(io::Error::other("The limiter engaged, stopping"));
+    }
+    for spk in st.speakers.iter() {
+        if spk.fault.is_some() || !spk.enabled {
+            return Err(io::Error::other(format!(
+                "{} is quarantined or disabled, stopping",
+                spk.name
+            )));
+        }
+    }
+
+    Ok(st)
+}
+
+/// Play the tone on `channel` and collect what every speaker saw
+fn measure(
+    socket: &Path,
+    pcm: &PCM,
+    rate: u32,
+    channels: usize,
+    channel: usize,
+    tone: &Tone,
+    speakers: usize,
+) -> io::Result<Vec<Response>> {
+    let amp = 10f64.powf(tone.level() / 20.) * 32767.;
+    let duration = tone.duration();
+    let chunk = (CHUNK * rate as f64) as usize;
+    let total = ((SETTLE + duration) * rate as f64) as usize;
+
+    let mut responses = vec![Response::default(); speakers];
+    let mut buf = vec![0i16; chunk * channels];
+
+    for start in (0..total).step_by(chunk) {
+        for (i, frame) in buf.chunks_exact_mut(channels).enumerate() {
+            let t = (start + i) as f64 / rate as f64 - SETTLE;
+            let env = (t / RAMP).min((duration - t) / RAMP).clamp(0., 1.);
+            frame.fill(0);
+            frame[channel] = (amp * env * (2. * PI * tone.freq * t).sin()) as i16;
+        }
+        write_all(pcm, &buf, channels).map_err(io::Error::other)?;
+
+        let st = poll(socket)?;
+        // Skip the start of the tone, it takes a while to show up in the sense data
+        if (start as f64) < 2. * SETTLE * rate as f64 {
+            continue;
+        }
+        for (resp, spk) in responses.iter_mut().zip(st.speakers.iter()) {
+            // Unknown (null) power counts as none
+            if !spk.power.is_nan() {
+                resp.power += spk.power;
+            }
+            resp.polls += 1;
+            if spk.impedance.is_finite() {
+                resp.impedance += spk.impedance;
+                resp.z_polls += 1;
+            }
+        }
+    }
+
+    // Let the tone drain and the speaker go quiet again before the next channel
+    buf.fill(0);
+    write_all(pcm, &buf, channels).map_err(io::Error::other)?;
+    thread::sleep(Duration::from_secs_f64(SETTLE));
+
+    Ok(responses)
+}
+
+/**
+    Run the self test against the daemon listening on `socket`, playing on
+    the PCM `pcm`. By default there is one output channel per speaker.
+    Returns whether every speaker passed.
+*/
+pub fn selftest(
+    socket: &Path,
+    pcm: &str,
+    channels: Option<usize>,
+    tone: &Tone,
+) -> io::Result<bool> {
+    let st = poll(socket)?;
+    let speakers: Vec<(String, f32)> = st
+        .speakers
+        .iter()
+        .map(|s| (s.name.clone(), s.z_nominal))
+        .collect();
+    let channels = channels.unwrap_or(speakers.len());
+    if speakers.is_empty() || channels == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Nothing to test",
+        ));
+    }
+
+    let (pcm, rate) = open_playback(pcm, channels as u32).map_err(io::Error::other)?;
+    println!(
+        "Playing {} Hz at {:.1} dBFS for {:.1} s on each of {} channels",
+        tone.freq,
+        tone.level(),
+        tone.duration(),
+        channels
+    );
+
+    // responses[channel][speaker]
+    let mut responses = Vec::new();
+    for channel in 0..channels {
+        println!("Channel {}...", channel);
+        responses.push(measure(
+            socket,
+            &pcm,
+            rate,
+            channels,
+            channel,
+            tone,
+            speakers.len(),
+        )?);
+    }
+    let _ = pcm.drain();
+
+    println!();
+    let mut pass = true;
+    for (idx, (name, z_nominal)) in speakers.iter().enumerate() {
+        let mut by_power: Vec<(usize, &Response)> =
+            responses.iter().map(|r| &r[idx]).enumerate().collect();
+        by_power.sort_by(|a, b| b.1.power().total_cmp(&a.1.power()));
+
+        let (channel, resp) = by_power[0];
+        let power = resp.power();
+        let other = by_power.get(1).map(|r| r.1.power()).unwrap_or(0.);
+        let z = resp.impedance();
+
+        let verdict = if power < MIN_POWER {
+            Err("no power seen on any channel, check vs_chan/is_chan".to_string())
+        } else if power < other * MIN_SEPARATION {
+            Err(format!(
+                "responds to channel {} as well, check vs_chan/is_chan",
+                by_power[1].0
+            ))
+        } else if !(z >= z_nominal * Z_RANGE.0 && z <= z_nominal * Z_RANGE.1) {
+            Err(format!(
+                "implausible impedance (nominal {:.2} ohm), check vs_scale/is_scale",
+                z_nominal
+            ))
+        } else {
+            Ok(())
+        };
+
+        println!(
+            "{:>15}: channel {} {:>7.4} W {:>6.2} ohm: {}",
+            name,
+            channel,
+            power,
+            z,
+            match &verdict {
+                Ok(_) => "PASS".to_string(),
+                Err(e) => format!("FAIL ({})", e),
+            }
+        );
+        pass &= verdict.is_ok();
+    }
+
+    Ok(pass)
+}
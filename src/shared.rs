@@ -0,0 +1,151 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    State shared between the protection loop and the helper threads, and
+    the rules for sharing it. The loop may run SCHED_FIFO with a period of
+    a few ms, and anything it waits on must not in turn wait on something
+    slower, least of all a client at the other end of a socket:
+
+    - It never blocks on a lock a client can hold. What it hands out goes
+      through a Snapshot, which it publishes to with try_lock(), skipping
+      the update if a reader is busy serializing the last one.
+    - Requests come in through channels it drains without blocking (see
+      status::Action), and work goes out through bounded channels with
+      try_send() (hooks, the RMS log, the blackbox writer), dropped rather
+      than waited for when a thread falls behind.
+    - The locks it does take every period are PiMutexes, held only for
+      bookkeeping and never across I/O. A helper thread holding one runs
+      at the loop's priority until it lets go, rather than being preempted
+      by whatever else runs at normal priority while the loop waits (the
+      writer thread's queue).
+
+    The audit log and the writer thread's control handle are the
+    exceptions, their locks are held while writing. The loop only takes
+    them for the writes it still does inline, during setup and when
+    restoring controls somebody tampered with.
+
+    Everything else (the speakers, the model, the other controls) belongs
+    to the loop alone.
+*/
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// A value the loop publishes for other threads to read
+pub struct Snapshot<T>(Mutex<T>);
+
+impl<T: Clone> Snapshot<T> {
+    pub fn new(value: &T) -> Snapshot<T> {
+        Snapshot(Mutex::new(value.clone()))
+    }
+
+    /// Replace the value, unless a reader holds it. Never blocks.
+    pub fn publish(&self, value: &T) -> bool {
+        match self.0.try_lock() {
+            Ok(mut shared) => {
+                shared.clone_from(value);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// The last value published, for readers (never the loop)
+    pub fn read(&self) -> MutexGuard<'_, T> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/**
+    A mutex with priority inheritance, for state the loop has to lock
+    every period. Unlike std's, there is no poisoning: the guard unlocks
+    on unwinding too, and state behind it has to stay consistent at every
+    point a panic can happen.
+*/
+pub struct PiMutex<T> {
+    // Boxed, as pthread mutexes must not move once initialized
+    raw: Box<UnsafeCell<libc::pthread_mutex_t>>,
+    data: UnsafeCell<T>,
+}
+
+// SAFETY: Access to data is serialized by the pthread mutex
+unsafe impl<T: Send> Send for PiMutex<T> {}
+unsafe impl<T: Send> Sync for PiMutex<T> {}
+
+impl<T> PiMutex<T> {
+    pub fn new(data: T) -> PiMutex<T> {
+        let raw = Box::new(UnsafeCell::new(libc::PTHREAD_MUTEX_INITIALIZER));
+        let mut attr: libc::pthread_mutexattr_t = unsafe { core::mem::zeroed() };
+        let ret = unsafe {
+            libc::pthread_mutexattr_init(&mut attr);
+            let ret =
+                match libc::pthread_mutexattr_setprotocol(&mut attr, libc::PTHREAD_PRIO_INHERIT) {
+                    0 => libc::pthread_mutex_init(raw.get(), &attr),
+                    e => e,
+                };
+            libc::pthread_mutexattr_destroy(&mut attr);
+            ret
+        };
+        if ret != 0 {
+            panic!(
+                "Could not create a priority inheritance mutex: error {}",
+                ret
+            );
+        }
+
+        PiMutex {
+            raw,
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> PiGuard<'_, T> {
+        let ret = unsafe { libc::pthread_mutex_lock(self.raw.get()) };
+        if ret != 0 {
+            panic!("Could not lock a mutex: error {}", ret);
+        }
+        PiGuard {
+            mutex: self,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl<T: Default> Default for PiMutex<T> {
+    fn default() -> PiMutex<T> {
+        PiMutex::new(T::default())
+    }
+}
+
+impl<T> Drop for PiMutex<T> {
+    fn drop(&mut self) {
+        unsafe { libc::pthread_mutex_destroy(self.raw.get()) };
+    }
+}
+
+pub struct PiGuard<'a, T> {
+    mutex: &'a PiMutex<T>,
+    // Only the thread that locked a mutex may unlock it
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T> Deref for PiGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for PiGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for PiGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe { libc::pthread_mutex_unlock(self.mutex.raw.get()) };
+    }
+}
@@ -0,0 +1,68 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    Startup deadline. Opening the card, setting up its controls and the
+    first read of sense data can each block in the kernel, and a daemon
+    stuck there at boot leaves the speakers on the kernel's limits with
+    nothing in the journal to say why. A watchdog thread exits with
+    the startup timeout status, naming the step we were stuck at, unless startup
+    is done by the deadline.
+*/
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use speakersafetyd_core::exit::Failure;
+
+/// How often the watchdog checks in
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+static STEP: Mutex<&str> = Mutex::new("starting up");
+static DONE: AtomicBool = AtomicBool::new(false);
+
+/// Note what we're about to do, for the timeout message
+pub fn step(what: &'static str) {
+    if let Ok(mut step) = STEP.lock() {
+        *step = what;
+    }
+}
+
+/// Startup is complete, call off the watchdog
+pub fn done(start: Instant) {
+    if !DONE.swap(true, Ordering::Relaxed) {
+        info!("Startup took {:.2} s", start.elapsed().as_secs_f64());
+    }
+}
+
+/// Something failed, call off the watchdog. Returns whether we were still starting up.
+pub fn failed() -> bool {
+    !DONE.swap(true, Ordering::Relaxed)
+}
+
+/// Exit unless done() is called within `timeout` of `start`
+pub fn supervise(start: Instant, timeout: Duration) {
+    thread::Builder::new()
+        .name("startup".into())
+        .spawn(move || {
+            while start.elapsed() < timeout {
+                if DONE.load(Ordering::Relaxed) {
+                    return;
+                }
+                thread::sleep(POLL_INTERVAL);
+            }
+            if DONE.load(Ordering::Relaxed) {
+                return;
+            }
+            let step = STEP.lock().map(|s| *s).unwrap_or("?");
+            warn!(
+                "Startup did not complete within {:.0} s, stuck {}. Exiting.",
+                timeout.as_secs_f64(),
+                step
+            );
+            std::process::exit(Failure::StartupTimeout.code());
+        })
+        .expect("Failed to start the startup watchdog");
+}
@@ -0,0 +1,250 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    Usage statistics. Lifetime statistics are accumulated per speaker across
+    daemon runs and persisted to a small JSON file every so often, so we
+    can tell how hard a given machine's speakers have actually been driven.
+    The gain reduction histograms only cover the current run. The unlock
+    heartbeat interruptions are kept for the machine as a whole.
+*/
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::heartbeat::Interruptions;
+use crate::types::{SpeakerState, MODEL_VERSION};
+
+/// How often to write the statistics out
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+pub const STATS_VERSION: u32 = 1;
+
+/// Upper edges of the gain reduction histogram buckets (dB)
+const GAIN_BUCKETS: [f32; 11] = [0.5, 1., 2., 3., 4., 6., 8., 10., 15., 20., f32::INFINITY];
+
+/// Time spent at each level of gain reduction
+#[derive(Debug, Default, Clone)]
+pub struct GainHistogram {
+    /// Time with no gain reduction (s)
+    pub nominal: f64,
+    /// Time with gain reduction within each of GAIN_BUCKETS (s)
+    pub buckets: [f64; GAIN_BUCKETS.len()],
+}
+
+impl GainHistogram {
+    pub fn add(&mut self, gain: f32, dt: f64) {
+        if gain.is_nan() {
+            return;
+        }
+        if gain >= 0. {
+            self.nominal += dt;
+            return;
+        }
+
+        let idx = GAIN_BUCKETS.iter().position(|&e| -gain <= e).unwrap();
+        self.buckets[idx] += dt;
+    }
+
+    pub fn report(&self) -> HistogramReport {
+        HistogramReport {
+            nominal: self.nominal,
+            buckets: GAIN_BUCKETS
+                .iter()
+                .zip(self.buckets.iter())
+                .map(|(edge, time)| BucketReport {
+                    max_reduction: Some(*edge).filter(|e| e.is_finite()),
+                    time: *time,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// A gain histogram in the status reply, see GainHistogram::report()
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HistogramReport {
+    /// Time with no gain reduction (s)
+    pub nominal: f64,
+    pub buckets: Vec<BucketReport>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BucketReport {
+    /// Upper edge of the bucket (dB), none for the last one
+    pub max_reduction: Option<f32>,
+    /// Time with a gain reduction within the bucket (s)
+    pub time: f64,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SpeakerStats {
+    /// Total energy dissipated in the voice coil (J)
+    pub energy: f64,
+    /// Total time with sense data (s)
+    pub runtime: f64,
+    /// Time spent in the limiter window (s)
+    pub time_above_window: f64,
+    /// Time spent above the temperature limit (s)
+    pub time_above_limit: f64,
+    /// Number of times the limiter kicked in for this speaker
+    pub limiter_engagements: u64,
+
+    #[serde(skip)]
+    limiting: bool,
+}
+
+impl SpeakerStats {
+    /// Forget the statistics that depend on the model, the energy and runtime don't
+    fn reset_model(&mut self) {
+        self.time_above_window = 0.;
+        self.time_above_limit = 0.;
+        self.limiter_engagements = 0;
+    }
+}
+
+/// The statistics file
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+struct StatsFile {
+    version: u32,
+    model_version: u32,
+    speakers: BTreeMap<String, SpeakerStats>,
+    heartbeat: Interruptions,
+}
+
+impl Default for StatsFile {
+    fn default() -> StatsFile {
+        StatsFile {
+            version: STATS_VERSION,
+            // Older files don't say
+            model_version: 1,
+            speakers: BTreeMap::new(),
+            heartbeat: Interruptions::default(),
+        }
+    }
+}
+
+pub struct Stats {
+    path: PathBuf,
+    speakers: BTreeMap<String, SpeakerStats>,
+    /// Heartbeat interruptions before this run, and in it
+    heartbeat: Interruptions,
+    heartbeat_run: Interruptions,
+    last_save: Instant,
+}
+
+impl Stats {
+    pub fn load(path: &Path) -> Stats {
+        let mut file = StatsFile::default();
+
+        let parse = |s: String| {
+            let v: serde_json::Value = serde_json::from_str(&s)?;
+            // Tell an unknown version from a broken file
+            if v["version"].as_u64() != Some(STATS_VERSION.into()) {
+                return Ok(None);
+            }
+            serde_json::from_value(v).map(Some)
+        };
+        match fs::read_to_string(path).map(parse) {
+            Ok(Ok(Some(v))) => {
+                file = v;
+                if file.model_version != MODEL_VERSION {
+                    warn!(
+                        "Usage statistics are from model version {}, this is version {}, resetting the limiter statistics",
+                        file.model_version, MODEL_VERSION
+                    );
+                    file.speakers
+                        .values_mut()
+                        .for_each(SpeakerStats::reset_model);
+                }
+                info!("Loaded usage statistics from {:?}", path);
+            }
+            Ok(Ok(None)) => warn!("Unknown usage statistics version, starting over"),
+            Ok(Err(e)) => warn!("Failed to parse usage statistics: {}", e),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => warn!("Failed to read usage statistics: {}", e),
+        }
+
+        Stats {
+            path: path.into(),
+            speakers: file.speakers,
+            heartbeat: file.heartbeat,
+            heartbeat_run: Default::default(),
+            last_save: Instant::now(),
+        }
+    }
+
+    /**
+        Account for one period of a speaker's operation. `headroom` is the
+        margin to the limiter window, `limit_margin` to the speaker's limits.
+    */
+    pub fn update(
+        &mut self,
+        name: &str,
+        s: &SpeakerState,
+        headroom: f32,
+        limit_margin: f32,
+        dt: f64,
+    ) {
+        let st = self.speakers.entry(name.to_string()).or_default();
+
+        st.energy += s.power as f64 * dt;
+        st.runtime += dt;
+        if headroom < 0. {
+            st.time_above_window += dt;
+        }
+        if limit_margin < 0. {
+            st.time_above_limit += dt;
+        }
+
+        let limiting = s.gain < 0.;
+        if limiting && !st.limiting {
+            st.limiter_engagements += 1;
+        }
+        st.limiting = limiting;
+    }
+
+    /// Note the heartbeat interruptions of this run so far
+    pub fn set_heartbeat(&mut self, run: Interruptions) {
+        self.heartbeat_run = run;
+    }
+
+    /// All the heartbeat interruptions on record, this run included
+    pub fn heartbeat(&self) -> Interruptions {
+        self.heartbeat.add(self.heartbeat_run)
+    }
+
+    /// Save the statistics if it has been long enough since the last save
+    pub fn save_periodic(&mut self) {
+        if self.last_save.elapsed() < SAVE_INTERVAL {
+            return;
+        }
+        self.last_save = Instant::now();
+
+        if let Err(e) = self.save() {
+            warn!("Failed to save usage statistics: {}", e);
+        }
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let out = StatsFile {
+            version: STATS_VERSION,
+            model_version: MODEL_VERSION,
+            speakers: self.speakers.clone(),
+            heartbeat: self.heartbeat(),
+        };
+
+        // Write and rename, so a crash can't leave a truncated file behind
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(&out)?)?;
+        fs::rename(&tmp, &self.path)
+    }
+}
@@ -0,0 +1,1096 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    Status interface. The daemon publishes a snapshot of its state once per
+    period, and a small server thread hands it out to clients connecting to
+    the status socket. The protection loop never blocks on clients: if the
+    snapshot is busy being serialized, that period's update is just skipped.
+
+    Clients running as root or in the control group may also request
+    actions, which are queued for the protection loop, waking it up to act
+    on them. The socket may be passed in by systemd, so clients keep working
+    across daemon restarts.
+
+    A client may also `subscribe`, keeping the connection open to get every
+    new event and a telemetry frame every TELEMETRY_INTERVAL, as JSON lines
+    with a `kind` of "event" or "telemetry". Each subscriber has a queue of
+    its own, and one that doesn't keep up gets a "dropped" line with the
+    count of what it missed, see broadcast.rs.
+*/
+use std::env;
+use std::ffi::CString;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, SendError, Sender, TryIter};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{info, warn, LevelFilter};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::broadcast::Broadcast;
+use crate::caps::CardCapabilities;
+use crate::heartbeat::HeartbeatReport;
+use crate::helpers;
+use crate::history::{EventRecord, History};
+use crate::reactor::Waker;
+use crate::schema::{self, Tag};
+use crate::sense::SenseFault;
+use crate::shared::Snapshot;
+use crate::stats::{GainHistogram, HistogramReport};
+use crate::types::{SpeakerParams, SpeakerState};
+use crate::varlink;
+
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Longest boost a client may ask for (s)
+const MAX_BOOST: f32 = 60.;
+
+const MAX_SUBSCRIBERS: usize = 8;
+/// Messages queued per subscriber before dropping
+const SUBSCRIBER_QUEUE: usize = 64;
+/// How often subscribers get a telemetry frame
+const TELEMETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The requests the socket understands
+pub const REQUESTS: &[&str] = &[
+    "status",
+    "limits",
+    "enable",
+    "disable",
+    "profile",
+    "blackbox",
+    "loglevel",
+    "reload",
+    "boost",
+    "safe",
+    "subscribe",
+];
+
+/// First file descriptor passed by systemd socket activation
+const LISTEN_FDS_START: i32 = 3;
+
+#[derive(Default, Clone)]
+pub struct SpeakerStatus {
+    pub name: String,
+    pub group: usize,
+    pub enabled: bool,
+    pub fault: Option<SenseFault>,
+    pub tamper_count: u64,
+    /// Times the level read back didn't match what was asked for
+    pub level_mismatches: u64,
+    /// Scale check windows where the sense data didn't fit the config
+    pub scale_mismatches: u64,
+    pub state: SpeakerState,
+    /// Temperature margin before the limiter engages (°C)
+    pub headroom: f32,
+    /// Estimated time until the limiter engages at the current power (s)
+    pub time_to_limit: Option<f32>,
+    /// How far the coil and the magnet are from their hard limits (°C)
+    pub margins: (f32, f32),
+    /// Min gain with the user volume at 0 dB (dB)
+    pub min_gain_full: f32,
+    /// Steady state power that takes the speaker to its limit (W)
+    pub max_power: f32,
+    /// Worst case peak power at full scale (W)
+    pub peak_power: f32,
+    pub z_nominal: f32,
+    /// The parsed speaker config, as the daemon sees it
+    pub params: Option<SpeakerParams>,
+}
+
+#[derive(Default, Clone)]
+pub struct GroupStatus {
+    pub group: usize,
+    pub name: Option<String>,
+    pub gain: f32,
+    pub histogram: GainHistogram,
+}
+
+#[derive(Default, Clone)]
+pub struct Status {
+    pub profile: Option<String>,
+    pub profiles: Vec<String>,
+    pub sample_rate: i32,
+    /// No playback, so we're not reading sense data
+    pub idle: bool,
+    pub short_reads: u64,
+    pub empty_reads: u64,
+    /// Updates with a time step that had to be clamped
+    pub timer_anomalies: u64,
+    /// Unlock heartbeat interruptions, see heartbeat::Heartbeat::report()
+    pub heartbeat: Option<HeartbeatReport>,
+    pub groups: Vec<GroupStatus>,
+    pub speakers: Vec<SpeakerStatus>,
+    pub history: History,
+    /// Time left on the current boost (s)
+    pub boost: Option<f32>,
+    /// Everything is held at min gain and the kernel's limits are in effect
+    pub safe_mode: bool,
+    pub config_path: String,
+    /// Hash of the config text that was loaded, see helpers::config_hash()
+    pub config_hash: String,
+    /// What the card's controls can do
+    pub card: Option<CardCapabilities>,
+}
+
+/// The status reply, see Status::report()
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct StatusReply {
+    pub schema: Tag,
+    pub profile: Option<String>,
+    pub profiles: Vec<String>,
+    pub config_path: String,
+    pub config_hash: String,
+    pub card: Option<CardCapabilities>,
+    pub log_level: String,
+    pub sample_rate: i32,
+    pub idle: bool,
+    pub headroom: Option<f32>,
+    pub time_to_limit: Option<f32>,
+    pub gain: Option<f32>,
+    pub boost: Option<f32>,
+    pub safe_mode: bool,
+    pub short_reads: u64,
+    pub empty_reads: u64,
+    pub timer_anomalies: u64,
+    pub heartbeat: Option<HeartbeatReport>,
+    pub groups: Vec<GroupReport>,
+    pub speakers: Vec<SpeakerReport>,
+    pub events: Vec<EventRecord>,
+    /// Number of events ever, to tell which of `events` are new
+    pub event_seq: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct GroupReport {
+    pub group: usize,
+    pub name: Option<String>,
+    #[serde(deserialize_with = "schema::nan")]
+    pub gain: f32,
+    pub histogram: HistogramReport,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct SpeakerReport {
+    pub name: String,
+    pub group: usize,
+    pub enabled: bool,
+    pub fault: Option<String>,
+    pub tamper_count: u64,
+    pub level_mismatches: u64,
+    pub scale_mismatches: u64,
+    #[serde(deserialize_with = "schema::nan")]
+    pub t_coil: f64,
+    #[serde(deserialize_with = "schema::nan")]
+    pub t_magnet: f64,
+    #[serde(deserialize_with = "schema::nan")]
+    pub t_ambient: f32,
+    #[serde(deserialize_with = "schema::nan")]
+    pub min_gain: f32,
+    #[serde(deserialize_with = "schema::nan")]
+    pub gain: f32,
+    #[serde(deserialize_with = "schema::nan")]
+    pub power: f32,
+    /// NaN (null) while idle
+    #[serde(deserialize_with = "schema::nan")]
+    pub impedance: f32,
+    #[serde(deserialize_with = "schema::nan")]
+    pub z_nominal: f32,
+    pub amp_fault: i32,
+    #[serde(deserialize_with = "schema::nan")]
+    pub headroom: f32,
+    pub time_to_limit: Option<f32>,
+    #[serde(deserialize_with = "schema::nan")]
+    pub margin_coil: f32,
+    #[serde(deserialize_with = "schema::nan")]
+    pub margin_magnet: f32,
+    #[serde(deserialize_with = "schema::nan")]
+    pub min_gain_full: f32,
+    #[serde(deserialize_with = "schema::nan")]
+    pub max_power: f32,
+    #[serde(deserialize_with = "schema::nan")]
+    pub peak_power: f32,
+    pub params: Option<SpeakerParams>,
+}
+
+/// The limits reply, see Status::limits()
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct LimitsReply {
+    pub schema: Tag,
+    /// The most any speaker may be taken down
+    pub min_gain: Option<f32>,
+    pub headroom: Option<f32>,
+    pub gain: Option<f32>,
+    pub speakers: Vec<SpeakerLimits>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct SpeakerLimits {
+    pub name: String,
+    pub group: usize,
+    pub enabled: bool,
+    /// How far the limiter may take the speaker down at the current volume
+    #[serde(deserialize_with = "schema::nan")]
+    pub min_gain: f32,
+    #[serde(deserialize_with = "schema::nan")]
+    pub min_gain_full: f32,
+    #[serde(deserialize_with = "schema::nan")]
+    pub max_power: f32,
+    #[serde(deserialize_with = "schema::nan")]
+    pub peak_power: f32,
+    #[serde(deserialize_with = "schema::nan")]
+    pub margin_coil: f32,
+    #[serde(deserialize_with = "schema::nan")]
+    pub margin_magnet: f32,
+    #[serde(deserialize_with = "schema::nan")]
+    pub headroom: f32,
+    pub time_to_limit: Option<f32>,
+}
+
+/// A speaker in telemetry frames and reports
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct SpeakerTelemetry {
+    pub name: String,
+    pub enabled: bool,
+    pub fault: Option<String>,
+    #[serde(deserialize_with = "schema::nan")]
+    pub t_coil: f64,
+    #[serde(deserialize_with = "schema::nan")]
+    pub t_magnet: f64,
+    #[serde(deserialize_with = "schema::nan")]
+    pub power: f32,
+    #[serde(deserialize_with = "schema::nan")]
+    pub gain: f32,
+}
+
+impl From<&SpeakerReport> for SpeakerTelemetry {
+    fn from(spk: &SpeakerReport) -> SpeakerTelemetry {
+        SpeakerTelemetry {
+            name: spk.name.clone(),
+            enabled: spk.enabled,
+            fault: spk.fault.clone(),
+            t_coil: spk.t_coil,
+            t_magnet: spk.t_magnet,
+            power: spk.power,
+            gain: spk.gain,
+        }
+    }
+}
+
+/// A telemetry frame for subscribers, see Status::telemetry()
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct TelemetryFrame {
+    pub idle: bool,
+    pub safe_mode: bool,
+    pub headroom: Option<f32>,
+    pub gain: Option<f32>,
+    pub speakers: Vec<SpeakerTelemetry>,
+}
+
+/// A line sent to subscribers
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum FeedLine {
+    Event(EventRecord),
+    Telemetry(TelemetryFrame),
+    /// Lines the subscriber missed for not keeping up
+    Dropped {
+        count: u64,
+    },
+}
+
+/// The reply to a request on the status socket
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub enum Reply {
+    Status(Box<StatusReply>),
+    Limits(LimitsReply),
+    /// An action was queued
+    Done {
+        ok: bool,
+    },
+    Error {
+        error: String,
+    },
+}
+
+impl Reply {
+    fn error(error: impl Into<String>) -> Reply {
+        Reply::Error {
+            error: error.into(),
+        }
+    }
+}
+
+impl Status {
+    /// The smallest temperature margin of any active speaker
+    pub fn headroom(&self) -> Option<f32> {
+        self.speakers
+            .iter()
+            .filter(|s| s.enabled)
+            .map(|s| s.headroom)
+            .reduce(f32::min)
+    }
+
+    /// The soonest any active speaker will start limiting
+    pub fn time_to_limit(&self) -> Option<f32> {
+        self.speakers
+            .iter()
+            .filter_map(|s| s.time_to_limit)
+            .reduce(f32::min)
+    }
+
+    /// The lowest group gain, i.e. the strongest limiting in effect
+    pub fn gain(&self) -> Option<f32> {
+        self.groups.iter().map(|g| g.gain).reduce(f32::min)
+    }
+
+    /**
+        The limits each speaker is held to, for audio configuration tools
+        to set their pre-gain by without redoing the math. min_gain is how
+        far the limiter may take a speaker down at the current volume, and
+        the overall min_gain the most any speaker may be taken down.
+    */
+    pub fn limits(&self) -> LimitsReply {
+        let speakers = self
+            .speakers
+            .iter()
+            .map(|spk| SpeakerLimits {
+                name: spk.name.clone(),
+                group: spk.group,
+                enabled: spk.enabled,
+                min_gain: spk.state.min_gain,
+                min_gain_full: spk.min_gain_full,
+                max_power: spk.max_power,
+                peak_power: spk.peak_power,
+                margin_coil: spk.margins.0,
+                margin_magnet: spk.margins.1,
+                headroom: spk.headroom,
+                time_to_limit: spk.time_to_limit,
+            })
+            .collect();
+
+        LimitsReply {
+            schema: schema::tag("limits", schema::LIMITS),
+            min_gain: self
+                .speakers
+                .iter()
+                .map(|s| s.state.min_gain)
+                .reduce(f32::min),
+            headroom: self.headroom(),
+            gain: self.gain(),
+            speakers,
+        }
+    }
+
+    pub fn report(&self) -> StatusReply {
+        let speakers = self
+            .speakers
+            .iter()
+            .map(|spk| SpeakerReport {
+                name: spk.name.clone(),
+                group: spk.group,
+                enabled: spk.enabled,
+                fault: spk.fault.map(|f| f.to_string()),
+                tamper_count: spk.tamper_count,
+                level_mismatches: spk.level_mismatches,
+                scale_mismatches: spk.scale_mismatches,
+                t_coil: spk.state.t_coil,
+                t_magnet: spk.state.t_magnet,
+                t_ambient: spk.state.t_ambient,
+                min_gain: spk.state.min_gain,
+                gain: spk.state.gain,
+                power: spk.state.power,
+                impedance: spk.state.impedance,
+                z_nominal: spk.z_nominal,
+                amp_fault: spk.state.amp_fault,
+                headroom: spk.headroom,
+                time_to_limit: spk.time_to_limit,
+                margin_coil: spk.margins.0,
+                margin_magnet: spk.margins.1,
+                min_gain_full: spk.min_gain_full,
+                max_power: spk.max_power,
+                peak_power: spk.peak_power,
+                params: spk.params.clone(),
+            })
+            .collect();
+
+        let groups = self
+            .groups
+            .iter()
+            .map(|grp| GroupReport {
+                group: grp.group,
+                name: grp.name.clone(),
+                gain: grp.gain,
+                histogram: grp.histogram.report(),
+            })
+            .collect();
+
+        StatusReply {
+            schema: schema::tag("status", schema::STATUS),
+            profile: self.profile.clone(),
+            profiles: self.profiles.clone(),
+            config_path: self.config_path.clone(),
+            config_hash: self.config_hash.clone(),
+            card: self.card.clone(),
+            log_level: log::max_level().to_string().to_lowercase(),
+            sample_rate: self.sample_rate,
+            idle: self.idle,
+            headroom: self.headroom(),
+            time_to_limit: self.time_to_limit(),
+            gain: self.gain(),
+            boost: self.boost,
+            safe_mode: self.safe_mode,
+            short_reads: self.short_reads,
+            empty_reads: self.empty_reads,
+            timer_anomalies: self.timer_anomalies,
+            heartbeat: self.heartbeat.clone(),
+            groups,
+            speakers,
+            events: self.history.records(),
+            event_seq: self.history.seq(),
+        }
+    }
+
+    /// A telemetry frame for subscribers: the overall state and each speaker's
+    pub fn telemetry(&self) -> TelemetryFrame {
+        TelemetryFrame {
+            idle: self.idle,
+            safe_mode: self.safe_mode,
+            headroom: self.headroom(),
+            gain: self.gain(),
+            speakers: self
+                .speakers
+                .iter()
+                .map(|spk| SpeakerTelemetry {
+                    name: spk.name.clone(),
+                    enabled: spk.enabled,
+                    fault: spk.fault.map(|f| f.to_string()),
+                    t_coil: spk.state.t_coil,
+                    t_magnet: spk.state.t_magnet,
+                    power: spk.state.power,
+                    gain: spk.state.gain,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Requests from clients that the protection loop needs to act on
+#[derive(Debug)]
+pub enum Action {
+    Enable(String),
+    Disable(String),
+    /// Switch to the given config profile (None for the default) and restart
+    SetProfile(Option<String>),
+    /// Save the blackbox now
+    TriggerBlackbox,
+    SetLogLevel(LevelFilter),
+    /// Restart, picking up any config changes
+    Reload,
+    /// Relax the limiter for this many seconds, if there is the headroom.
+    /// The verdict goes back to the client.
+    Boost(f32, Sender<Result<(), String>>),
+    /// Hold everything at min gain and stop unlocking the kernel's limits
+    /// (or go back to normal operation)
+    SafeMode(bool),
+}
+
+/// Queues actions for the protection loop and wakes it up
+#[derive(Clone)]
+pub struct ActionQueue {
+    tx: Sender<Action>,
+    waker: Waker,
+}
+
+impl ActionQueue {
+    fn send(&self, action: Action) -> Result<(), SendError<Action>> {
+        self.tx.send(action)?;
+        self.waker.wake();
+        Ok(())
+    }
+}
+
+pub struct StatusServer {
+    shared: Arc<Snapshot<Status>>,
+    actions: Receiver<Action>,
+    /// For other front ends to queue actions with
+    tx: ActionQueue,
+    control_gid: Option<u32>,
+    /// Serialized events and telemetry frames, for subscribers
+    feed: Arc<Broadcast<Arc<str>>>,
+    /// Last event sent to subscribers
+    feed_seq: u64,
+    last_telemetry: Option<Instant>,
+}
+
+/// The listening socket passed in by systemd, if any
+fn activated_listener() -> Option<UnixListener> {
+    let pid: u32 = env::var("LISTEN_PID").ok()?.parse().ok()?;
+    let fds: i32 = env::var("LISTEN_FDS").ok()?.parse().ok()?;
+
+    if pid != std::process::id() || fds < 1 {
+        return None;
+    }
+
+    Some(unsafe { UnixListener::from_raw_fd(LISTEN_FDS_START) })
+}
+
+fn lookup_group(group: &str) -> Option<u32> {
+    let cgroup = CString::new(group).ok()?;
+    let mut grp: libc::group = unsafe { core::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let mut result: *mut libc::group = core::ptr::null_mut();
+
+    let ret = unsafe {
+        libc::getgrnam_r(
+            cgroup.as_ptr(),
+            &mut grp,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if ret != 0 || result.is_null() {
+        return None;
+    }
+
+    Some(grp.gr_gid)
+}
+
+impl StatusServer {
+    /**
+        Start serving status on `path` (or the socket passed in by systemd).
+        Members of `control_group` may request actions, besides root, and
+        `waker` gets the protection loop to them.
+    */
+    pub fn new(
+        path: &Path,
+        status: &Status,
+        control_group: &str,
+        waker: Waker,
+    ) -> io::Result<StatusServer> {
+        let listener = match activated_listener() {
+            Some(listener) => {
+                info!("Status socket: passed in by systemd");
+                listener
+            }
+            None => {
+                // Clean up after a previous instance that did not exit cleanly
+                let _ = fs::remove_file(path);
+                let listener = UnixListener::bind(path)?;
+                fs::set_permissions(path, fs::Permissions::from_mode(0o666))?;
+                info!("Status socket: {:?}", path);
+                listener
+            }
+        };
+
+        let control_gid = lookup_group(control_group);
+        if control_gid.is_none() {
+            info!(
+                "Control group '{}' does not exist, only root may request actions",
+                control_group
+            );
+        }
+
+        let shared = Arc::new(Snapshot::new(status));
+        let server = Arc::clone(&shared);
+        let (tx, actions) = mpsc::channel();
+        let tx = ActionQueue { tx, waker };
+        let server_tx = tx.clone();
+        let feed = Arc::new(Broadcast::new(MAX_SUBSCRIBERS, SUBSCRIBER_QUEUE));
+        let server_feed = Arc::clone(&feed);
+
+        thread::Builder::new()
+            .name("status".into())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => {
+                            if let Err(e) = handle_client(
+                                stream,
+                                &server,
+                                &server_tx,
+                                &server_feed,
+                                control_gid,
+                            ) {
+                                warn!("Status client error: {}", e);
+                            }
+                        }
+                        Err(e) => warn!("Status socket accept failed: {}", e),
+                    }
+                }
+            })?;
+
+        Ok(StatusServer {
+            shared,
+            actions,
+            tx,
+            control_gid,
+            feed_seq: status.history.seq(),
+            feed,
+            last_telemetry: None,
+        })
+    }
+
+    /// Also serve the varlink interface on `path`, see varlink.rs
+    pub fn serve_varlink(&self, path: &Path) -> io::Result<()> {
+        varlink::serve(
+            path,
+            Arc::clone(&self.shared),
+            self.tx.clone(),
+            self.control_gid,
+        )
+    }
+
+    pub fn publish(&self, status: &Status) {
+        self.shared.publish(status);
+    }
+
+    /// Send subscribers the events since the last call, and telemetry when due
+    pub fn broadcast(&mut self, status: &Status, now: Instant) {
+        let seq = status.history.seq();
+        let new = seq.saturating_sub(self.feed_seq) as usize;
+        self.feed_seq = seq;
+        if self.feed.is_empty() {
+            return;
+        }
+
+        for (time, event) in status.history.last(new) {
+            let line = FeedLine::Event(EventRecord {
+                time: time.to_rfc3339(),
+                event: event.to_string(),
+            });
+            self.feed.send(&schema::dump(&line).into());
+        }
+
+        if self
+            .last_telemetry
+            .is_none_or(|t| now - t >= TELEMETRY_INTERVAL)
+        {
+            self.last_telemetry = Some(now);
+            let line = FeedLine::Telemetry(status.telemetry());
+            self.feed.send(&schema::dump(&line).into());
+        }
+    }
+
+    /// Pending client actions, without blocking
+    pub fn actions(&self) -> TryIter<'_, Action> {
+        self.actions.try_iter()
+    }
+}
+
+fn peer_cred(stream: &UnixStream) -> Option<libc::ucred> {
+    let mut cred: libc::ucred = unsafe { core::mem::zeroed() };
+    let mut len = core::mem::size_of::<libc::ucred>() as libc::socklen_t;
+
+    if unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    } != 0
+    {
+        return None;
+    }
+
+    Some(cred)
+}
+
+/// The supplementary groups of the peer, as of when it connected
+fn peer_groups(stream: &UnixStream) -> Option<Vec<libc::gid_t>> {
+    let mut groups: Vec<libc::gid_t> = vec![0; 64];
+
+    loop {
+        let mut len = (groups.len() * core::mem::size_of::<libc::gid_t>()) as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERGROUPS,
+                groups.as_mut_ptr() as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        let count = len as usize / core::mem::size_of::<libc::gid_t>();
+
+        if ret == 0 {
+            groups.truncate(count);
+            return Some(groups);
+        }
+        // The kernel tells us how much space it needs
+        if io::Error::last_os_error().raw_os_error() != Some(libc::ERANGE) || count <= groups.len()
+        {
+            return None;
+        }
+        groups.resize(count, 0);
+    }
+}
+
+/// Whether the peer may request actions: root or a member of the control group
+fn authorized(stream: &UnixStream, control_gid: Option<u32>) -> bool {
+    let cred = match peer_cred(stream) {
+        Some(cred) => cred,
+        None => return false,
+    };
+
+    if cred.uid == 0 {
+        return true;
+    }
+
+    match control_gid {
+        Some(gid) if cred.gid == gid => true,
+        Some(gid) => peer_groups(stream).is_some_and(|g| g.contains(&gid)),
+        None => false,
+    }
+}
+
+fn handle_action(
+    stream: &UnixStream,
+    status: &Snapshot<Status>,
+    tx: &ActionQueue,
+    control_gid: Option<u32>,
+    action: Action,
+) -> Reply {
+    if !authorized(stream, control_gid) {
+        return Reply::error("Permission denied");
+    }
+
+    let status = status.read();
+    match &action {
+        Action::Enable(name) | Action::Disable(name)
+            if !status.speakers.iter().any(|s| &s.name == name) =>
+        {
+            return Reply::error(format!("Unknown speaker '{}'", name));
+        }
+        Action::SetProfile(Some(profile)) if !status.profiles.contains(profile) => {
+            return Reply::error(format!("Unknown profile '{}'", profile));
+        }
+        _ => {}
+    }
+    drop(status);
+
+    info!("Client requested {:?}", action);
+    match tx.send(action) {
+        Ok(_) => Reply::Done { ok: true },
+        Err(_) => Reply::error("Daemon is shutting down"),
+    }
+}
+
+/**
+    Hand `stream` over to a thread of its own that passes on what `feed`
+    sends, until the client hangs up or stops reading for CLIENT_TIMEOUT.
+*/
+fn subscribe(stream: UnixStream, feed: &Broadcast<Arc<str>>) -> io::Result<()> {
+    let Some(sub) = feed.subscribe() else {
+        let reply = Reply::error("Too many subscribers");
+        return (&stream).write_all(schema::dump(&reply).as_bytes());
+    };
+
+    thread::Builder::new()
+        .name("subscriber".into())
+        .spawn(move || {
+            while let Some(msg) = sub.recv() {
+                let mut out = String::new();
+                let dropped = sub.take_dropped();
+                if dropped > 0 {
+                    out += &schema::dump(&FeedLine::Dropped { count: dropped });
+                    out.push('\n');
+                }
+                out += &msg;
+                out.push('\n');
+                if (&stream).write_all(out.as_bytes()).is_err() {
+                    break;
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+fn handle_client(
+    stream: UnixStream,
+    status: &Snapshot<Status>,
+    tx: &ActionQueue,
+    feed: &Broadcast<Arc<str>>,
+    control_gid: Option<u32>,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+
+    if request.trim() == "subscribe" {
+        return subscribe(stream, feed);
+    }
+
+    let reply = handle_request(&stream, request.trim(), status, tx, control_gid);
+    (&stream).write_all(schema::dump(&reply).as_bytes())
+}
+
+/// Answer a request, on behalf of the client at the other end of `stream`
+pub fn handle_request(
+    stream: &UnixStream,
+    request: &str,
+    status: &Snapshot<Status>,
+    tx: &ActionQueue,
+    control_gid: Option<u32>,
+) -> Reply {
+    let action = |a| handle_action(stream, status, tx, control_gid, a);
+
+    match request.split_once(' ') {
+        None if request == "status" => Reply::Status(Box::new(status.read().report())),
+        None if request == "limits" => Reply::Limits(status.read().limits()),
+        None if request == "profile" => action(Action::SetProfile(None)),
+        None if request == "blackbox" => action(Action::TriggerBlackbox),
+        None if request == "reload" => action(Action::Reload),
+        None if request == "safe" => action(Action::SafeMode(true)),
+        Some(("safe", "off")) => action(Action::SafeMode(false)),
+        Some(("enable", name)) => action(Action::Enable(name.into())),
+        Some(("disable", name)) => action(Action::Disable(name.into())),
+        Some(("profile", name)) => action(Action::SetProfile(Some(name.into()))),
+        Some(("loglevel", level)) => match level.parse() {
+            Ok(level) => action(Action::SetLogLevel(level)),
+            Err(_) => Reply::error(format!("Unknown log level '{}'", level)),
+        },
+        Some(("boost", seconds)) => match seconds.parse::<f32>() {
+            Ok(seconds) if seconds > 0. && seconds <= MAX_BOOST => {
+                let (reply_tx, reply) = mpsc::channel();
+                let ret = action(Action::Boost(seconds, reply_tx));
+                if !matches!(ret, Reply::Done { ok: true }) {
+                    ret
+                } else {
+                    // The protection loop answers within a period or so
+                    match reply.recv_timeout(CLIENT_TIMEOUT) {
+                        Ok(Ok(())) => ret,
+                        Ok(Err(e)) => Reply::error(e),
+                        Err(_) => Reply::error("No answer from the daemon"),
+                    }
+                }
+            }
+            _ => {
+                let error = format!("Invalid boost duration '{}' (max {} s)", seconds, MAX_BOOST);
+                Reply::error(error)
+            }
+        },
+        _ => Reply::error(format!("Unknown request '{}'", request)),
+    }
+}
+
+/// Subscribe to the running daemon and print what it sends, as JSON lines
+pub fn follow(path: &Path) -> io::Result<()> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.write_all(b"subscribe\n")?;
+
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if let Ok(Failure { error }) = serde_json::from_str(&line) {
+            return Err(io::Error::other(error));
+        }
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+/// What the daemon answers a request it can't fulfill with
+#[derive(Deserialize)]
+struct Failure {
+    error: String,
+}
+
+/**
+    Send a request to the running daemon and return the parsed reply, or
+    the error the daemon answered with. Requests that only queue an action
+    can take any reply, e.g. serde::de::IgnoredAny.
+*/
+pub fn query<T: DeserializeOwned>(path: &Path, request: &str) -> io::Result<Result<T, String>> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.write_all(request.as_bytes())?;
+    stream.write_all(b"\n")?;
+
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+
+    if let Ok(Failure { error }) = serde_json::from_str(&reply) {
+        return Ok(Err(error));
+    }
+    serde_json::from_str(&reply)
+        .map(Ok)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// The number and name of a group in a status reply
+pub fn group_label(grp: &GroupReport) -> String {
+    helpers::group_label(grp.group, grp.name.as_deref())
+}
+
+/// Pretty-print a status reply for humans.
+pub fn print_status(status: &StatusReply) {
+    println!(
+        "Profile: {} (available: {})",
+        status.profile.as_deref().unwrap_or("default"),
+        status.profiles.join(", ")
+    );
+    if !status.config_path.is_empty() {
+        let (path, loaded) = (&status.config_path, &status.config_hash);
+        // Catch configs edited (or reinstalled) since the daemon loaded them
+        match fs::read_to_string(path) {
+            Ok(text) if helpers::config_hash(&text) == *loaded => {
+                println!("Config: {} ({})", path, loaded)
+            }
+            Ok(_) => println!(
+                "Config: {} ({}, changed on disk since it was loaded!)",
+                path, loaded
+            ),
+            Err(_) => println!("Config: {} ({}, not readable here)", path, loaded),
+        }
+    }
+    for discrepancy in status.card.iter().flat_map(|c| c.discrepancies()) {
+        println!("Hardware discrepancy: {}", discrepancy);
+    }
+    println!("Log level: {}", status.log_level);
+    println!(
+        "Sample rate: {} Hz{}",
+        status.sample_rate,
+        if status.idle { " (idle)" } else { "" }
+    );
+    if let Some(headroom) = status.headroom {
+        println!("Headroom: {:.1} °C", headroom);
+    }
+    if status.safe_mode {
+        println!("Safe mode: on, speakers held at min gain");
+    }
+    if let Some(boost) = status.boost {
+        println!("Boost: {:.0} s left", boost);
+    }
+    match status.time_to_limit {
+        Some(ttl) if ttl > 0. => println!("Limiting in: ~{:.0} s at current power", ttl),
+        Some(_) => println!("Limiting in: now"),
+        None => {}
+    }
+    println!(
+        "Short reads: {} ({} empty)",
+        status.short_reads, status.empty_reads
+    );
+    if status.timer_anomalies > 0 {
+        println!("Timer anomalies: {}", status.timer_anomalies);
+    }
+    if let Some(hb) = status.heartbeat.as_ref() {
+        let lifetime = hb.lifetime.unwrap_or_default();
+        if hb.counts.failed > 0 || hb.counts.withheld > 0 || lifetime.failed > 0 {
+            let mut line = format!(
+                "Heartbeat interruptions: {} failed, {} withheld",
+                hb.counts.failed, hb.counts.withheld
+            );
+            if hb.lifetime.is_some() {
+                line += &format!(
+                    " ({} failed, {} withheld ever)",
+                    lifetime.failed, lifetime.withheld
+                );
+            }
+            println!("{}", line);
+        }
+        for control in hb.interrupted.iter() {
+            println!("Kernel limits may apply: {} not refreshed", control);
+        }
+    }
+
+    for grp in status.groups.iter() {
+        println!("Group {}: Gain {:>6.2} dB", group_label(grp), grp.gain);
+
+        let hist = &grp.histogram;
+        let mut lower = 0.;
+        println!("    {:>12}: {:>10.1} s", "nominal", hist.nominal);
+        for bucket in hist.buckets.iter() {
+            let range = match bucket.max_reduction {
+                Some(upper) => format!("{}..{} dB", lower, upper),
+                None => format!(">{} dB", lower),
+            };
+            println!("    {:>12}: {:>10.1} s", range, bucket.time);
+            lower = bucket.max_reduction.unwrap_or(f32::INFINITY);
+        }
+    }
+
+    for spk in status.speakers.iter() {
+        println!(
+            "{:>15} (group {}): Coil {:>6.2} °C Magnet {:>6.2} °C Power {:>5.2} W Gain {:>6.2} dB{}",
+            spk.name,
+            match spk.params.as_ref().and_then(|p| p.group_name.as_ref()) {
+                Some(name) => name.to_string(),
+                None => spk.group.to_string(),
+            },
+            spk.t_coil,
+            spk.t_magnet,
+            spk.power,
+            spk.gain,
+            match (spk.enabled, spk.fault.as_ref()) {
+                (_, Some(fault)) => format!(" (quarantined: {})", fault),
+                (false, _) => " (disabled)".into(),
+                _ => "".into(),
+            } + &match spk.amp_fault {
+                0 => "".into(),
+                fault => format!(" (amp fault 0x{:x})", fault),
+            } + &match spk.level_mismatches {
+                0 => "".into(),
+                n => format!(" ({} level mismatches)", n),
+            } + &match spk.scale_mismatches {
+                0 => "".into(),
+                n => format!(" ({} scale mismatches)", n),
+            },
+        );
+    }
+}
+
+/// Pretty-print a limits reply for humans.
+pub fn print_limits(limits: &LimitsReply) {
+    println!(
+        "Min gain: {:.2} dB, gain {:.2} dB, headroom {:.1} °C",
+        limits.min_gain.unwrap_or(f32::NAN),
+        limits.gain.unwrap_or(f32::NAN),
+        limits.headroom.unwrap_or(f32::NAN),
+    );
+    println!(
+        "{:<16} {:>9} {:>9} {:>8} {:>8} {:>8} {:>8}",
+        "Speaker", "Min gain", "At 0 dB", "Max W", "Peak W", "Coil", "Magnet"
+    );
+    for spk in limits.speakers.iter() {
+        println!(
+            "{:<16} {:>6.2} dB {:>6.2} dB {:>8.2} {:>8.2} {:>5.1} °C {:>5.1} °C{}",
+            spk.name,
+            spk.min_gain,
+            spk.min_gain_full,
+            spk.max_power,
+            spk.peak_power,
+            spk.margin_coil,
+            spk.margin_magnet,
+            if spk.enabled { "" } else { " (disabled)" },
+        );
+    }
+}
+
+/// Print the event history from a status reply.
+pub fn print_events(status: &StatusReply) {
+    println!("Events:");
+    for ev in status.events.iter() {
+        println!("    {}: {}", ev.time, ev.event);
+    }
+}
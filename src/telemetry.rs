@@ -0,0 +1,167 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    Remote telemetry, for headless machines whose speakers nobody listens
+    to. Like the PipeWire bridge, this polls the daemon over the status
+    socket, so the daemon itself never touches the network. Every interval,
+    the speaker temperatures and any new events are POSTed as JSON to an
+    HTTP endpoint, from where a monitoring stack can alert on them. Only
+    plain HTTP is spoken, use a local relay for anything fancier.
+*/
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::history::EventRecord;
+use crate::schema::{self, Tag};
+use crate::status::{self, SpeakerTelemetry, StatusReply};
+
+/// A report, see run_publisher()
+#[derive(Serialize, Default)]
+struct Report {
+    schema: Tag,
+    host: String,
+    running: bool,
+    /// Only while the daemon is reachable
+    #[serde(flatten)]
+    daemon: Option<DaemonReport>,
+}
+
+#[derive(Serialize)]
+struct DaemonReport {
+    gain: Option<f32>,
+    headroom: Option<f32>,
+    speakers: Vec<SpeakerTelemetry>,
+    events: Vec<EventRecord>,
+}
+
+/// Timeout for connecting and talking to the endpoint
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Endpoint {
+    /// host:port
+    addr: String,
+    host: String,
+    path: String,
+}
+
+impl Endpoint {
+    fn parse(url: &str) -> io::Result<Endpoint> {
+        let rest = url.strip_prefix("http://").ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Only http:// URLs are supported",
+            )
+        })?;
+        let (host, path) = match rest.find('/') {
+            Some(idx) => (&rest[..idx], &rest[idx..]),
+            None => (rest, "/"),
+        };
+        if host.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "No host in URL",
+            ));
+        }
+
+        Ok(Endpoint {
+            addr: if host.contains(':') {
+                host.to_string()
+            } else {
+                host.to_string() + ":80"
+            },
+            host: host.to_string(),
+            path: path.to_string(),
+        })
+    }
+
+    fn post(&self, body: &str) -> io::Result<()> {
+        let mut stream = TcpStream::connect(&self.addr)?;
+        stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+        stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.path,
+            self.host,
+            body.len(),
+            body
+        )?;
+
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply)?;
+        let code = reply
+            .split_whitespace()
+            .nth(1)
+            .and_then(|c| c.parse::<u32>().ok())
+            .unwrap_or(0);
+        if !(200..300).contains(&code) {
+            return Err(io::Error::other(format!(
+                "Endpoint replied: {}",
+                reply.lines().next().unwrap_or("nothing")
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// The events in `st` we haven't sent yet
+fn new_events(st: &StatusReply, last_seq: Option<u64>) -> Vec<EventRecord> {
+    let seq = st.event_seq;
+    let events = &st.events;
+    let count = match last_seq {
+        // On startup, only report what happens from now on
+        None => 0,
+        Some(last) if seq >= last => ((seq - last) as usize).min(events.len()),
+        // The daemon restarted
+        Some(_) => events.len(),
+    };
+
+    events[events.len() - count..].to_vec()
+}
+
+/**
+    Publish until killed. Each report has the overall `gain` and `headroom`,
+    the temperatures, power and gain of every speaker, and the `events`
+    since the last report. An unreachable daemon is reported as such.
+*/
+pub fn run_publisher(socket: &Path, url: &str, interval: Duration) -> io::Result<()> {
+    let endpoint = Endpoint::parse(url)?;
+    let host = std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|h| h.trim().to_string())
+        .unwrap_or_default();
+    let mut last_seq = None;
+
+    loop {
+        let mut report = Report {
+            schema: schema::tag("telemetry", schema::TELEMETRY),
+            host: host.clone(),
+            ..Default::default()
+        };
+        let mut seq = last_seq;
+        if let Ok(Ok(st)) = status::query::<StatusReply>(socket, "status") {
+            report.running = true;
+            report.daemon = Some(DaemonReport {
+                gain: st.gain,
+                headroom: st.headroom,
+                speakers: st.speakers.iter().map(SpeakerTelemetry::from).collect(),
+                events: new_events(&st, last_seq),
+            });
+            seq = Some(st.event_seq);
+        }
+
+        // Events that didn't make it out are sent with the next report
+        match endpoint.post(&schema::dump(&report)) {
+            Ok(_) => last_seq = seq,
+            Err(e) => eprintln!("Failed to publish telemetry: {}", e),
+        }
+
+        thread::sleep(interval);
+    }
+}
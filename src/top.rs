@@ -0,0 +1,184 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    `speakersafetyd top`, a live dashboard of the running daemon for tuning
+    configs on the target machine (usually over SSH). It's purely a client
+    of the status socket, polling it about once per period and redrawing
+    the terminal with plain ANSI escapes.
+
+    The temperature bars fill up towards the point where the limiter
+    engages, whichever of the coil and the magnet gets there first, and the
+    gain bars towards each speaker's minimum gain.
+*/
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::status::{self, StatusReply};
+
+/// Bars only start filling up above this (°C), below it nothing is warm
+const T_FLOOR: f64 = 20.;
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const RED: &str = "\x1b[31m";
+
+/// Width of everything on a speaker line but the two bars
+const FIXED_WIDTH: usize = 60;
+
+fn term_width() -> usize {
+    let mut ws: libc::winsize = unsafe { std::mem::zeroed() };
+    match unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut ws) } {
+        0 if ws.ws_col > 0 => ws.ws_col as usize,
+        _ => 80,
+    }
+}
+
+/// A `width` wide bar filled to `frac`, green to red as it fills up
+fn bar(frac: f64, width: usize) -> String {
+    let frac = if frac.is_finite() {
+        frac.clamp(0., 1.)
+    } else {
+        0.
+    };
+    let filled = (frac * width as f64).round() as usize;
+    let color = match frac {
+        f if f >= 0.9 => RED,
+        f if f >= 0.7 => YELLOW,
+        _ => GREEN,
+    };
+
+    format!(
+        "[{}{}{}{}]",
+        color,
+        "#".repeat(filled),
+        RESET,
+        ".".repeat(width - filled)
+    )
+}
+
+/// One screenful for the status reply `status`, `cols` columns wide
+fn render(status: &StatusReply, cols: usize) -> String {
+    let bar_width = (cols.saturating_sub(FIXED_WIDTH) / 2).clamp(10, 40);
+    let mut out = String::new();
+
+    let _ = writeln!(
+        out,
+        "{}speakersafetyd top{} | {} Hz{} | profile {} | log {}",
+        BOLD,
+        RESET,
+        status.sample_rate,
+        if status.idle { " (idle)" } else { "" },
+        status.profile.as_deref().unwrap_or("default"),
+        status.log_level,
+    );
+
+    let mut summary = format!(
+        "Headroom {:.1} °C | Gain {:.2} dB",
+        status.headroom.unwrap_or(f32::NAN),
+        status.gain.unwrap_or(f32::NAN),
+    );
+    match status.time_to_limit {
+        Some(ttl) if ttl > 0. => summary += &format!(" | Limiting in ~{:.0} s", ttl),
+        Some(_) => summary += &format!(" | {}Limiting{}", RED, RESET),
+        None => {}
+    }
+    if let Some(boost) = status.boost {
+        summary += &format!(" | Boost {:.0} s left", boost);
+    }
+    summary += &format!(
+        " | Short reads {} ({} empty)",
+        status.short_reads, status.empty_reads
+    );
+    let _ = writeln!(out, "{}", summary);
+
+    for grp in status.groups.iter() {
+        let _ = write!(
+            out,
+            "\nGroup {}: Gain {:>6.2} dB",
+            status::group_label(grp),
+            grp.gain
+        );
+        let _ = writeln!(
+            out,
+            "\n{}{:<16} {:<w$}  {:>7} {:>7} {:>7}  {:<w$}  {:>7}{}",
+            BOLD,
+            "Speaker",
+            "Temperature",
+            "Coil",
+            "Magnet",
+            "Power",
+            "Gain",
+            "dB",
+            RESET,
+            w = bar_width + 2,
+        );
+
+        for spk in status.speakers.iter().filter(|s| s.group == grp.group) {
+            let t_coil = spk.t_coil;
+            let headroom = spk.headroom as f64;
+            let heat = (t_coil - T_FLOOR) / (t_coil + headroom - T_FLOOR);
+            let gain = spk.gain as f64;
+            let reduction = gain / spk.min_gain as f64;
+
+            let _ = write!(
+                out,
+                "{:<16} {}  {:>7.1} {:>7.1} {:>6.2}W  {}  {:>7.2}",
+                spk.name,
+                bar(heat, bar_width),
+                t_coil,
+                spk.t_magnet,
+                spk.power,
+                bar(reduction, bar_width),
+                gain,
+            );
+            match (spk.enabled, spk.fault.as_ref()) {
+                (_, Some(fault)) => {
+                    let _ = write!(out, " {}quarantined: {}{}", RED, fault, RESET);
+                }
+                (false, _) => out += " disabled",
+                _ => {}
+            }
+            if spk.amp_fault != 0 {
+                let _ = write!(out, " {}amp fault 0x{:x}{}", RED, spk.amp_fault, RESET);
+            }
+            out += "\n";
+        }
+    }
+
+    out += "\nCtrl-C to quit\n";
+    out
+}
+
+/// Run the dashboard against the daemon at `socket` until interrupted
+pub fn run(socket: &Path, interval: Duration) {
+    let quit = Arc::new(AtomicBool::new(false));
+    for sig in [signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM] {
+        signal_hook::flag::register(sig, Arc::clone(&quit)).unwrap();
+    }
+
+    let mut stdout = io::stdout().lock();
+    // Alternate screen, cursor hidden
+    let _ = write!(stdout, "\x1b[?1049h\x1b[?25l");
+
+    while !quit.load(Ordering::Relaxed) {
+        // Keep going across daemon restarts, that's when it gets interesting
+        let screen = match status::query(socket, "status") {
+            Ok(Ok(reply)) => render(&reply, term_width()),
+            Ok(Err(e)) => format!("Error: {}\n", e),
+            Err(e) => format!("Failed to query daemon at {:?}: {}\n", socket, e),
+        };
+        let _ = write!(stdout, "\x1b[H\x1b[2J{}", screen);
+        let _ = stdout.flush();
+        thread::sleep(interval);
+    }
+
+    let _ = write!(stdout, "\x1b[?25h\x1b[?1049l");
+    let _ = stdout.flush();
+}
@@ -3,25 +3,71 @@
 
 use alsa::ctl::Ctl;
 use configparser::ini::Ini;
-use log::{debug, info};
+use log::{info, warn};
 use std::ffi::{CStr, CString};
 
+pub use speakersafetyd_core::types::*;
+
+use crate::caps::CardCapabilities;
 use crate::helpers;
+use crate::writer;
 
 /**
     Struct with fields necessary for manipulating an ALSA elem.
 
     The val field is created using a wrapper so that we can handle
     any errors.
+
+    Elements may have several channels (values). Writes go to all of them,
+    or just to `index` when the element is shared between speakers, one
+    channel each. Reads are of the first channel we write.
 */
 pub struct Elem {
     elem_name: String,
     id: alsa::ctl::ElemId,
     val: alsa::ctl::ElemValue,
+    count: u32,
+    index: Option<u32>,
 }
 
 impl Elem {
     pub fn new(name: String, card: &Ctl, t: alsa::ctl::ElemType) -> Elem {
+        Elem::open(name, card, t, true)
+    }
+
+    /// Open an element we only ever read, without locking it
+    pub fn new_readonly(name: String, card: &Ctl, t: alsa::ctl::ElemType) -> Elem {
+        Elem::open(name, card, t, false)
+    }
+
+    /**
+        Open channel `index` of an element shared with other speakers. The
+        first one to get here locks it for all of them, there's no telling
+        our own lock from another process's (but the instance lock makes
+        sure that's no other speakersafetyd).
+    */
+    pub fn new_shared(name: String, card: &Ctl, t: alsa::ctl::ElemType, index: u32) -> Elem {
+        let mut elem = Elem::open(name, card, t, false);
+        match card.elem_lock(&elem.id) {
+            Ok(_) => {}
+            Err(e) if e.errno() == libc::EBUSY => {
+                info!(
+                    "  {} is already locked, assuming it's shared",
+                    elem.elem_name
+                )
+            }
+            Err(e) => panic!(
+                "Could not lock elem {}. alsa-lib error: {:?}",
+                elem.elem_name, e
+            ),
+        }
+        elem.count = helpers::elem_count(card, &elem.id);
+        elem.index = Some(index);
+
+        elem
+    }
+
+    fn open(name: String, card: &Ctl, t: alsa::ctl::ElemType, lock: bool) -> Elem {
         // CString::new() cannot borrow a String. We want name for the elem
         // for error identification though, so it can't consume name directly.
         let borrow: String = name.clone();
@@ -31,6 +77,8 @@ impl Elem {
                 elem_name: name,
                 id: alsa::ctl::ElemId::new(alsa::ctl::ElemIface::Mixer),
                 val: helpers::new_elemvalue(t),
+                count: 1,
+                index: None,
             }
         };
 
@@ -39,25 +87,203 @@ impl Elem {
 
         new_elem.id.set_name(cstr);
         new_elem.val.set_id(&new_elem.id);
-        helpers::lock_el(card, &new_elem.id, &new_elem.elem_name);
+        if lock {
+            helpers::lock_el(card, &new_elem.id, &new_elem.elem_name);
+            new_elem.count = helpers::elem_count(card, &new_elem.id);
+            if new_elem.count > 1 {
+                info!("  {}: {} channels", new_elem.elem_name, new_elem.count);
+            }
+        }
         helpers::read_ev(card, &mut new_elem.val, &new_elem.elem_name);
 
         new_elem
     }
 
-    pub fn read_int(&mut self, card: &Ctl) -> i32 {
+    pub fn name(&self) -> &str {
+        &self.elem_name
+    }
+
+    pub fn id(&self) -> &alsa::ctl::ElemId {
+        &self.id
+    }
+
+    /// Number of channels, only known for elements we write
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    /// Whether the card has an integer element by this name
+    pub fn exists(name: &str, card: &Ctl) -> bool {
+        let Ok(cname) = CString::new(name) else {
+            return false;
+        };
+        let mut id = alsa::ctl::ElemId::new(alsa::ctl::ElemIface::Mixer);
+        id.set_name(&cname);
+        let mut val = helpers::new_elemvalue(alsa::ctl::ElemType::Integer);
+        val.set_id(&id);
+
+        card.elem_read(&mut val).is_ok()
+    }
+
+    /**
+        Drop and retake our lock on the element. Fails if somebody else
+        holds the lock.
+    */
+    pub fn relock(&self, card: &Ctl) -> bool {
+        let _ = card.elem_unlock(&self.id);
+        card.elem_lock(&self.id).is_ok()
+    }
+
+    /// The channels we write
+    fn channels(&self) -> std::ops::Range<u32> {
+        match self.index {
+            Some(index) => index..index + 1,
+            None => 0..self.count,
+        }
+    }
+
+    /// Read the element, with its name in any error
+    fn fetch(&mut self, card: &Ctl) {
         helpers::read_ev(card, &mut self.val, &self.elem_name);
+    }
+
+    fn get<T: ElemData>(&self, idx: u32) -> T {
+        T::get(&self.val, idx).unwrap_or_else(|| {
+            panic!(
+                "Could not read {} channel {} as {}",
+                self.elem_name,
+                idx,
+                std::any::type_name::<T>()
+            )
+        })
+    }
+
+    /// The value of the first channel we write
+    pub fn read<T: ElemData>(&mut self, card: &Ctl) -> T {
+        self.fetch(card);
+        self.get(self.channels().start)
+    }
+
+    /// Channel `idx` of a boolean element (switches, fault bits)
+    #[allow(dead_code)]
+    pub fn read_bool(&mut self, card: &Ctl, idx: u32) -> bool {
+        self.fetch(card);
+        self.get(idx)
+    }
 
+    /// The contents of a bytes element (TLV data, register dumps)
+    #[allow(dead_code)]
+    pub fn read_bytes(&mut self, card: &Ctl) -> Vec<u8> {
+        self.fetch(card);
         self.val
-            .get_integer(0)
-            .unwrap_or_else(|| panic!("Could not read {}", self.elem_name))
+            .get_bytes()
+            .unwrap_or_else(|| panic!("Could not read {} as bytes", self.elem_name))
+            .to_vec()
     }
 
-    pub fn write_int(&mut self, card: &Ctl, value: i32) {
+    /// Whether all the channels we write hold `value`
+    pub fn holds<T: ElemData>(&mut self, card: &Ctl, value: T) -> bool {
+        self.fetch(card);
+        self.channels().all(|i| T::get(&self.val, i) == Some(value))
+    }
+
+    pub fn write<T: ElemData>(&mut self, card: &Ctl, value: T) {
+        self.set(card, value);
+        helpers::write_ev(card, &self.val, &self.elem_name);
+    }
+
+    /// write(), through the writer thread. See writer::Job for `fallible`.
+    pub fn queue(&self, writer: &writer::Handle, value: i32, fallible: bool) {
+        writer.queue(writer::Job {
+            id: self.id.clone(),
+            name: self.elem_name.clone(),
+            channels: self.channels(),
+            shared: self.index.is_some(),
+            value,
+            fallible,
+        });
+    }
+
+    /// The value the writer thread last wrote to our channels
+    pub fn written(&self, writer: &writer::Handle) -> Option<i32> {
+        writer.written(&self.elem_name, self.channels().start)
+    }
+
+    /// Set the value to write on all the channels we write
+    fn set<T: ElemData>(&mut self, card: &Ctl, value: T) {
+        // Leave the other speakers' channels as they are
+        if self.index.is_some() {
+            self.fetch(card);
+        }
+        for i in self.channels() {
+            T::set(&mut self.val, i, value).unwrap_or_else(|| {
+                panic!(
+                    "Could not set {} channel {} as {}",
+                    self.elem_name,
+                    i,
+                    std::any::type_name::<T>()
+                )
+            });
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn write_bytes(&mut self, card: &Ctl, value: &[u8]) {
         self.val
-            .set_integer(0, value)
-            .unwrap_or_else(|| panic!("Could not set {}", self.elem_name));
-        helpers::write_ev(card, &mut self.val, &self.elem_name);
+            .set_bytes(value)
+            .unwrap_or_else(|| panic!("Could not set {} as bytes", self.elem_name));
+        helpers::write_ev(card, &self.val, &self.elem_name);
+    }
+}
+
+/**
+    The types element values can be read and written as, one per ALSA
+    element type: bool for Boolean, i32 for Integer, i64 for Integer64 and
+    u32 for Enumerated (the item index). Bytes elements have their own
+    methods, as they're a single value.
+*/
+pub trait ElemData: Copy + PartialEq {
+    fn get(val: &alsa::ctl::ElemValue, idx: u32) -> Option<Self>;
+    fn set(val: &mut alsa::ctl::ElemValue, idx: u32, value: Self) -> Option<()>;
+}
+
+impl ElemData for bool {
+    fn get(val: &alsa::ctl::ElemValue, idx: u32) -> Option<bool> {
+        val.get_boolean(idx)
+    }
+
+    fn set(val: &mut alsa::ctl::ElemValue, idx: u32, value: bool) -> Option<()> {
+        val.set_boolean(idx, value)
+    }
+}
+
+impl ElemData for i32 {
+    fn get(val: &alsa::ctl::ElemValue, idx: u32) -> Option<i32> {
+        val.get_integer(idx)
+    }
+
+    fn set(val: &mut alsa::ctl::ElemValue, idx: u32, value: i32) -> Option<()> {
+        val.set_integer(idx, value)
+    }
+}
+
+impl ElemData for i64 {
+    fn get(val: &alsa::ctl::ElemValue, idx: u32) -> Option<i64> {
+        val.get_integer64(idx)
+    }
+
+    fn set(val: &mut alsa::ctl::ElemValue, idx: u32, value: i64) -> Option<()> {
+        val.set_integer64(idx, value)
+    }
+}
+
+impl ElemData for u32 {
+    fn get(val: &alsa::ctl::ElemValue, idx: u32) -> Option<u32> {
+        val.get_enumerated(idx)
+    }
+
+    fn set(val: &mut alsa::ctl::ElemValue, idx: u32, value: u32) -> Option<()> {
+        val.set_enumerated(idx, value)
     }
 }
 
@@ -65,25 +291,44 @@ impl Elem {
     Mixer struct representing the controls associated with a given
     Speaker. Populated with the important ALSA controls at runtime.
 
-    level:  mixer volume control
+    level:  gain control, the dedicated limiter control if there is one,
+            otherwise the mixer volume control, or the speaker's own
+            level_control (one channel of it, with level_index)
     vsense: VSENSE switch
     isense: ISENSE switch
+    fault:  amp fault status register (optional)
+    volume: user volume control, when tracked alongside a limiter control
 
 */
-struct Mixer {
-    drv: String,
+pub struct Mixer {
     level: Elem,
     amp_gain: Elem,
+    fault: Option<Elem>,
+    volume: Option<Elem>,
+    // Values we last wrote, to detect tampering
+    level_val: Option<i32>,
+    amp_gain_val: i32,
+    /// Where level writes go, if not inline
+    writer: Option<writer::Handle>,
 }
 
 impl Mixer {
     // TODO: implement turning on V/ISENSE
-    fn new(name: &str, card: &Ctl, globals: &Globals) -> Mixer {
+    fn new(
+        name: &str,
+        card: &Ctl,
+        globals: &Globals,
+        config: &Ini,
+        caps: &mut CardCapabilities,
+        writer: Option<writer::Handle>,
+    ) -> Mixer {
         let prefix = if name == "Mono" {
             "".to_string()
         } else {
             name.to_owned() + " "
         };
+        let section = "Speaker/".to_owned() + name;
+        let level_index: Option<u32> = helpers::parse_opt_int(config, &section, "level_index");
 
         let mut vs = Elem::new(
             prefix.clone() + &globals.ctl_vsense,
@@ -91,10 +336,9 @@ impl Mixer {
             alsa::ctl::ElemType::Boolean,
         );
 
-        vs.val.set_boolean(0, true);
-        helpers::write_ev(card, &vs.val, &vs.elem_name);
-        helpers::read_ev(card, &mut vs.val, &vs.elem_name);
-        assert!(vs.val.get_boolean(0).unwrap());
+        caps.probe(card, &vs);
+        vs.write(card, true);
+        assert!(vs.holds(card, true), "{} did not turn on", vs.elem_name);
 
         let mut is = Elem::new(
             prefix.clone() + &globals.ctl_isense,
@@ -102,326 +346,256 @@ impl Mixer {
             alsa::ctl::ElemType::Boolean,
         );
 
-        is.val.set_boolean(0, true);
-        helpers::write_ev(card, &is.val, &is.elem_name);
-        helpers::read_ev(card, &mut vs.val, &vs.elem_name);
-        assert!(vs.val.get_boolean(0).unwrap());
+        caps.probe(card, &is);
+        is.write(card, true);
+        assert!(is.holds(card, true), "{} did not turn on", is.elem_name);
+
+        // Prefer a dedicated limiter control, so the user's volume stays untouched
+        let volume = prefix.clone() + &globals.ctl_volume;
+        let (level, volume) = match globals.ctl_limiter.as_ref().map(|l| prefix.clone() + l) {
+            // A control of its own, e.g. one channel of a stereo volume control
+            _ if config.get(&section, "level_control").is_some() => {
+                let level = config.get(&section, "level_control").unwrap();
+                info!("  Gain control: {}", level);
+                (level, None)
+            }
+            Some(limiter) if Elem::exists(&limiter, card) => {
+                info!("  Gain control: {}", limiter);
+                (limiter, globals.track_volume.then_some(volume))
+            }
+            Some(limiter) => {
+                info!("  No {} control, falling back to volume", limiter);
+                (volume, None)
+            }
+            None => (volume, None),
+        };
+        if globals.track_volume && volume.is_none() {
+            warn!("  Volume tracking needs a limiter control, not tracking");
+        }
+
+        // The writer thread writes the level, so it has to hold the lock
+        let writer_card = writer.as_ref().map(|w| w.card());
+        let level_card = writer_card.as_deref().unwrap_or(card);
+        let level = match level_index {
+            Some(index) => {
+                info!("  Gain control channel: {}", index);
+                let level =
+                    Elem::new_shared(level, level_card, alsa::ctl::ElemType::Integer, index);
+                if index >= level.count {
+                    panic!("{}/level_index: Out of bounds", section);
+                }
+                level
+            }
+            None => Elem::new(level, level_card, alsa::ctl::ElemType::Integer),
+        };
+        drop(writer_card);
 
         let mut ret = Mixer {
-            drv: name.to_owned(),
-            level: Elem::new(
-                prefix.clone() + &globals.ctl_volume,
-                card,
-                alsa::ctl::ElemType::Integer,
-            ),
+            level,
             amp_gain: Elem::new(
-                prefix + &globals.ctl_amp_gain,
+                prefix.clone() + &globals.ctl_amp_gain,
                 card,
                 alsa::ctl::ElemType::Integer,
             ),
+            fault: globals
+                .ctl_fault
+                .as_ref()
+                .map(|ctl| Elem::new_readonly(prefix + ctl, card, alsa::ctl::ElemType::Integer)),
+            volume: volume.map(|name| Elem::new_readonly(name, card, alsa::ctl::ElemType::Integer)),
+            level_val: None,
+            amp_gain_val: 0,
+            writer,
         };
 
+        for elem in [&ret.level, &ret.amp_gain]
+            .into_iter()
+            .chain(ret.fault.as_ref())
+            .chain(ret.volume.as_ref())
+        {
+            caps.probe(card, elem);
+        }
+
         /*
          * Set amp gain to max available (kernel should've clamped).
          * alsa-rs only has bindings for range in dB, so we go through
          * that.
          */
+        let (_min, max_int) = caps
+            .get(ret.amp_gain.name())
+            .and_then(|c| c.int_range)
+            .unwrap_or_else(|| panic!("Could not get the range of {}", ret.amp_gain.name()));
 
-        let (_min, max) =
-            helpers::get_range_db(card, &mut ret.amp_gain.id, &ret.amp_gain.elem_name);
-        let max_int = card
-            .convert_from_db(&mut ret.amp_gain.id, max, alsa::Round::Floor)
-            .unwrap();
-
-        ret.amp_gain.val.set_integer(0, max_int.try_into().unwrap());
-
-        helpers::write_ev(card, &ret.amp_gain.val, &ret.amp_gain.elem_name);
+        ret.amp_gain_val = max_int.try_into().unwrap();
+        ret.amp_gain.write(card, ret.amp_gain_val);
 
         ret
     }
 
-    fn get_amp_gain(&mut self, card: &Ctl) -> f32 {
-        helpers::read_ev(card, &mut self.amp_gain.val, &self.amp_gain.elem_name);
-
-        let val = self
-            .amp_gain
-            .val
-            .get_integer(0)
-            .unwrap_or_else(|| panic!("Could not read amp gain for {}", self.drv));
-
-        helpers::int_to_db(card, &self.amp_gain.id, val).to_db()
+    /// Whether the last level we asked for has been written
+    fn level_written(&self) -> bool {
+        self.writer
+            .as_ref()
+            .is_none_or(|w| self.level.written(w) == self.level_val)
     }
 
-    /*
-    fn get_lvl(&mut self, card: &Ctl) -> f32 {
-        helpers::read_ev(card, &mut self.level.val, &self.level.elem_name);
-
-        let val = self
-            .level
-            .val
-            .get_integer(0)
-            .expect(&format!("Could not read level for {}", self.drv));
+    /// Check that our controls still hold the values we last wrote
+    fn verify(&mut self, card: &Ctl) -> bool {
+        // A level still on its way can't be checked yet
+        let level_ok = match self.level_val {
+            Some(val) if self.level_written() => self.level.holds(card, val),
+            _ => true,
+        };
 
-        helpers::int_to_db(card, &self.level.id, val).to_db()
+        level_ok && self.amp_gain.holds(card, self.amp_gain_val)
     }
-    */
 
-    fn set_lvl(&mut self, card: &Ctl, lvl: f32) {
-        let new_val: i32 = helpers::db_to_int(card, &self.level.id, lvl);
+    /// Retake our locks and rewrite the values we expect
+    fn restore(&mut self, card: &Ctl) -> bool {
+        {
+            // The level's lock is on the writer thread's handle, if there is one
+            let writer_card = self.writer.as_ref().map(|w| w.card());
+            let level_card = writer_card.as_deref().unwrap_or(card);
+            if !self.level.relock(level_card) || !self.amp_gain.relock(card) {
+                return false;
+            }
 
-        match self.level.val.set_integer(0, new_val) {
-            Some(_) => {}
-            None => {
-                panic!("Could not set level for {}", self.drv);
+            if let Some(val) = self.level_val {
+                self.level.write(level_card, val);
             }
-        };
+        }
+        self.amp_gain.write(card, self.amp_gain_val);
 
-        helpers::write_ev(card, &self.level.val, &self.level.elem_name);
+        self.verify(card)
     }
-}
 
-#[derive(Clone)]
-pub struct Globals {
-    pub visense_pcm: usize,
-    pub channels: usize,
-    pub period: usize,
-    pub t_ambient: f32,
-    pub t_window: f32,
-    pub t_hysteresis: f32,
-    pub ctl_vsense: String,
-    pub ctl_isense: String,
-    pub ctl_amp_gain: String,
-    pub ctl_volume: String,
-    pub uclamp_min: Option<usize>,
-    pub uclamp_max: Option<usize>,
-}
+    fn get_amp_gain(&mut self, card: &Ctl) -> f32 {
+        let val = self.amp_gain.read(card);
 
-impl Globals {
-    pub fn parse(config: &Ini) -> Self {
-        Self {
-            visense_pcm: helpers::parse_int(config, "Globals", "visense_pcm"),
-            channels: helpers::parse_int(config, "Globals", "channels"),
-            period: helpers::parse_int(config, "Globals", "period"),
-            t_ambient: helpers::parse_float(config, "Globals", "t_ambient"),
-            t_window: helpers::parse_float(config, "Globals", "t_window"),
-            t_hysteresis: helpers::parse_float(config, "Globals", "t_hysteresis"),
-            ctl_vsense: helpers::parse_string(config, "Controls", "vsense"),
-            ctl_isense: helpers::parse_string(config, "Controls", "isense"),
-            ctl_amp_gain: helpers::parse_string(config, "Controls", "amp_gain"),
-            ctl_volume: helpers::parse_string(config, "Controls", "volume"),
-            uclamp_min: helpers::parse_opt_int(config, "Globals", "uclamp_min"),
-            uclamp_max: helpers::parse_opt_int(config, "Globals", "uclamp_max"),
-        }
+        helpers::int_to_db(card, &self.amp_gain.id, val).to_db()
     }
-}
 
-/**
-    Struct representing a driver. Parameters are parsed out of a config
-    file, which is loaded at runtime based on the machine's DT compatible
-    string.
-
-    name:        driver name as it appears in ALSA
-    alsa_iface:  Mixer struct with handles to the driver's control elements
-    r_dc:        dc resistance of the voice coil (ohms)
-    tau_coil:    voice coil ramp time constant (seconds)
-    tau_magnet:  magnet ramp time constant (seconds)
-    tr_coil:     thermal resistance of voice coil (*C/W)
-    t_limit:  absolute max temp of the voice coil (*C)
-
-    Borrows the handle to the control interface to do calculations.
-*/
-#[derive(Debug, Default, Copy, Clone)]
-pub struct SpeakerState {
-    pub t_coil: f64,
-    pub t_magnet: f64,
-
-    pub t_coil_hyst: f32,
-    pub t_magnet_hyst: f32,
+    fn owns(&self, name: &str) -> bool {
+        self.level.name() == name
+            || self.amp_gain.name() == name
+            || self.fault.as_ref().is_some_and(|f| f.name() == name)
+            || self.volume.as_ref().is_some_and(|v| v.name() == name)
+    }
 
-    pub min_gain: f32,
-    pub gain: f32,
-}
+    fn get_fault(&mut self, card: &Ctl) -> Option<i32> {
+        self.fault.as_mut().map(|f| f.read(card))
+    }
 
-pub struct Speaker {
-    pub name: String,
-    pub group: usize,
-    alsa_iface: Mixer,
-    tau_coil: f32,
-    tau_magnet: f32,
-    tr_coil: f32,
-    tr_magnet: f32,
-    t_limit: f32,
-    t_headroom: f32,
-    z_nominal: f32,
-    is_scale: f32,
-    vs_scale: f32,
-    is_chan: usize,
-    vs_chan: usize,
-
-    g: Globals,
-    pub s: SpeakerState,
-}
+    /// The user volume (dB), if we track it
+    fn get_volume(&mut self, card: &Ctl) -> Option<f32> {
+        let volume = self.volume.as_mut()?;
+        let val = volume.read(card);
 
-impl Speaker {
-    pub fn new(globals: &Globals, name: &str, config: &Ini, ctl: &Ctl, cold_boot: bool) -> Speaker {
-        info!("Speaker [{}]:", name);
+        Some(helpers::int_to_db(card, &volume.id, val).to_db())
+    }
 
-        let section = "Speaker/".to_owned() + name;
-        let mut new_speaker: Speaker = Speaker {
-            name: name.to_string(),
-            alsa_iface: Mixer::new(name, ctl, globals),
-            group: helpers::parse_int(config, &section, "group"),
-            tau_coil: helpers::parse_float(config, &section, "tau_coil"),
-            tau_magnet: helpers::parse_float(config, &section, "tau_magnet"),
-            tr_coil: helpers::parse_float(config, &section, "tr_coil"),
-            tr_magnet: helpers::parse_float(config, &section, "tr_magnet"),
-            t_limit: helpers::parse_float(config, &section, "t_limit"),
-            t_headroom: helpers::parse_float(config, &section, "t_headroom"),
-            z_nominal: helpers::parse_float(config, &section, "z_nominal"),
-            is_scale: helpers::parse_float(config, &section, "is_scale"),
-            vs_scale: helpers::parse_float(config, &section, "vs_scale"),
-            is_chan: helpers::parse_int(config, &section, "is_chan"),
-            vs_chan: helpers::parse_int(config, &section, "vs_chan"),
-            g: globals.clone(),
-            s: Default::default(),
-        };
+    /*
+    fn get_lvl(&mut self, card: &Ctl) -> f32 {
+        let val = self.level.read(card);
 
-        let s = &mut new_speaker.s;
+        helpers::int_to_db(card, &self.level.id, val).to_db()
+    }
+    */
 
-        s.t_coil = if cold_boot {
-            // Assume warm but not warm enough to limit
-            (new_speaker.t_limit - globals.t_window) as f64 - 1f64
+    /**
+        The level the control holds (dB), if it isn't what asking for `lvl`
+        should have given: the closest step at or below it, within the
+        control's range.
+    */
+    fn check_lvl(&mut self, card: &Ctl, lvl: f32) -> Option<f32> {
+        let val = self.level.read(card);
+        let db = |val: i32| card.convert_to_db(&self.level.id, val.into()).ok();
+        let applied = db(val)?.to_db();
+        let (min, max) = card.get_db_range(&self.level.id).ok()?;
+        let lvl = lvl.clamp(min.to_db(), max.to_db());
+
+        // Steps need not be even, so take the larger of the ones around us
+        let step = [db(val - 1), db(val + 1)]
+            .into_iter()
+            .flatten()
+            .map(|d| (d.to_db() - applied).abs())
+            .fold(0., f32::max);
+        let tolerance = 0.01;
+        if applied > lvl + tolerance || applied < lvl - step - tolerance {
+            Some(applied)
         } else {
-            // Worst case startup assumption
-            new_speaker.t_limit as f64
-        };
-        s.t_magnet = globals.t_ambient as f64
-            + (s.t_coil - globals.t_ambient as f64)
-                * (new_speaker.tr_magnet / (new_speaker.tr_magnet + new_speaker.tr_coil)) as f64;
-
-        let max_dt = new_speaker.t_limit - globals.t_ambient;
-        let max_pwr = max_dt / (new_speaker.tr_magnet + new_speaker.tr_coil);
-
-        let amp_gain = new_speaker.alsa_iface.get_amp_gain(ctl);
-
-        // Worst-case peak power is 2x RMS power
-        let peak_pwr = 10f32.powf(amp_gain / 10.) / new_speaker.z_nominal * 2.;
-
-        s.min_gain = ((max_pwr / peak_pwr).log10() * 10.).min(0.);
-
-        assert!(new_speaker.is_chan < globals.channels);
-        assert!(new_speaker.vs_chan < globals.channels);
-        assert!(new_speaker.t_limit - globals.t_window > globals.t_ambient);
-
-        info!("  Group: {}", new_speaker.group);
-        info!("  Max temperature: {:.1} °C", new_speaker.t_limit);
-        info!("  Amp gain: {} dBV", amp_gain);
-        info!("  Max power: {:.2} W", max_pwr);
-        info!("  Peak power: {} W", peak_pwr);
-        info!("  Min gain: {:.2} dB", s.min_gain);
-
-        new_speaker
+            None
+        }
     }
 
-    pub fn run_model(&mut self, buf: &[i16], sample_rate: f32) -> f32 {
-        let s = &mut self.s;
-
-        let step = 1. / sample_rate;
-        let alpha_coil = (step / (self.tau_coil + step)) as f64;
-        let alpha_magnet = (step / (self.tau_magnet + step)) as f64;
-
-        let mut pwr_sum = 0f32;
-
-        for sample in buf.chunks(self.g.channels) {
-            assert!(sample.len() == self.g.channels);
-
-            let v = sample[self.vs_chan] as f32 / 32768.0 * self.vs_scale;
-            let i = sample[self.is_chan] as f32 / 32768.0 * self.is_scale;
-            let p = v * i;
-
-            let t_coil_target = s.t_magnet + (p * self.tr_coil) as f64;
-            let t_magnet_target = (self.g.t_ambient + p * self.tr_magnet) as f64;
-
-            s.t_coil = t_coil_target * alpha_coil + s.t_coil * (1. - alpha_coil);
-            s.t_magnet = t_magnet_target * alpha_magnet + s.t_magnet * (1. - alpha_magnet);
-
-            if s.t_coil > (self.t_limit + self.t_headroom) as f64 {
-                panic!(
-                    "{}: Coil temperature limit exceeded ({} > {})",
-                    self.name, s.t_coil, self.t_limit
-                );
-            }
-            if s.t_magnet > (self.t_limit + self.t_headroom) as f64 {
-                panic!(
-                    "{}: Magnet temperature limit exceeded ({} > {})",
-                    self.name, s.t_magnet, self.t_limit
-                );
-            }
+    fn set_lvl(&mut self, card: &Ctl, lvl: f32) {
+        let new_val: i32 = helpers::db_to_int(card, &self.level.id, lvl);
 
-            pwr_sum += p;
+        match self.writer.as_ref() {
+            Some(writer) => self.level.queue(writer, new_val, false),
+            None => self.level.write(card, new_val),
         }
+        self.level_val = Some(new_val);
+    }
+}
 
-        let pwr_avg: f32 = pwr_sum / ((buf.len() / self.g.channels) as f32);
-        /*
-         * This really shouldn't happen other than rounding error,
-         * if it does there's probably something wrong with the ivsense
-         * data.
-         */
-        if pwr_avg < -0.01 {
-            panic!(
-                "{}: Negative power, bad ivsense data? ({})",
-                self.name, pwr_avg
-            );
-        }
-        let pwr_avg = pwr_avg.max(0.0);
-
-        s.t_coil_hyst = s
-            .t_coil_hyst
-            .max(s.t_coil as f32)
-            .min(s.t_coil as f32 + self.g.t_hysteresis);
-        s.t_magnet_hyst = s
-            .t_magnet_hyst
-            .max(s.t_magnet as f32)
-            .min(s.t_magnet as f32 + self.g.t_hysteresis);
-
-        let temp = s.t_coil_hyst.max(s.t_magnet_hyst);
-
-        let reduction = (temp - (self.t_limit - self.g.t_window)) / self.g.t_window;
-        let gain = s.min_gain * reduction.max(0.);
-
-        s.gain = gain;
+impl Controls for Mixer {
+    type Handle = Ctl;
 
-        if s.gain > -0.01 {
-            s.gain = 0.;
-        }
+    fn volume(&mut self, card: &Ctl) -> Option<f32> {
+        self.get_volume(card)
+    }
 
-        debug!(
-            "{:>15}: Coil {:>6.2} °C Magnet {:>6.2} °C Power {:>5.2} W Gain {:>6.2} dB",
-            self.name, s.t_coil, s.t_magnet, pwr_avg, s.gain
-        );
+    fn fault(&mut self, card: &Ctl) -> Option<i32> {
+        self.get_fault(card)
+    }
 
-        s.gain
+    fn owns(&self, name: &str) -> bool {
+        Mixer::owns(self, name)
     }
 
-    pub fn skip_model(&mut self, time: f64) {
-        let s = &mut self.s;
-        let t_coil = s.t_coil - self.g.t_ambient as f64;
-        let t_magnet = s.t_magnet - self.g.t_ambient as f64;
+    fn verify(&mut self, card: &Ctl) -> bool {
+        Mixer::verify(self, card)
+    }
 
-        let eta = 1f64 / (1f64 - (self.tau_coil / self.tau_magnet) as f64);
-        let a = (-time / self.tau_coil as f64).exp() * (t_coil - eta * t_magnet);
-        let b = (-time / self.tau_magnet as f64).exp() * t_magnet;
+    fn restore(&mut self, card: &Ctl) -> bool {
+        Mixer::restore(self, card)
+    }
 
-        s.t_coil = self.g.t_ambient as f64 + a + b * eta;
-        s.t_magnet = self.g.t_ambient as f64 + b;
+    fn set_level(&mut self, card: &Ctl, gain: f32) {
+        self.set_lvl(card, gain)
+    }
 
-        debug!(
-            "{}: SKIP: Coil {:.2} °C Magnet {:.2} °C ({:.2} seconds)",
-            self.name, s.t_coil, s.t_magnet, time
-        );
+    fn level_settled(&self) -> bool {
+        self.level_written()
     }
 
-    pub fn update(&mut self, ctl: &Ctl, gain: f32) {
-        self.alsa_iface.set_lvl(ctl, gain);
+    fn check_level(&mut self, card: &Ctl, gain: f32) -> Option<f32> {
+        self.check_lvl(card, gain)
     }
 }
+
+/// A speaker driven through its ALSA controls
+pub type Speaker = speakersafetyd_core::types::Speaker<Mixer>;
+
+/**
+    Set up the named speaker, taking over its controls on the card. Level
+    writes go through `writer` if given, inline otherwise.
+*/
+pub fn new_speaker(
+    globals: &Globals,
+    name: &str,
+    config: &Ini,
+    ctl: &Ctl,
+    cold_boot: bool,
+    caps: &mut CardCapabilities,
+    writer: Option<writer::Handle>,
+) -> Speaker {
+    let mut mixer = Mixer::new(name, ctl, globals, config, caps, writer);
+    let amp_gain = mixer.get_amp_gain(ctl);
+    let level = mixer.level.name().to_string();
+    let amp_gain_name = mixer.amp_gain.name().to_string();
+    let speaker = Speaker::with_controls(globals, name, config, amp_gain, cold_boot, mixer, ctl);
+    caps.check_speaker(name, &level, &amp_gain_name, speaker.min_gain_full(), amp_gain);
+
+    speaker
+}
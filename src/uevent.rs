@@ -0,0 +1,136 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    Kernel uevents of the sound subsystem, straight from netlink rather
+    than through udev, which may not be running yet when we start. They
+    tell us about the card going away (e.g. its driver being unbound) as it
+    happens, even while the PCM is closed and no control changes.
+*/
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+use log::warn;
+
+/// The multicast group of the events as the kernel sends them
+const KERNEL_GROUP: u32 = 1;
+
+#[derive(Debug)]
+pub struct Uevent {
+    /// "add", "remove", "change" and so on
+    pub action: String,
+    /// Relative to /sys
+    pub devpath: String,
+}
+
+impl Uevent {
+    /// Whether this is card `index` (rather than one of its devices) going away
+    pub fn card_removed(&self, index: i32) -> bool {
+        self.action == "remove" && self.devpath.ends_with(&format!("/sound/card{}", index))
+    }
+}
+
+pub struct Uevents {
+    sock: OwnedFd,
+}
+
+impl Uevents {
+    pub fn new() -> io::Result<Uevents> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                libc::NETLINK_KOBJECT_UEVENT,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let sock = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut addr: libc::sockaddr_nl = unsafe { core::mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = KERNEL_GROUP;
+        if unsafe {
+            libc::bind(
+                fd,
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                core::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        } != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Uevents { sock })
+    }
+
+    /// Drain the pending events of the sound subsystem, without blocking
+    pub fn read(&self) -> Vec<Uevent> {
+        let mut events = Vec::new();
+        let mut buf = [0u8; 8192];
+
+        loop {
+            let mut addr: libc::sockaddr_nl = unsafe { core::mem::zeroed() };
+            let mut len = core::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t;
+            let n = unsafe {
+                libc::recvfrom(
+                    self.sock.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    0,
+                    &mut addr as *mut libc::sockaddr_nl as *mut libc::sockaddr,
+                    &mut len,
+                )
+            };
+            if n < 0 {
+                let e = io::Error::last_os_error();
+                match e.raw_os_error() {
+                    Some(libc::EAGAIN) => break,
+                    // Some got dropped, the rest is still there
+                    Some(libc::ENOBUFS) | Some(libc::EINTR) => continue,
+                    _ => {
+                        warn!("Failed to read uevents: {}", e);
+                        break;
+                    }
+                }
+            }
+            // Only the kernel's own, nobody else's
+            if addr.nl_pid != 0 {
+                continue;
+            }
+            if let Some(ev) = parse(&buf[..n as usize]) {
+                events.push(ev);
+            }
+        }
+
+        events
+    }
+}
+
+impl AsRawFd for Uevents {
+    fn as_raw_fd(&self) -> RawFd {
+        self.sock.as_raw_fd()
+    }
+}
+
+/// "action@devpath", then NUL separated KEY=value pairs
+fn parse(msg: &[u8]) -> Option<Uevent> {
+    let (mut action, mut devpath, mut subsystem) = (None, None, None);
+
+    for field in msg.split(|&b| b == 0).skip(1) {
+        let Ok(field) = std::str::from_utf8(field) else {
+            continue;
+        };
+        match field.split_once('=') {
+            Some(("ACTION", v)) => action = Some(v),
+            Some(("DEVPATH", v)) => devpath = Some(v),
+            Some(("SUBSYSTEM", v)) => subsystem = Some(v),
+            _ => {}
+        }
+    }
+
+    (subsystem? == "sound").then(|| Uevent {
+        action: action.unwrap_or_default().to_string(),
+        devpath: devpath.unwrap_or_default().to_string(),
+    })
+}
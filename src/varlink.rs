@@ -0,0 +1,296 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    The control and status interface over varlink, as
+    `org.asahilinux.speakersafetyd`, for scripts and tools that would rather
+    discover the interface than learn the status socket's line protocol:
+
+        varlinkctl introspect /run/org.asahilinux.speakersafetyd
+        varlinkctl call /run/org.asahilinux.speakersafetyd \
+            org.asahilinux.speakersafetyd.GetStatus '{}'
+
+    Calls are translated into status socket requests and answered the same
+    way, with the same permission checks, so the two can't drift apart.
+    Only what's needed of the protocol is implemented: calls are answered
+    one at a time, `more` is ignored and idle connections are closed after
+    CLIENT_TIMEOUT.
+*/
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::schema;
+use crate::shared::Snapshot;
+use crate::status::{self, ActionQueue, Reply, Status};
+
+const INTERFACE: &str = "org.asahilinux.speakersafetyd";
+const SERVICE: &str = "org.varlink.service";
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+const DESCRIPTION: &str = "\
+# Speaker protection daemon control and status
+interface org.asahilinux.speakersafetyd
+
+# The daemon's state, as in `speakersafetyd status --json`
+method GetStatus() -> (status: object)
+
+# The limits each speaker is held to, as in `speakersafetyd limits --json`
+method GetLimits() -> (limits: object)
+
+# Re-enable a speaker
+method Enable(speaker: string) -> ()
+
+# Disable a speaker, holding it at min gain
+method Disable(speaker: string) -> ()
+
+# Switch to a config profile (null for the default) and restart
+method SetProfile(profile: ?string) -> ()
+
+# Save the blackbox now
+method SaveBlackbox() -> ()
+
+# off, error, warn, info, debug or trace
+method SetLogLevel(level: string) -> ()
+
+# Restart, picking up config changes
+method Reload() -> ()
+
+# Relax the limiter for this many seconds (up to 60), if there is the
+# thermal headroom for it
+method Boost(seconds: float) -> ()
+
+# Hold the speakers at min gain and leave them to the kernel's limits
+method SetSafeMode(on: bool) -> ()
+
+# Only root and the control group may change anything
+error PermissionDenied ()
+
+# The daemon turned the request down
+error RequestFailed (message: string)
+";
+
+const SERVICE_DESCRIPTION: &str = "\
+# The Varlink Service Interface is provided by every varlink service. It
+# describes the service and the interfaces it implements.
+interface org.varlink.service
+
+# Get a list of all the interfaces a service provides and information
+# about the implementation.
+method GetInfo() -> (
+  vendor: string,
+  product: string,
+  version: string,
+  url: string,
+  interfaces: []string
+)
+
+# Get the description of an interface that is implemented by this service.
+method GetInterfaceDescription(interface: string) -> (description: string)
+
+# The requested interface was not found.
+error InterfaceNotFound (interface: string)
+
+# The requested method was not found
+error MethodNotFound (method: string)
+
+# The interface defines the requested method, but the service does not
+# implement it.
+error MethodNotImplemented (method: string)
+
+# One of the passed parameters is invalid.
+error InvalidParameter (parameter: string)
+";
+
+/// A message from a client
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Call {
+    method: String,
+    /// Whatever the method takes
+    parameters: Value,
+    /// No reply wanted
+    oneway: bool,
+}
+
+fn error(name: &str, parameters: Value) -> Value {
+    json!({
+        "error": name,
+        "parameters": parameters,
+    })
+}
+
+fn invalid(parameter: &str) -> Value {
+    error(
+        &format!("{}.InvalidParameter", SERVICE),
+        json!({ "parameter": parameter }),
+    )
+}
+
+/// The status socket request for a call to one of our methods
+fn request(method: &str, params: &Value) -> Result<String, Value> {
+    let string = |name: &str| params[name].as_str().ok_or_else(|| invalid(name));
+
+    Ok(match method {
+        "GetStatus" => "status".into(),
+        "GetLimits" => "limits".into(),
+        "Enable" => format!("enable {}", string("speaker")?),
+        "Disable" => format!("disable {}", string("speaker")?),
+        "SetProfile" if params["profile"].is_null() => "profile".into(),
+        "SetProfile" => format!("profile {}", string("profile")?),
+        "SaveBlackbox" => "blackbox".into(),
+        "SetLogLevel" => format!("loglevel {}", string("level")?),
+        "Reload" => "reload".into(),
+        "Boost" => match params["seconds"].as_f64() {
+            Some(seconds) => format!("boost {}", seconds as f32),
+            None => return Err(invalid("seconds")),
+        },
+        "SetSafeMode" => match params["on"].as_bool() {
+            Some(true) => "safe".into(),
+            Some(false) => "safe off".into(),
+            None => return Err(invalid("on")),
+        },
+        _ => {
+            return Err(error(
+                &format!("{}.MethodNotFound", SERVICE),
+                json!({ "method": format!("{}.{}", INTERFACE, method) }),
+            ))
+        }
+    })
+}
+
+fn call(
+    stream: &UnixStream,
+    call: &Call,
+    status: &Snapshot<Status>,
+    tx: &ActionQueue,
+    control_gid: Option<u32>,
+) -> Value {
+    let params = &call.parameters;
+
+    match call.method.rsplit_once('.') {
+        Some((SERVICE, "GetInfo")) => json!({
+            "parameters": {
+                "vendor": "Asahi Linux",
+                "product": "speakersafetyd",
+                "version": env!("CARGO_PKG_VERSION"),
+                "url": env!("CARGO_PKG_REPOSITORY"),
+                "interfaces": [SERVICE, INTERFACE],
+            }
+        }),
+        Some((SERVICE, "GetInterfaceDescription")) => match params["interface"].as_str() {
+            Some(INTERFACE) => json!({ "parameters": { "description": DESCRIPTION } }),
+            Some(SERVICE) => json!({ "parameters": { "description": SERVICE_DESCRIPTION } }),
+            Some(other) => error(
+                &format!("{}.InterfaceNotFound", SERVICE),
+                json!({ "interface": other }),
+            ),
+            None => invalid("interface"),
+        },
+        Some((INTERFACE, name)) => {
+            let request = match request(name, params) {
+                Ok(request) => request,
+                Err(e) => return e,
+            };
+            match status::handle_request(stream, &request, status, tx, control_gid) {
+                Reply::Error { error: message } if message == "Permission denied" => {
+                    error(&format!("{}.PermissionDenied", INTERFACE), json!({}))
+                }
+                Reply::Error { error: message } => error(
+                    &format!("{}.RequestFailed", INTERFACE),
+                    json!({ "message": message }),
+                ),
+                Reply::Status(status) => json!({ "parameters": { "status": status } }),
+                Reply::Limits(limits) => json!({ "parameters": { "limits": limits } }),
+                Reply::Done { .. } => json!({ "parameters": {} }),
+            }
+        }
+        Some((interface, _)) => error(
+            &format!("{}.InterfaceNotFound", SERVICE),
+            json!({ "interface": interface }),
+        ),
+        None => invalid("method"),
+    }
+}
+
+fn handle_client(
+    stream: UnixStream,
+    status: &Snapshot<Status>,
+    tx: &ActionQueue,
+    control_gid: Option<u32>,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+    // Messages are JSON objects, each terminated by a NUL
+    let mut reader = BufReader::new(&stream);
+    loop {
+        let mut message = Vec::new();
+        match reader.read_until(0, &mut message) {
+            // Idle for too long
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Ok(())
+            }
+            Err(e) => return Err(e),
+            Ok(_) if message.pop() != Some(0) => return Ok(()),
+            Ok(_) => {}
+        }
+        let Ok(message) = serde_json::from_slice::<Call>(&message) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid message",
+            ));
+        };
+
+        let reply = call(&stream, &message, status, tx, control_gid);
+        if !message.oneway {
+            let mut reply = schema::dump(&reply).into_bytes();
+            reply.push(0);
+            (&stream).write_all(&reply)?;
+        }
+    }
+}
+
+/// Serve the interface on `path`, with the status server's state and queue
+pub fn serve(
+    path: &Path,
+    status: Arc<Snapshot<Status>>,
+    tx: ActionQueue,
+    control_gid: Option<u32>,
+) -> io::Result<()> {
+    // Clean up after a previous instance that did not exit cleanly
+    let _ = fs::remove_file(path);
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o666))?;
+    info!("Varlink socket: {:?}", path);
+
+    thread::Builder::new()
+        .name("varlink".into())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(e) = handle_client(stream, &status, &tx, control_gid) {
+                            warn!("Varlink client error: {}", e);
+                        }
+                    }
+                    Err(e) => warn!("Varlink socket accept failed: {}", e),
+                }
+            }
+        })?;
+
+    Ok(())
+}
@@ -0,0 +1,229 @@
+// SPDX-License-Identifier: MIT
+// (C) 2022 The Asahi Linux Contributors
+/*!
+    Control writes, off the capture path. An elem_write can block for a
+    long time (suspend transitions are the known case), and while the loop
+    sits in one nobody drains the capture buffer, which overruns and ends
+    in an EPIPE panic. So the writes of the steady state, the speaker levels
+    and the unlock heartbeat, are queued for a thread of their own, and
+    setup and the rare restores stay inline.
+
+    A write still queued is replaced by a newer one to the same control
+    channel, only the latest value matters, so a stalled write doesn't
+    leave a backlog behind. The loop hears how the writes went on its next
+    period through check(): level writes that fail panic there, as they did
+    inline, and the heartbeat collects the outcome of its own writes.
+
+    The kernel ties control locks to the file they were taken on, so the
+    thread has a control handle of its own, and the controls it writes are
+    locked on that. The loop borrows it for the writes to those controls
+    that stay inline, see Handle::card().
+*/
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use alsa::ctl::Ctl;
+use log::warn;
+
+use crate::helpers;
+use crate::shared::PiMutex;
+
+/// Writes taking longer than this get reported
+const STALL: Duration = Duration::from_secs(1);
+
+/// A control channel: element name and first channel written
+type Key = (String, u32);
+
+/// A queued write of `value` to `channels` of an integer element
+pub struct Job {
+    pub id: alsa::ctl::ElemId,
+    pub name: String,
+    pub channels: Range<u32>,
+    /// Whether other channels belong to somebody else and must be kept
+    pub shared: bool,
+    pub value: i32,
+    /// Whether failures are for the caller to collect, rather than fatal
+    pub fallible: bool,
+}
+
+#[derive(Default)]
+struct State {
+    queue: BTreeMap<Key, Job>,
+    /// The write in progress, since when, and whether it was reported
+    busy: Option<(String, Instant, bool)>,
+    /// The value last written to each control channel
+    written: BTreeMap<Key, i32>,
+    /// Outcomes of fallible writes, until collected
+    outcomes: BTreeMap<String, alsa::Result<()>>,
+    /// A write that failed for good
+    fatal: Option<String>,
+    quit: bool,
+}
+
+/// The loop takes the state lock every period, see shared.rs
+struct Shared {
+    state: PiMutex<State>,
+    /// Our own handle on the card, held across the writes
+    card: Mutex<Ctl>,
+    /// Rung after queueing, holds one ring
+    doorbell: SyncSender<()>,
+}
+
+/// Where to queue writes, for whoever owns the controls
+#[derive(Clone)]
+pub struct Handle(Arc<Shared>);
+
+impl Handle {
+    /// Queue `job`, replacing any write to the same control channel still queued
+    pub fn queue(&self, job: Job) {
+        let key = (job.name.clone(), job.channels.start);
+        self.0.state.lock().queue.insert(key, job);
+        let _ = self.0.doorbell.try_send(());
+    }
+
+    /// The value last written to `channel` of the control `name`
+    pub fn written(&self, name: &str, channel: u32) -> Option<i32> {
+        let state = self.0.state.lock();
+        state.written.get(&(name.to_string(), channel)).copied()
+    }
+
+    /// How the last fallible write to `name` went, once it's done
+    pub fn outcome(&self, name: &str) -> Option<alsa::Result<()>> {
+        self.0.state.lock().outcomes.remove(name)
+    }
+
+    /**
+        The thread's control handle, to lock the controls it writes on and
+        for the writes to them that stay inline. Waits for any write in
+        progress, so not for the steady state.
+    */
+    pub fn card(&self) -> MutexGuard<'_, Ctl> {
+        self.0.card.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+pub struct Writer {
+    handle: Handle,
+    thread: Option<JoinHandle<()>>,
+}
+
+fn write(card: &Ctl, job: &Job) -> alsa::Result<()> {
+    let mut val = helpers::new_elemvalue(alsa::ctl::ElemType::Integer);
+    val.set_id(&job.id);
+    // Leave the other speakers' channels as they are
+    if job.shared {
+        card.elem_read(&mut val)?;
+    }
+    for i in job.channels.clone() {
+        val.set_integer(i, job.value)
+            .unwrap_or_else(|| panic!("Could not set {} channel {}", job.name, i));
+    }
+    helpers::try_write_ev(card, &val, &job.name)
+}
+
+fn run(shared: Arc<Shared>, doorbell: Receiver<()>) {
+    for () in doorbell.iter() {
+        loop {
+            let mut state = shared.state.lock();
+            let Some((key, job)) = state.queue.pop_first() else {
+                break;
+            };
+            state.busy = Some((job.name.clone(), Instant::now(), false));
+            drop(state);
+
+            let card = shared.card.lock().unwrap_or_else(PoisonError::into_inner);
+            let result = write(&card, &job);
+            drop(card);
+
+            let mut state = shared.state.lock();
+            if let Some((_, since, true)) = state.busy.take() {
+                warn!(
+                    "Control write to {} went through after {:.1} s",
+                    job.name,
+                    since.elapsed().as_secs_f64()
+                );
+            }
+            match result {
+                Ok(()) => {
+                    state.written.insert(key, job.value);
+                }
+                Err(e) if !job.fallible => {
+                    state.fatal.get_or_insert(format!(
+                        "Could not write elem value {}. alsa-lib error: {:?}",
+                        job.name, e
+                    ));
+                }
+                Err(_) => {}
+            }
+            if job.fallible {
+                state.outcomes.insert(job.name, result);
+            }
+        }
+        // Anything still queued went out before quitting
+        if shared.state.lock().quit {
+            return;
+        }
+    }
+}
+
+impl Writer {
+    /// Start the writer thread, with a handle of its own on `card`
+    pub fn new(card: &str) -> Writer {
+        let (doorbell, rx) = mpsc::sync_channel(1);
+        let shared = Arc::new(Shared {
+            state: PiMutex::default(),
+            card: Mutex::new(helpers::open_card(card)),
+            doorbell,
+        });
+        let thread = {
+            let shared = Arc::clone(&shared);
+            thread::Builder::new()
+                .name("ctl-writer".into())
+                .spawn(move || run(shared, rx))
+                .expect("Failed to start control writer thread")
+        };
+
+        Writer {
+            handle: Handle(shared),
+            thread: Some(thread),
+        }
+    }
+
+    pub fn handle(&self) -> Handle {
+        self.handle.clone()
+    }
+
+    /**
+        Check on the writes since the last period: panic if one failed for
+        good, and report a write that has been stuck for a while, once.
+    */
+    pub fn check(&self) {
+        let mut state = self.handle.0.state.lock();
+        if let Some(fatal) = state.fatal.take() {
+            drop(state);
+            panic!("{}", fatal);
+        }
+        let queued = state.queue.len();
+        if let Some((name, since, reported)) = state.busy.as_mut() {
+            if !*reported && since.elapsed() > STALL {
+                *reported = true;
+                warn!("Control write to {} is stuck, {} more queued", name, queued);
+            }
+        }
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        self.handle.0.state.lock().quit = true;
+        let _ = self.handle.0.doorbell.try_send(());
+        // Wait for what's still queued to go out
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -1,4 +1,4 @@
-import json, sys, os.path, configparser
+import json, sys, os.path, configparser, struct
 import numpy as np
 import matplotlib.pyplot as plt
 from scipy.signal import butter, sosfilt, freqz
@@ -70,7 +70,7 @@ class Model:
         self.is_chan = int(conf["is_chan"])
         self.vs_chan = int(conf["vs_chan"])
 
-        self.t_ambient = an.fdr["t_ambient"]
+        self.t_ambient = float(conf.get("t_ambient", an.fdr["t_ambient"]))
 
         self.t_coil = an.fdr["blocks"][0]["speakers"][self.idx]["t_coil"]
         self.t_magnet = an.fdr["blocks"][0]["speakers"][self.idx]["t_magnet"]
@@ -188,17 +188,34 @@ class Model:
         plt.savefig(outfile)
 
 
+BLACKBOX_MAGIC = b"SSDBBOX\0"
+
+def load_blackbox(path):
+    """Load a blackbox dump: a v2 .bbox file, or a v1 .fdr/.cvr pair (either file or the base name)"""
+    if path.endswith(".bbox"):
+        data = open(path, "rb").read()
+        assert data[:8] == BLACKBOX_MAGIC, "Not a blackbox file"
+        version, hlen = struct.unpack("<II", data[8:16])
+        assert version == 2, f"Unsupported blackbox version {version}"
+        return json.loads(data[16:16 + hlen]), data[16 + hlen:]
+
+    base = os.path.splitext(path)[0] if path.endswith((".fdr", ".cvr")) else path
+    return json.load(open(base + ".fdr")), open(base + ".cvr", "rb").read()
+
 class Analyzer:
-    def __init__(self, base):
-        self.fdr = json.load(open(base + ".fdr"))
-        data = open(base + ".cvr", "rb").read()
+    def __init__(self, path):
+        self.fdr, data = load_blackbox(path)
         cvr = np.frombuffer(data, dtype="int16").astype("float") / 32768
 
-        maker, model = self.fdr["machine"].split(",")
-        cf = os.path.join(CONFDIR, maker, model + ".conf")
-        print(f"Using config file: {cf}")
         self.conf = configparser.ConfigParser()
-        self.conf.read(cf)
+        if "config" in self.fdr:
+            print("Using config from the blackbox")
+            self.conf.read_string(self.fdr["config"])
+        else:
+            maker, model = self.fdr["machine"].split(",")
+            cf = os.path.join(CONFDIR, maker, model + ".conf")
+            print(f"Using config file: {cf}")
+            self.conf.read(cf)
 
         ch = int(self.conf["Globals"]["channels"])
         samples = len(cvr) // ch